@@ -1,25 +1,158 @@
 extern crate bindgen;
 
+use std::cell::RefCell;
 use std::env;
+use std::fmt::Write;
 use std::path::PathBuf;
+use std::rc::Rc;
+
+use bindgen::callbacks::{IntKind, ParseCallbacks};
+
+/// Where to find the `SQLite` headers used to generate bindings.
+enum Headers {
+    /// The headers vendored in `sqlite3/`
+    Vendored,
+    /// `sqlite3ext.h` from the directory named by `SQLITE3_INCLUDE_DIR`
+    Dir(PathBuf),
+    /// `sqlite3ext.h` from the compiler's default include path
+    System,
+}
+
+impl Headers {
+    fn builder(&self) -> bindgen::Builder {
+        let builder = bindgen::Builder::default()
+            .use_core()
+            .default_macro_constant_type(bindgen::MacroTypeVariation::Signed);
+        match self {
+            Headers::Vendored => builder.header("sqlite3/sqlite3ext.h"),
+            Headers::Dir(dir) => builder
+                .header(dir.join("sqlite3ext.h").to_string_lossy())
+                .clang_arg(format!("-I{}", dir.display())),
+            Headers::System => builder.header_contents("wrapper.h", "#include <sqlite3ext.h>"),
+        }
+    }
+}
+
+/// The primary result codes. Extended result codes are named after their
+/// primary code and share its low byte.
+const PRIMARY_RESULT_CODES: &[&str] = &[
+    "SQLITE_OK",
+    "SQLITE_ERROR",
+    "SQLITE_INTERNAL",
+    "SQLITE_PERM",
+    "SQLITE_ABORT",
+    "SQLITE_BUSY",
+    "SQLITE_LOCKED",
+    "SQLITE_NOMEM",
+    "SQLITE_READONLY",
+    "SQLITE_INTERRUPT",
+    "SQLITE_IOERR",
+    "SQLITE_CORRUPT",
+    "SQLITE_NOTFOUND",
+    "SQLITE_FULL",
+    "SQLITE_CANTOPEN",
+    "SQLITE_PROTOCOL",
+    "SQLITE_EMPTY",
+    "SQLITE_SCHEMA",
+    "SQLITE_TOOBIG",
+    "SQLITE_CONSTRAINT",
+    "SQLITE_MISMATCH",
+    "SQLITE_MISUSE",
+    "SQLITE_NOLFS",
+    "SQLITE_AUTH",
+    "SQLITE_FORMAT",
+    "SQLITE_RANGE",
+    "SQLITE_NOTADB",
+    "SQLITE_NOTICE",
+    "SQLITE_WARNING",
+    "SQLITE_ROW",
+    "SQLITE_DONE",
+];
+
+/// Collects the integer constants seen by bindgen, in header order.
+#[derive(Debug, Default, Clone)]
+struct IntMacros(Rc<RefCell<Vec<(String, i64)>>>);
+
+impl ParseCallbacks for IntMacros {
+    fn int_macro(&self, name: &str, value: i64) -> Option<IntKind> {
+        self.0.borrow_mut().push((name.to_owned(), value));
+        None
+    }
+}
+
+impl IntMacros {
+    /// Returns the result codes defined by the headers, skipping aliases of
+    /// codes which were already seen.
+    fn result_codes(&self) -> Vec<String> {
+        let macros = self.0.borrow();
+        let primary_value = |name: &str| {
+            macros
+                .iter()
+                .find(|(n, _)| n == name)
+                .map(|(_, value)| *value)
+        };
+        let mut seen = Vec::new();
+        let mut codes = Vec::new();
+        for (name, value) in macros.iter() {
+            let is_code = PRIMARY_RESULT_CODES.iter().any(|primary| {
+                name == primary
+                    || (name.starts_with(&format!("{primary}_"))
+                        && primary_value(primary) == Some(value & 0xff))
+            });
+            if is_code && !seen.contains(value) {
+                seen.push(*value);
+                codes.push(name.clone());
+            }
+        }
+        codes
+    }
+}
 
 fn main() {
     println!("cargo:rerun-if-changed=sqlite3/sqlite3.h");
     println!("cargo:rerun-if-changed=sqlite3/sqlite3ext.h");
+    println!("cargo:rerun-if-env-changed=SQLITE3_INCLUDE_DIR");
+    println!("cargo:rerun-if-env-changed=SQLITE3_LIB_DIR");
+
+    let system_sqlite = env::var_os("CARGO_FEATURE_SYSTEM_SQLITE").is_some();
 
-    let vars = bindgen::Builder::default()
-        .header("sqlite3/sqlite3ext.h")
+    // SQLITE3_INCLUDE_DIR takes precedence, so the system-sqlite feature can
+    // be pointed at headers outside of the default include path
+    let headers = match env::var_os("SQLITE3_INCLUDE_DIR") {
+        Some(dir) => {
+            let dir = PathBuf::from(dir);
+            println!("cargo:rerun-if-changed={}", dir.join("sqlite3.h").display());
+            println!(
+                "cargo:rerun-if-changed={}",
+                dir.join("sqlite3ext.h").display()
+            );
+            Headers::Dir(dir)
+        }
+        None if system_sqlite => Headers::System,
+        None => Headers::Vendored,
+    };
+
+    if system_sqlite {
+        if let Some(dir) = env::var_os("SQLITE3_LIB_DIR") {
+            println!(
+                "cargo:rustc-link-search=native={}",
+                PathBuf::from(dir).display()
+            );
+        }
+        println!("cargo:rustc-link-lib=sqlite3");
+    }
+
+    let int_macros = IntMacros::default();
+    let vars = headers
+        .builder()
         .allowlist_item("SQLITE_.*")
-        .use_core()
-        .default_macro_constant_type(bindgen::MacroTypeVariation::Signed)
+        .parse_callbacks(Box::new(int_macros.clone()))
         .generate()
         .expect("Unable to generate bindings");
 
-    let bindings = bindgen::Builder::default()
-        .header("sqlite3/sqlite3ext.h")
+    let bindings = headers
+        .builder()
         .blocklist_item("SQLITE_.*")
-        .use_core()
-        .default_macro_constant_type(bindgen::MacroTypeVariation::Signed)
         .generate()
         .expect("Unable to generate bindings");
 
@@ -29,4 +162,14 @@ fn main() {
     bindings
         .write_to_file(out_path.join("bindings.rs"))
         .expect("Couldn't write bindings!");
+
+    // src/error.rs defines the result_codes! macro which turns this list into
+    // a table of names
+    let mut result_codes = String::from("result_codes! {\n");
+    for code in int_macros.result_codes() {
+        writeln!(result_codes, "    {code},").unwrap();
+    }
+    result_codes.push_str("}\n");
+    std::fs::write(out_path.join("result_codes.rs"), result_codes)
+        .expect("Couldn't write result codes!");
 }
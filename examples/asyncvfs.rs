@@ -0,0 +1,436 @@
+// cargo run --example asyncvfs
+//
+// A vfs backed by an async object store, showing how to drive async I/O from
+// SQLite's synchronous vfs methods.
+//
+// The vfs owns a tokio runtime, and each open file is served by an actor task
+// running on it. The `Vfs::Handle` carries a channel to its actor, and every
+// vfs method sends a request and blocks the calling thread on a std channel
+// until the actor replies. Writes are buffered by the actor and uploaded when
+// SQLite syncs the file, which is how most object store vfs's batch writes.
+//
+// Avoiding deadlocks:
+// - Don't call `Runtime::block_on` or `oneshot::Receiver::blocking_recv` from
+//   vfs methods. SQLite may be called from inside an async task, where both
+//   panic. Waiting on a std channel works from any thread.
+// - Waiting still blocks the calling thread. Run the vfs's futures on a
+//   runtime SQLite is never called from, otherwise a connection used inside a
+//   `current_thread` runtime blocks the only thread able to make progress.
+// - SQLite holds the connection's mutex while it calls into the vfs, so the
+//   actor must never call back into SQLite, for example to log through the
+//   same connection.
+// - Don't hold a lock while waiting for a reply if the actor needs it.
+//
+// Locking is a no-op, so only one connection may write at a time. A real
+// implementation would acquire a lease from the store in `lock`, bounded by
+// the connection's busy timeout.
+
+use std::{
+    collections::HashMap,
+    sync::{
+        Arc,
+        atomic::{AtomicU64, Ordering},
+        mpsc::{SyncSender, sync_channel},
+    },
+    time::Duration,
+};
+
+use sqlite_plugin::{
+    flags::{AccessFlags, LockLevel, OpenOpts, SyncFlags},
+    vars,
+    vfs::{RegisterOpts, Vfs, VfsHandle, VfsResult, register_static_simple},
+};
+use tokio::{
+    runtime::Runtime,
+    sync::{RwLock, mpsc},
+};
+
+/// A stand-in for an async object store client, such as an S3 SDK.
+#[derive(Clone, Default)]
+struct ObjectStore {
+    objects: Arc<RwLock<HashMap<String, Vec<u8>>>>,
+}
+
+impl ObjectStore {
+    /// Simulates a network round trip.
+    async fn round_trip() {
+        tokio::time::sleep(Duration::from_micros(50)).await;
+    }
+
+    async fn exists(&self, key: &str) -> bool {
+        Self::round_trip().await;
+        self.objects.read().await.contains_key(key)
+    }
+
+    async fn size(&self, key: &str) -> usize {
+        Self::round_trip().await;
+        self.objects.read().await.get(key).map_or(0, Vec::len)
+    }
+
+    /// Returns the bytes of `key` in `offset..offset+len` which exist.
+    async fn get_range(&self, key: &str, offset: usize, len: usize) -> Vec<u8> {
+        Self::round_trip().await;
+        let objects = self.objects.read().await;
+        let object = objects.get(key).map_or(&[][..], Vec::as_slice);
+        let start = offset.min(object.len());
+        let end = offset.saturating_add(len).min(object.len());
+        object[start..end].to_vec()
+    }
+
+    async fn get(&self, key: &str) -> Vec<u8> {
+        Self::round_trip().await;
+        self.objects
+            .read()
+            .await
+            .get(key)
+            .cloned()
+            .unwrap_or_default()
+    }
+
+    async fn put(&self, key: &str, data: Vec<u8>) {
+        Self::round_trip().await;
+        self.objects.write().await.insert(key.to_owned(), data);
+    }
+
+    async fn delete(&self, key: &str) -> bool {
+        Self::round_trip().await;
+        self.objects.write().await.remove(key).is_some()
+    }
+}
+
+type Reply<T> = SyncSender<T>;
+
+enum Request {
+    Read {
+        offset: usize,
+        len: usize,
+        reply: Reply<Vec<u8>>,
+    },
+    /// Writes are buffered by the actor, so they don't wait for a reply.
+    /// Failures to upload them are reported by the next sync.
+    Write {
+        offset: usize,
+        data: Vec<u8>,
+    },
+    Truncate {
+        size: usize,
+        reply: Reply<()>,
+    },
+    Size {
+        reply: Reply<usize>,
+    },
+    Sync {
+        reply: Reply<()>,
+    },
+    /// Uploads any remaining writes and stops the actor.
+    Close {
+        reply: Reply<()>,
+    },
+}
+
+/// Serves the requests for a single file until its handle is closed.
+async fn file_actor(
+    store: ObjectStore,
+    key: String,
+    mut requests: mpsc::UnboundedReceiver<Request>,
+) {
+    let mut size = store.size(&key).await;
+    // writes which haven't been uploaded yet, in the order they were made
+    let mut pending: Vec<(usize, Vec<u8>)> = Vec::new();
+
+    async fn flush(
+        store: &ObjectStore,
+        key: &str,
+        pending: &mut Vec<(usize, Vec<u8>)>,
+        size: usize,
+    ) {
+        let mut object = store.get(key).await;
+        for (offset, data) in pending.drain(..) {
+            let end = offset + data.len();
+            if end > object.len() {
+                object.resize(end, 0);
+            }
+            object[offset..end].copy_from_slice(&data);
+        }
+        object.resize(size, 0);
+        store.put(key, object).await;
+    }
+
+    while let Some(request) = requests.recv().await {
+        match request {
+            Request::Read { offset, len, reply } => {
+                let mut buf = store.get_range(&key, offset, len).await;
+                buf.resize(len, 0);
+                // overlay the writes which haven't been uploaded
+                for (write_offset, data) in &pending {
+                    let start = offset.max(*write_offset);
+                    let end = (offset + len).min(write_offset + data.len());
+                    if start < end {
+                        buf[start - offset..end - offset]
+                            .copy_from_slice(&data[start - write_offset..end - write_offset]);
+                    }
+                }
+                buf.truncate(size.saturating_sub(offset));
+                let _ = reply.send(buf);
+            }
+            Request::Write { offset, data } => {
+                size = size.max(offset + data.len());
+                pending.push((offset, data));
+            }
+            Request::Truncate { size: new_size, reply } => {
+                size = new_size;
+                flush(&store, &key, &mut pending, size).await;
+                let _ = reply.send(());
+            }
+            Request::Size { reply } => {
+                let _ = reply.send(size);
+            }
+            Request::Sync { reply } => {
+                if !pending.is_empty() {
+                    flush(&store, &key, &mut pending, size).await;
+                }
+                let _ = reply.send(());
+            }
+            Request::Close { reply } => {
+                // SQLite doesn't sync every file before closing it, for
+                // example with `PRAGMA synchronous=OFF`
+                if !pending.is_empty() {
+                    flush(&store, &key, &mut pending, size).await;
+                }
+                let _ = reply.send(());
+                return;
+            }
+        }
+    }
+}
+
+struct Handle {
+    key: String,
+    requests: mpsc::UnboundedSender<Request>,
+    readonly: bool,
+    delete_on_close: bool,
+}
+
+impl VfsHandle for Handle {
+    fn readonly(&self) -> bool {
+        self.readonly
+    }
+
+    fn in_memory(&self) -> bool {
+        false
+    }
+}
+
+impl Handle {
+    /// Sends a request to the file's actor and blocks until it replies.
+    fn call<T>(&self, request: impl FnOnce(Reply<T>) -> Request) -> VfsResult<T> {
+        let (reply, response) = sync_channel(1);
+        self.requests
+            .send(request(reply))
+            .map_err(|_| vars::SQLITE_IOERR)?;
+        response.recv().map_err(|_| vars::SQLITE_IOERR)
+    }
+}
+
+struct AsyncVfs {
+    runtime: Runtime,
+    store: ObjectStore,
+    next_temp_file: AtomicU64,
+}
+
+impl AsyncVfs {
+    fn new(store: ObjectStore) -> std::io::Result<Self> {
+        let runtime = tokio::runtime::Builder::new_multi_thread()
+            .worker_threads(2)
+            .thread_name("asyncvfs")
+            .enable_time()
+            .build()?;
+        Ok(Self {
+            runtime,
+            store,
+            next_temp_file: AtomicU64::new(0),
+        })
+    }
+
+    /// Runs `fut` on the vfs's runtime and blocks until it completes.
+    fn block_on<T: Send + 'static>(
+        &self,
+        fut: impl Future<Output = T> + Send + 'static,
+    ) -> VfsResult<T> {
+        let (reply, response) = sync_channel(1);
+        self.runtime.spawn(async move {
+            let _ = reply.send(fut.await);
+        });
+        response.recv().map_err(|_| vars::SQLITE_IOERR)
+    }
+}
+
+impl Vfs for AsyncVfs {
+    type Handle = Handle;
+
+    fn open(&self, path: Option<&str>, opts: OpenOpts) -> VfsResult<Self::Handle> {
+        let mode = opts.mode();
+        let key = match path {
+            Some(path) => path.to_owned(),
+            None => {
+                let n = self.next_temp_file.fetch_add(1, Ordering::Relaxed);
+                format!("temp-{n}")
+            }
+        };
+
+        let store = self.store.clone();
+        let exists = self.block_on({
+            let key = key.clone();
+            async move { store.exists(&key).await }
+        })?;
+        if exists && mode.must_create() {
+            return Err(vars::SQLITE_CANTOPEN);
+        }
+        if !exists && !mode.can_create() {
+            return Err(vars::SQLITE_CANTOPEN);
+        }
+
+        let (requests, receiver) = mpsc::unbounded_channel();
+        self.runtime
+            .spawn(file_actor(self.store.clone(), key.clone(), receiver));
+        Ok(Handle {
+            key,
+            requests,
+            readonly: mode.is_readonly(),
+            delete_on_close: opts.delete_on_close() || path.is_none(),
+        })
+    }
+
+    fn delete(&self, path: &str, _sync_dir: bool) -> VfsResult<()> {
+        let store = self.store.clone();
+        let key = path.to_owned();
+        if self.block_on(async move { store.delete(&key).await })? {
+            Ok(())
+        } else {
+            Err(vars::SQLITE_IOERR_DELETE_NOENT)
+        }
+    }
+
+    fn access(&self, path: &str, _flags: AccessFlags) -> VfsResult<bool> {
+        let store = self.store.clone();
+        let key = path.to_owned();
+        self.block_on(async move { store.exists(&key).await })
+    }
+
+    fn file_size(&self, handle: &mut Self::Handle) -> VfsResult<usize> {
+        handle.call(|reply| Request::Size { reply })
+    }
+
+    fn truncate(&self, handle: &mut Self::Handle, size: usize) -> VfsResult<()> {
+        handle.call(|reply| Request::Truncate { size, reply })
+    }
+
+    fn write(&self, handle: &mut Self::Handle, offset: usize, data: &[u8]) -> VfsResult<usize> {
+        let len = data.len();
+        let data = data.to_vec();
+        handle
+            .requests
+            .send(Request::Write { offset, data })
+            .map_err(|_| vars::SQLITE_IOERR_WRITE)?;
+        Ok(len)
+    }
+
+    fn read(&self, handle: &mut Self::Handle, offset: usize, data: &mut [u8]) -> VfsResult<usize> {
+        let len = data.len();
+        let buf = handle.call(|reply| Request::Read { offset, len, reply })?;
+        data[..buf.len()].copy_from_slice(&buf);
+        Ok(buf.len())
+    }
+
+    fn sync(&self, handle: &mut Self::Handle, _flags: SyncFlags) -> VfsResult<()> {
+        handle.call(|reply| Request::Sync { reply })
+    }
+
+    fn lock(&self, _handle: &mut Self::Handle, _level: LockLevel) -> VfsResult<()> {
+        Ok(())
+    }
+
+    fn unlock(&self, _handle: &mut Self::Handle, _level: LockLevel) -> VfsResult<()> {
+        Ok(())
+    }
+
+    fn check_reserved_lock(&self, _handle: &mut Self::Handle) -> VfsResult<bool> {
+        Ok(false)
+    }
+
+    fn close(&self, handle: Self::Handle) -> VfsResult<()> {
+        if handle.delete_on_close {
+            let store = self.store.clone();
+            self.block_on(async move { store.delete(&handle.key).await })?;
+        } else {
+            handle.call(|reply| Request::Close { reply })?;
+        }
+        Ok(())
+    }
+}
+
+fn main() -> Result<(), Box<dyn std::error::Error>> {
+    let store = ObjectStore::default();
+    register_static_simple(
+        c"async".to_owned(),
+        AsyncVfs::new(store.clone())?,
+        RegisterOpts::default(),
+    )
+    .map_err(|err| format!("failed to register vfs: {err}"))?;
+
+    let flags =
+        rusqlite::OpenFlags::SQLITE_OPEN_READ_WRITE | rusqlite::OpenFlags::SQLITE_OPEN_CREATE;
+    let conn = rusqlite::Connection::open_with_flags_and_vfs("async.db", flags, "async")?;
+    conn.execute_batch(
+        "CREATE TABLE t (id INTEGER PRIMARY KEY, v TEXT);
+         WITH RECURSIVE n(i) AS (SELECT 1 UNION ALL SELECT i + 1 FROM n WHERE i < 1000)
+         INSERT INTO t (v) SELECT 'row ' || i FROM n;",
+    )?;
+    drop(conn);
+
+    // the database now lives in the object store
+    let size = store.objects.blocking_read()["async.db"].len();
+    println!("uploaded {size} bytes");
+
+    let conn = rusqlite::Connection::open_with_flags_and_vfs("async.db", flags, "async")?;
+    let (count, last): (i64, String) =
+        conn.query_row("SELECT count(*), max(v) FROM t", [], |r| {
+            Ok((r.get(0)?, r.get(1)?))
+        })?;
+    assert_eq!(count, 1000);
+    println!("{count} rows, last is {last:?}");
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn example() {
+        main().unwrap();
+    }
+
+    #[test]
+    fn called_from_async_task() -> Result<(), Box<dyn std::error::Error>> {
+        let store = ObjectStore::default();
+        let vfs = AsyncVfs::new(store)?;
+        register_static_simple(c"async_task".to_owned(), vfs, RegisterOpts::default())
+            .map_err(|err| format!("failed to register vfs: {err}"))?;
+
+        // blocking a current_thread runtime is fine, since the vfs's futures
+        // run on its own runtime
+        let runtime = tokio::runtime::Builder::new_current_thread().build()?;
+        let count = runtime.block_on(async {
+            let flags = rusqlite::OpenFlags::SQLITE_OPEN_READ_WRITE
+                | rusqlite::OpenFlags::SQLITE_OPEN_CREATE;
+            let conn =
+                rusqlite::Connection::open_with_flags_and_vfs("task.db", flags, "async_task")?;
+            conn.execute_batch("CREATE TABLE t (v); INSERT INTO t VALUES (1), (2);")?;
+            conn.query_row("SELECT count(*) FROM t", [], |r| r.get::<_, i64>(0))
+        })?;
+        assert_eq!(count, 2);
+        Ok(())
+    }
+}
@@ -0,0 +1,206 @@
+// cargo run --example cryptvfs
+//
+// An encryption layer built on `layer::VfsLayer`, which scrambles every byte
+// written to an inner in-memory vfs and unscrambles it again when read. Only
+// reads and writes are implemented by the layer, every other method is
+// forwarded to the inner vfs by `layer::DelegatingVfs`.
+//
+// The cipher XORs each byte with a keystream derived from the key and the
+// byte's offset in the file. It keeps the example short, but it is NOT secure.
+// A real layer would use an authenticated cipher over whole pages, and reserve
+// space at the end of each page for the nonce and tag by having the
+// application call `sqlite3_file_control` with `SQLITE_FCNTL_RESERVE_BYTES`
+// before the database is created.
+
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+};
+
+use sqlite_plugin::{
+    flags::{AccessFlags, LockLevel, OpenOpts},
+    layer::{DelegatingVfs, LayerHandle, VfsLayer},
+    vfs::{RegisterOpts, Vfs, VfsHandle, VfsResult, register_static_simple},
+};
+
+type FileData = Arc<Mutex<Vec<u8>>>;
+
+struct Handle {
+    data: FileData,
+    readonly: bool,
+}
+
+impl VfsHandle for Handle {
+    fn readonly(&self) -> bool {
+        self.readonly
+    }
+
+    fn in_memory(&self) -> bool {
+        true
+    }
+}
+
+/// The inner vfs, which stores files in memory without any locking.
+#[derive(Default)]
+struct MemStore {
+    files: Mutex<HashMap<String, FileData>>,
+}
+
+impl MemStore {
+    /// Returns the raw bytes stored for `name`.
+    fn contents(&self, name: &str) -> Option<Vec<u8>> {
+        let files = self.files.lock().unwrap();
+        files.get(name).map(|data| data.lock().unwrap().clone())
+    }
+}
+
+impl Vfs for MemStore {
+    type Handle = Handle;
+
+    fn open(&self, path: Option<&str>, opts: OpenOpts) -> VfsResult<Self::Handle> {
+        let mut files = self.files.lock().unwrap();
+        let data = match path {
+            Some(path) => files.entry(path.to_owned()).or_default().clone(),
+            None => FileData::default(),
+        };
+        Ok(Handle { data, readonly: opts.is_readonly() })
+    }
+
+    fn delete(&self, path: &str, _sync_dir: bool) -> VfsResult<()> {
+        self.files.lock().unwrap().remove(path);
+        Ok(())
+    }
+
+    fn access(&self, path: &str, _flags: AccessFlags) -> VfsResult<bool> {
+        Ok(self.files.lock().unwrap().contains_key(path))
+    }
+
+    fn file_size(&self, handle: &mut Self::Handle) -> VfsResult<usize> {
+        Ok(handle.data.lock().unwrap().len())
+    }
+
+    fn truncate(&self, handle: &mut Self::Handle, size: usize) -> VfsResult<()> {
+        handle.data.lock().unwrap().resize(size, 0);
+        Ok(())
+    }
+
+    fn write(&self, handle: &mut Self::Handle, offset: usize, buf: &[u8]) -> VfsResult<usize> {
+        let end = offset + buf.len();
+        let mut data = handle.data.lock().unwrap();
+        if end > data.len() {
+            data.resize(end, 0);
+        }
+        data[offset..end].copy_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn read(&self, handle: &mut Self::Handle, offset: usize, buf: &mut [u8]) -> VfsResult<usize> {
+        let data = handle.data.lock().unwrap();
+        let available = data.get(offset..).unwrap_or_default();
+        let len = buf.len().min(available.len());
+        buf[..len].copy_from_slice(&available[..len]);
+        Ok(len)
+    }
+
+    fn lock(&self, _handle: &mut Self::Handle, _level: LockLevel) -> VfsResult<()> {
+        Ok(())
+    }
+
+    fn unlock(&self, _handle: &mut Self::Handle, _level: LockLevel) -> VfsResult<()> {
+        Ok(())
+    }
+
+    fn check_reserved_lock(&self, _handle: &mut Self::Handle) -> VfsResult<bool> {
+        Ok(false)
+    }
+
+    fn close(&self, _handle: Self::Handle) -> VfsResult<()> {
+        Ok(())
+    }
+}
+
+struct Cipher {
+    key: Vec<u8>,
+    inner: Arc<MemStore>,
+}
+
+impl Cipher {
+    /// XORs `buf`, which starts at `offset` in the file, with the keystream.
+    fn apply(&self, offset: usize, buf: &mut [u8]) {
+        for (i, byte) in buf.iter_mut().enumerate() {
+            let pos = offset + i;
+            *byte ^= self.key[pos % self.key.len()] ^ (pos / self.key.len()) as u8;
+        }
+    }
+}
+
+impl VfsLayer for Cipher {
+    type Inner = MemStore;
+
+    fn inner(&self) -> &MemStore {
+        &self.inner
+    }
+
+    fn write(
+        &self,
+        handle: &mut LayerHandle<Self>,
+        offset: usize,
+        data: &[u8],
+    ) -> VfsResult<usize> {
+        let mut encrypted = data.to_vec();
+        self.apply(offset, &mut encrypted);
+        self.inner.write(handle, offset, &encrypted)
+    }
+
+    fn read(
+        &self,
+        handle: &mut LayerHandle<Self>,
+        offset: usize,
+        data: &mut [u8],
+    ) -> VfsResult<usize> {
+        let n = self.inner.read(handle, offset, data)?;
+        self.apply(offset, &mut data[..n]);
+        Ok(n)
+    }
+}
+
+fn main() -> Result<(), Box<dyn std::error::Error>> {
+    let store = Arc::new(MemStore::default());
+    let cipher = Cipher {
+        key: b"correct horse battery staple".to_vec(),
+        inner: store.clone(),
+    };
+    register_static_simple(
+        c"crypt".to_owned(),
+        DelegatingVfs(cipher),
+        RegisterOpts::default(),
+    )
+    .map_err(|err| format!("failed to register vfs: {err}"))?;
+
+    let flags =
+        rusqlite::OpenFlags::SQLITE_OPEN_READ_WRITE | rusqlite::OpenFlags::SQLITE_OPEN_CREATE;
+    let conn = rusqlite::Connection::open_with_flags_and_vfs("crypt.db", flags, "crypt")?;
+    conn.execute_batch(
+        "create table secrets (val text);
+        insert into secrets values ('the eagle lands at midnight');",
+    )?;
+    let val: String = conn.query_row("select val from secrets", [], |r| r.get(0))?;
+    println!("read back: {val}");
+
+    // the inner vfs only ever sees scrambled bytes
+    let raw = store.contents("crypt.db").ok_or("missing database file")?;
+    assert!(!raw.starts_with(b"SQLite format 3\0"));
+    assert!(!raw.windows(val.len()).any(|w| w == val.as_bytes()));
+    println!("stored {} scrambled bytes", raw.len());
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn example() {
+        main().unwrap();
+    }
+}
@@ -0,0 +1,446 @@
+// cargo run --example fsvfs
+//
+// A minimal vfs which passes all file operations through to the local
+// filesystem using std::fs, and implements SQLite's locking protocol using
+// fcntl byte-range locks. It's intended as a correctness baseline to fork and
+// compare custom vfs implementations against.
+//
+// This vfs doesn't implement shared memory, so WAL mode is only available with
+// `PRAGMA locking_mode=EXCLUSIVE`.
+
+use std::{
+    borrow::Cow,
+    fs::{self, OpenOptions},
+    io,
+    os::{
+        fd::AsRawFd,
+        unix::fs::{FileExt, MetadataExt, OpenOptionsExt},
+    },
+    path::{Path, PathBuf},
+    sync::atomic::{AtomicU64, Ordering},
+};
+
+use sqlite_plugin::{
+    error::VfsError,
+    flags::{
+        AccessFlags, CreateMode, DeviceCharacteristics, LockLevel, OpenMode, OpenOpts, SyncFlags,
+    },
+    vars,
+    vfs::{RegisterOpts, Vfs, VfsHandle, VfsResult, register_static_simple},
+};
+
+// SQLite's locking protocol uses the following byte ranges of the main
+// database file. These match the offsets used by SQLite's builtin unix vfs so
+// that both can safely access the same database concurrently.
+const PENDING_BYTE: i64 = 0x40000000;
+const RESERVED_BYTE: i64 = PENDING_BYTE + 1;
+const SHARED_FIRST: i64 = PENDING_BYTE + 2;
+const SHARED_SIZE: i64 = 510;
+
+// Open file description locks are owned by the file handle rather than the
+// process, which means that multiple connections to the same database within
+// a single process correctly exclude each other. Elsewhere we fall back to
+// classic POSIX locks which don't have this property.
+#[cfg(target_os = "linux")]
+const F_SETLK: libc::c_int = libc::F_OFD_SETLK;
+#[cfg(target_os = "linux")]
+const F_GETLK: libc::c_int = libc::F_OFD_GETLK;
+#[cfg(not(target_os = "linux"))]
+const F_SETLK: libc::c_int = libc::F_SETLK;
+#[cfg(not(target_os = "linux"))]
+const F_GETLK: libc::c_int = libc::F_GETLK;
+
+struct Handle {
+    /// Taken when the handle is closed
+    file: Option<fs::File>,
+    /// None for anonymous temporary files
+    path: Option<PathBuf>,
+    readonly: bool,
+    lock: LockLevel,
+}
+
+impl VfsHandle for Handle {
+    fn readonly(&self) -> bool {
+        self.readonly
+    }
+
+    fn in_memory(&self) -> bool {
+        false
+    }
+}
+
+impl Handle {
+    fn file(&self) -> VfsResult<&fs::File> {
+        self.file.as_ref().ok_or(vars::SQLITE_IOERR)
+    }
+
+    /// Returns the device and inode of the open file. No extended code
+    /// applies here, so `?` maps I/O errors to a `VfsError` directly.
+    fn identity(&self) -> VfsResult<(u64, u64), VfsError> {
+        let meta = self.file()?.metadata()?;
+        Ok((meta.dev(), meta.ino()))
+    }
+
+    /// Builds a `libc::flock` describing the byte range `start..start+len`.
+    fn flock(kind: libc::c_int, start: i64, len: i64) -> libc::flock {
+        // Safety: flock is a plain C struct for which all zeroes is valid,
+        // and OFD locks require l_pid to be zero
+        let mut fl: libc::flock = unsafe { std::mem::zeroed() };
+        fl.l_type = kind as libc::c_short;
+        fl.l_whence = libc::SEEK_SET as libc::c_short;
+        fl.l_start = start as libc::off_t;
+        fl.l_len = len as libc::off_t;
+        fl
+    }
+
+    /// Attempts to change the lock on a byte range without blocking. Returns
+    /// false if the lock is held by someone else.
+    fn set_lock(&self, kind: libc::c_int, start: i64, len: i64) -> VfsResult<bool> {
+        let fl = Self::flock(kind, start, len);
+        let rc = unsafe { libc::fcntl(self.file()?.as_raw_fd(), F_SETLK, &fl) };
+        if rc == 0 {
+            return Ok(true);
+        }
+        match io::Error::last_os_error().raw_os_error() {
+            Some(libc::EAGAIN | libc::EACCES) => Ok(false),
+            _ => Err(vars::SQLITE_IOERR_LOCK),
+        }
+    }
+
+    /// Returns true if someone else holds a lock which conflicts with a lock
+    /// of the given kind on the byte range.
+    fn is_locked(&self, kind: libc::c_int, start: i64, len: i64) -> VfsResult<bool> {
+        let mut fl = Self::flock(kind, start, len);
+        let rc = unsafe { libc::fcntl(self.file()?.as_raw_fd(), F_GETLK, &mut fl) };
+        if rc != 0 {
+            return Err(vars::SQLITE_IOERR_CHECKRESERVEDLOCK);
+        }
+        Ok(fl.l_type != libc::F_UNLCK as libc::c_short)
+    }
+}
+
+/// Returns a closure mapping an `io::Error` to a `VfsError`, falling back to
+/// `fallback` for errors without a more specific `SQLite` equivalent. Only
+/// needed where `SQLite` expects a specific extended code such as
+/// `SQLITE_IOERR_WRITE`, elsewhere `?` converts `io::Error` to `IoErr`.
+fn io(fallback: VfsError) -> impl Fn(io::Error) -> VfsError {
+    move |err| VfsError::from_io_error(&err, fallback)
+}
+
+struct FsVfs;
+
+impl Vfs for FsVfs {
+    type Handle = Handle;
+
+    fn canonical_path<'a>(&self, path: Cow<'a, str>) -> VfsResult<Cow<'a, str>> {
+        if path.starts_with('/') {
+            return Ok(path);
+        }
+        let cwd = std::env::current_dir().map_err(|_| vars::SQLITE_CANTOPEN_FULLPATH)?;
+        let full = cwd.join(path.as_ref());
+        let full = full.to_str().ok_or(vars::SQLITE_CANTOPEN_FULLPATH)?;
+        Ok(Cow::Owned(full.to_owned()))
+    }
+
+    fn same_file(&self, a: &str, b: &str) -> VfsResult<bool> {
+        // compare inodes so that hard links and symlinks are recognized
+        match (fs::metadata(a), fs::metadata(b)) {
+            (Ok(a), Ok(b)) => Ok((a.dev(), a.ino()) == (b.dev(), b.ino())),
+            (Err(e), _) | (_, Err(e)) if e.kind() != io::ErrorKind::NotFound => {
+                Err(VfsError::from_io_error(&e, VfsError::IoErrFstat).into())
+            }
+            // a file which doesn't exist can only be the same as itself
+            _ => Ok(self.canonical_path(a.into())? == self.canonical_path(b.into())?),
+        }
+    }
+
+    fn open(&self, path: Option<&str>, opts: OpenOpts) -> VfsResult<Self::Handle> {
+        let mode = opts.mode();
+        let mut options = OpenOptions::new();
+        options.read(true).mode(0o644);
+        match mode {
+            OpenMode::ReadOnly => {}
+            OpenMode::ReadWrite { create: CreateMode::None } => {
+                options.write(true);
+            }
+            OpenMode::ReadWrite { create: CreateMode::Create } => {
+                options.write(true).create(true);
+            }
+            OpenMode::ReadWrite { create: CreateMode::MustCreate } => {
+                options.write(true).create_new(true);
+            }
+        }
+        if opts.no_follow() {
+            options.custom_flags(libc::O_NOFOLLOW);
+        }
+
+        let (file, path) = match path {
+            Some(path) => {
+                let file = options.open(path).map_err(|err| match err.raw_os_error() {
+                    // report the specific reason the file couldn't be opened
+                    Some(libc::ELOOP) if opts.no_follow() => VfsError::CantOpenSymlink,
+                    Some(libc::EISDIR) => VfsError::CantOpenIsDir,
+                    _ => io(VfsError::CantOpen)(err),
+                })?;
+                (file, Some(PathBuf::from(path)))
+            }
+            None => (open_anonymous(&mut options)?, None),
+        };
+
+        // Like the unix vfs, we unlink delete-on-close files immediately. The
+        // data remains accessible through the open handle until it's closed.
+        if opts.delete_on_close() {
+            if let Some(path) = &path {
+                let _ = fs::remove_file(path);
+            }
+        }
+
+        Ok(Handle {
+            file: Some(file),
+            path,
+            readonly: mode.is_readonly(),
+            lock: LockLevel::Unlocked,
+        })
+    }
+
+    fn delete(&self, path: &str, sync_dir: bool) -> VfsResult<()> {
+        match fs::remove_file(path) {
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {
+                return Err(vars::SQLITE_IOERR_DELETE_NOENT);
+            }
+            result => result.map_err(io(VfsError::IoErrDelete))?,
+        }
+        if sync_dir {
+            // the unlink is only durable once the directory entry is synced
+            let dir = match Path::new(path).parent() {
+                Some(dir) if !dir.as_os_str().is_empty() => dir,
+                _ => Path::new("."),
+            };
+            fs::File::open(dir)
+                .and_then(|dir| dir.sync_all())
+                .map_err(|_| vars::SQLITE_IOERR_DIR_FSYNC)?;
+        }
+        Ok(())
+    }
+
+    fn access(&self, path: &str, flags: AccessFlags) -> VfsResult<bool> {
+        let meta = match fs::metadata(path) {
+            Ok(meta) => meta,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(false),
+            Err(e) => return Err(io(VfsError::IoErrAccess)(e).into()),
+        };
+        Ok(match flags {
+            AccessFlags::Exists | AccessFlags::Read => true,
+            AccessFlags::ReadWrite => !meta.permissions().readonly(),
+        })
+    }
+
+    fn file_size(&self, handle: &mut Self::Handle) -> VfsResult<usize> {
+        let meta = handle
+            .file()?
+            .metadata()
+            .map_err(io(VfsError::IoErrFstat))?;
+        usize::try_from(meta.len()).map_err(|_| vars::SQLITE_IOERR_FSTAT)
+    }
+
+    fn truncate(&self, handle: &mut Self::Handle, size: usize) -> VfsResult<()> {
+        Ok(handle
+            .file()?
+            .set_len(size as u64)
+            .map_err(io(VfsError::IoErrTruncate))?)
+    }
+
+    fn write(&self, handle: &mut Self::Handle, offset: usize, buf: &[u8]) -> VfsResult<usize> {
+        handle
+            .file()?
+            .write_all_at(buf, offset as u64)
+            .map_err(io(VfsError::IoErrWrite))?;
+        Ok(buf.len())
+    }
+
+    fn read(&self, handle: &mut Self::Handle, offset: usize, buf: &mut [u8]) -> VfsResult<usize> {
+        // read_at may return fewer bytes than requested before EOF, so keep
+        // reading until the buffer is full or we hit the end of the file
+        let file = handle.file()?;
+        let mut n = 0;
+        while n < buf.len() {
+            match file.read_at(&mut buf[n..], (offset + n) as u64) {
+                Ok(0) => break,
+                Ok(read) => n += read,
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+                Err(e) => return Err(io(VfsError::IoErrRead)(e).into()),
+            }
+        }
+        Ok(n)
+    }
+
+    fn sync(&self, handle: &mut Self::Handle, flags: SyncFlags) -> VfsResult<()> {
+        let file = handle.file()?;
+        let result = if flags.data_only() {
+            file.sync_data()
+        } else {
+            file.sync_all()
+        };
+        Ok(result.map_err(io(VfsError::IoErrFsync))?)
+    }
+
+    fn lock(&self, handle: &mut Self::Handle, level: LockLevel) -> VfsResult<()> {
+        if handle.lock >= level {
+            return Ok(());
+        }
+        match level {
+            LockLevel::Unlocked => {}
+            LockLevel::Shared => {
+                // A read lock on the pending byte prevents us from acquiring a
+                // shared lock while a writer is waiting for an exclusive lock.
+                if !handle.set_lock(libc::F_RDLCK, PENDING_BYTE, 1)? {
+                    return Err(vars::SQLITE_BUSY);
+                }
+                let acquired = handle.set_lock(libc::F_RDLCK, SHARED_FIRST, SHARED_SIZE);
+                handle.set_lock(libc::F_UNLCK, PENDING_BYTE, 1)?;
+                if !acquired? {
+                    return Err(vars::SQLITE_BUSY);
+                }
+            }
+            LockLevel::Reserved => {
+                if !handle.set_lock(libc::F_WRLCK, RESERVED_BYTE, 1)? {
+                    return Err(vars::SQLITE_BUSY);
+                }
+            }
+            LockLevel::Pending | LockLevel::Exclusive => {
+                if handle.lock < LockLevel::Pending {
+                    // stop new readers from acquiring a shared lock
+                    if !handle.set_lock(libc::F_WRLCK, PENDING_BYTE, 1)? {
+                        return Err(vars::SQLITE_BUSY);
+                    }
+                    handle.lock = LockLevel::Pending;
+                }
+                if level == LockLevel::Exclusive {
+                    // wait for existing readers to finish
+                    if !handle.set_lock(libc::F_WRLCK, SHARED_FIRST, SHARED_SIZE)? {
+                        return Err(vars::SQLITE_BUSY);
+                    }
+                }
+            }
+        }
+        handle.lock = level;
+        Ok(())
+    }
+
+    fn unlock(&self, handle: &mut Self::Handle, level: LockLevel) -> VfsResult<()> {
+        if handle.lock <= level {
+            return Ok(());
+        }
+        match level {
+            LockLevel::Shared => {
+                if handle.lock == LockLevel::Exclusive
+                    && !handle.set_lock(libc::F_RDLCK, SHARED_FIRST, SHARED_SIZE)?
+                {
+                    return Err(vars::SQLITE_IOERR_RDLOCK);
+                }
+                // release the pending and reserved bytes
+                handle.set_lock(libc::F_UNLCK, PENDING_BYTE, 2)?;
+            }
+            _ => {
+                handle.set_lock(libc::F_UNLCK, PENDING_BYTE, 2 + SHARED_SIZE)?;
+            }
+        }
+        handle.lock = level;
+        Ok(())
+    }
+
+    fn check_reserved_lock(&self, handle: &mut Self::Handle) -> VfsResult<bool> {
+        if handle.lock >= LockLevel::Reserved {
+            return Ok(true);
+        }
+        handle.is_locked(libc::F_WRLCK, RESERVED_BYTE, 1)
+    }
+
+    fn lock_state(&self, handle: &mut Self::Handle) -> VfsResult<LockLevel> {
+        Ok(handle.lock)
+    }
+
+    fn close(&self, mut handle: Self::Handle) -> VfsResult<()> {
+        // closing the file releases any locks it holds
+        drop(handle.file.take());
+        Ok(())
+    }
+
+    fn has_moved(&self, handle: &mut Self::Handle) -> VfsResult<bool> {
+        // like the unix vfs, the file has moved if its path no longer refers
+        // to the same inode
+        let Some(path) = &handle.path else {
+            return Ok(false);
+        };
+        let identity = handle.identity()?;
+        Ok(match fs::metadata(path) {
+            Ok(current) => (current.dev(), current.ino()) != identity,
+            Err(_) => true,
+        })
+    }
+
+    fn device_characteristics(&self, _handle: &mut Self::Handle) -> VfsResult<i32> {
+        // A regular filesystem makes no atomicity guarantees, so the crate's
+        // defaults (which are tuned for in-memory and object storage) don't
+        // apply here.
+        Ok(DeviceCharacteristics::new().powersafe_overwrite().bits())
+    }
+}
+
+/// Opens a new temporary file which is unlinked immediately.
+fn open_anonymous(options: &mut OpenOptions) -> VfsResult<fs::File> {
+    static COUNTER: AtomicU64 = AtomicU64::new(0);
+    let name = format!(
+        "fsvfs-{}-{}.tmp",
+        std::process::id(),
+        COUNTER.fetch_add(1, Ordering::Relaxed)
+    );
+    let path = std::env::temp_dir().join(name);
+    let file = options
+        .write(true)
+        .create_new(true)
+        .open(&path)
+        .map_err(io(VfsError::CantOpen))?;
+    let _ = fs::remove_file(&path);
+    Ok(file)
+}
+
+fn main() -> Result<(), Box<dyn std::error::Error>> {
+    register_static_simple(c"fs".to_owned(), FsVfs, RegisterOpts::default())
+        .map_err(|err| format!("failed to register vfs: {err}"))?;
+
+    let dir = tempfile::tempdir()?;
+    let path = dir.path().join("fsvfs.db");
+    let flags =
+        rusqlite::OpenFlags::SQLITE_OPEN_READ_WRITE | rusqlite::OpenFlags::SQLITE_OPEN_CREATE;
+
+    let conn = rusqlite::Connection::open_with_flags_and_vfs(&path, flags, "fs")?;
+    conn.execute_batch(
+        "CREATE TABLE t (id INTEGER PRIMARY KEY, v TEXT);
+         INSERT INTO t (v) VALUES ('hello'), ('world');",
+    )?;
+
+    // a second connection can read concurrently, but can't write while the
+    // first connection holds a write transaction
+    let other = rusqlite::Connection::open_with_flags_and_vfs(&path, flags, "fs")?;
+    conn.execute_batch("BEGIN IMMEDIATE; INSERT INTO t (v) VALUES ('pending');")?;
+    let count: i64 = other.query_row("SELECT count(*) FROM t", [], |r| r.get(0))?;
+    assert_eq!(count, 2);
+    // only OFD locks exclude connections within the same process
+    #[cfg(target_os = "linux")]
+    assert!(
+        other
+            .execute("INSERT INTO t (v) VALUES ('blocked')", [])
+            .is_err()
+    );
+    conn.execute_batch("COMMIT")?;
+
+    let mut stmt = other.prepare("SELECT id, v FROM t")?;
+    let rows = stmt.query_map([], |r| Ok((r.get::<_, i64>(0)?, r.get::<_, String>(1)?)))?;
+    for row in rows {
+        let (id, v) = row?;
+        println!("{id}: {v}");
+    }
+
+    Ok(())
+}
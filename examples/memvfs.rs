@@ -1,17 +1,19 @@
-// cargo build --example memvfs --features dynamic
+// cargo build --example memvfs --features dynamic,log
 
-use std::{ffi::c_void, os::raw::c_char, ptr::NonNull, sync::Arc};
+use std::{ffi::c_void, mem::MaybeUninit, os::raw::c_char, ptr::NonNull, sync::Arc};
 
 use parking_lot::Mutex;
 use sqlite_plugin::{
-    flags::{AccessFlags, LockLevel, OpenOpts, ShmLockMode},
-    logger::{SqliteLogLevel, SqliteLogger},
+    flags::{AccessFlags, LockLevel, OpenKind, OpenOpts, ShmLockMode, SyncFlags, VfsCapabilities},
+    logger::install_as_global,
     sqlite3_api_routines, vars,
-    vfs::{Pragma, PragmaErr, RegisterOpts, Vfs, VfsHandle, VfsResult, register_dynamic},
+    vfs::{
+        Pragma, PragmaErr, PragmaResult, RegisterOpts, Vfs, VfsHandle, VfsResult, register_dynamic,
+    },
 };
 
 #[derive(Debug, Clone)]
-struct File {
+pub struct File {
     name: Option<String>,
     data: Arc<Mutex<Vec<u8>>>,
     /// Single shared-memory page used for WAL index.
@@ -34,23 +36,58 @@ impl VfsHandle for File {
     fn in_memory(&self) -> bool {
         true
     }
+
+    fn open_kind(&self) -> OpenKind {
+        self.opts.kind()
+    }
 }
 
-struct MemVfs {
+/// An in-memory vfs. Besides being loaded as an extension, it can be
+/// registered with `register_static` and its files saved and restored with
+/// [`MemVfs::serialize`] and [`MemVfs::deserialize`].
+pub struct MemVfs {
     files: Arc<Mutex<Vec<File>>>,
 }
 
+impl MemVfs {
+    /// Returns a copy of the contents of the file `name`, for example to
+    /// persist a database between runs, or `None` if there is no such file.
+    ///
+    /// Only the database file is copied, so in WAL mode run
+    /// `PRAGMA wal_checkpoint(TRUNCATE)` first.
+    pub fn serialize(&self, name: &str) -> Option<Vec<u8>> {
+        let files = self.files.lock();
+        let file = files.iter().find(|file| file.is_named(name))?;
+        Some(file.data.lock().clone())
+    }
+
+    /// Stores `bytes` as the contents of the file `name`, for example to
+    /// reload a database returned by `serialize`. Replaces any existing file
+    /// of that name, although handles which already have it open keep
+    /// reading the old contents.
+    pub fn deserialize(&self, name: &str, bytes: Vec<u8>) {
+        let mut files = self.files.lock();
+        files.retain(|file| !file.is_named(name));
+        files.push(File {
+            name: Some(name.to_owned()),
+            data: Arc::new(Mutex::new(bytes)),
+            shm: Default::default(),
+            delete_on_close: false,
+            opts: OpenOpts::new(vars::SQLITE_OPEN_MAIN_DB | vars::SQLITE_OPEN_READWRITE),
+        });
+    }
+}
+
 impl Vfs for MemVfs {
     type Handle = File;
 
+    fn capabilities(&self) -> VfsCapabilities {
+        VfsCapabilities::new().shm()
+    }
+
     fn open(&self, path: Option<&str>, opts: OpenOpts) -> VfsResult<Self::Handle> {
-        log::debug!("open: path={:?}, opts={:?}", path, opts);
+        log::debug!("open: path={path:?}, opts={opts:?}");
         let mode = opts.mode();
-        if mode.is_readonly() {
-            // readonly makes no sense since an in-memory VFS is not backed by
-            // any pre-existing data.
-            return Err(vars::SQLITE_CANTOPEN);
-        }
 
         if let Some(path) = path {
             let mut files = self.files.lock();
@@ -60,10 +97,21 @@ impl Vfs for MemVfs {
                     if mode.must_create() {
                         return Err(vars::SQLITE_CANTOPEN);
                     }
-                    return Ok(file.clone());
+                    // share the existing data, but take the flags from this
+                    // open so that a readonly handle rejects writes and
+                    // delete-on-close only applies to the handle asking for it
+                    return Ok(File {
+                        delete_on_close: opts.delete_on_close(),
+                        opts,
+                        ..file.clone()
+                    });
                 }
             }
 
+            if !mode.can_create() {
+                return Err(vars::SQLITE_CANTOPEN);
+            }
+
             let file = File {
                 name: Some(path.to_owned()),
                 data: Default::default(),
@@ -74,6 +122,9 @@ impl Vfs for MemVfs {
             files.push(file.clone());
             Ok(file)
         } else {
+            if mode.is_readonly() {
+                return Err(vars::SQLITE_CANTOPEN);
+            }
             let file = File {
                 name: None,
                 data: Default::default(),
@@ -85,8 +136,8 @@ impl Vfs for MemVfs {
         }
     }
 
-    fn delete(&self, path: &str) -> VfsResult<()> {
-        log::debug!("delete: path={}", path);
+    fn delete(&self, path: &str, _sync_dir: bool) -> VfsResult<()> {
+        log::debug!("delete: path={path}");
         let mut found = false;
         self.files.lock().retain(|file| {
             if file.is_named(path) {
@@ -103,7 +154,7 @@ impl Vfs for MemVfs {
     }
 
     fn access(&self, path: &str, flags: AccessFlags) -> VfsResult<bool> {
-        log::debug!("access: path={}, flags={:?}", path, flags);
+        log::debug!("access: path={path}, flags={flags:?}");
         Ok(self.files.lock().iter().any(|f| f.is_named(path)))
     }
 
@@ -145,11 +196,14 @@ impl Vfs for MemVfs {
             offset,
             buf.len()
         );
+        let end = offset
+            .checked_add(buf.len())
+            .ok_or(vars::SQLITE_IOERR_WRITE)?;
         let mut data = handle.data.lock();
-        if offset + buf.len() > data.len() {
-            data.resize(offset + buf.len(), 0);
+        if end > data.len() {
+            data.resize(end, 0);
         }
-        data[offset..offset + buf.len()].copy_from_slice(buf);
+        data[offset..end].copy_from_slice(buf);
         Ok(buf.len())
     }
 
@@ -169,17 +223,36 @@ impl Vfs for MemVfs {
         Ok(len)
     }
 
-    fn sync(&self, handle: &mut Self::Handle) -> VfsResult<()> {
-        log::debug!("sync: file={:?}", handle.name);
+    fn read_into_uninit(
+        &self,
+        handle: &mut Self::Handle,
+        offset: usize,
+        buf: &mut [MaybeUninit<u8>],
+    ) -> VfsResult<usize> {
+        // copy straight into SQLite's buffer, skipping the zero-fill done by
+        // the default implementation
+        let data = handle.data.lock();
+        let available = data.get(offset..).unwrap_or_default();
+        let len = buf.len().min(available.len());
+        for (dst, src) in buf.iter_mut().zip(&available[..len]) {
+            dst.write(*src);
+        }
+        Ok(len)
+    }
+
+    fn sync(&self, handle: &mut Self::Handle, flags: SyncFlags) -> VfsResult<()> {
+        log::debug!("sync: file={:?}, flags={:?}", handle.name, flags);
         Ok(())
     }
 
     fn close(&self, handle: Self::Handle) -> VfsResult<()> {
         log::debug!("close: file={:?}", handle.name);
-        if handle.delete_on_close {
-            if let Some(ref name) = handle.name {
-                self.delete(name)?;
-            }
+        // anonymous files are dropped along with their last handle, while named
+        // ones are unlinked unless the name was deleted or reused meanwhile
+        if handle.delete_on_close && handle.name.is_some() {
+            self.files
+                .lock()
+                .retain(|file| !Arc::ptr_eq(&file.data, &handle.data));
         }
         Ok(())
     }
@@ -188,7 +261,7 @@ impl Vfs for MemVfs {
         &self,
         handle: &mut Self::Handle,
         pragma: Pragma<'_>,
-    ) -> Result<Option<String>, PragmaErr> {
+    ) -> Result<PragmaResult, PragmaErr> {
         log::debug!("pragma: file={:?}, pragma={:?}", handle.name, pragma);
         Err(PragmaErr::NotFound)
     }
@@ -253,33 +326,6 @@ impl Vfs for MemVfs {
     }
 }
 
-fn setup_logger(logger: SqliteLogger) {
-    struct LogCompat {
-        logger: Mutex<SqliteLogger>,
-    }
-
-    impl log::Log for LogCompat {
-        fn enabled(&self, _metadata: &log::Metadata) -> bool {
-            true
-        }
-
-        fn log(&self, record: &log::Record) {
-            let level = match record.level() {
-                log::Level::Error => SqliteLogLevel::Error,
-                log::Level::Warn => SqliteLogLevel::Warn,
-                _ => SqliteLogLevel::Notice,
-            };
-            let msg = format!("{}", record.args());
-            self.logger.lock().log(level, &msg);
-        }
-
-        fn flush(&self) {}
-    }
-
-    let log = LogCompat { logger: Mutex::new(logger) };
-    log::set_boxed_logger(Box::new(log)).expect("failed to setup global logger");
-}
-
 /// This function is called by `SQLite` when the extension is loaded. It registers
 /// the memvfs VFS with `SQLite`.
 /// # Safety
@@ -295,10 +341,19 @@ pub unsafe extern "C" fn sqlite3_memvfs_init(
             p_api,
             c"mem".to_owned(),
             MemVfs { files: Default::default() },
-            RegisterOpts { make_default: true },
+            RegisterOpts {
+                make_default: true,
+                // allow long, object-store style file names
+                max_path_len: Some(4096),
+                ..Default::default()
+            },
         )
     } {
-        Ok(logger) => setup_logger(logger),
+        Ok(registration) => {
+            // panicking would unwind into SQLite's extension loader, and the
+            // only failure is another logger already being installed
+            let _ = install_as_global(registration.logger());
+        }
         Err(err) => return err,
     };
 
@@ -307,3 +362,149 @@ pub unsafe extern "C" fn sqlite3_memvfs_init(
 
     vars::SQLITE_OK_LOAD_PERMANENTLY
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rusqlite::{Connection, OpenFlags};
+    use sqlite_plugin::vfs::register_static;
+
+    #[test]
+    fn readonly_open_of_existing_file() -> rusqlite::Result<()> {
+        let vfs = MemVfs { files: Default::default() };
+        register_static(c"mem_readonly".to_owned(), vfs, RegisterOpts::default())
+            .expect("failed to register vfs");
+
+        let writer = Connection::open_with_flags_and_vfs(
+            "readonly.db",
+            OpenFlags::SQLITE_OPEN_READ_WRITE | OpenFlags::SQLITE_OPEN_CREATE,
+            "mem_readonly",
+        )?;
+        writer.execute_batch("create table t (val int); insert into t values (42);")?;
+
+        let reader = Connection::open_with_flags_and_vfs(
+            "readonly.db",
+            OpenFlags::SQLITE_OPEN_READ_ONLY,
+            "mem_readonly",
+        )?;
+        let val: i64 = reader.query_row("select val from t", [], |r| r.get(0))?;
+        assert_eq!(val, 42);
+        let err = reader.execute("insert into t values (1)", []).unwrap_err();
+        assert_eq!(err.sqlite_error_code(), Some(rusqlite::ErrorCode::ReadOnly));
+
+        // there is nothing to read from a file which doesn't exist yet
+        let err = Connection::open_with_flags_and_vfs(
+            "missing.db",
+            OpenFlags::SQLITE_OPEN_READ_ONLY,
+            "mem_readonly",
+        )
+        .unwrap_err();
+        assert_eq!(
+            err.sqlite_error_code(),
+            Some(rusqlite::ErrorCode::CannotOpen)
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn serialize_round_trip() -> rusqlite::Result<()> {
+        let files = Arc::new(Mutex::new(Vec::new()));
+        let vfs = MemVfs { files: files.clone() };
+        register_static(c"mem_serialize".to_owned(), vfs, RegisterOpts::default())
+            .expect("failed to register vfs");
+        let conn = Connection::open_with_flags_and_vfs(
+            "snapshot.db",
+            OpenFlags::SQLITE_OPEN_READ_WRITE | OpenFlags::SQLITE_OPEN_CREATE,
+            "mem_serialize",
+        )?;
+        conn.execute_batch("create table t (val text); insert into t values ('saved');")?;
+        drop(conn);
+
+        let bytes = MemVfs { files }.serialize("snapshot.db").unwrap();
+        assert!(bytes.starts_with(b"SQLite format 3\0"));
+        assert_eq!(
+            MemVfs { files: Default::default() }.serialize("snapshot.db"),
+            None
+        );
+
+        // a fresh vfs, as if in a new process
+        let vfs = MemVfs { files: Default::default() };
+        vfs.deserialize("restored.db", bytes);
+        register_static(c"mem_deserialize".to_owned(), vfs, RegisterOpts::default())
+            .expect("failed to register vfs");
+        let conn = Connection::open_with_flags_and_vfs(
+            "restored.db",
+            OpenFlags::SQLITE_OPEN_READ_WRITE,
+            "mem_deserialize",
+        )?;
+        let val: String = conn.query_row("select val from t", [], |r| r.get(0))?;
+        assert_eq!(val, "saved");
+        conn.execute("insert into t values ('more')", [])?;
+        Ok(())
+    }
+
+    #[test]
+    fn reads_across_eof() {
+        let vfs = MemVfs { files: Default::default() };
+        let opts = OpenOpts::new(
+            vars::SQLITE_OPEN_MAIN_DB | vars::SQLITE_OPEN_READWRITE | vars::SQLITE_OPEN_CREATE,
+        );
+        let mut file = vfs.open(Some("eof.db"), opts).unwrap();
+        let data = b"0123456789";
+        assert_eq!(vfs.write(&mut file, 0, data), Ok(data.len()));
+
+        for offset in 0..14 {
+            for len in 0..14 {
+                let end = (offset + len).min(data.len());
+                let read = data.get(offset..end).unwrap_or_default();
+
+                let mut buf = [0xaa; 14];
+                let n = vfs.read(&mut file, offset, &mut buf[..len]);
+                assert_eq!(n, Ok(read.len()), "offset={offset} len={len}");
+                assert_eq!(&buf[..read.len()], read);
+
+                let mut buf = [MaybeUninit::new(0xaa); 14];
+                let result = vfs.read_exact(&mut file, offset, &mut buf[..len]);
+                let expected = if read.len() == len {
+                    Ok(())
+                } else {
+                    Err(vars::SQLITE_IOERR_SHORT_READ)
+                };
+                assert_eq!(result, expected, "offset={offset} len={len}");
+                let buf = buf.map(|b| unsafe { b.assume_init() });
+                assert_eq!(&buf[..read.len()], read);
+                assert!(buf[read.len()..len].iter().all(|&b| b == 0));
+            }
+        }
+    }
+
+    #[test]
+    fn delete_on_close() {
+        let vfs = MemVfs { files: Default::default() };
+        let opts =
+            |flags| OpenOpts::new(vars::SQLITE_OPEN_READWRITE | vars::SQLITE_OPEN_CREATE | flags);
+        let temp = opts(vars::SQLITE_OPEN_TEMP_JOURNAL | vars::SQLITE_OPEN_DELETEONCLOSE);
+
+        // a named file is unlinked when the handle asking for it is closed
+        let other = vfs.open(Some("temp.db"), opts(0)).unwrap();
+        let file = vfs.open(Some("temp.db"), temp).unwrap();
+        assert_eq!(vfs.close(other), Ok(()));
+        assert_eq!(vfs.access("temp.db", AccessFlags::Exists), Ok(true));
+        assert_eq!(vfs.close(file), Ok(()));
+        assert_eq!(vfs.access("temp.db", AccessFlags::Exists), Ok(false));
+
+        // but only once, leaving a new file created under the same name alone
+        let file = vfs.open(Some("temp.db"), temp).unwrap();
+        assert_eq!(vfs.delete("temp.db", false), Ok(()));
+        let other = vfs.open(Some("temp.db"), opts(0)).unwrap();
+        assert_eq!(vfs.close(file), Ok(()));
+        assert_eq!(vfs.access("temp.db", AccessFlags::Exists), Ok(true));
+        assert_eq!(vfs.close(other), Ok(()));
+
+        // an anonymous file is reclaimed along with its handle
+        let file = vfs.open(None, temp).unwrap();
+        let data = Arc::downgrade(&file.data);
+        assert_eq!(vfs.close(file), Ok(()));
+        assert!(data.upgrade().is_none());
+    }
+}
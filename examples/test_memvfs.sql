@@ -1,6 +1,6 @@
 -- Load the memvfs extension and open a new connection using it
 -- Build the memvfs extension using the following command:
---   cargo build --example memvfs --features dynamic
+--   cargo build --example memvfs --features dynamic,log
 
 -- uncomment to enable verbose logs
 -- .log stderr
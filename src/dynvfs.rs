@@ -0,0 +1,730 @@
+//! Choosing a vfs implementation at runtime.
+//!
+//! [`register_static`](crate::vfs::register_static) is generic over the vfs
+//! type, so registering one of several backends picked from configuration
+//! would otherwise need a match with a call per backend. [`Vfs`] is dyn
+//! compatible instead, and `Box<V>` implements [`Vfs`] for any boxed vfs, so:
+//!
+//! - backends which share a handle type can be registered as a
+//!   `Box<dyn Vfs<Handle = H>>` directly.
+//! - backends with different handle types can be erased with [`into_dyn`],
+//!   which boxes each handle they open as a [`DynHandle`]. Every backend
+//!   erased this way has the same type, [`DynVfs`].
+//!
+//! ```ignore
+//! let vfs: DynVfs = match config.backend {
+//!     Backend::Memory => into_dyn(MemVfs::default()),
+//!     Backend::Remote => into_dyn(RemoteVfs::connect(&config.url)?),
+//! };
+//! register_static(name, vfs, RegisterOpts::default())?;
+//! ```
+//!
+//! Erasing costs an allocation per opened file and a virtual call per
+//! method, which is small next to the I/O itself.
+
+use alloc::borrow::Cow;
+use alloc::boxed::Box;
+use alloc::string::String;
+use core::any::Any;
+use core::ffi::c_void;
+use core::mem::MaybeUninit;
+use core::ptr::NonNull;
+
+use crate::flags::{
+    AccessFlags, LockLevel, OpenKind, OpenOpts, ShmLockMode, SyncFlags, VfsCapabilities,
+};
+use crate::vars;
+use crate::vfs::{BusyHandler, Pragma, PragmaErr, PragmaResult, Vfs, VfsHandle, VfsResult};
+
+/// A vfs whose handle type has been erased by [`into_dyn`].
+pub type DynVfs = Box<dyn Vfs<Handle = DynHandle>>;
+
+/// Erases the handle type of `vfs`, so that it can be stored alongside other
+/// vfs implementations as a [`DynVfs`].
+pub fn into_dyn<V>(vfs: V) -> DynVfs
+where
+    V: Vfs + 'static,
+    V::Handle: 'static,
+{
+    Box::new(Erased(vfs))
+}
+
+/// Lets [`DynHandle`] downcast to the handle it boxes.
+trait AnyHandle: VfsHandle + Any {
+    fn as_any(&self) -> &dyn Any;
+    fn as_any_mut(&mut self) -> &mut dyn Any;
+    fn into_any(self: Box<Self>) -> Box<dyn Any>;
+}
+
+impl<H: VfsHandle + 'static> AnyHandle for H {
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+
+    fn as_any_mut(&mut self) -> &mut dyn Any {
+        self
+    }
+
+    fn into_any(self: Box<Self>) -> Box<dyn Any> {
+        self
+    }
+}
+
+/// A boxed handle opened by a vfs erased with [`into_dyn`].
+pub struct DynHandle(Box<dyn AnyHandle>);
+
+impl DynHandle {
+    pub fn new<H: VfsHandle + 'static>(handle: H) -> Self {
+        Self(Box::new(handle))
+    }
+
+    /// Returns the boxed handle if it is an `H`.
+    pub fn downcast_mut<H: VfsHandle + 'static>(&mut self) -> Option<&mut H> {
+        self.0.as_any_mut().downcast_mut()
+    }
+
+    /// Unboxes the handle if it is an `H`, otherwise returns `self`.
+    pub fn downcast<H: VfsHandle + 'static>(self) -> Result<H, Self> {
+        if self.0.as_any().is::<H>() {
+            let handle = self.0.into_any().downcast().expect("handle is an H");
+            Ok(*handle)
+        } else {
+            Err(self)
+        }
+    }
+}
+
+impl VfsHandle for DynHandle {
+    fn readonly(&self) -> bool {
+        self.0.readonly()
+    }
+
+    fn in_memory(&self) -> bool {
+        self.0.in_memory()
+    }
+
+    fn open_kind(&self) -> OpenKind {
+        self.0.open_kind()
+    }
+}
+
+impl core::fmt::Debug for DynHandle {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        f.debug_struct("DynHandle")
+            .field("open_kind", &self.open_kind())
+            .finish_non_exhaustive()
+    }
+}
+
+impl<V: Vfs + ?Sized> Vfs for Box<V> {
+    type Handle = V::Handle;
+
+    fn on_register(&self, name: &str, db: *mut c_void) {
+        (**self).on_register(name, db)
+    }
+
+    fn capabilities(&self) -> VfsCapabilities {
+        (**self).capabilities()
+    }
+
+    fn shutdown(&self) -> VfsResult<()> {
+        (**self).shutdown()
+    }
+
+    fn canonical_path<'a>(&self, path: Cow<'a, str>) -> VfsResult<Cow<'a, str>> {
+        (**self).canonical_path(path)
+    }
+
+    fn same_file(&self, a: &str, b: &str) -> VfsResult<bool> {
+        (**self).same_file(a, b)
+    }
+
+    fn open(&self, path: Option<&str>, opts: OpenOpts) -> VfsResult<Self::Handle> {
+        (**self).open(path, opts)
+    }
+
+    fn post_open(&self, handle: &mut Self::Handle, flags: &mut i32) {
+        (**self).post_open(handle, flags)
+    }
+
+    fn delete(&self, path: &str, sync_dir: bool) -> VfsResult<()> {
+        (**self).delete(path, sync_dir)
+    }
+
+    fn access(&self, path: &str, flags: AccessFlags) -> VfsResult<bool> {
+        (**self).access(path, flags)
+    }
+
+    fn file_size(&self, handle: &mut Self::Handle) -> VfsResult<usize> {
+        (**self).file_size(handle)
+    }
+
+    fn truncate(&self, handle: &mut Self::Handle, size: usize) -> VfsResult<()> {
+        (**self).truncate(handle, size)
+    }
+
+    fn min_file_size(&self, handle: &mut Self::Handle) -> usize {
+        (**self).min_file_size(handle)
+    }
+
+    fn write(&self, handle: &mut Self::Handle, offset: usize, data: &[u8]) -> VfsResult<usize> {
+        (**self).write(handle, offset, data)
+    }
+
+    fn read(&self, handle: &mut Self::Handle, offset: usize, data: &mut [u8]) -> VfsResult<usize> {
+        (**self).read(handle, offset, data)
+    }
+
+    fn read_into_uninit(
+        &self,
+        handle: &mut Self::Handle,
+        offset: usize,
+        data: &mut [MaybeUninit<u8>],
+    ) -> VfsResult<usize> {
+        (**self).read_into_uninit(handle, offset, data)
+    }
+
+    fn read_exact(
+        &self,
+        handle: &mut Self::Handle,
+        offset: usize,
+        data: &mut [MaybeUninit<u8>],
+    ) -> VfsResult<()> {
+        (**self).read_exact(handle, offset, data)
+    }
+
+    fn lock(&self, handle: &mut Self::Handle, level: LockLevel) -> VfsResult<()> {
+        (**self).lock(handle, level)
+    }
+
+    fn unlock(&self, handle: &mut Self::Handle, level: LockLevel) -> VfsResult<()> {
+        (**self).unlock(handle, level)
+    }
+
+    fn check_reserved_lock(&self, handle: &mut Self::Handle) -> VfsResult<bool> {
+        (**self).check_reserved_lock(handle)
+    }
+
+    fn sync(&self, handle: &mut Self::Handle, flags: SyncFlags) -> VfsResult<()> {
+        (**self).sync(handle, flags)
+    }
+
+    fn barrier(&self, handle: &mut Self::Handle) -> VfsResult<()> {
+        (**self).barrier(handle)
+    }
+
+    fn sync_fcntl(&self, handle: &mut Self::Handle, super_journal: Option<&str>) -> VfsResult<()> {
+        (**self).sync_fcntl(handle, super_journal)
+    }
+
+    fn commit_phase_two(&self, handle: &mut Self::Handle) -> VfsResult<()> {
+        (**self).commit_phase_two(handle)
+    }
+
+    fn overwrite_hint(&self, handle: &mut Self::Handle) -> VfsResult<()> {
+        (**self).overwrite_hint(handle)
+    }
+
+    fn close(&self, handle: Self::Handle) -> VfsResult<()> {
+        (**self).close(handle)
+    }
+
+    fn pragma(
+        &self,
+        handle: &mut Self::Handle,
+        pragma: Pragma<'_>,
+    ) -> Result<PragmaResult, PragmaErr> {
+        (**self).pragma(handle, pragma)
+    }
+
+    fn set_config(&self, handle: &mut Self::Handle, key: &str, value: &str) -> VfsResult<()> {
+        (**self).set_config(handle, key, value)
+    }
+
+    fn sector_size(&self, handle: &mut Self::Handle) -> VfsResult<i32> {
+        (**self).sector_size(handle)
+    }
+
+    fn device_characteristics(&self, handle: &mut Self::Handle) -> VfsResult<i32> {
+        (**self).device_characteristics(handle)
+    }
+
+    fn shm_map(
+        &self,
+        handle: &mut Self::Handle,
+        region_idx: usize,
+        region_size: usize,
+        extend: bool,
+    ) -> VfsResult<Option<NonNull<u8>>> {
+        (**self).shm_map(handle, region_idx, region_size, extend)
+    }
+
+    fn shm_lock(
+        &self,
+        handle: &mut Self::Handle,
+        offset: u32,
+        count: u32,
+        mode: ShmLockMode,
+    ) -> VfsResult<()> {
+        (**self).shm_lock(handle, offset, count, mode)
+    }
+
+    fn shm_barrier(&self, handle: &mut Self::Handle) {
+        (**self).shm_barrier(handle)
+    }
+
+    fn shm_unmap(&self, handle: &mut Self::Handle, delete: bool) -> VfsResult<()> {
+        (**self).shm_unmap(handle, delete)
+    }
+
+    fn fetch(
+        &self,
+        handle: &mut Self::Handle,
+        offset: i64,
+        amt: usize,
+    ) -> VfsResult<Option<NonNull<u8>>> {
+        (**self).fetch(handle, offset, amt)
+    }
+
+    fn unfetch(&self, handle: &mut Self::Handle, offset: i64, ptr: *mut u8) -> VfsResult<()> {
+        (**self).unfetch(handle, offset, ptr)
+    }
+
+    fn mmap_size(&self, handle: &mut Self::Handle, requested: Option<i64>) -> VfsResult<i64> {
+        (**self).mmap_size(handle, requested)
+    }
+
+    fn set_busy_handler(&self, handle: &mut Self::Handle, handler: BusyHandler) {
+        (**self).set_busy_handler(handle, handler)
+    }
+
+    fn has_moved(&self, handle: &mut Self::Handle) -> VfsResult<bool> {
+        (**self).has_moved(handle)
+    }
+
+    fn lock_state(&self, handle: &mut Self::Handle) -> VfsResult<LockLevel> {
+        (**self).lock_state(handle)
+    }
+
+    fn lock_timeout(&self, handle: &mut Self::Handle, millis: i32) -> VfsResult<()> {
+        (**self).lock_timeout(handle, millis)
+    }
+
+    fn persist_wal(&self, handle: &mut Self::Handle, value: Option<bool>) -> VfsResult<bool> {
+        (**self).persist_wal(handle, value)
+    }
+
+    fn powersafe_overwrite(
+        &self,
+        handle: &mut Self::Handle,
+        value: Option<bool>,
+    ) -> VfsResult<bool> {
+        (**self).powersafe_overwrite(handle, value)
+    }
+
+    fn chunk_size(&self, handle: &mut Self::Handle, size: usize) -> VfsResult<()> {
+        (**self).chunk_size(handle, size)
+    }
+
+    fn begin_atomic_write(&self, handle: &mut Self::Handle) -> VfsResult<()> {
+        (**self).begin_atomic_write(handle)
+    }
+
+    fn commit_atomic_write(&self, handle: &mut Self::Handle) -> VfsResult<()> {
+        (**self).commit_atomic_write(handle)
+    }
+
+    fn rollback_atomic_write(&self, handle: &mut Self::Handle) -> VfsResult<()> {
+        (**self).rollback_atomic_write(handle)
+    }
+
+    fn temp_filename(&self) -> VfsResult<String> {
+        (**self).temp_filename()
+    }
+
+    fn randomness(&self, buf: &mut [u8]) -> VfsResult<usize> {
+        (**self).randomness(buf)
+    }
+
+    fn sleep(&self, micros: i32) -> VfsResult<i32> {
+        (**self).sleep(micros)
+    }
+
+    fn current_time(&self) -> VfsResult<f64> {
+        (**self).current_time()
+    }
+
+    fn current_time_int64(&self) -> VfsResult<i64> {
+        (**self).current_time_int64()
+    }
+}
+
+/// The vfs behind [`into_dyn`], which boxes the handles of `V`.
+struct Erased<V>(V);
+
+impl<V> Erased<V>
+where
+    V: Vfs,
+    V::Handle: 'static,
+{
+    /// Handles only ever come from `open` below, so this only fails if
+    /// `SQLite` passes a file to the wrong vfs.
+    fn handle(handle: &mut DynHandle) -> VfsResult<&mut V::Handle> {
+        handle.downcast_mut().ok_or(vars::SQLITE_INTERNAL)
+    }
+}
+
+impl<V> Vfs for Erased<V>
+where
+    V: Vfs,
+    V::Handle: 'static,
+{
+    type Handle = DynHandle;
+
+    fn on_register(&self, name: &str, db: *mut c_void) {
+        self.0.on_register(name, db)
+    }
+
+    fn capabilities(&self) -> VfsCapabilities {
+        self.0.capabilities()
+    }
+
+    fn shutdown(&self) -> VfsResult<()> {
+        self.0.shutdown()
+    }
+
+    fn canonical_path<'a>(&self, path: Cow<'a, str>) -> VfsResult<Cow<'a, str>> {
+        self.0.canonical_path(path)
+    }
+
+    fn same_file(&self, a: &str, b: &str) -> VfsResult<bool> {
+        self.0.same_file(a, b)
+    }
+
+    fn open(&self, path: Option<&str>, opts: OpenOpts) -> VfsResult<Self::Handle> {
+        self.0.open(path, opts).map(DynHandle::new)
+    }
+
+    fn post_open(&self, handle: &mut Self::Handle, flags: &mut i32) {
+        if let Ok(handle) = Self::handle(handle) {
+            self.0.post_open(handle, flags)
+        }
+    }
+
+    fn delete(&self, path: &str, sync_dir: bool) -> VfsResult<()> {
+        self.0.delete(path, sync_dir)
+    }
+
+    fn access(&self, path: &str, flags: AccessFlags) -> VfsResult<bool> {
+        self.0.access(path, flags)
+    }
+
+    fn file_size(&self, handle: &mut Self::Handle) -> VfsResult<usize> {
+        self.0.file_size(Self::handle(handle)?)
+    }
+
+    fn truncate(&self, handle: &mut Self::Handle, size: usize) -> VfsResult<()> {
+        self.0.truncate(Self::handle(handle)?, size)
+    }
+
+    fn min_file_size(&self, handle: &mut Self::Handle) -> usize {
+        Self::handle(handle).map_or(0, |handle| self.0.min_file_size(handle))
+    }
+
+    fn write(&self, handle: &mut Self::Handle, offset: usize, data: &[u8]) -> VfsResult<usize> {
+        self.0.write(Self::handle(handle)?, offset, data)
+    }
+
+    fn read(&self, handle: &mut Self::Handle, offset: usize, data: &mut [u8]) -> VfsResult<usize> {
+        self.0.read(Self::handle(handle)?, offset, data)
+    }
+
+    fn read_into_uninit(
+        &self,
+        handle: &mut Self::Handle,
+        offset: usize,
+        data: &mut [MaybeUninit<u8>],
+    ) -> VfsResult<usize> {
+        self.0.read_into_uninit(Self::handle(handle)?, offset, data)
+    }
+
+    fn read_exact(
+        &self,
+        handle: &mut Self::Handle,
+        offset: usize,
+        data: &mut [MaybeUninit<u8>],
+    ) -> VfsResult<()> {
+        match Self::handle(handle) {
+            Ok(handle) => self.0.read_exact(handle, offset, data),
+            Err(err) => {
+                // every byte must be initialized, even on failure
+                data.fill(MaybeUninit::new(0));
+                Err(err)
+            }
+        }
+    }
+
+    fn lock(&self, handle: &mut Self::Handle, level: LockLevel) -> VfsResult<()> {
+        self.0.lock(Self::handle(handle)?, level)
+    }
+
+    fn unlock(&self, handle: &mut Self::Handle, level: LockLevel) -> VfsResult<()> {
+        self.0.unlock(Self::handle(handle)?, level)
+    }
+
+    fn check_reserved_lock(&self, handle: &mut Self::Handle) -> VfsResult<bool> {
+        self.0.check_reserved_lock(Self::handle(handle)?)
+    }
+
+    fn sync(&self, handle: &mut Self::Handle, flags: SyncFlags) -> VfsResult<()> {
+        self.0.sync(Self::handle(handle)?, flags)
+    }
+
+    fn barrier(&self, handle: &mut Self::Handle) -> VfsResult<()> {
+        self.0.barrier(Self::handle(handle)?)
+    }
+
+    fn sync_fcntl(&self, handle: &mut Self::Handle, super_journal: Option<&str>) -> VfsResult<()> {
+        self.0.sync_fcntl(Self::handle(handle)?, super_journal)
+    }
+
+    fn commit_phase_two(&self, handle: &mut Self::Handle) -> VfsResult<()> {
+        self.0.commit_phase_two(Self::handle(handle)?)
+    }
+
+    fn overwrite_hint(&self, handle: &mut Self::Handle) -> VfsResult<()> {
+        self.0.overwrite_hint(Self::handle(handle)?)
+    }
+
+    fn close(&self, handle: Self::Handle) -> VfsResult<()> {
+        let handle = handle.downcast().map_err(|_| vars::SQLITE_INTERNAL)?;
+        self.0.close(handle)
+    }
+
+    fn pragma(
+        &self,
+        handle: &mut Self::Handle,
+        pragma: Pragma<'_>,
+    ) -> Result<PragmaResult, PragmaErr> {
+        let handle = Self::handle(handle).map_err(|_| PragmaErr::NotFound)?;
+        self.0.pragma(handle, pragma)
+    }
+
+    fn set_config(&self, handle: &mut Self::Handle, key: &str, value: &str) -> VfsResult<()> {
+        self.0.set_config(Self::handle(handle)?, key, value)
+    }
+
+    fn sector_size(&self, handle: &mut Self::Handle) -> VfsResult<i32> {
+        self.0.sector_size(Self::handle(handle)?)
+    }
+
+    fn device_characteristics(&self, handle: &mut Self::Handle) -> VfsResult<i32> {
+        self.0.device_characteristics(Self::handle(handle)?)
+    }
+
+    fn shm_map(
+        &self,
+        handle: &mut Self::Handle,
+        region_idx: usize,
+        region_size: usize,
+        extend: bool,
+    ) -> VfsResult<Option<NonNull<u8>>> {
+        self.0
+            .shm_map(Self::handle(handle)?, region_idx, region_size, extend)
+    }
+
+    fn shm_lock(
+        &self,
+        handle: &mut Self::Handle,
+        offset: u32,
+        count: u32,
+        mode: ShmLockMode,
+    ) -> VfsResult<()> {
+        self.0.shm_lock(Self::handle(handle)?, offset, count, mode)
+    }
+
+    fn shm_barrier(&self, handle: &mut Self::Handle) {
+        if let Ok(handle) = Self::handle(handle) {
+            self.0.shm_barrier(handle)
+        }
+    }
+
+    fn shm_unmap(&self, handle: &mut Self::Handle, delete: bool) -> VfsResult<()> {
+        self.0.shm_unmap(Self::handle(handle)?, delete)
+    }
+
+    fn fetch(
+        &self,
+        handle: &mut Self::Handle,
+        offset: i64,
+        amt: usize,
+    ) -> VfsResult<Option<NonNull<u8>>> {
+        self.0.fetch(Self::handle(handle)?, offset, amt)
+    }
+
+    fn unfetch(&self, handle: &mut Self::Handle, offset: i64, ptr: *mut u8) -> VfsResult<()> {
+        self.0.unfetch(Self::handle(handle)?, offset, ptr)
+    }
+
+    fn mmap_size(&self, handle: &mut Self::Handle, requested: Option<i64>) -> VfsResult<i64> {
+        self.0.mmap_size(Self::handle(handle)?, requested)
+    }
+
+    fn set_busy_handler(&self, handle: &mut Self::Handle, handler: BusyHandler) {
+        if let Ok(handle) = Self::handle(handle) {
+            self.0.set_busy_handler(handle, handler)
+        }
+    }
+
+    fn has_moved(&self, handle: &mut Self::Handle) -> VfsResult<bool> {
+        self.0.has_moved(Self::handle(handle)?)
+    }
+
+    fn lock_state(&self, handle: &mut Self::Handle) -> VfsResult<LockLevel> {
+        self.0.lock_state(Self::handle(handle)?)
+    }
+
+    fn lock_timeout(&self, handle: &mut Self::Handle, millis: i32) -> VfsResult<()> {
+        self.0.lock_timeout(Self::handle(handle)?, millis)
+    }
+
+    fn persist_wal(&self, handle: &mut Self::Handle, value: Option<bool>) -> VfsResult<bool> {
+        self.0.persist_wal(Self::handle(handle)?, value)
+    }
+
+    fn powersafe_overwrite(
+        &self,
+        handle: &mut Self::Handle,
+        value: Option<bool>,
+    ) -> VfsResult<bool> {
+        self.0.powersafe_overwrite(Self::handle(handle)?, value)
+    }
+
+    fn chunk_size(&self, handle: &mut Self::Handle, size: usize) -> VfsResult<()> {
+        self.0.chunk_size(Self::handle(handle)?, size)
+    }
+
+    fn begin_atomic_write(&self, handle: &mut Self::Handle) -> VfsResult<()> {
+        self.0.begin_atomic_write(Self::handle(handle)?)
+    }
+
+    fn commit_atomic_write(&self, handle: &mut Self::Handle) -> VfsResult<()> {
+        self.0.commit_atomic_write(Self::handle(handle)?)
+    }
+
+    fn rollback_atomic_write(&self, handle: &mut Self::Handle) -> VfsResult<()> {
+        self.0.rollback_atomic_write(Self::handle(handle)?)
+    }
+
+    fn temp_filename(&self) -> VfsResult<String> {
+        self.0.temp_filename()
+    }
+
+    fn randomness(&self, buf: &mut [u8]) -> VfsResult<usize> {
+        self.0.randomness(buf)
+    }
+
+    fn sleep(&self, micros: i32) -> VfsResult<i32> {
+        self.0.sleep(micros)
+    }
+
+    fn current_time(&self) -> VfsResult<f64> {
+        self.0.current_time()
+    }
+
+    fn current_time_int64(&self) -> VfsResult<i64> {
+        self.0.current_time_int64()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    // tests use std
+    extern crate std;
+
+    use super::*;
+    use crate::layer::{DelegatingVfs, VfsLayer};
+    use crate::mock::{MockHandle, MockState, MockVfs, NoopHooks};
+    use crate::vfs::{RegisterOpts, register_static};
+    use alloc::{ffi::CString, format, sync::Arc};
+    use parking_lot::Mutex;
+    use rusqlite::{Connection, OpenFlags};
+
+    /// A layer which changes nothing, to have a second vfs type.
+    struct Passthrough(MockVfs);
+
+    impl VfsLayer for Passthrough {
+        type Inner = MockVfs;
+
+        fn inner(&self) -> &MockVfs {
+            &self.0
+        }
+    }
+
+    /// Picks a backend by name, like an application reading its config.
+    fn backend(name: &str, shared: Arc<Mutex<MockState>>) -> DynVfs {
+        match name {
+            "mock" => into_dyn(MockVfs::new(shared)),
+            _ => into_dyn(DelegatingVfs(Passthrough(MockVfs::new(shared)))),
+        }
+    }
+
+    fn register<V: Vfs + 'static>(name: &str, vfs: V, shared: &Arc<Mutex<MockState>>) {
+        let registration =
+            register_static(CString::new(name).unwrap(), vfs, RegisterOpts::default())
+                .expect("failed to register vfs");
+        shared.lock().setup_logger(registration.logger());
+    }
+
+    fn round_trip(vfs: &str) -> rusqlite::Result<()> {
+        let conn = Connection::open_with_flags_and_vfs(
+            "dyn.db",
+            OpenFlags::SQLITE_OPEN_READ_WRITE | OpenFlags::SQLITE_OPEN_CREATE,
+            vfs,
+        )?;
+        conn.execute("create table t (val text)", [])?;
+        conn.execute("insert into t values ('hello')", [])?;
+        let val: String = conn.query_row("select val from t", [], |r| r.get(0))?;
+        assert_eq!(val, "hello");
+        Ok(())
+    }
+
+    #[test]
+    fn registers_backends_chosen_at_runtime() -> rusqlite::Result<()> {
+        for name in ["mock", "layer"] {
+            let shared = Arc::new(Mutex::new(MockState::new(Box::new(NoopHooks))));
+            let vfs_name = format!("dyn_{name}");
+            register(&vfs_name, backend(name, shared.clone()), &shared);
+            round_trip(&vfs_name)?;
+            assert!(shared.lock().file_names().contains(&"dyn.db"));
+        }
+
+        // backends sharing a handle type don't need their handles erased
+        let shared = Arc::new(Mutex::new(MockState::new(Box::new(NoopHooks))));
+        let vfs: Box<dyn Vfs<Handle = MockHandle>> = Box::new(MockVfs::new(shared.clone()));
+        register("dyn_boxed", vfs, &shared);
+        round_trip("dyn_boxed")?;
+        assert!(shared.lock().file_names().contains(&"dyn.db"));
+        Ok(())
+    }
+
+    #[test]
+    fn downcasts_handles() {
+        let shared = Arc::new(Mutex::new(MockState::new(Box::new(NoopHooks))));
+        // the mock logs through the registration
+        register("dyn_downcast", MockVfs::new(shared.clone()), &shared);
+        let vfs = into_dyn(MockVfs::new(shared));
+        let opts = OpenOpts::new(
+            vars::SQLITE_OPEN_MAIN_DB | vars::SQLITE_OPEN_READWRITE | vars::SQLITE_OPEN_CREATE,
+        );
+        let mut handle = vfs.open(Some("downcast.db"), opts).unwrap();
+        assert_eq!(handle.open_kind(), OpenKind::MainDb);
+        assert!(!handle.readonly());
+        assert!(handle.downcast_mut::<MockHandle>().is_some());
+        assert!(handle.downcast_mut::<DynHandle>().is_none());
+
+        // a failed downcast hands the handle back
+        let handle = handle.downcast::<DynHandle>().unwrap_err();
+        vfs.close(handle).unwrap();
+    }
+}
@@ -0,0 +1,380 @@
+use core::fmt::{self, Display};
+
+use crate::vars;
+use crate::vfs::SqliteErr;
+
+macro_rules! vfs_errors {
+    ($($variant:ident => $code:ident,)*) => {
+        /// A structured alternative to returning bare `SQLite` result codes.
+        ///
+        /// `VfsError` converts to and from [`SqliteErr`], so `Vfs`
+        /// implementations can return it via `?` or `.into()` while the
+        /// trait methods keep returning [`crate::vfs::VfsResult`]. Extended
+        /// result codes such as `SQLITE_IOERR_WRITE` are preserved as distinct
+        /// variants rather than collapsing to their primary code. Codes without
+        /// a dedicated variant round trip through [`VfsError::Other`].
+        #[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+        #[non_exhaustive]
+        pub enum VfsError {
+            $($variant,)*
+            /// Any other `SQLite` result code
+            Other(SqliteErr),
+        }
+
+        impl VfsError {
+            /// Returns the `SQLite` result code for this error.
+            pub const fn code(self) -> SqliteErr {
+                match self {
+                    $(Self::$variant => vars::$code,)*
+                    Self::Other(code) => code,
+                }
+            }
+
+            /// Returns the name of the `SQLite` constant for this error, if
+            /// it has a dedicated variant.
+            pub const fn name(self) -> Option<&'static str> {
+                match self {
+                    $(Self::$variant => Some(stringify!($code)),)*
+                    Self::Other(_) => None,
+                }
+            }
+        }
+
+        impl From<SqliteErr> for VfsError {
+            fn from(code: SqliteErr) -> Self {
+                match code {
+                    $(vars::$code => Self::$variant,)*
+                    code => Self::Other(code),
+                }
+            }
+        }
+    };
+}
+
+vfs_errors! {
+    Error => SQLITE_ERROR,
+    Internal => SQLITE_INTERNAL,
+    Perm => SQLITE_PERM,
+    Busy => SQLITE_BUSY,
+    Locked => SQLITE_LOCKED,
+    NoMem => SQLITE_NOMEM,
+    ReadOnly => SQLITE_READONLY,
+    IoErr => SQLITE_IOERR,
+    IoErrRead => SQLITE_IOERR_READ,
+    IoErrShortRead => SQLITE_IOERR_SHORT_READ,
+    IoErrWrite => SQLITE_IOERR_WRITE,
+    IoErrFsync => SQLITE_IOERR_FSYNC,
+    IoErrTruncate => SQLITE_IOERR_TRUNCATE,
+    IoErrFstat => SQLITE_IOERR_FSTAT,
+    IoErrUnlock => SQLITE_IOERR_UNLOCK,
+    IoErrRdLock => SQLITE_IOERR_RDLOCK,
+    IoErrDelete => SQLITE_IOERR_DELETE,
+    IoErrNoMem => SQLITE_IOERR_NOMEM,
+    IoErrAccess => SQLITE_IOERR_ACCESS,
+    IoErrCheckReservedLock => SQLITE_IOERR_CHECKRESERVEDLOCK,
+    IoErrLock => SQLITE_IOERR_LOCK,
+    IoErrClose => SQLITE_IOERR_CLOSE,
+    IoErrShmOpen => SQLITE_IOERR_SHMOPEN,
+    IoErrShmSize => SQLITE_IOERR_SHMSIZE,
+    IoErrShmLock => SQLITE_IOERR_SHMLOCK,
+    IoErrShmMap => SQLITE_IOERR_SHMMAP,
+    IoErrDeleteNoent => SQLITE_IOERR_DELETE_NOENT,
+    IoErrMmap => SQLITE_IOERR_MMAP,
+    Corrupt => SQLITE_CORRUPT,
+    NotFound => SQLITE_NOTFOUND,
+    Full => SQLITE_FULL,
+    CantOpen => SQLITE_CANTOPEN,
+    CantOpenNoTempDir => SQLITE_CANTOPEN_NOTEMPDIR,
+    CantOpenIsDir => SQLITE_CANTOPEN_ISDIR,
+    CantOpenFullPath => SQLITE_CANTOPEN_FULLPATH,
+    CantOpenConvPath => SQLITE_CANTOPEN_CONVPATH,
+    CantOpenSymlink => SQLITE_CANTOPEN_SYMLINK,
+    Protocol => SQLITE_PROTOCOL,
+    Misuse => SQLITE_MISUSE,
+    NoLfs => SQLITE_NOLFS,
+    Auth => SQLITE_AUTH,
+    NotADb => SQLITE_NOTADB,
+}
+
+macro_rules! result_codes {
+    ($($code:ident,)*) => {
+        /// Returns the name of a `SQLite` result code, such as
+        /// `"SQLITE_IOERR_READ"` for `266`, or `None` if the code is unknown.
+        ///
+        /// The names cover every primary and extended result code defined by
+        /// the `SQLite` headers this crate was built against.
+        pub const fn err_name(code: SqliteErr) -> Option<&'static str> {
+            match code {
+                $(vars::$code => Some(stringify!($code)),)*
+                _ => None,
+            }
+        }
+    };
+}
+
+// generated by build.rs from the result codes in the SQLite headers
+include!(concat!(env!("OUT_DIR"), "/result_codes.rs"));
+
+/// Displays a `SQLite` result code by name, falling back to the number for
+/// unknown codes. Use this when logging a [`SqliteErr`]:
+///
+/// ```
+/// # use sqlite_plugin::{error::DisplaySqliteErr, vars};
+/// assert_eq!(
+///     DisplaySqliteErr(vars::SQLITE_IOERR_READ).to_string(),
+///     "SQLITE_IOERR_READ"
+/// );
+/// assert_eq!(DisplaySqliteErr(12345).to_string(), "SQLite error code 12345");
+/// ```
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct DisplaySqliteErr(pub SqliteErr);
+
+impl Display for DisplaySqliteErr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match err_name(self.0) {
+            Some(name) => write!(f, "{name}"),
+            None => write!(f, "SQLite error code {}", self.0),
+        }
+    }
+}
+
+impl VfsError {
+    /// Returns the primary result code for this error, such as `SQLITE_IOERR`
+    /// for `IoErrWrite`.
+    pub const fn primary_code(self) -> SqliteErr {
+        primary_code(self.code())
+    }
+}
+
+/// Builds the extended result code numbered `extended` under `primary`. For
+/// example, `extended_code(SQLITE_IOERR, 3)` is `SQLITE_IOERR_WRITE`. Prefer
+/// the named [`VfsError`] variants for codes which have one.
+pub const fn extended_code(primary: SqliteErr, extended: i32) -> SqliteErr {
+    primary | (extended << 8)
+}
+
+/// Returns the primary result code of a possibly extended result code.
+pub const fn primary_code(code: SqliteErr) -> SqliteErr {
+    code & 0xff
+}
+
+impl From<VfsError> for SqliteErr {
+    fn from(err: VfsError) -> Self {
+        err.code()
+    }
+}
+
+impl Display for VfsError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        DisplaySqliteErr(self.code()).fmt(f)
+    }
+}
+
+impl core::error::Error for VfsError {}
+
+/// Returned by [`crate::vfs::SqliteApi::try_new_dynamic`] when `SQLite`
+/// doesn't provide a routine required by this crate, which can happen with
+/// builds that omit parts of the API. Converts to `SQLITE_INTERNAL`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MissingApiRoutine {
+    /// The name of the missing `sqlite3_api_routines` field.
+    pub name: &'static str,
+}
+
+impl From<MissingApiRoutine> for SqliteErr {
+    fn from(_: MissingApiRoutine) -> Self {
+        vars::SQLITE_INTERNAL
+    }
+}
+
+impl Display for MissingApiRoutine {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "SQLite API routine {} is missing", self.name)
+    }
+}
+
+impl core::error::Error for MissingApiRoutine {}
+
+/// Maps a [`std::io::Error`] to the closest `SQLite` result code:
+///
+/// | `io::ErrorKind`                                       | `VfsError`       |
+/// |-------------------------------------------------------|------------------|
+/// | `NotFound`, `AlreadyExists`, `IsADirectory`, `NotADirectory` | `CantOpen` |
+/// | `PermissionDenied`                                    | `Perm`           |
+/// | `ReadOnlyFilesystem`                                  | `ReadOnly`       |
+/// | `StorageFull`, `QuotaExceeded`, `FileTooLarge`        | `Full`           |
+/// | `OutOfMemory`                                         | `NoMem`          |
+/// | `WouldBlock`, `ResourceBusy`                          | `Busy`           |
+/// | `UnexpectedEof`                                       | `IoErrShortRead` |
+/// | anything else                                         | `IoErr`          |
+///
+/// Use [`VfsError::from_io_error`] to choose a more specific fallback than
+/// `IoErr`, such as `IoErrWrite` when handling a failed write.
+#[cfg(feature = "std")]
+impl From<std::io::Error> for VfsError {
+    fn from(err: std::io::Error) -> Self {
+        Self::from_io_error(&err, Self::IoErr)
+    }
+}
+
+#[cfg(feature = "std")]
+impl VfsError {
+    /// Maps `err` to a `VfsError` using the table documented on
+    /// `From<std::io::Error>`, returning `fallback` for unmapped errors.
+    pub fn from_io_error(err: &std::io::Error, fallback: Self) -> Self {
+        use std::io::ErrorKind;
+        match err.kind() {
+            ErrorKind::NotFound
+            | ErrorKind::AlreadyExists
+            | ErrorKind::IsADirectory
+            | ErrorKind::NotADirectory => Self::CantOpen,
+            ErrorKind::PermissionDenied => Self::Perm,
+            ErrorKind::ReadOnlyFilesystem => Self::ReadOnly,
+            ErrorKind::StorageFull | ErrorKind::QuotaExceeded | ErrorKind::FileTooLarge => {
+                Self::Full
+            }
+            ErrorKind::OutOfMemory => Self::NoMem,
+            ErrorKind::WouldBlock | ErrorKind::ResourceBusy => Self::Busy,
+            ErrorKind::UnexpectedEof => Self::IoErrShortRead,
+            _ => fallback,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloc::string::ToString;
+
+    #[test]
+    fn round_trip() {
+        for code in [
+            vars::SQLITE_IOERR,
+            vars::SQLITE_IOERR_WRITE,
+            vars::SQLITE_CANTOPEN,
+            vars::SQLITE_NOTFOUND,
+            vars::SQLITE_FULL,
+            vars::SQLITE_CORRUPT_VTAB,
+            12345,
+        ] {
+            assert_eq!(SqliteErr::from(VfsError::from(code)), code);
+        }
+    }
+
+    #[test]
+    fn preserves_extended_codes() {
+        assert_eq!(
+            VfsError::from(vars::SQLITE_IOERR_WRITE),
+            VfsError::IoErrWrite
+        );
+        assert_eq!(VfsError::from(vars::SQLITE_IOERR), VfsError::IoErr);
+        assert_eq!(
+            VfsError::from(vars::SQLITE_CORRUPT_VTAB),
+            VfsError::Other(vars::SQLITE_CORRUPT_VTAB)
+        );
+    }
+
+    #[test]
+    fn cantopen_extended_codes() {
+        let cases = [
+            (VfsError::CantOpenNoTempDir, 1),
+            (VfsError::CantOpenIsDir, 2),
+            (VfsError::CantOpenFullPath, 3),
+            (VfsError::CantOpenConvPath, 4),
+            (VfsError::CantOpenSymlink, 6),
+        ];
+        for (err, extended) in cases {
+            assert_eq!(err.code(), vars::SQLITE_CANTOPEN | (extended << 8));
+            assert_eq!(VfsError::from(err.code()), err);
+        }
+    }
+
+    #[test]
+    fn extended_code_layout() {
+        let cases = [
+            (vars::SQLITE_IOERR, 1, vars::SQLITE_IOERR_READ),
+            (vars::SQLITE_IOERR, 2, vars::SQLITE_IOERR_SHORT_READ),
+            (vars::SQLITE_IOERR, 3, vars::SQLITE_IOERR_WRITE),
+            (vars::SQLITE_IOERR, 23, vars::SQLITE_IOERR_DELETE_NOENT),
+            (vars::SQLITE_CANTOPEN, 2, vars::SQLITE_CANTOPEN_ISDIR),
+            (vars::SQLITE_BUSY, 1, vars::SQLITE_BUSY_RECOVERY),
+            (vars::SQLITE_READONLY, 1, vars::SQLITE_READONLY_RECOVERY),
+        ];
+        for (primary, extended, code) in cases {
+            assert_eq!(extended_code(primary, extended), code);
+            assert_eq!(primary_code(code), primary);
+        }
+        assert_eq!(VfsError::IoErrShortRead.primary_code(), vars::SQLITE_IOERR);
+        assert_eq!(VfsError::Busy.primary_code(), vars::SQLITE_BUSY);
+    }
+
+    #[test]
+    fn names_result_codes() {
+        let cases = [
+            (vars::SQLITE_OK, "SQLITE_OK"),
+            (vars::SQLITE_BUSY, "SQLITE_BUSY"),
+            (vars::SQLITE_IOERR_READ, "SQLITE_IOERR_READ"),
+            (vars::SQLITE_IOERR_SHORT_READ, "SQLITE_IOERR_SHORT_READ"),
+            (vars::SQLITE_CANTOPEN_ISDIR, "SQLITE_CANTOPEN_ISDIR"),
+            (vars::SQLITE_CORRUPT_VTAB, "SQLITE_CORRUPT_VTAB"),
+            (vars::SQLITE_DONE, "SQLITE_DONE"),
+        ];
+        for (code, name) in cases {
+            assert_eq!(err_name(code), Some(name));
+            assert_eq!(DisplaySqliteErr(code).to_string(), name);
+        }
+        assert_eq!(err_name(266), Some("SQLITE_IOERR_READ"));
+
+        // unknown codes
+        assert_eq!(err_name(vars::SQLITE_IOERR | (200 << 8)), None);
+        assert_eq!(err_name(-1), None);
+        assert_eq!(DisplaySqliteErr(-1).to_string(), "SQLite error code -1");
+
+        // every named error agrees with the generated names
+        for code in [vars::SQLITE_IOERR_MMAP, vars::SQLITE_NOTADB, 12345] {
+            let err = VfsError::from(code);
+            if let Some(name) = err.name() {
+                assert_eq!(err_name(code), Some(name));
+            }
+            assert_eq!(err.to_string(), DisplaySqliteErr(code).to_string());
+        }
+        assert_eq!(
+            VfsError::Other(vars::SQLITE_CORRUPT_VTAB).to_string(),
+            "SQLITE_CORRUPT_VTAB"
+        );
+    }
+
+    #[test]
+    fn question_mark_converts() {
+        fn inner() -> Result<(), VfsError> {
+            Err(VfsError::IoErrShortRead)
+        }
+        fn outer() -> crate::vfs::VfsResult<()> {
+            inner()?;
+            Ok(())
+        }
+        assert_eq!(outer(), Err(vars::SQLITE_IOERR_SHORT_READ));
+    }
+
+    #[cfg(feature = "std")]
+    #[test]
+    fn from_io_error() {
+        extern crate std;
+        use std::io::{Error, ErrorKind};
+
+        let cases = [
+            (ErrorKind::NotFound, VfsError::CantOpen),
+            (ErrorKind::PermissionDenied, VfsError::Perm),
+            (ErrorKind::StorageFull, VfsError::Full),
+            (ErrorKind::UnexpectedEof, VfsError::IoErrShortRead),
+            (ErrorKind::Other, VfsError::IoErr),
+        ];
+        for (kind, expected) in cases {
+            assert_eq!(VfsError::from(Error::from(kind)), expected);
+        }
+        assert_eq!(
+            VfsError::from_io_error(&Error::from(ErrorKind::Other), VfsError::IoErrWrite),
+            VfsError::IoErrWrite
+        );
+    }
+}
@@ -1,8 +1,11 @@
+use alloc::ffi::CString;
+use core::ffi::{CStr, c_char, c_int};
 use core::fmt::{self, Debug, Formatter};
+use core::ops::{BitAnd, BitOr, BitOrAssign, Not};
 
-use crate::vars;
+use crate::{ffi, vars};
 
-#[derive(Debug, PartialEq, Eq)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
 pub enum OpenKind {
     Unknown,
     MainDb,
@@ -19,24 +22,71 @@ impl OpenKind {
     pub fn is_temp(&self) -> bool {
         matches!(self, Self::TempDb | Self::TempJournal | Self::TransientDb)
     }
+
+    /// Returns true for rollback journals, statement journals, and super-journals.
+    pub fn is_journal(&self) -> bool {
+        matches!(
+            self,
+            Self::MainJournal | Self::TempJournal | Self::SubJournal | Self::SuperJournal
+        )
+    }
+
+    /// Returns true for write-ahead log files.
+    pub fn is_wal(&self) -> bool {
+        matches!(self, Self::Wal)
+    }
+}
+
+impl OpenKind {
+    /// The `SQLITE_OPEN_*` bits which give the kind of file being opened,
+    /// exactly one of which `SQLite` sets when opening a file.
+    const MASK: i32 = vars::SQLITE_OPEN_MAIN_DB
+        | vars::SQLITE_OPEN_MAIN_JOURNAL
+        | vars::SQLITE_OPEN_TEMP_DB
+        | vars::SQLITE_OPEN_TEMP_JOURNAL
+        | vars::SQLITE_OPEN_TRANSIENT_DB
+        | vars::SQLITE_OPEN_SUBJOURNAL
+        | vars::SQLITE_OPEN_SUPER_JOURNAL
+        | vars::SQLITE_OPEN_WAL;
 }
 
 impl From<i32> for OpenKind {
+    /// Returns the kind of file given by the `SQLITE_OPEN_*` flags, ignoring
+    /// flags which don't describe the kind. Returns `Unknown` if no kind, or
+    /// more than one, is set.
     fn from(flags: i32) -> Self {
-        match flags {
-            flags if flags & vars::SQLITE_OPEN_MAIN_DB > 0 => Self::MainDb,
-            flags if flags & vars::SQLITE_OPEN_MAIN_JOURNAL > 0 => Self::MainJournal,
-            flags if flags & vars::SQLITE_OPEN_TEMP_DB > 0 => Self::TempDb,
-            flags if flags & vars::SQLITE_OPEN_TEMP_JOURNAL > 0 => Self::TempJournal,
-            flags if flags & vars::SQLITE_OPEN_TRANSIENT_DB > 0 => Self::TransientDb,
-            flags if flags & vars::SQLITE_OPEN_SUBJOURNAL > 0 => Self::SubJournal,
-            flags if flags & vars::SQLITE_OPEN_SUPER_JOURNAL > 0 => Self::SuperJournal,
-            flags if flags & vars::SQLITE_OPEN_WAL > 0 => Self::Wal,
+        match flags & Self::MASK {
+            vars::SQLITE_OPEN_MAIN_DB => Self::MainDb,
+            vars::SQLITE_OPEN_MAIN_JOURNAL => Self::MainJournal,
+            vars::SQLITE_OPEN_TEMP_DB => Self::TempDb,
+            vars::SQLITE_OPEN_TEMP_JOURNAL => Self::TempJournal,
+            vars::SQLITE_OPEN_TRANSIENT_DB => Self::TransientDb,
+            vars::SQLITE_OPEN_SUBJOURNAL => Self::SubJournal,
+            vars::SQLITE_OPEN_SUPER_JOURNAL => Self::SuperJournal,
+            vars::SQLITE_OPEN_WAL => Self::Wal,
             _ => Self::Unknown,
         }
     }
 }
 
+impl From<OpenKind> for i32 {
+    /// Returns the `SQLITE_OPEN_*` flag for the kind of file, or 0 for
+    /// `Unknown`.
+    fn from(kind: OpenKind) -> Self {
+        match kind {
+            OpenKind::Unknown => 0,
+            OpenKind::MainDb => vars::SQLITE_OPEN_MAIN_DB,
+            OpenKind::MainJournal => vars::SQLITE_OPEN_MAIN_JOURNAL,
+            OpenKind::TempDb => vars::SQLITE_OPEN_TEMP_DB,
+            OpenKind::TempJournal => vars::SQLITE_OPEN_TEMP_JOURNAL,
+            OpenKind::TransientDb => vars::SQLITE_OPEN_TRANSIENT_DB,
+            OpenKind::SubJournal => vars::SQLITE_OPEN_SUBJOURNAL,
+            OpenKind::SuperJournal => vars::SQLITE_OPEN_SUPER_JOURNAL,
+            OpenKind::Wal => vars::SQLITE_OPEN_WAL,
+        }
+    }
+}
+
 #[derive(Debug, PartialEq, Eq)]
 pub enum CreateMode {
     None,
@@ -71,25 +121,209 @@ impl OpenMode {
     pub fn must_create(&self) -> bool {
         matches!(self, Self::ReadWrite { create: CreateMode::MustCreate })
     }
+    /// Returns true if a missing file should be created rather than failing
+    /// the open with `SQLITE_CANTOPEN`.
+    pub fn can_create(&self) -> bool {
+        matches!(
+            self,
+            Self::ReadWrite {
+                create: CreateMode::Create | CreateMode::MustCreate
+            }
+        )
+    }
     pub fn is_readonly(&self) -> bool {
         matches!(self, Self::ReadOnly)
     }
 }
 
+macro_rules! open_flags {
+    ($($(#[$doc:meta])* $name:ident => $flag:ident,)*) => {
+        impl OpenFlags {
+            $($(#[$doc])* pub const $name: Self = Self { bits: vars::$flag };)*
+        }
+
+        impl Debug for OpenFlags {
+            fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+                let mut list = f.debug_list();
+                let mut unknown = self.bits;
+                $(
+                    if self.contains(Self::$name) {
+                        list.entry(&format_args!(stringify!($name)));
+                        unknown &= !vars::$flag;
+                    }
+                )*
+                if unknown != 0 {
+                    list.entry(&format_args!("{unknown:#x}"));
+                }
+                list.finish()
+            }
+        }
+    };
+}
+
+/// The `SQLITE_OPEN_*` flags passed to `Vfs::open`.
+/// See [SQLite documentation](https://www.sqlite.org/c3ref/c_open_autoproxy.html) for more information.
+///
+/// ```
+/// use sqlite_plugin::flags::OpenFlags;
+/// let flags = OpenFlags::from(0x102);
+/// assert!(flags.contains(OpenFlags::MAIN_DB | OpenFlags::READWRITE));
+/// assert!(!flags.contains(OpenFlags::MAIN_DB | OpenFlags::CREATE));
+/// ```
+#[derive(Clone, Copy, Default, PartialEq, Eq, Hash)]
+pub struct OpenFlags {
+    bits: i32,
+}
+
+impl OpenFlags {
+    pub const fn empty() -> Self {
+        Self { bits: 0 }
+    }
+
+    pub const fn from_bits(bits: i32) -> Self {
+        Self { bits }
+    }
+
+    pub const fn bits(self) -> i32 {
+        self.bits
+    }
+
+    /// Returns true if all flags in `other` are also set in `self`.
+    pub const fn contains(self, other: Self) -> bool {
+        self.bits & other.bits == other.bits
+    }
+
+    /// Returns true if any flag in `other` is also set in `self`.
+    pub const fn intersects(self, other: Self) -> bool {
+        self.bits & other.bits != 0
+    }
+}
+
+open_flags! {
+    READONLY => SQLITE_OPEN_READONLY,
+    READWRITE => SQLITE_OPEN_READWRITE,
+    CREATE => SQLITE_OPEN_CREATE,
+    DELETEONCLOSE => SQLITE_OPEN_DELETEONCLOSE,
+    EXCLUSIVE => SQLITE_OPEN_EXCLUSIVE,
+    AUTOPROXY => SQLITE_OPEN_AUTOPROXY,
+    URI => SQLITE_OPEN_URI,
+    MEMORY => SQLITE_OPEN_MEMORY,
+    MAIN_DB => SQLITE_OPEN_MAIN_DB,
+    TEMP_DB => SQLITE_OPEN_TEMP_DB,
+    TRANSIENT_DB => SQLITE_OPEN_TRANSIENT_DB,
+    MAIN_JOURNAL => SQLITE_OPEN_MAIN_JOURNAL,
+    TEMP_JOURNAL => SQLITE_OPEN_TEMP_JOURNAL,
+    SUBJOURNAL => SQLITE_OPEN_SUBJOURNAL,
+    SUPER_JOURNAL => SQLITE_OPEN_SUPER_JOURNAL,
+    NOMUTEX => SQLITE_OPEN_NOMUTEX,
+    FULLMUTEX => SQLITE_OPEN_FULLMUTEX,
+    SHAREDCACHE => SQLITE_OPEN_SHAREDCACHE,
+    PRIVATECACHE => SQLITE_OPEN_PRIVATECACHE,
+    WAL => SQLITE_OPEN_WAL,
+    NOFOLLOW => SQLITE_OPEN_NOFOLLOW,
+    EXRESCODE => SQLITE_OPEN_EXRESCODE,
+}
+
+impl BitOr for OpenFlags {
+    type Output = Self;
+    fn bitor(self, rhs: Self) -> Self {
+        Self { bits: self.bits | rhs.bits }
+    }
+}
+
+impl BitOrAssign for OpenFlags {
+    fn bitor_assign(&mut self, rhs: Self) {
+        self.bits |= rhs.bits;
+    }
+}
+
+impl BitAnd for OpenFlags {
+    type Output = Self;
+    fn bitand(self, rhs: Self) -> Self {
+        Self { bits: self.bits & rhs.bits }
+    }
+}
+
+impl Not for OpenFlags {
+    type Output = Self;
+    fn not(self) -> Self {
+        Self { bits: !self.bits }
+    }
+}
+
+impl From<i32> for OpenFlags {
+    fn from(bits: i32) -> Self {
+        Self::from_bits(bits)
+    }
+}
+
+impl From<OpenFlags> for i32 {
+    fn from(flags: OpenFlags) -> Self {
+        flags.bits()
+    }
+}
+
+/// The `SQLite` routines used to read URI query parameters.
+#[derive(Clone, Copy)]
+pub(crate) struct UriRoutines {
+    pub(crate) parameter:
+        unsafe extern "C" fn(z: ffi::sqlite3_filename, param: *const c_char) -> *const c_char,
+    pub(crate) boolean: unsafe extern "C" fn(
+        z: ffi::sqlite3_filename,
+        param: *const c_char,
+        default: c_int,
+    ) -> c_int,
+    pub(crate) int64: unsafe extern "C" fn(
+        z: ffi::sqlite3_filename,
+        param: *const c_char,
+        default: ffi::sqlite3_int64,
+    ) -> ffi::sqlite3_int64,
+}
+
+/// The filename passed to xOpen along with the `SQLite` routines needed to
+/// read its URI query parameters.
+#[derive(Clone, Copy)]
+struct Uri {
+    filename: ffi::sqlite3_filename,
+    routines: UriRoutines,
+}
+
+/// The options passed to `Vfs::open`. Each `SQLITE_OPEN_*` flag which
+/// describes the file has an accessor. Flags which only affect the
+/// connection, such as `SQLITE_OPEN_NOMUTEX`, `SQLITE_OPEN_SHAREDCACHE`, and
+/// `SQLITE_OPEN_EXRESCODE`, are available through `open_flags`.
 #[derive(Clone, Copy)]
 pub struct OpenOpts {
     flags: i32,
+    uri: Option<Uri>,
+    anonymous: bool,
 }
 
+// Safety: the only pointer held by OpenOpts is the filename passed to xOpen,
+// which is never mutated and is only read by the unsafe uri_* accessors,
+// whose callers must ensure the file is still open.
+unsafe impl Send for OpenOpts {}
+unsafe impl Sync for OpenOpts {}
+
 impl OpenOpts {
     pub fn new(flags: i32) -> Self {
-        Self { flags }
+        Self { flags, uri: None, anonymous: false }
+    }
+
+    pub(crate) fn with_uri(self, filename: ffi::sqlite3_filename, routines: UriRoutines) -> Self {
+        let anonymous = filename.is_null();
+        let uri = (!anonymous).then_some(Uri { filename, routines });
+        Self { uri, anonymous, ..self }
     }
 
     pub fn flags(&self) -> i32 {
         self.flags
     }
 
+    pub fn open_flags(&self) -> OpenFlags {
+        self.flags.into()
+    }
+
     pub fn kind(&self) -> OpenKind {
         self.flags.into()
     }
@@ -99,13 +333,167 @@ impl OpenOpts {
     }
 
     pub fn delete_on_close(&self) -> bool {
-        self.flags & vars::SQLITE_OPEN_DELETEONCLOSE > 0
+        self.open_flags().contains(OpenFlags::DELETEONCLOSE)
+    }
+
+    /// If true, `SQLite` is opening a temporary file without a name. The vfs
+    /// chooses where to store the file, which can't be reopened and must be
+    /// deleted when it's closed.
+    pub fn is_temp_file(&self) -> bool {
+        self.anonymous && self.kind().is_temp()
+    }
+
+    /// If true, the file must be created by this open call (`SQLITE_OPEN_EXCLUSIVE`).
+    pub fn is_exclusive(&self) -> bool {
+        self.open_flags().contains(OpenFlags::EXCLUSIVE)
+    }
+
+    /// If true, the filename may be interpreted as a URI (`SQLITE_OPEN_URI`).
+    pub fn is_uri(&self) -> bool {
+        self.open_flags().contains(OpenFlags::URI)
+    }
+
+    /// If true, the filename must not be a symbolic link (`SQLITE_OPEN_NOFOLLOW`).
+    pub fn no_follow(&self) -> bool {
+        self.open_flags().contains(OpenFlags::NOFOLLOW)
+    }
+
+    /// If true, the file is opened for reading only (`SQLITE_OPEN_READONLY`).
+    pub fn is_readonly(&self) -> bool {
+        self.open_flags().contains(OpenFlags::READONLY)
+    }
+
+    /// If true, the file is opened for reading and writing (`SQLITE_OPEN_READWRITE`).
+    pub fn is_readwrite(&self) -> bool {
+        self.open_flags().contains(OpenFlags::READWRITE)
+    }
+
+    /// If true, the file should be created if it doesn't exist (`SQLITE_OPEN_CREATE`).
+    pub fn is_create(&self) -> bool {
+        self.open_flags().contains(OpenFlags::CREATE)
+    }
+
+    /// If true, the database is held in memory (`SQLITE_OPEN_MEMORY`).
+    pub fn is_memory(&self) -> bool {
+        self.open_flags().contains(OpenFlags::MEMORY)
+    }
+
+    /// If true, the file may use the proxy locking style on macOS
+    /// (`SQLITE_OPEN_AUTOPROXY`).
+    pub fn is_autoproxy(&self) -> bool {
+        self.open_flags().contains(OpenFlags::AUTOPROXY)
+    }
+
+    /// If true, the file is a main database (`SQLITE_OPEN_MAIN_DB`).
+    pub fn is_main_db(&self) -> bool {
+        self.kind() == OpenKind::MainDb
+    }
+
+    /// If true, the file is a main database's rollback journal
+    /// (`SQLITE_OPEN_MAIN_JOURNAL`).
+    pub fn is_main_journal(&self) -> bool {
+        self.kind() == OpenKind::MainJournal
+    }
+
+    /// If true, the file is the database for temporary tables (`SQLITE_OPEN_TEMP_DB`).
+    pub fn is_temp_db(&self) -> bool {
+        self.kind() == OpenKind::TempDb
+    }
+
+    /// If true, the file is the rollback journal of the database for temporary
+    /// tables (`SQLITE_OPEN_TEMP_JOURNAL`).
+    pub fn is_temp_journal(&self) -> bool {
+        self.kind() == OpenKind::TempJournal
+    }
+
+    /// If true, the file is a transient database used for sorting and
+    /// materializing views (`SQLITE_OPEN_TRANSIENT_DB`).
+    pub fn is_transient_db(&self) -> bool {
+        self.kind() == OpenKind::TransientDb
+    }
+
+    /// If true, the file is a statement journal (`SQLITE_OPEN_SUBJOURNAL`).
+    pub fn is_subjournal(&self) -> bool {
+        self.kind() == OpenKind::SubJournal
+    }
+
+    /// If true, the file is the super-journal of a multi-database transaction
+    /// (`SQLITE_OPEN_SUPER_JOURNAL`).
+    pub fn is_super_journal(&self) -> bool {
+        self.kind() == OpenKind::SuperJournal
+    }
+
+    /// If true, the file is a write-ahead log (`SQLITE_OPEN_WAL`).
+    pub fn is_wal(&self) -> bool {
+        self.kind() == OpenKind::Wal
     }
 
     pub fn set_readonly(&mut self) {
         self.flags &= !vars::SQLITE_OPEN_READWRITE;
         self.flags |= vars::SQLITE_OPEN_READONLY;
     }
+
+    pub fn set_readwrite(&mut self) {
+        self.flags &= !vars::SQLITE_OPEN_READONLY;
+        self.flags |= vars::SQLITE_OPEN_READWRITE;
+    }
+
+    /// Returns the value of the query parameter `key` if the file was opened
+    /// using a URI filename such as `file:data.db?bucket=mybucket`.
+    /// Parameters without an explicit value return an empty string.
+    ///
+    /// Returns `None` if the parameter is missing, the filename is not a URI,
+    /// or the file has no name.
+    ///
+    /// # Safety
+    /// The parameters are read from the filename passed to xOpen, which is
+    /// owned by `SQLite` and only valid until the file is closed. The options
+    /// passed to `Vfs::open` may be read until the handle it returns is passed
+    /// to `Vfs::close`, or right away if the open fails. Copy any parameters
+    /// which are needed for longer.
+    pub unsafe fn uri_parameter(&self, key: &str) -> Option<&str> {
+        let uri = self.uri?;
+        let key = CString::new(key).ok()?;
+        unsafe {
+            let value = (uri.routines.parameter)(uri.filename, key.as_ptr());
+            value.as_ref().and_then(|p| CStr::from_ptr(p).to_str().ok())
+        }
+    }
+
+    /// Returns the query parameter `key` interpreted as a boolean, as
+    /// `sqlite3_uri_boolean` does. `1`, `yes`, `true`, and `on` are true, and
+    /// `0`, `no`, `false`, and `off` are false, ignoring case. Any other value
+    /// returns `default`.
+    ///
+    /// Like [`OpenOpts::uri_parameter`], this only finds parameters when the
+    /// file was opened using a URI filename, which requires `SQLITE_OPEN_URI`
+    /// or `SQLite` to be configured with URI support, and returns `default`
+    /// otherwise.
+    ///
+    /// # Safety
+    /// See [`OpenOpts::uri_parameter`].
+    pub unsafe fn uri_boolean(&self, key: &str, default: bool) -> bool {
+        let Some(uri) = self.uri else { return default };
+        let Ok(key) = CString::new(key) else {
+            return default;
+        };
+        unsafe { (uri.routines.boolean)(uri.filename, key.as_ptr(), default.into()) != 0 }
+    }
+
+    /// Returns the query parameter `key` parsed as a decimal or hexadecimal
+    /// 64-bit integer, as `sqlite3_uri_int64` does, or `default` if it's
+    /// missing or isn't an integer. See [`OpenOpts::uri_boolean`] for when
+    /// parameters are available.
+    ///
+    /// # Safety
+    /// See [`OpenOpts::uri_parameter`].
+    pub unsafe fn uri_int64(&self, key: &str, default: i64) -> i64 {
+        let Some(uri) = self.uri else { return default };
+        let Ok(key) = CString::new(key) else {
+            return default;
+        };
+        unsafe { (uri.routines.int64)(uri.filename, key.as_ptr(), default) }
+    }
 }
 
 impl From<i32> for OpenOpts {
@@ -117,10 +505,75 @@ impl From<i32> for OpenOpts {
 impl Debug for OpenOpts {
     fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
         f.debug_struct("OpenOpts")
-            .field("flags", &self.flags)
+            .field("flags", &self.open_flags())
             .field("kind", &self.kind())
             .field("mode", &self.mode())
             .field("delete_on_close", &self.delete_on_close())
+            .field("exclusive", &self.is_exclusive())
+            .field("uri", &self.is_uri())
+            .field("no_follow", &self.no_follow())
+            .field("temp_file", &self.is_temp_file())
+            .finish()
+    }
+}
+
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum SyncMode {
+    /// Use normal `fsync()` semantics.
+    Normal,
+    /// Use Mac OS X style fullsync semantics.
+    Full,
+}
+
+impl From<i32> for SyncMode {
+    fn from(flags: i32) -> Self {
+        // the sync mode is stored in the lower four bits
+        match flags & 0x0f {
+            vars::SQLITE_SYNC_FULL => Self::Full,
+            _ => Self::Normal,
+        }
+    }
+}
+
+/// The flags passed to `Vfs::sync`.
+/// See [SQLite documentation](https://www.sqlite.org/c3ref/c_sync_dataonly.html) for more information.
+#[derive(Clone, Copy)]
+pub struct SyncFlags {
+    flags: i32,
+}
+
+impl SyncFlags {
+    pub fn new(flags: i32) -> Self {
+        Self { flags }
+    }
+
+    pub fn flags(&self) -> i32 {
+        self.flags
+    }
+
+    pub fn mode(&self) -> SyncMode {
+        self.flags.into()
+    }
+
+    /// If true, only the file's data needs to be synced. Syncing the file's
+    /// metadata (such as its size) is unnecessary.
+    pub fn data_only(&self) -> bool {
+        self.flags & vars::SQLITE_SYNC_DATAONLY > 0
+    }
+}
+
+impl From<i32> for SyncFlags {
+    fn from(flags: i32) -> Self {
+        Self::new(flags)
+    }
+}
+
+impl Debug for SyncFlags {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        f.debug_struct("SyncFlags")
+            .field("flags", &self.flags)
+            .field("mode", &self.mode())
+            .field("data_only", &self.data_only())
             .finish()
     }
 }
@@ -135,14 +588,26 @@ pub enum AccessFlags {
 impl From<i32> for AccessFlags {
     fn from(flags: i32) -> Self {
         match flags {
-            flags if flags == vars::SQLITE_ACCESS_EXISTS => Self::Exists,
-            flags if flags & vars::SQLITE_ACCESS_READ > 0 => Self::Read,
-            flags if flags & vars::SQLITE_ACCESS_READWRITE > 0 => Self::ReadWrite,
+            vars::SQLITE_ACCESS_EXISTS => Self::Exists,
+            vars::SQLITE_ACCESS_READ => Self::Read,
+            vars::SQLITE_ACCESS_READWRITE => Self::ReadWrite,
+            // SQLite only ever passes one of the three values above; treat
+            // anything else as the weakest check rather than guessing at bits.
             _ => Self::Exists,
         }
     }
 }
 
+impl From<AccessFlags> for i32 {
+    fn from(flags: AccessFlags) -> Self {
+        match flags {
+            AccessFlags::Exists => vars::SQLITE_ACCESS_EXISTS,
+            AccessFlags::Read => vars::SQLITE_ACCESS_READ,
+            AccessFlags::ReadWrite => vars::SQLITE_ACCESS_READWRITE,
+        }
+    }
+}
+
 /// Represents one of the 5 `SQLite` locking levels.
 /// See [SQLite documentation](https://www.sqlite.org/lockingv3.html) for more information.
 #[derive(Debug, PartialEq, Eq, PartialOrd, Ord, Clone, Copy)]
@@ -179,6 +644,41 @@ impl From<i32> for LockLevel {
     }
 }
 
+impl From<LockLevel> for i32 {
+    fn from(level: LockLevel) -> Self {
+        match level {
+            LockLevel::Unlocked => vars::SQLITE_LOCK_NONE,
+            LockLevel::Shared => vars::SQLITE_LOCK_SHARED,
+            LockLevel::Reserved => vars::SQLITE_LOCK_RESERVED,
+            LockLevel::Pending => vars::SQLITE_LOCK_PENDING,
+            LockLevel::Exclusive => vars::SQLITE_LOCK_EXCLUSIVE,
+        }
+    }
+}
+
+impl LockLevel {
+    /// Returns true if `SQLite` may move a file from this lock level to
+    /// `target`, either by calling `Vfs::lock` with a higher level or
+    /// `Vfs::unlock` with a lower one. Requesting the current level is a
+    /// no-op and always allowed.
+    ///
+    /// `SQLite` never requests `Pending` itself, it's only held while an
+    /// `Exclusive` request waits for readers, and `unlock` only ever drops to
+    /// `Shared` or `Unlocked`. Useful for debug assertions in `lock` and
+    /// `unlock`.
+    pub fn can_transition_to(&self, target: LockLevel) -> bool {
+        use LockLevel::*;
+        match (*self, target) {
+            (from, to) if from == to => true,
+            (from, Shared | Unlocked) if from > target => true,
+            (Unlocked, Shared) => true,
+            (Shared, Reserved | Exclusive) => true,
+            (Reserved | Pending, Exclusive) => true,
+            _ => false,
+        }
+    }
+}
+
 #[derive(Copy, Clone, Debug)]
 pub enum ShmLockMode {
     LockShared,
@@ -204,3 +704,435 @@ impl TryFrom<i32> for ShmLockMode {
         })
     }
 }
+
+macro_rules! device_characteristics {
+    ($($(#[$doc:meta])* $name:ident => $flag:ident,)*) => {
+        impl DeviceCharacteristics {
+            $(
+                $(#[$doc])*
+                pub const fn $name(self) -> Self {
+                    Self { bits: self.bits | vars::$flag }
+                }
+            )*
+        }
+
+        impl Debug for DeviceCharacteristics {
+            fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+                let mut list = f.debug_list();
+                $(
+                    if self.contains(Self::new().$name()) {
+                        list.entry(&format_args!(stringify!($name)));
+                    }
+                )*
+                list.finish()
+            }
+        }
+    };
+}
+
+/// The `SQLITE_IOCAP_*` flags returned by `Vfs::device_characteristics`,
+/// which describe the guarantees the underlying storage provides.
+///
+/// ```
+/// use sqlite_plugin::flags::DeviceCharacteristics;
+/// let characteristics = DeviceCharacteristics::new().powersafe_overwrite().safe_append();
+/// assert_eq!(characteristics.bits(), 0x1200);
+/// ```
+#[derive(Clone, Copy, Default, PartialEq, Eq, Hash)]
+pub struct DeviceCharacteristics {
+    bits: i32,
+}
+
+impl DeviceCharacteristics {
+    /// Returns a set with no characteristics.
+    pub const fn new() -> Self {
+        Self { bits: 0 }
+    }
+
+    pub const fn from_bits(bits: i32) -> Self {
+        Self { bits }
+    }
+
+    pub const fn bits(self) -> i32 {
+        self.bits
+    }
+
+    /// Returns true if all characteristics in `other` are also in `self`.
+    pub const fn contains(self, other: Self) -> bool {
+        self.bits & other.bits == other.bits
+    }
+}
+
+device_characteristics! {
+    /// Writes of any size are atomic.
+    atomic => SQLITE_IOCAP_ATOMIC,
+    /// Aligned writes of 512 bytes are atomic.
+    atomic_512 => SQLITE_IOCAP_ATOMIC512,
+    /// Aligned writes of 1KiB are atomic.
+    atomic_1k => SQLITE_IOCAP_ATOMIC1K,
+    /// Aligned writes of 2KiB are atomic.
+    atomic_2k => SQLITE_IOCAP_ATOMIC2K,
+    /// Aligned writes of 4KiB are atomic.
+    atomic_4k => SQLITE_IOCAP_ATOMIC4K,
+    /// Aligned writes of 8KiB are atomic.
+    atomic_8k => SQLITE_IOCAP_ATOMIC8K,
+    /// Aligned writes of 16KiB are atomic.
+    atomic_16k => SQLITE_IOCAP_ATOMIC16K,
+    /// Aligned writes of 32KiB are atomic.
+    atomic_32k => SQLITE_IOCAP_ATOMIC32K,
+    /// Aligned writes of 64KiB are atomic.
+    atomic_64k => SQLITE_IOCAP_ATOMIC64K,
+    /// When data is appended to a file, the data is appended first then the
+    /// size of the file is extended, never the other way around.
+    safe_append => SQLITE_IOCAP_SAFE_APPEND,
+    /// Information is written to disk in the same order as calls to `xWrite`.
+    sequential => SQLITE_IOCAP_SEQUENTIAL,
+    /// Files cannot be deleted while they are open.
+    undeletable_when_open => SQLITE_IOCAP_UNDELETABLE_WHEN_OPEN,
+    /// After a crash or power loss, only bytes which were written at the
+    /// application level might have changed; adjacent bytes, even within the
+    /// same sector, are unchanged.
+    powersafe_overwrite => SQLITE_IOCAP_POWERSAFE_OVERWRITE,
+    /// The file is read-only and can't change, even by other processes.
+    /// `SQLite` opens such databases read-only and skips locking entirely.
+    immutable => SQLITE_IOCAP_IMMUTABLE,
+    /// The vfs supports batch atomic writes via `Vfs::begin_atomic_write`.
+    batch_atomic => SQLITE_IOCAP_BATCH_ATOMIC,
+}
+
+impl From<i32> for DeviceCharacteristics {
+    fn from(bits: i32) -> Self {
+        Self::from_bits(bits)
+    }
+}
+
+impl From<DeviceCharacteristics> for i32 {
+    fn from(characteristics: DeviceCharacteristics) -> Self {
+        characteristics.bits()
+    }
+}
+
+macro_rules! vfs_capabilities {
+    ($($(#[$doc:meta])* $name:ident => $bit:expr,)*) => {
+        impl VfsCapabilities {
+            $(
+                $(#[$doc])*
+                pub const fn $name(self) -> Self {
+                    Self { bits: self.bits | $bit }
+                }
+            )*
+        }
+
+        impl Debug for VfsCapabilities {
+            fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+                let mut list = f.debug_list();
+                $(
+                    if self.contains(Self::new().$name()) {
+                        list.entry(&format_args!(stringify!($name)));
+                    }
+                )*
+                list.finish()
+            }
+        }
+    };
+}
+
+/// The optional features implemented by a vfs, returned by
+/// `Vfs::capabilities`. Rust can't tell which trait methods a vfs overrides,
+/// so the features are declared explicitly and used at registration to
+/// decide which `sqlite3_io_methods` to provide.
+///
+/// ```
+/// use sqlite_plugin::flags::VfsCapabilities;
+/// let capabilities = VfsCapabilities::new().shm();
+/// assert!(capabilities.contains(VfsCapabilities::new().shm()));
+/// assert!(!capabilities.contains(VfsCapabilities::new().fetch()));
+/// ```
+#[derive(Clone, Copy, Default, PartialEq, Eq, Hash)]
+pub struct VfsCapabilities {
+    bits: u32,
+}
+
+impl VfsCapabilities {
+    /// Returns a set with no optional features.
+    pub const fn new() -> Self {
+        Self { bits: 0 }
+    }
+
+    pub const fn bits(self) -> u32 {
+        self.bits
+    }
+
+    /// Returns true if all features in `other` are also in `self`.
+    pub const fn contains(self, other: Self) -> bool {
+        self.bits & other.bits == other.bits
+    }
+
+    /// Returns the features in `self` which aren't in `other`.
+    pub const fn difference(self, other: Self) -> Self {
+        Self { bits: self.bits & !other.bits }
+    }
+}
+
+vfs_capabilities! {
+    /// Shared memory via `Vfs::shm_map`, `shm_lock`, `shm_barrier`, and
+    /// `shm_unmap`, which `SQLite` needs for WAL mode unless
+    /// `locking_mode=EXCLUSIVE`.
+    shm => 1 << 0,
+    /// Memory-mapped reads via `Vfs::fetch` and `unfetch`.
+    fetch => 1 << 1,
+    /// Batch atomic writes via `Vfs::begin_atomic_write`,
+    /// `commit_atomic_write`, and `rollback_atomic_write`. Without this,
+    /// `SQLITE_IOCAP_BATCH_ATOMIC` is removed from the device characteristics
+    /// reported to `SQLite`.
+    batch_atomic => 1 << 2,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn open_opts_accessors() {
+        let mut opts = OpenOpts::new(
+            vars::SQLITE_OPEN_READWRITE
+                | vars::SQLITE_OPEN_CREATE
+                | vars::SQLITE_OPEN_EXCLUSIVE
+                | vars::SQLITE_OPEN_NOFOLLOW,
+        );
+        assert!(opts.is_exclusive());
+        assert!(opts.no_follow());
+        assert!(!opts.is_uri());
+        assert!(opts.mode().must_create());
+
+        opts.set_readonly();
+        assert_eq!(opts.mode(), OpenMode::ReadOnly);
+        opts.set_readwrite();
+        assert_eq!(
+            opts.mode(),
+            OpenMode::ReadWrite { create: CreateMode::MustCreate }
+        );
+    }
+
+    #[test]
+    fn open_opts_predicates() {
+        let opts = OpenOpts::new(
+            vars::SQLITE_OPEN_READWRITE | vars::SQLITE_OPEN_CREATE | vars::SQLITE_OPEN_MEMORY,
+        );
+        assert!(opts.is_readwrite() && opts.is_create() && opts.is_memory());
+        assert!(!opts.is_readonly() && !opts.is_autoproxy());
+        let opts = OpenOpts::new(vars::SQLITE_OPEN_READONLY | vars::SQLITE_OPEN_AUTOPROXY);
+        assert!(opts.is_readonly() && opts.is_autoproxy());
+        assert!(!opts.is_readwrite() && !opts.is_create() && !opts.is_memory());
+
+        type Predicate = fn(&OpenOpts) -> bool;
+        let kinds: [(i32, Predicate); 8] = [
+            (vars::SQLITE_OPEN_MAIN_DB, OpenOpts::is_main_db),
+            (vars::SQLITE_OPEN_MAIN_JOURNAL, OpenOpts::is_main_journal),
+            (vars::SQLITE_OPEN_TEMP_DB, OpenOpts::is_temp_db),
+            (vars::SQLITE_OPEN_TEMP_JOURNAL, OpenOpts::is_temp_journal),
+            (vars::SQLITE_OPEN_TRANSIENT_DB, OpenOpts::is_transient_db),
+            (vars::SQLITE_OPEN_SUBJOURNAL, OpenOpts::is_subjournal),
+            (vars::SQLITE_OPEN_SUPER_JOURNAL, OpenOpts::is_super_journal),
+            (vars::SQLITE_OPEN_WAL, OpenOpts::is_wal),
+        ];
+        for (flag, _) in kinds {
+            let opts = OpenOpts::new(flag | vars::SQLITE_OPEN_READWRITE);
+            for (other, is_kind) in kinds {
+                assert_eq!(is_kind(&opts), flag == other, "{flag:#x} vs {other:#x}");
+            }
+        }
+    }
+
+    #[test]
+    fn access_flags_from_i32() {
+        assert_eq!(
+            AccessFlags::from(vars::SQLITE_ACCESS_EXISTS),
+            AccessFlags::Exists
+        );
+        assert_eq!(
+            AccessFlags::from(vars::SQLITE_ACCESS_READ),
+            AccessFlags::Read
+        );
+        assert_eq!(
+            AccessFlags::from(vars::SQLITE_ACCESS_READWRITE),
+            AccessFlags::ReadWrite
+        );
+        assert_eq!(AccessFlags::from(0x40), AccessFlags::Exists);
+        assert_eq!(AccessFlags::from(-1), AccessFlags::Exists);
+    }
+
+    #[test]
+    fn round_trips() {
+        for level in [
+            vars::SQLITE_LOCK_NONE,
+            vars::SQLITE_LOCK_SHARED,
+            vars::SQLITE_LOCK_RESERVED,
+            vars::SQLITE_LOCK_PENDING,
+            vars::SQLITE_LOCK_EXCLUSIVE,
+        ] {
+            assert_eq!(i32::from(LockLevel::from(level)), level);
+        }
+        for flags in [
+            vars::SQLITE_ACCESS_EXISTS,
+            vars::SQLITE_ACCESS_READ,
+            vars::SQLITE_ACCESS_READWRITE,
+        ] {
+            assert_eq!(i32::from(AccessFlags::from(flags)), flags);
+        }
+        for kind in [
+            0,
+            vars::SQLITE_OPEN_MAIN_DB,
+            vars::SQLITE_OPEN_MAIN_JOURNAL,
+            vars::SQLITE_OPEN_TEMP_DB,
+            vars::SQLITE_OPEN_TEMP_JOURNAL,
+            vars::SQLITE_OPEN_TRANSIENT_DB,
+            vars::SQLITE_OPEN_SUBJOURNAL,
+            vars::SQLITE_OPEN_SUPER_JOURNAL,
+            vars::SQLITE_OPEN_WAL,
+        ] {
+            assert_eq!(i32::from(OpenKind::from(kind)), kind);
+        }
+    }
+
+    #[test]
+    fn device_characteristics() {
+        let dc = DeviceCharacteristics::new().atomic().sequential();
+        assert_eq!(
+            dc.bits(),
+            vars::SQLITE_IOCAP_ATOMIC | vars::SQLITE_IOCAP_SEQUENTIAL
+        );
+        assert!(dc.contains(DeviceCharacteristics::new().atomic()));
+        assert!(!dc.contains(DeviceCharacteristics::new().batch_atomic()));
+        assert_eq!(DeviceCharacteristics::from(dc.bits()), dc);
+        assert_eq!(alloc::format!("{dc:?}"), "[atomic, sequential]");
+    }
+
+    #[test]
+    fn open_flags() {
+        let opts = OpenOpts::new(
+            vars::SQLITE_OPEN_MAIN_DB | vars::SQLITE_OPEN_READWRITE | vars::SQLITE_OPEN_CREATE,
+        );
+        let flags = opts.open_flags();
+        assert!(flags.contains(OpenFlags::MAIN_DB | OpenFlags::READWRITE));
+        assert!(!flags.contains(OpenFlags::MAIN_DB | OpenFlags::READONLY));
+        assert!(flags.intersects(OpenFlags::READONLY | OpenFlags::READWRITE));
+        assert_eq!((flags & !OpenFlags::CREATE).bits(), 0x102);
+        assert_eq!(
+            alloc::format!("{:?}", flags | OpenFlags::from_bits(0x1000_0000)),
+            "[READWRITE, CREATE, MAIN_DB, 0x10000000]"
+        );
+    }
+
+    #[test]
+    fn lock_level_transitions() {
+        use LockLevel::*;
+        let levels = [Unlocked, Shared, Reserved, Pending, Exclusive];
+        let allowed = [
+            (Unlocked, Shared),
+            (Shared, Unlocked),
+            (Shared, Reserved),
+            (Shared, Exclusive),
+            (Reserved, Unlocked),
+            (Reserved, Shared),
+            (Reserved, Exclusive),
+            (Pending, Unlocked),
+            (Pending, Shared),
+            (Pending, Exclusive),
+            (Exclusive, Unlocked),
+            (Exclusive, Shared),
+        ];
+        for from in levels {
+            for to in levels {
+                let expected = from == to || allowed.contains(&(from, to));
+                assert_eq!(from.can_transition_to(to), expected, "{from:?} -> {to:?}");
+            }
+        }
+    }
+
+    #[test]
+    fn open_kind_predicates() {
+        let cases = [
+            (vars::SQLITE_OPEN_MAIN_DB, OpenKind::MainDb, false, false),
+            (vars::SQLITE_OPEN_TEMP_DB, OpenKind::TempDb, false, false),
+            (
+                vars::SQLITE_OPEN_TRANSIENT_DB,
+                OpenKind::TransientDb,
+                false,
+                false,
+            ),
+            (
+                vars::SQLITE_OPEN_MAIN_JOURNAL,
+                OpenKind::MainJournal,
+                true,
+                false,
+            ),
+            (
+                vars::SQLITE_OPEN_TEMP_JOURNAL,
+                OpenKind::TempJournal,
+                true,
+                false,
+            ),
+            (
+                vars::SQLITE_OPEN_SUBJOURNAL,
+                OpenKind::SubJournal,
+                true,
+                false,
+            ),
+            (
+                vars::SQLITE_OPEN_SUPER_JOURNAL,
+                OpenKind::SuperJournal,
+                true,
+                false,
+            ),
+            (vars::SQLITE_OPEN_WAL, OpenKind::Wal, false, true),
+            (0, OpenKind::Unknown, false, false),
+        ];
+        for (flags, kind, journal, wal) in cases {
+            let opened = OpenKind::from(flags | vars::SQLITE_OPEN_READWRITE);
+            assert_eq!(opened, kind);
+            assert_eq!(opened.is_journal(), journal, "{kind:?}");
+            assert_eq!(opened.is_wal(), wal, "{kind:?}");
+        }
+    }
+
+    #[test]
+    fn open_kind_ignores_other_flags() {
+        let kinds = [
+            (vars::SQLITE_OPEN_MAIN_DB, OpenKind::MainDb),
+            (vars::SQLITE_OPEN_MAIN_JOURNAL, OpenKind::MainJournal),
+            (vars::SQLITE_OPEN_TEMP_DB, OpenKind::TempDb),
+            (vars::SQLITE_OPEN_TEMP_JOURNAL, OpenKind::TempJournal),
+            (vars::SQLITE_OPEN_TRANSIENT_DB, OpenKind::TransientDb),
+            (vars::SQLITE_OPEN_SUBJOURNAL, OpenKind::SubJournal),
+            (vars::SQLITE_OPEN_SUPER_JOURNAL, OpenKind::SuperJournal),
+            (vars::SQLITE_OPEN_WAL, OpenKind::Wal),
+        ];
+        let others = [
+            0,
+            vars::SQLITE_OPEN_READONLY,
+            vars::SQLITE_OPEN_READWRITE | vars::SQLITE_OPEN_CREATE,
+            vars::SQLITE_OPEN_READWRITE
+                | vars::SQLITE_OPEN_CREATE
+                | vars::SQLITE_OPEN_EXCLUSIVE
+                | vars::SQLITE_OPEN_DELETEONCLOSE,
+            vars::SQLITE_OPEN_READWRITE | vars::SQLITE_OPEN_URI | vars::SQLITE_OPEN_NOFOLLOW,
+            vars::SQLITE_OPEN_MEMORY | vars::SQLITE_OPEN_EXRESCODE | vars::SQLITE_OPEN_NOMUTEX,
+        ];
+        for other in others {
+            assert_eq!(OpenKind::from(other), OpenKind::Unknown, "{other:#x}");
+            for (flag, kind) in kinds {
+                assert_eq!(OpenKind::from(flag | other), kind, "{other:#x}");
+                // SQLite never sets more than one kind, so a mix is unknown
+                for (second, _) in kinds.iter().filter(|(f, _)| *f != flag) {
+                    assert_eq!(
+                        OpenKind::from(flag | second | other),
+                        OpenKind::Unknown,
+                        "{:#x}",
+                        flag | second | other
+                    );
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,372 @@
+//! Layering a vfs over another vfs.
+//!
+//! Implement [`VfsLayer`] to transform the data read and written by an inner
+//! [`Vfs`], for example to encrypt or compress it, then register the layer
+//! wrapped in a [`DelegatingVfs`]. Every other method is forwarded to the
+//! inner vfs. Layers which need to change more than reads, writes, and file
+//! sizes should implement [`Vfs`] directly.
+//!
+//! Layers which store extra data with each page, such as the nonce and tag
+//! of an authenticated cipher, need `SQLite` to leave room at the end of every
+//! page. The application asks for this with `sqlite3_file_control(db,
+//! "main", SQLITE_FCNTL_RESERVE_BYTES, &n)` before the database is created,
+//! or before a `VACUUM` which rebuilds it. `SQLite` handles that op itself and
+//! never passes it to the vfs. The reserve is stored in byte 20 of the
+//! database header, and reduces the usable size of each page, not the page
+//! size, so reads and writes still cover whole pages.
+
+use alloc::borrow::Cow;
+use alloc::string::String;
+use core::ffi::c_void;
+use core::ptr::NonNull;
+
+use crate::flags::{AccessFlags, LockLevel, OpenOpts, ShmLockMode, SyncFlags, VfsCapabilities};
+use crate::vfs::{BusyHandler, Pragma, PragmaErr, PragmaResult, Vfs, VfsResult};
+
+/// The handle type of the vfs beneath a layer.
+pub type LayerHandle<L> = <<L as VfsLayer>::Inner as Vfs>::Handle;
+
+/// A layer over the vfs returned by [`VfsLayer::inner`]. Each method defaults
+/// to calling the inner vfs, so layers only override the methods they change.
+pub trait VfsLayer: Send + Sync {
+    type Inner: Vfs;
+
+    /// Returns the vfs beneath this layer.
+    fn inner(&self) -> &Self::Inner;
+
+    /// See [`Vfs::file_size`].
+    fn file_size(&self, handle: &mut LayerHandle<Self>) -> VfsResult<usize> {
+        self.inner().file_size(handle)
+    }
+
+    /// See [`Vfs::truncate`].
+    fn truncate(&self, handle: &mut LayerHandle<Self>, size: usize) -> VfsResult<()> {
+        self.inner().truncate(handle, size)
+    }
+
+    /// See [`Vfs::write`].
+    fn write(
+        &self,
+        handle: &mut LayerHandle<Self>,
+        offset: usize,
+        data: &[u8],
+    ) -> VfsResult<usize> {
+        self.inner().write(handle, offset, data)
+    }
+
+    /// See [`Vfs::read`]. Reads from `SQLite` always go through this method,
+    /// even if the inner vfs overrides [`Vfs::read_exact`].
+    fn read(
+        &self,
+        handle: &mut LayerHandle<Self>,
+        offset: usize,
+        data: &mut [u8],
+    ) -> VfsResult<usize> {
+        self.inner().read(handle, offset, data)
+    }
+}
+
+/// Implements [`Vfs`] for a [`VfsLayer`], forwarding every method the layer
+/// doesn't handle to its inner vfs.
+///
+/// Memory-mapped I/O (`fetch` and `unfetch`) is never forwarded, since pages
+/// mapped by the inner vfs would bypass the layer's reads.
+#[derive(Debug, Clone)]
+pub struct DelegatingVfs<L>(pub L);
+
+impl<L: VfsLayer> DelegatingVfs<L> {
+    fn inner(&self) -> &L::Inner {
+        self.0.inner()
+    }
+}
+
+impl<L: VfsLayer> Vfs for DelegatingVfs<L> {
+    type Handle = LayerHandle<L>;
+
+    fn on_register(&self, name: &str, db: *mut c_void) {
+        self.inner().on_register(name, db)
+    }
+
+    fn capabilities(&self) -> VfsCapabilities {
+        self.inner()
+            .capabilities()
+            .difference(VfsCapabilities::new().fetch())
+    }
+
+    fn shutdown(&self) -> VfsResult<()> {
+        self.inner().shutdown()
+    }
+
+    fn canonical_path<'a>(&self, path: Cow<'a, str>) -> VfsResult<Cow<'a, str>> {
+        self.inner().canonical_path(path)
+    }
+
+    fn same_file(&self, a: &str, b: &str) -> VfsResult<bool> {
+        self.inner().same_file(a, b)
+    }
+
+    fn open(&self, path: Option<&str>, opts: OpenOpts) -> VfsResult<Self::Handle> {
+        self.inner().open(path, opts)
+    }
+
+    fn post_open(&self, handle: &mut Self::Handle, flags: &mut i32) {
+        self.inner().post_open(handle, flags)
+    }
+
+    fn delete(&self, path: &str, sync_dir: bool) -> VfsResult<()> {
+        self.inner().delete(path, sync_dir)
+    }
+
+    fn access(&self, path: &str, flags: AccessFlags) -> VfsResult<bool> {
+        self.inner().access(path, flags)
+    }
+
+    fn file_size(&self, handle: &mut Self::Handle) -> VfsResult<usize> {
+        self.0.file_size(handle)
+    }
+
+    fn truncate(&self, handle: &mut Self::Handle, size: usize) -> VfsResult<()> {
+        self.0.truncate(handle, size)
+    }
+
+    fn min_file_size(&self, handle: &mut Self::Handle) -> usize {
+        self.inner().min_file_size(handle)
+    }
+
+    fn write(&self, handle: &mut Self::Handle, offset: usize, data: &[u8]) -> VfsResult<usize> {
+        self.0.write(handle, offset, data)
+    }
+
+    fn read(&self, handle: &mut Self::Handle, offset: usize, data: &mut [u8]) -> VfsResult<usize> {
+        self.0.read(handle, offset, data)
+    }
+
+    fn lock(&self, handle: &mut Self::Handle, level: LockLevel) -> VfsResult<()> {
+        self.inner().lock(handle, level)
+    }
+
+    fn unlock(&self, handle: &mut Self::Handle, level: LockLevel) -> VfsResult<()> {
+        self.inner().unlock(handle, level)
+    }
+
+    fn check_reserved_lock(&self, handle: &mut Self::Handle) -> VfsResult<bool> {
+        self.inner().check_reserved_lock(handle)
+    }
+
+    fn sync(&self, handle: &mut Self::Handle, flags: SyncFlags) -> VfsResult<()> {
+        self.inner().sync(handle, flags)
+    }
+
+    fn barrier(&self, handle: &mut Self::Handle) -> VfsResult<()> {
+        self.inner().barrier(handle)
+    }
+
+    fn sync_fcntl(&self, handle: &mut Self::Handle, super_journal: Option<&str>) -> VfsResult<()> {
+        self.inner().sync_fcntl(handle, super_journal)
+    }
+
+    fn commit_phase_two(&self, handle: &mut Self::Handle) -> VfsResult<()> {
+        self.inner().commit_phase_two(handle)
+    }
+
+    fn overwrite_hint(&self, handle: &mut Self::Handle) -> VfsResult<()> {
+        self.inner().overwrite_hint(handle)
+    }
+
+    fn close(&self, handle: Self::Handle) -> VfsResult<()> {
+        self.inner().close(handle)
+    }
+
+    fn pragma(
+        &self,
+        handle: &mut Self::Handle,
+        pragma: Pragma<'_>,
+    ) -> Result<PragmaResult, PragmaErr> {
+        self.inner().pragma(handle, pragma)
+    }
+
+    fn set_config(&self, handle: &mut Self::Handle, key: &str, value: &str) -> VfsResult<()> {
+        self.inner().set_config(handle, key, value)
+    }
+
+    fn sector_size(&self, handle: &mut Self::Handle) -> VfsResult<i32> {
+        self.inner().sector_size(handle)
+    }
+
+    fn device_characteristics(&self, handle: &mut Self::Handle) -> VfsResult<i32> {
+        self.inner().device_characteristics(handle)
+    }
+
+    fn shm_map(
+        &self,
+        handle: &mut Self::Handle,
+        region_idx: usize,
+        region_size: usize,
+        extend: bool,
+    ) -> VfsResult<Option<NonNull<u8>>> {
+        self.inner()
+            .shm_map(handle, region_idx, region_size, extend)
+    }
+
+    fn shm_lock(
+        &self,
+        handle: &mut Self::Handle,
+        offset: u32,
+        count: u32,
+        mode: ShmLockMode,
+    ) -> VfsResult<()> {
+        self.inner().shm_lock(handle, offset, count, mode)
+    }
+
+    fn shm_barrier(&self, handle: &mut Self::Handle) {
+        self.inner().shm_barrier(handle)
+    }
+
+    fn shm_unmap(&self, handle: &mut Self::Handle, delete: bool) -> VfsResult<()> {
+        self.inner().shm_unmap(handle, delete)
+    }
+
+    fn set_busy_handler(&self, handle: &mut Self::Handle, handler: BusyHandler) {
+        self.inner().set_busy_handler(handle, handler)
+    }
+
+    fn has_moved(&self, handle: &mut Self::Handle) -> VfsResult<bool> {
+        self.inner().has_moved(handle)
+    }
+
+    fn lock_state(&self, handle: &mut Self::Handle) -> VfsResult<LockLevel> {
+        self.inner().lock_state(handle)
+    }
+
+    fn lock_timeout(&self, handle: &mut Self::Handle, millis: i32) -> VfsResult<()> {
+        self.inner().lock_timeout(handle, millis)
+    }
+
+    fn persist_wal(&self, handle: &mut Self::Handle, value: Option<bool>) -> VfsResult<bool> {
+        self.inner().persist_wal(handle, value)
+    }
+
+    fn powersafe_overwrite(
+        &self,
+        handle: &mut Self::Handle,
+        value: Option<bool>,
+    ) -> VfsResult<bool> {
+        self.inner().powersafe_overwrite(handle, value)
+    }
+
+    fn chunk_size(&self, handle: &mut Self::Handle, size: usize) -> VfsResult<()> {
+        self.inner().chunk_size(handle, size)
+    }
+
+    fn begin_atomic_write(&self, handle: &mut Self::Handle) -> VfsResult<()> {
+        self.inner().begin_atomic_write(handle)
+    }
+
+    fn commit_atomic_write(&self, handle: &mut Self::Handle) -> VfsResult<()> {
+        self.inner().commit_atomic_write(handle)
+    }
+
+    fn rollback_atomic_write(&self, handle: &mut Self::Handle) -> VfsResult<()> {
+        self.inner().rollback_atomic_write(handle)
+    }
+
+    fn temp_filename(&self) -> VfsResult<String> {
+        self.inner().temp_filename()
+    }
+
+    fn randomness(&self, buf: &mut [u8]) -> VfsResult<usize> {
+        self.inner().randomness(buf)
+    }
+
+    fn sleep(&self, micros: i32) -> VfsResult<i32> {
+        self.inner().sleep(micros)
+    }
+
+    fn current_time(&self) -> VfsResult<f64> {
+        self.inner().current_time()
+    }
+
+    fn current_time_int64(&self) -> VfsResult<i64> {
+        self.inner().current_time_int64()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    // tests use std
+    extern crate std;
+
+    use super::*;
+    use crate::mock::{MockState, MockVfs, NoopHooks};
+    use crate::vfs::{RegisterOpts, register_static};
+    use alloc::{boxed::Box, ffi::CString, sync::Arc, vec::Vec};
+    use parking_lot::Mutex;
+    use rusqlite::{Connection, OpenFlags};
+
+    /// Flips every bit of the data stored by the inner vfs.
+    struct Invert(MockVfs);
+
+    impl VfsLayer for Invert {
+        type Inner = MockVfs;
+
+        fn inner(&self) -> &MockVfs {
+            &self.0
+        }
+
+        fn write(
+            &self,
+            handle: &mut LayerHandle<Self>,
+            offset: usize,
+            data: &[u8],
+        ) -> VfsResult<usize> {
+            let inverted: Vec<u8> = data.iter().map(|b| !b).collect();
+            self.0.write(handle, offset, &inverted)
+        }
+
+        fn read(
+            &self,
+            handle: &mut LayerHandle<Self>,
+            offset: usize,
+            data: &mut [u8],
+        ) -> VfsResult<usize> {
+            let n = self.0.read(handle, offset, data)?;
+            data[..n].iter_mut().for_each(|b| *b = !*b);
+            Ok(n)
+        }
+    }
+
+    #[test]
+    fn layer_transforms_reads_and_writes() -> rusqlite::Result<()> {
+        let shared = Arc::new(Mutex::new(MockState::new(Box::new(NoopHooks))));
+        let layer = DelegatingVfs(Invert(MockVfs::new(shared.clone())));
+        let registration = register_static(
+            CString::new("mock_layer").unwrap(),
+            layer,
+            RegisterOpts::default(),
+        )
+        .expect("failed to register vfs");
+        shared.lock().setup_logger(registration.logger());
+
+        let conn = Connection::open_with_flags_and_vfs(
+            "layer.db",
+            OpenFlags::SQLITE_OPEN_READ_WRITE | OpenFlags::SQLITE_OPEN_CREATE,
+            "mock_layer",
+        )?;
+        conn.execute("create table t (val text)", [])?;
+        conn.execute("insert into t values ('hello')", [])?;
+        let val: String = conn.query_row("select val from t", [], |r| r.get(0))?;
+        assert_eq!(val, "hello");
+
+        // the inner vfs only sees the transformed data
+        let inner = MockVfs::new(shared.clone());
+        let opts =
+            OpenOpts::new(crate::vars::SQLITE_OPEN_MAIN_DB | crate::vars::SQLITE_OPEN_READWRITE);
+        let mut handle = inner.open(Some("layer.db"), opts).unwrap();
+        let mut header = [0; 16];
+        assert_eq!(inner.read(&mut handle, 0, &mut header), Ok(16));
+        header.iter_mut().for_each(|b| *b = !*b);
+        assert_eq!(&header, b"SQLite format 3\0");
+        inner.close(handle).unwrap();
+        Ok(())
+    }
+}
@@ -1,5 +1,7 @@
 #![no_std]
 extern crate alloc;
+#[cfg(feature = "std")]
+extern crate std;
 
 pub mod vars {
     include!(concat!(env!("OUT_DIR"), "/vars.rs"));
@@ -13,12 +15,16 @@ mod ffi {
     include!(concat!(env!("OUT_DIR"), "/bindings.rs"));
 }
 
-mod mock;
-
+pub mod dynvfs;
+pub mod error;
 pub mod flags;
+pub mod layer;
 pub mod logger;
+#[cfg(any(test, feature = "testing"))]
+pub mod mock;
+pub mod util;
 pub mod vfs;
-pub use ffi::sqlite3_api_routines;
+pub use ffi::{sqlite3_api_routines, sqlite3_vfs};
 
 #[cfg(test)]
 mod tests {
@@ -6,40 +6,176 @@ use crate::vars;
 #[allow(non_snake_case)]
 type Sqlite3Log = unsafe extern "C" fn(iErrCode: c_int, arg2: *const c_char, ...);
 
+/// Log levels ordered from most to least severe.
 #[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
 pub enum SqliteLogLevel {
     Error = 1,
     Warn,
     Notice,
+    Info,
+    Debug,
 }
 
 impl SqliteLogLevel {
     fn into_err_code(self) -> c_int {
         match self {
-            Self::Notice => vars::SQLITE_NOTICE,
+            Self::Debug | Self::Info | Self::Notice => vars::SQLITE_NOTICE,
             Self::Warn => vars::SQLITE_WARNING,
-            Self::Error => vars::SQLITE_INTERNAL,
+            Self::Error => vars::SQLITE_ERROR,
+        }
+    }
+
+    /// `SQLite` has no result codes for informational or debug messages, so
+    /// they are logged as `SQLITE_NOTICE` with the level prefixed to the text.
+    fn prefix(self) -> &'static [u8] {
+        match self {
+            Self::Debug => b"debug: ",
+            Self::Info => b"info: ",
+            Self::Notice | Self::Warn | Self::Error => b"",
         }
     }
 }
 
+/// The default maximum number of bytes passed to `SQLite` in a single log
+/// call. `sqlite3_log` formats each message into a fixed size stack buffer
+/// and silently truncates anything longer. Recent `SQLite` versions size the
+/// buffer with the `SQLITE_MAX_LOG_MESSAGE` compile-time option (700 bytes by
+/// default), while older versions use `SQLITE_PRINT_BUF_SIZE * 3` (210 bytes).
+pub const DEFAULT_MAX_LOG_LEN: usize = 230;
+
 #[derive(Clone, Copy)]
 pub struct SqliteLogger {
     log: Sqlite3Log,
+    max_len: usize,
 }
 
 impl SqliteLogger {
     pub(crate) fn new(log: Sqlite3Log) -> Self {
-        Self { log }
+        Self { log, max_len: DEFAULT_MAX_LOG_LEN }
     }
 
-    /// Log bytes directly to the `SQLite3` log handle.
-    /// Note that `SQLite` silently truncates writes larger than
-    /// roughly 230 bytes by default. It's recommended that you
-    /// split your log messages by lines before calling this method.
+    /// Returns a copy of this logger which splits messages into chunks of at
+    /// most `max_len` bytes. Use this if the `sqlite3_log` buffer of your
+    /// `SQLite` build is smaller than [`DEFAULT_MAX_LOG_LEN`], such as the 210
+    /// byte buffer of older versions, or to make use of a larger one.
+    pub fn with_max_len(self, max_len: usize) -> Self {
+        Self { max_len: max_len.max(1), ..self }
+    }
+
+    /// Log a message to the `SQLite3` log handle.
+    /// See [`SqliteLogger::log_bytes`] for details on how messages are split.
     pub fn log(&self, level: SqliteLogLevel, msg: &str) {
+        self.log_bytes(level, msg.as_bytes());
+    }
+
+    /// Log raw bytes directly to the `SQLite3` log handle.
+    /// The bytes are passed through to `SQLite` as is, without requiring
+    /// them to be valid UTF-8. Messages containing an interior NUL byte
+    /// can't be represented as a C string and are dropped.
+    ///
+    /// Since `SQLite` silently truncates long log messages, the message is
+    /// split on newlines and any line longer than the configured max length
+    /// is further split into chunks, each logged separately. Chunks are
+    /// never split within a UTF-8 sequence. Empty lines are skipped.
+    ///
+    /// `Info` and `Debug` messages are logged as `SQLITE_NOTICE`, with each
+    /// chunk prefixed by `info: ` or `debug: ` respectively.
+    pub fn log_bytes(&self, level: SqliteLogLevel, msg: &[u8]) {
+        if msg.contains(&0) {
+            return;
+        }
         let code = level.into_err_code();
-        let z_format = CString::new(msg).unwrap();
-        unsafe { (self.log)(code, z_format.as_ptr()) }
+        let prefix = level.prefix();
+        let max_len = self.max_len.saturating_sub(prefix.len()).max(1);
+        for line in msg.split(|&b| b == b'\n') {
+            let mut rest = line;
+            while !rest.is_empty() {
+                let (chunk, tail) = rest.split_at(chunk_len(rest, max_len));
+                self.log_chunk(code, prefix, chunk);
+                rest = tail;
+            }
+        }
+    }
+
+    fn log_chunk(&self, code: c_int, prefix: &[u8], chunk: &[u8]) {
+        let Ok(chunk) = CString::new([prefix, chunk].concat()) else {
+            return;
+        };
+        unsafe { (self.log)(code, c"%s".as_ptr(), chunk.as_ptr()) }
+    }
+}
+
+/// Returns the length of the next chunk of `msg`, backing off to avoid
+/// splitting a UTF-8 sequence when possible.
+fn chunk_len(msg: &[u8], max_len: usize) -> usize {
+    if msg.len() <= max_len {
+        return msg.len();
+    }
+    let mut len = max_len;
+    while len > 0 && is_utf8_continuation(msg[len]) {
+        len -= 1;
+    }
+    if len == 0 { max_len } else { len }
+}
+
+fn is_utf8_continuation(b: u8) -> bool {
+    b & 0b1100_0000 == 0b1000_0000
+}
+
+#[cfg(feature = "log")]
+impl From<log::Level> for SqliteLogLevel {
+    fn from(level: log::Level) -> Self {
+        match level {
+            log::Level::Error => Self::Error,
+            log::Level::Warn => Self::Warn,
+            log::Level::Info => Self::Info,
+            log::Level::Debug | log::Level::Trace => Self::Debug,
+        }
+    }
+}
+
+/// A [`log::Log`] implementation which forwards records to a [`SqliteLogger`].
+///
+/// Long records are split into multiple `SQLite` log calls by
+/// [`SqliteLogger::log`]. The mutex ensures that chunks from concurrent
+/// records are not interleaved.
+#[cfg(feature = "log")]
+pub struct SqliteLogBridge {
+    logger: parking_lot::Mutex<SqliteLogger>,
+}
+
+#[cfg(feature = "log")]
+impl SqliteLogBridge {
+    pub fn new(logger: SqliteLogger) -> Self {
+        Self { logger: parking_lot::Mutex::new(logger) }
     }
 }
+
+#[cfg(feature = "log")]
+impl log::Log for SqliteLogBridge {
+    fn enabled(&self, _metadata: &log::Metadata<'_>) -> bool {
+        true
+    }
+
+    fn log(&self, record: &log::Record<'_>) {
+        let level = record.level().into();
+        let msg = alloc::format!("{}", record.args());
+        self.logger.lock().log(level, &msg);
+    }
+
+    fn flush(&self) {}
+}
+
+/// Install a [`SqliteLogBridge`] wrapping `logger` as the global logger for
+/// the `log` crate. Note that this does not change `log`'s max level, which
+/// must be configured separately via [`log::set_max_level`].
+#[cfg(feature = "log")]
+pub fn install_as_global(logger: SqliteLogger) -> Result<(), log::SetLoggerError> {
+    use alloc::boxed::Box;
+    let bridge: &'static SqliteLogBridge = Box::leak(Box::new(SqliteLogBridge::new(logger)));
+    log::set_logger(bridge).inspect_err(|_| {
+        // Safety: the bridge was leaked above and set_logger failed, so
+        // nothing else references it
+        drop(unsafe { Box::from_raw(core::ptr::from_ref(bridge).cast_mut()) });
+    })
+}
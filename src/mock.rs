@@ -1,62 +1,186 @@
-#![cfg(test)]
+//! An in-memory mock vfs for testing code built on top of this crate.
+//!
+//! Register a [`MockVfs`] with a [`Hooks`] implementation to observe or
+//! influence the calls `SQLite` makes into the vfs. This module requires the
+//! `testing` feature and depends on `std`.
 
-// tests use std
+// the mock uses std
 extern crate std;
 
+use core::ffi::c_void;
 use core::fmt::{self, Display};
+use core::ptr::NonNull;
+use core::sync::atomic::{Ordering, fence};
 use std::boxed::Box;
 use std::collections::HashMap;
-use std::println;
 use std::{string::String, vec::Vec};
 
 use alloc::borrow::{Cow, ToOwned};
 use alloc::format;
 use alloc::sync::Arc;
+use alloc::vec;
 use parking_lot::{Mutex, MutexGuard};
 
-use crate::flags::{self, AccessFlags, LockLevel, OpenOpts};
+use crate::flags::{
+    self, AccessFlags, LockLevel, OpenKind, OpenOpts, ShmLockMode, SyncFlags, VfsCapabilities,
+};
 use crate::logger::{SqliteLogLevel, SqliteLogger};
+use crate::util::WriteCoalescer;
 use crate::vars;
 use crate::vfs::{
-    DEFAULT_DEVICE_CHARACTERISTICS, DEFAULT_SECTOR_SIZE, Pragma, PragmaErr, Vfs, VfsHandle,
-    VfsResult,
+    BusyHandler, DEFAULT_DEVICE_CHARACTERISTICS, DEFAULT_SECTOR_SIZE, Pragma, PragmaErr,
+    PragmaResult, Vfs, VfsHandle, VfsResult,
 };
 
+/// A file stored by [`MockVfs`].
 pub struct File {
+    /// The name the file was opened with, which may since have been deleted
     pub name: String,
     pub data: Vec<u8>,
+    /// If true, the file's name has been removed and the file is dropped
+    /// when its last handle is closed
     pub delete_on_close: bool,
+    /// A copy of data taken at the start of an atomic write batch
+    pub atomic_snapshot: Option<Vec<u8>>,
+    /// The number of open handles referring to this file
+    pub open_handles: usize,
+}
+
+impl File {
+    fn write_at(&mut self, offset: usize, data: &[u8]) {
+        let end = offset + data.len();
+        if end > self.data.len() {
+            self.data.resize(end, 0);
+        }
+        self.data[offset..end].copy_from_slice(data);
+    }
 }
 
+/// Callbacks invoked by [`MockVfs`] before it handles each operation.
+/// All methods have default implementations which do nothing.
 #[allow(unused_variables)]
 pub trait Hooks {
-    fn canonical_path(&mut self, path: &str) {}
+    fn on_register(&mut self, name: &str, db: *mut c_void) {}
+    /// The mock implements shared memory and batch atomic writes, so
+    /// declares both by default. It doesn't implement memory-mapped I/O.
+    fn capabilities(&mut self) -> VfsCapabilities {
+        VfsCapabilities::new().shm().batch_atomic()
+    }
+    fn shutdown(&mut self) -> VfsResult<()> {
+        Ok(())
+    }
+    /// Returning `Some` replaces the canonical path.
+    fn canonical_path(&mut self, path: &str) -> Option<String> {
+        None
+    }
     fn open(&mut self, path: &Option<&str>, opts: &OpenOpts) {}
-    fn delete(&mut self, path: &str) {}
+    fn post_open(&mut self, handle: MockHandle, flags: &mut i32) {}
+    fn delete(&mut self, path: &str, sync_dir: bool) {}
     fn access(&mut self, path: &str, flags: AccessFlags) {}
     fn file_size(&mut self, handle: MockHandle) {}
     fn truncate(&mut self, handle: MockHandle, size: usize) {}
+    fn min_file_size(&mut self, handle: MockHandle) -> usize {
+        0
+    }
     fn write(&mut self, handle: MockHandle, offset: usize, buf: &[u8]) {}
+    /// Limits the number of bytes accepted by each write to simulate short
+    /// writes.
+    fn max_write(&mut self, handle: MockHandle) -> Option<usize> {
+        None
+    }
+    /// Buffers writes to the handle until it is synced or closed, like a vfs
+    /// backed by remote storage. Buffered writes are also flushed before the
+    /// handle reads, truncates, or reports the file size.
+    fn buffer_writes(&mut self, handle: MockHandle) -> bool {
+        false
+    }
     fn read(&mut self, handle: MockHandle, offset: usize, buf: &[u8]) {}
-    fn sync(&mut self, handle: MockHandle) {}
-    fn close(&mut self, handle: MockHandle) {}
+    /// Limits the number of bytes returned by each read to simulate short
+    /// reads.
+    fn max_read(&mut self, handle: MockHandle) -> Option<usize> {
+        None
+    }
+    fn sync(&mut self, handle: MockHandle, flags: SyncFlags) {}
+    fn sync_fcntl(&mut self, handle: MockHandle, super_journal: Option<&str>) {}
+    fn commit_phase_two(&mut self, handle: MockHandle) {}
+    fn overwrite_hint(&mut self, handle: MockHandle) {}
+    fn lock(&mut self, handle: MockHandle, level: LockLevel) -> VfsResult<()> {
+        Ok(())
+    }
+    fn unlock(&mut self, handle: MockHandle, level: LockLevel) {}
+    /// Returning an error fails the close, the file is closed regardless.
+    fn close(&mut self, handle: MockHandle) -> VfsResult<()> {
+        Ok(())
+    }
     fn pragma(
         &mut self,
         handle: MockHandle,
         pragma: Pragma<'_>,
-    ) -> Result<Option<String>, PragmaErr> {
+    ) -> Result<PragmaResult, PragmaErr> {
         Err(PragmaErr::NotFound)
     }
-    fn sector_size(&mut self, handle: MockHandle) {}
-    fn device_characteristics(&mut self, handle: MockHandle) {
-        println!("device_characteristics");
+    fn set_config(&mut self, handle: MockHandle, key: &str, value: &str) -> VfsResult<()> {
+        Err(vars::SQLITE_NOTFOUND)
+    }
+    fn chunk_size(&mut self, handle: MockHandle, size: usize) {}
+    fn has_moved(&mut self, handle: MockHandle) -> VfsResult<bool> {
+        Ok(false)
+    }
+    fn lock_state(&mut self, handle: MockHandle) -> VfsResult<LockLevel> {
+        Ok(LockLevel::Unlocked)
+    }
+    fn mmap_size(&mut self, handle: MockHandle, requested: Option<i64>) -> VfsResult<i64> {
+        Err(vars::SQLITE_NOTFOUND)
+    }
+    fn lock_timeout(&mut self, handle: MockHandle, millis: i32) -> VfsResult<()> {
+        Ok(())
+    }
+    fn set_busy_handler(&mut self, handle: MockHandle, handler: BusyHandler) {}
+    fn begin_atomic_write(&mut self, handle: MockHandle) {}
+    fn commit_atomic_write(&mut self, handle: MockHandle) {}
+    fn rollback_atomic_write(&mut self, handle: MockHandle) {}
+    fn sector_size(&mut self, handle: MockHandle) -> VfsResult<i32> {
+        Ok(DEFAULT_SECTOR_SIZE)
+    }
+    fn device_characteristics(&mut self, handle: MockHandle) -> VfsResult<i32> {
+        Ok(DEFAULT_DEVICE_CHARACTERISTICS)
+    }
+    fn shm_map(&mut self, handle: MockHandle, region_idx: usize, extend: bool) {}
+    fn shm_lock(
+        &mut self,
+        handle: MockHandle,
+        offset: u32,
+        count: u32,
+        mode: ShmLockMode,
+    ) -> VfsResult<()> {
+        Ok(())
+    }
+    fn shm_unmap(&mut self, handle: MockHandle, delete: bool) {}
+    fn temp_filename(&mut self) -> VfsResult<String> {
+        Err(vars::SQLITE_NOTFOUND)
+    }
+    fn randomness(&mut self, buf: &mut [u8]) -> VfsResult<usize> {
+        Err(vars::SQLITE_NOTFOUND)
+    }
+    fn sleep(&mut self, micros: i32) -> VfsResult<i32> {
+        Err(vars::SQLITE_NOTFOUND)
+    }
+    fn current_time_int64(&mut self) -> VfsResult<i64> {
+        Err(vars::SQLITE_NOTFOUND)
     }
 }
 
+/// A [`Hooks`] implementation which does nothing.
+pub struct NoopHooks;
+
+impl Hooks for NoopHooks {}
+
 #[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
 pub struct MockHandle {
     id: usize,
     readonly: bool,
+    in_memory: bool,
+    kind: OpenKind,
 }
 
 impl Display for MockHandle {
@@ -67,7 +191,25 @@ impl Display for MockHandle {
 
 impl MockHandle {
     pub fn new(id: usize, readonly: bool) -> Self {
-        Self { id, readonly }
+        Self {
+            id,
+            readonly,
+            in_memory: false,
+            kind: OpenKind::Unknown,
+        }
+    }
+
+    /// Sets the kind of file the handle reports it was opened as.
+    pub fn with_open_kind(mut self, kind: OpenKind) -> Self {
+        self.kind = kind;
+        self
+    }
+
+    /// Sets whether the handle reports itself as in-memory, which controls
+    /// whether xOpen reports `SQLITE_OPEN_MEMORY`.
+    pub fn with_in_memory(mut self, in_memory: bool) -> Self {
+        self.in_memory = in_memory;
+        self
     }
 }
 
@@ -77,19 +219,35 @@ impl VfsHandle for MockHandle {
     }
 
     fn in_memory(&self) -> bool {
-        false
+        self.in_memory
+    }
+
+    fn open_kind(&self) -> OpenKind {
+        self.kind
     }
 }
 
-// MockVfs implements a very simple in-memory VFS for testing purposes.
-// See the memvfs example for a more complete implementation.
+/// `MockVfs` implements a very simple in-memory VFS for testing purposes.
+/// See the memvfs example for a more complete implementation.
+///
+/// Clones share the same [`MockState`].
+#[derive(Clone)]
 pub struct MockVfs {
     state: Arc<Mutex<MockState>>,
 }
 
+/// The state shared between a [`MockVfs`] and the test which registered it.
 pub struct MockState {
     next_id: usize,
-    files: HashMap<MockHandle, File>,
+    /// Files keyed by id, which outlive their name while they have open handles
+    files: HashMap<usize, File>,
+    /// The id of the file stored under each name
+    names: HashMap<String, usize>,
+    /// The file and flags of each open handle
+    handles: HashMap<MockHandle, OpenHandle>,
+    /// The shared memory regions of each file, kept until they're unmapped
+    /// with `delete` or the file is removed
+    shm: HashMap<usize, Vec<Box<[u8]>>>,
     hooks: Box<dyn Hooks + Send>,
     log: Option<SqliteLogger>,
 }
@@ -99,14 +257,59 @@ impl MockState {
         MockState {
             next_id: 0,
             files: HashMap::new(),
+            names: HashMap::new(),
+            handles: HashMap::new(),
+            shm: HashMap::new(),
             hooks,
             log: None,
         }
     }
 
+    /// Sets the logger used by the mock, which must be called with the
+    /// logger returned when registering the vfs before it is used.
     pub fn setup_logger(&mut self, logger: SqliteLogger) {
         self.log = Some(logger)
     }
+
+    /// Returns the names of all files currently stored by the mock.
+    pub fn file_names(&self) -> Vec<&str> {
+        self.names.keys().map(String::as_str).collect()
+    }
+
+    /// Returns the file `handle` refers to.
+    pub fn file(&self, handle: &MockHandle) -> Option<&File> {
+        self.files.get(&self.handles.get(handle)?.file_id)
+    }
+
+    fn file_mut(&mut self, handle: &MockHandle) -> Option<&mut File> {
+        self.files.get_mut(&self.handles.get(handle)?.file_id)
+    }
+
+    /// Applies the writes buffered by `handle` to its file.
+    fn flush(&mut self, handle: &MockHandle) {
+        let Some(open) = self.handles.get_mut(handle) else {
+            return;
+        };
+        let regions = open.pending.take_flush();
+        if let Some(file) = self.files.get_mut(&open.file_id) {
+            for (offset, data) in regions {
+                file.write_at(offset, &data);
+            }
+        }
+    }
+}
+
+/// The state the mock keeps for each open handle.
+struct OpenHandle {
+    /// The id of the file the handle refers to
+    file_id: usize,
+    /// If true, the file is unlinked when this handle is closed
+    delete_on_close: bool,
+    /// The shared memory locks held by this handle, one bit per lock
+    shm_shared: u8,
+    shm_exclusive: u8,
+    /// Writes buffered when `Hooks::buffer_writes` is true
+    pending: WriteCoalescer,
 }
 
 impl MockVfs {
@@ -114,6 +317,27 @@ impl MockVfs {
         Self { state }
     }
 
+    /// Returns the sorted names of all files with at least one open handle,
+    /// including files which have since been deleted.
+    pub fn open_files(&self) -> Vec<String> {
+        let state = self.state();
+        let mut names: Vec<String> = state
+            .files
+            .values()
+            .filter(|file| file.open_handles > 0)
+            .map(|file| file.name.clone())
+            .collect();
+        names.sort();
+        names
+    }
+
+    /// Returns a copy of the contents of the file stored under `name`.
+    pub fn file_contents(&self, name: &str) -> Option<Vec<u8>> {
+        let state = self.state();
+        let file_id = state.names.get(name)?;
+        state.files.get(file_id).map(|file| file.data.clone())
+    }
+
     fn state(&self) -> MutexGuard<'_, MockState> {
         self.state.lock()
     }
@@ -139,11 +363,27 @@ impl Vfs for MockVfs {
     // a simple usize that represents a file handle.
     type Handle = MockHandle;
 
+    fn on_register(&self, name: &str, db: *mut c_void) {
+        self.state().hooks.on_register(name, db);
+    }
+
+    fn capabilities(&self) -> VfsCapabilities {
+        self.state().hooks.capabilities()
+    }
+
+    fn shutdown(&self) -> VfsResult<()> {
+        let mut state = self.state();
+        state.log(format_args!("shutdown"));
+        state.hooks.shutdown()
+    }
+
     fn canonical_path<'a>(&self, path: Cow<'a, str>) -> VfsResult<Cow<'a, str>> {
         let mut state = self.state();
         state.log(format_args!("canonical_path: path={path:?}"));
-        state.hooks.canonical_path(&path);
-        Ok(path)
+        match state.hooks.canonical_path(&path) {
+            Some(canonical) => Ok(Cow::Owned(canonical)),
+            None => Ok(path),
+        }
     }
 
     fn open(&self, path: Option<&str>, opts: flags::OpenOpts) -> VfsResult<Self::Handle> {
@@ -151,33 +391,65 @@ impl Vfs for MockVfs {
         state.log(format_args!("open: path={path:?} opts={opts:?}"));
         state.hooks.open(&path, &opts);
 
-        let id = state.next_id();
-        let file_handle = MockHandle::new(id, opts.mode().is_readonly());
-
         if let Some(path) = path {
-            // if file is already open return existing handle
-            for (handle, file) in state.files.iter() {
-                if file.name == path {
-                    return Ok(*handle);
-                }
+            let exists = state.names.contains_key(path);
+            if (exists && opts.mode().must_create()) || (!exists && !opts.mode().can_create()) {
+                return Err(vars::SQLITE_CANTOPEN);
             }
-            state.files.insert(
-                file_handle,
-                File {
-                    name: path.to_owned(),
-                    data: Vec::new(),
-                    delete_on_close: opts.delete_on_close(),
-                },
-            );
         }
+
+        let id = state.next_id();
+        let file_handle = MockHandle::new(id, opts.mode().is_readonly())
+            .with_in_memory(opts.is_memory())
+            .with_open_kind(opts.kind());
+
+        // every open gets a new handle, opens of an existing name share its file
+        let name = path.map_or_else(|| format!("anonymous-{id}"), ToOwned::to_owned);
+        let file_id = *state.names.entry(name.clone()).or_insert(id);
+        let file = state.files.entry(file_id).or_insert_with(|| File {
+            name,
+            data: Vec::new(),
+            delete_on_close: false,
+            atomic_snapshot: None,
+            open_handles: 0,
+        });
+        file.open_handles += 1;
+        // anonymous files can't be reopened, so are always temporary
+        let delete_on_close = opts.delete_on_close() || path.is_none();
+        state.handles.insert(
+            file_handle,
+            OpenHandle {
+                file_id,
+                delete_on_close,
+                shm_shared: 0,
+                shm_exclusive: 0,
+                pending: WriteCoalescer::new(usize::MAX),
+            },
+        );
         Ok(file_handle)
     }
 
-    fn delete(&self, path: &str) -> VfsResult<()> {
+    fn post_open(&self, meta: &mut Self::Handle, flags: &mut i32) {
+        let mut state = self.state();
+        state.log(format_args!("post_open: handle={meta:?} flags={flags:#x}"));
+        state.hooks.post_open(*meta, flags);
+    }
+
+    fn delete(&self, path: &str, sync_dir: bool) -> VfsResult<()> {
         let mut state = self.state();
-        state.log(format_args!("delete: path={path:?}"));
-        state.hooks.delete(path);
-        state.files.retain(|_, file| file.name != path);
+        state.log(format_args!("delete: path={path:?} sync_dir={sync_dir}"));
+        state.hooks.delete(path, sync_dir);
+        // like unlink, the name is removed straight away while open handles
+        // keep using the file until they're closed
+        if let Some(file_id) = state.names.remove(path) {
+            match state.files.get_mut(&file_id) {
+                Some(file) if file.open_handles > 0 => file.delete_on_close = true,
+                _ => {
+                    state.files.remove(&file_id);
+                    state.shm.remove(&file_id);
+                }
+            }
+        }
         Ok(())
     }
 
@@ -185,21 +457,29 @@ impl Vfs for MockVfs {
         let mut state = self.state();
         state.log(format_args!("access: path={path:?} flags={flags:?}"));
         state.hooks.access(path, flags);
-        Ok(state.files.values().any(|file| file.name == path))
+        Ok(state.names.contains_key(path))
     }
 
     fn file_size(&self, meta: &mut Self::Handle) -> VfsResult<usize> {
         let mut state = self.state();
         state.log(format_args!("file_size: handle={meta:?}"));
         state.hooks.file_size(*meta);
-        Ok(state.files.get(meta).map_or(0, |file| file.data.len()))
+        state.flush(meta);
+        Ok(state.file(meta).map_or(0, |file| file.data.len()))
+    }
+
+    fn min_file_size(&self, meta: &mut Self::Handle) -> usize {
+        let mut state = self.state();
+        state.log(format_args!("min_file_size: handle={meta:?}"));
+        state.hooks.min_file_size(*meta)
     }
 
     fn truncate(&self, meta: &mut Self::Handle, size: usize) -> VfsResult<()> {
         let mut state = self.state();
         state.log(format_args!("truncate: handle={meta:?} size={size:?}"));
         state.hooks.truncate(*meta, size);
-        if let Some(file) = state.files.get_mut(meta) {
+        state.flush(meta);
+        if let Some(file) = state.file_mut(meta) {
             if size > file.data.len() {
                 file.data.resize(size, 0);
             } else {
@@ -218,15 +498,25 @@ impl Vfs for MockVfs {
             buf.len()
         ));
         state.hooks.write(*meta, offset, buf);
-        if let Some(file) = state.files.get_mut(meta) {
-            if offset + buf.len() > file.data.len() {
-                file.data.resize(offset + buf.len(), 0);
-            }
-            file.data[offset..offset + buf.len()].copy_from_slice(buf);
-            Ok(buf.len())
-        } else {
-            Err(vars::SQLITE_IOERR_WRITE)
+        let buf = match state.hooks.max_write(*meta) {
+            Some(max) => &buf[..buf.len().min(max)],
+            None => buf,
+        };
+        offset
+            .checked_add(buf.len())
+            .ok_or(vars::SQLITE_IOERR_WRITE)?;
+        if state.hooks.buffer_writes(*meta) {
+            let open = state
+                .handles
+                .get_mut(meta)
+                .ok_or(vars::SQLITE_IOERR_WRITE)?;
+            open.pending.push(offset, buf);
+            return Ok(buf.len());
         }
+        state.flush(meta);
+        let file = state.file_mut(meta).ok_or(vars::SQLITE_IOERR_WRITE)?;
+        file.write_at(offset, buf);
+        Ok(buf.len())
     }
 
     fn read(&self, meta: &mut Self::Handle, offset: usize, buf: &mut [u8]) -> VfsResult<usize> {
@@ -238,34 +528,61 @@ impl Vfs for MockVfs {
             buf.len()
         ));
         state.hooks.read(*meta, offset, buf);
-        if let Some(file) = state.files.get(meta) {
-            if offset > file.data.len() {
-                return Ok(0);
-            }
-            let len = buf.len().min(file.data.len() - offset);
-            buf[..len].copy_from_slice(&file.data[offset..offset + len]);
+        let max = state.hooks.max_read(*meta).unwrap_or(usize::MAX);
+        state.flush(meta);
+        if let Some(file) = state.file(meta) {
+            // reads past EOF return the available bytes followed by zeros
+            let available = file.data.get(offset..).unwrap_or_default();
+            let len = buf.len().min(available.len()).min(max);
+            buf[..len].copy_from_slice(&available[..len]);
+            buf[len..].fill(0);
             Ok(len)
         } else {
             Err(vars::SQLITE_IOERR_READ)
         }
     }
 
-    fn sync(&self, meta: &mut Self::Handle) -> VfsResult<()> {
+    fn sync(&self, meta: &mut Self::Handle, flags: SyncFlags) -> VfsResult<()> {
+        let mut state = self.state();
+        state.log(format_args!("sync: handle={meta:?} flags={flags:?}"));
+        state.hooks.sync(*meta, flags);
+        state.flush(meta);
+        Ok(())
+    }
+
+    fn sync_fcntl(&self, meta: &mut Self::Handle, super_journal: Option<&str>) -> VfsResult<()> {
+        let mut state = self.state();
+        state.log(format_args!(
+            "sync_fcntl: handle={meta:?} super_journal={super_journal:?}"
+        ));
+        state.hooks.sync_fcntl(*meta, super_journal);
+        Ok(())
+    }
+
+    fn commit_phase_two(&self, meta: &mut Self::Handle) -> VfsResult<()> {
+        let mut state = self.state();
+        state.log(format_args!("commit_phase_two: handle={meta:?}"));
+        state.hooks.commit_phase_two(*meta);
+        Ok(())
+    }
+
+    fn overwrite_hint(&self, meta: &mut Self::Handle) -> VfsResult<()> {
         let mut state = self.state();
-        state.log(format_args!("sync: handle={meta:?}"));
-        state.hooks.sync(*meta);
+        state.log(format_args!("overwrite_hint: handle={meta:?}"));
+        state.hooks.overwrite_hint(*meta);
         Ok(())
     }
 
     fn lock(&self, meta: &mut Self::Handle, level: LockLevel) -> VfsResult<()> {
-        let state = self.state();
+        let mut state = self.state();
         state.log(format_args!("lock: handle={meta:?} level={level:?}"));
-        Ok(())
+        state.hooks.lock(*meta, level)
     }
 
     fn unlock(&self, meta: &mut Self::Handle, level: LockLevel) -> VfsResult<()> {
-        let state = self.state();
+        let mut state = self.state();
         state.log(format_args!("unlock: handle={meta:?} level={level:?}"));
+        state.hooks.unlock(*meta, level);
         Ok(())
     }
 
@@ -278,36 +595,262 @@ impl Vfs for MockVfs {
     fn close(&self, meta: Self::Handle) -> VfsResult<()> {
         let mut state = self.state();
         state.log(format_args!("close: handle={meta:?}"));
-        state.hooks.close(meta);
-        if let Some(file) = state.files.get(&meta) {
-            if file.delete_on_close {
-                state.files.remove(&meta);
+        let result = state.hooks.close(meta);
+        let Some(OpenHandle {
+            file_id, delete_on_close, mut pending, ..
+        }) = state.handles.remove(&meta)
+        else {
+            return result;
+        };
+        let Some(file) = state.files.get_mut(&file_id) else {
+            return result;
+        };
+        file.open_handles -= 1;
+        // buffered writes are persisted, unless the file is being deleted
+        if !delete_on_close && !file.delete_on_close {
+            for (offset, data) in pending.take_flush() {
+                file.write_at(offset, &data);
             }
         }
-        Ok(())
+        if delete_on_close && !file.delete_on_close {
+            // unlink the name, unless it was deleted or reused meanwhile
+            file.delete_on_close = true;
+            let name = file.name.clone();
+            if state.names.get(&name) == Some(&file_id) {
+                state.names.remove(&name);
+            }
+        }
+        if state
+            .files
+            .get(&file_id)
+            .is_some_and(|f| f.open_handles == 0 && f.delete_on_close)
+        {
+            state.files.remove(&file_id);
+            state.shm.remove(&file_id);
+        }
+        result
     }
 
     fn pragma(
         &self,
         meta: &mut Self::Handle,
         pragma: Pragma<'_>,
-    ) -> Result<Option<String>, PragmaErr> {
+    ) -> Result<PragmaResult, PragmaErr> {
         let mut state = self.state();
         state.log(format_args!("pragma: handle={meta:?} pragma={pragma:?}"));
         state.hooks.pragma(*meta, pragma)
     }
 
+    fn set_config(&self, handle: &mut Self::Handle, key: &str, value: &str) -> VfsResult<()> {
+        let mut state = self.state();
+        state.log(format_args!(
+            "set_config: handle={handle:?} key={key:?} value={value:?}"
+        ));
+        state.hooks.set_config(*handle, key, value)
+    }
+
+    fn chunk_size(&self, handle: &mut Self::Handle, size: usize) -> VfsResult<()> {
+        let mut state = self.state();
+        state.log(format_args!("chunk_size: handle={handle:?} size={size:?}"));
+        state.hooks.chunk_size(*handle, size);
+        Ok(())
+    }
+
+    fn has_moved(&self, handle: &mut Self::Handle) -> VfsResult<bool> {
+        let mut state = self.state();
+        state.log(format_args!("has_moved: handle={handle:?}"));
+        state.hooks.has_moved(*handle)
+    }
+
+    fn lock_state(&self, handle: &mut Self::Handle) -> VfsResult<LockLevel> {
+        let mut state = self.state();
+        state.log(format_args!("lock_state: handle={handle:?}"));
+        state.hooks.lock_state(*handle)
+    }
+
+    fn mmap_size(&self, handle: &mut Self::Handle, requested: Option<i64>) -> VfsResult<i64> {
+        let mut state = self.state();
+        state.log(format_args!(
+            "mmap_size: handle={handle:?} requested={requested:?}"
+        ));
+        state.hooks.mmap_size(*handle, requested)
+    }
+
+    fn set_busy_handler(&self, handle: &mut Self::Handle, handler: BusyHandler) {
+        let mut state = self.state();
+        state.log(format_args!("set_busy_handler: handle={handle:?}"));
+        state.hooks.set_busy_handler(*handle, handler);
+    }
+
+    fn lock_timeout(&self, handle: &mut Self::Handle, millis: i32) -> VfsResult<()> {
+        let mut state = self.state();
+        state.log(format_args!(
+            "lock_timeout: handle={handle:?} millis={millis}"
+        ));
+        state.hooks.lock_timeout(*handle, millis)
+    }
+
+    fn begin_atomic_write(&self, handle: &mut Self::Handle) -> VfsResult<()> {
+        let mut state = self.state();
+        state.log(format_args!("begin_atomic_write: handle={handle:?}"));
+        state.hooks.begin_atomic_write(*handle);
+        let file = state
+            .file_mut(handle)
+            .ok_or(vars::SQLITE_IOERR_BEGIN_ATOMIC)?;
+        file.atomic_snapshot = Some(file.data.clone());
+        Ok(())
+    }
+
+    fn commit_atomic_write(&self, handle: &mut Self::Handle) -> VfsResult<()> {
+        let mut state = self.state();
+        state.log(format_args!("commit_atomic_write: handle={handle:?}"));
+        state.hooks.commit_atomic_write(*handle);
+        let file = state
+            .file_mut(handle)
+            .ok_or(vars::SQLITE_IOERR_COMMIT_ATOMIC)?;
+        file.atomic_snapshot = None;
+        Ok(())
+    }
+
+    fn rollback_atomic_write(&self, handle: &mut Self::Handle) -> VfsResult<()> {
+        let mut state = self.state();
+        state.log(format_args!("rollback_atomic_write: handle={handle:?}"));
+        state.hooks.rollback_atomic_write(*handle);
+        let file = state
+            .file_mut(handle)
+            .ok_or(vars::SQLITE_IOERR_ROLLBACK_ATOMIC)?;
+        if let Some(snapshot) = file.atomic_snapshot.take() {
+            file.data = snapshot;
+        }
+        Ok(())
+    }
+
     fn sector_size(&self, handle: &mut Self::Handle) -> VfsResult<i32> {
         let mut state = self.state();
         state.log(format_args!("sector_size"));
-        state.hooks.sector_size(*handle);
-        Ok(DEFAULT_SECTOR_SIZE)
+        state.hooks.sector_size(*handle)
     }
 
     fn device_characteristics(&self, handle: &mut Self::Handle) -> VfsResult<i32> {
         let mut state = self.state();
         state.log(format_args!("device_characteristics"));
-        state.hooks.device_characteristics(*handle);
-        Ok(DEFAULT_DEVICE_CHARACTERISTICS)
+        state.hooks.device_characteristics(*handle)
+    }
+
+    fn shm_map(
+        &self,
+        handle: &mut Self::Handle,
+        region_idx: usize,
+        region_size: usize,
+        extend: bool,
+    ) -> VfsResult<Option<NonNull<u8>>> {
+        let mut state = self.state();
+        state.log(format_args!(
+            "shm_map: handle={handle:?} region_idx={region_idx} region_size={region_size} extend={extend}"
+        ));
+        state.hooks.shm_map(*handle, region_idx, extend);
+        let file_id = state
+            .handles
+            .get(handle)
+            .ok_or(vars::SQLITE_IOERR_SHMMAP)?
+            .file_id;
+        let regions = state.shm.entry(file_id).or_default();
+        if region_idx >= regions.len() {
+            if !extend {
+                return Ok(None);
+            }
+            regions.resize_with(region_idx + 1, || vec![0; region_size].into_boxed_slice());
+        }
+        // the regions are boxed, so they don't move as more are added
+        Ok(NonNull::new(regions[region_idx].as_mut_ptr()))
+    }
+
+    fn shm_lock(
+        &self,
+        handle: &mut Self::Handle,
+        offset: u32,
+        count: u32,
+        mode: ShmLockMode,
+    ) -> VfsResult<()> {
+        let mut state = self.state();
+        state.log(format_args!(
+            "shm_lock: handle={handle:?} offset={offset} count={count} mode={mode:?}"
+        ));
+        state.hooks.shm_lock(*handle, offset, count, mode)?;
+        if offset + count > vars::SQLITE_SHM_NLOCK as u32 {
+            return Err(vars::SQLITE_IOERR_SHMLOCK);
+        }
+        let mask = (((1_u32 << count) - 1) << offset) as u8;
+        let file_id = state
+            .handles
+            .get(handle)
+            .ok_or(vars::SQLITE_IOERR_SHMLOCK)?
+            .file_id;
+
+        // the locks held by every other handle to the same file
+        let (shared, exclusive) = state
+            .handles
+            .iter()
+            .filter(|(other, open)| *other != handle && open.file_id == file_id)
+            .fold((0, 0), |(shared, exclusive), (_, open)| {
+                (shared | open.shm_shared, exclusive | open.shm_exclusive)
+            });
+        let open = state
+            .handles
+            .get_mut(handle)
+            .ok_or(vars::SQLITE_IOERR_SHMLOCK)?;
+        match mode {
+            ShmLockMode::LockShared if exclusive & mask != 0 => return Err(vars::SQLITE_BUSY),
+            ShmLockMode::LockShared => open.shm_shared |= mask,
+            ShmLockMode::LockExclusive if (shared | exclusive) & mask != 0 => {
+                return Err(vars::SQLITE_BUSY);
+            }
+            ShmLockMode::LockExclusive => open.shm_exclusive |= mask,
+            ShmLockMode::UnlockShared => open.shm_shared &= !mask,
+            ShmLockMode::UnlockExclusive => open.shm_exclusive &= !mask,
+        }
+        Ok(())
+    }
+
+    fn shm_barrier(&self, _handle: &mut Self::Handle) {
+        fence(Ordering::SeqCst);
+    }
+
+    fn shm_unmap(&self, handle: &mut Self::Handle, delete: bool) -> VfsResult<()> {
+        let mut state = self.state();
+        state.log(format_args!("shm_unmap: handle={handle:?} delete={delete}"));
+        state.hooks.shm_unmap(*handle, delete);
+        let open = state.handles.get_mut(handle).ok_or(vars::SQLITE_IOERR)?;
+        open.shm_shared = 0;
+        open.shm_exclusive = 0;
+        let file_id = open.file_id;
+        if delete {
+            state.shm.remove(&file_id);
+        }
+        Ok(())
+    }
+
+    fn temp_filename(&self) -> VfsResult<String> {
+        let mut state = self.state();
+        state.log(format_args!("temp_filename"));
+        state.hooks.temp_filename()
+    }
+
+    fn randomness(&self, buf: &mut [u8]) -> VfsResult<usize> {
+        let mut state = self.state();
+        state.log(format_args!("randomness: buf.len={}", buf.len()));
+        state.hooks.randomness(buf)
+    }
+
+    fn sleep(&self, micros: i32) -> VfsResult<i32> {
+        let mut state = self.state();
+        state.log(format_args!("sleep: micros={micros}"));
+        state.hooks.sleep(micros)
+    }
+
+    fn current_time_int64(&self) -> VfsResult<i64> {
+        let mut state = self.state();
+        state.log(format_args!("current_time_int64"));
+        state.hooks.current_time_int64()
     }
 }
@@ -0,0 +1,267 @@
+//! Helpers for building vfs implementations.
+
+use alloc::collections::VecDeque;
+use alloc::vec::Vec;
+
+/// Buffers writes so that adjacent writes can be flushed as one region.
+///
+/// `SQLite` writes one page per xWrite call, for example while
+/// checkpointing, which is slow for vfs implementations where each write is
+/// a network request. Hold a `WriteCoalescer` in the handle, [`push`] each
+/// write into it, and write out the regions returned by [`take_flush`] when
+/// the file is synced.
+///
+/// A write which starts where the previous one ended extends its region,
+/// unless that would grow the region beyond the configured max length. Any
+/// other write starts a new region, so regions must be written out in order
+/// for later writes to overwrite earlier ones. Buffered writes aren't visible
+/// to reads, so flush before reading, truncating, or reporting the file size.
+///
+/// [`push`]: WriteCoalescer::push
+/// [`take_flush`]: WriteCoalescer::take_flush
+#[derive(Debug, Clone)]
+pub struct WriteCoalescer {
+    max_len: usize,
+    regions: Vec<(usize, Vec<u8>)>,
+    len: usize,
+}
+
+impl WriteCoalescer {
+    /// Creates a coalescer which grows regions up to `max_len` bytes. Single
+    /// writes longer than `max_len` are kept whole.
+    pub fn new(max_len: usize) -> Self {
+        Self { max_len, regions: Vec::new(), len: 0 }
+    }
+
+    /// Buffers a write of `data` at `offset`.
+    pub fn push(&mut self, offset: usize, data: &[u8]) {
+        self.len += data.len();
+        if let Some((start, region)) = self.regions.last_mut() {
+            if *start + region.len() == offset && region.len() + data.len() <= self.max_len {
+                region.extend_from_slice(data);
+                return;
+            }
+        }
+        self.regions.push((offset, data.to_vec()));
+    }
+
+    /// Returns the total number of bytes buffered. Use this to flush early
+    /// once enough data has been buffered.
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    /// Returns true if no writes are buffered.
+    pub fn is_empty(&self) -> bool {
+        self.regions.is_empty()
+    }
+
+    /// Takes the buffered regions as `(offset, data)` pairs, in the order
+    /// they must be written.
+    pub fn take_flush(&mut self) -> Vec<(usize, Vec<u8>)> {
+        self.len = 0;
+        core::mem::take(&mut self.regions)
+    }
+}
+
+/// A least recently used cache of reads, keyed by offset and length.
+///
+/// `SQLite` reads one page at a time and often reads the same pages
+/// repeatedly, for example the root pages of btrees, which is slow for vfs
+/// implementations where each read is a network request. Hold a `PageCache`
+/// in the handle and serve reads with [`get_or_fetch`].
+///
+/// The cache doesn't see writes, so call [`invalidate`] with the range of
+/// every write, and from the new size to `usize::MAX` on truncate. Call
+/// [`clear`] when the file may have been changed by another connection,
+/// such as when a lock is acquired.
+///
+/// [`get_or_fetch`]: PageCache::get_or_fetch
+/// [`invalidate`]: PageCache::invalidate
+/// [`clear`]: PageCache::clear
+#[derive(Debug, Clone)]
+pub struct PageCache {
+    capacity: usize,
+    /// Cached reads as `(offset, len, data)`, most recently used last
+    entries: VecDeque<(usize, usize, Vec<u8>)>,
+}
+
+impl PageCache {
+    /// Creates a cache holding up to `capacity` reads, at least one.
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            capacity: capacity.max(1),
+            entries: VecDeque::new(),
+        }
+    }
+
+    /// Returns the cached data for a read of `len` bytes at `offset`, or
+    /// calls `fetch` with the offset and length to read it and caches the
+    /// result. `fetch` may return less than `len` bytes at the end of the
+    /// file, errors are returned without being cached.
+    ///
+    /// Evicts the least recently used read once the cache is full.
+    pub fn get_or_fetch<E>(
+        &mut self,
+        offset: usize,
+        len: usize,
+        fetch: impl FnOnce(usize, usize) -> Result<Vec<u8>, E>,
+    ) -> Result<&[u8], E> {
+        let cached = self
+            .entries
+            .iter()
+            .position(|(o, l, _)| *o == offset && *l == len);
+        let entry = match cached {
+            Some(i) => self.entries.remove(i).expect("index is in bounds"),
+            None => {
+                let data = fetch(offset, len)?;
+                if self.entries.len() >= self.capacity {
+                    self.entries.pop_front();
+                }
+                (offset, len, data)
+            }
+        };
+        self.entries.push_back(entry);
+        let (_, _, data) = self.entries.back().expect("entry was just pushed");
+        Ok(data)
+    }
+
+    /// Removes every cached read overlapping the `len` bytes at `offset`.
+    pub fn invalidate(&mut self, offset: usize, len: usize) {
+        let end = offset.saturating_add(len);
+        self.entries
+            .retain(|(o, l, _)| len == 0 || o.saturating_add(*l) <= offset || *o >= end);
+    }
+
+    /// Removes every cached read.
+    pub fn clear(&mut self) {
+        self.entries.clear();
+    }
+
+    /// Returns the number of cached reads.
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    /// Returns true if no reads are cached.
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloc::vec;
+    use core::cell::Cell;
+    use core::convert::Infallible;
+
+    /// Reads from a file whose bytes are their offset, counting the reads.
+    fn read(fetches: &Cell<usize>) -> impl FnOnce(usize, usize) -> Result<Vec<u8>, Infallible> {
+        move |offset, len| {
+            fetches.set(fetches.get() + 1);
+            Ok((offset..offset + len).map(|b| b as u8).collect())
+        }
+    }
+
+    #[test]
+    fn coalesces_sequential_pages() {
+        let mut writes = WriteCoalescer::new(usize::MAX);
+        for page in 0..100_u8 {
+            writes.push(usize::from(page) * 4096, &[page; 4096]);
+        }
+        assert_eq!(writes.len(), 100 * 4096);
+
+        let regions = writes.take_flush();
+        assert_eq!(regions.len(), 1);
+        let (offset, data) = &regions[0];
+        assert_eq!(*offset, 0);
+        assert_eq!(data.len(), 100 * 4096);
+        assert!(
+            data.chunks(4096)
+                .enumerate()
+                .all(|(i, page)| page == [i as u8; 4096])
+        );
+
+        assert!(writes.is_empty());
+        assert_eq!(writes.len(), 0);
+    }
+
+    #[test]
+    fn splits_gaps_and_long_regions() {
+        let mut writes = WriteCoalescer::new(8);
+        writes.push(0, &[1; 4]);
+        writes.push(4, &[2; 4]);
+        // the region is full
+        writes.push(8, &[3; 4]);
+        // not adjacent to the previous write
+        writes.push(0, &[4; 4]);
+        // longer than the max length
+        writes.push(4, &[5; 16]);
+        assert_eq!(
+            writes.take_flush(),
+            vec![
+                (0, vec![1, 1, 1, 1, 2, 2, 2, 2]),
+                (8, vec![3; 4]),
+                (0, vec![4; 4]),
+                (4, vec![5; 16]),
+            ]
+        );
+    }
+
+    #[test]
+    fn evicts_least_recently_used() {
+        let mut cache = PageCache::new(2);
+        let fetches = Cell::new(0);
+        assert_eq!(
+            cache.get_or_fetch(0, 4, read(&fetches)),
+            Ok(&[0, 1, 2, 3][..])
+        );
+        cache.get_or_fetch(4, 4, read(&fetches)).unwrap();
+        // reading the first page again makes the second the least recently used
+        cache.get_or_fetch(0, 4, |_, _| Err("cached")).unwrap();
+        cache.get_or_fetch(8, 4, read(&fetches)).unwrap();
+        assert_eq!(cache.len(), 2);
+        assert_eq!(fetches.get(), 3);
+
+        cache.get_or_fetch(0, 4, |_, _| Err("cached")).unwrap();
+        cache.get_or_fetch(8, 4, |_, _| Err("cached")).unwrap();
+        assert_eq!(
+            cache.get_or_fetch(4, 4, |_, _| Err("evicted")),
+            Err("evicted")
+        );
+        // a different length at the same offset is a different read
+        cache.get_or_fetch(0, 2, read(&fetches)).unwrap();
+        assert_eq!(fetches.get(), 4);
+
+        cache.clear();
+        assert!(cache.is_empty());
+    }
+
+    #[test]
+    fn invalidates_overlapping_reads() {
+        let mut cache = PageCache::new(8);
+        let fetches = Cell::new(0);
+        for page in 0..4 {
+            cache.get_or_fetch(page * 4, 4, read(&fetches)).unwrap();
+        }
+
+        // a write to the end of the second page and the start of the third
+        cache.invalidate(7, 2);
+        assert_eq!(cache.len(), 2);
+        assert_eq!(fetches.get(), 4);
+        cache.get_or_fetch(0, 4, |_, _| Err("cached")).unwrap();
+        cache.get_or_fetch(12, 4, |_, _| Err("cached")).unwrap();
+        assert_eq!(cache.get_or_fetch(4, 4, |_, _| Err("stale")), Err("stale"));
+        assert_eq!(cache.get_or_fetch(8, 4, |_, _| Err("stale")), Err("stale"));
+
+        // adjacent and empty ranges don't overlap
+        cache.invalidate(4, 8);
+        cache.invalidate(0, 0);
+        assert_eq!(cache.len(), 2);
+
+        // truncating to 2 bytes
+        cache.invalidate(2, usize::MAX);
+        assert!(cache.is_empty());
+    }
+}
@@ -1,43 +1,70 @@
-use crate::flags::{AccessFlags, LockLevel, OpenOpts, ShmLockMode};
-use crate::logger::SqliteLogger;
+use crate::flags::{
+    AccessFlags, DeviceCharacteristics, LockLevel, OpenKind, OpenOpts, ShmLockMode, SyncFlags,
+    UriRoutines, VfsCapabilities,
+};
+use crate::logger::{SqliteLogLevel, SqliteLogger};
 use crate::vars::SQLITE_ERROR;
 use crate::{ffi, vars};
 use alloc::borrow::Cow;
 use alloc::boxed::Box;
 use alloc::ffi::CString;
 use alloc::format;
-use alloc::string::String;
-use core::mem::{ManuallyDrop, size_of};
+use alloc::string::{String, ToString};
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::cell::UnsafeCell;
+use core::mem::{ManuallyDrop, MaybeUninit, size_of};
 use core::slice;
+use core::str::FromStr;
+use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
 use core::{
     ffi::{CStr, c_char, c_int, c_void},
     ptr::{NonNull, null_mut},
 };
 
-/// The minimim supported `SQLite` version.
+/// The minimim supported `SQLite` version. Registering a vfs with an older
+/// version of `SQLite` fails with `SQLITE_ERROR`.
 // If you need to make this earlier, make sure the tests are testing the earlier version
 pub const MIN_SQLITE_VERSION_NUMBER: i32 = 3037002;
 
+/// Returns the version number of the statically linked `SQLite`, in the same
+/// format as [`MIN_SQLITE_VERSION_NUMBER`]. Use [`SqliteApi::version_number`]
+/// when loaded as an extension.
+#[cfg(feature = "static")]
+pub fn sqlite_version_number() -> i32 {
+    unsafe { ffi::sqlite3_libversion_number() }
+}
+
 const DEFAULT_MAX_PATH_LEN: i32 = 512;
+/// The maximum length in bytes of a vfs name accepted by the register functions.
+pub const MAX_VFS_NAME_LEN: usize = 256;
 pub const DEFAULT_SECTOR_SIZE: i32 = 4096;
+/// The smallest sector size accepted from [`Vfs::sector_size`].
+pub const MIN_SECTOR_SIZE: i32 = 512;
+/// The largest sector size accepted from [`Vfs::sector_size`].
+pub const MAX_SECTOR_SIZE: i32 = 65536;
 
-pub const DEFAULT_DEVICE_CHARACTERISTICS: i32 =
+pub const DEFAULT_DEVICE_CHARACTERISTICS: i32 = DeviceCharacteristics::new()
     // writes of any size are atomic
-    vars::SQLITE_IOCAP_ATOMIC |
+    .atomic()
     // after reboot following a crash or power loss, the only bytes in a file that were written
     // at the application level might have changed and that adjacent bytes, even bytes within
     // the same sector are guaranteed to be unchanged
-    vars::SQLITE_IOCAP_POWERSAFE_OVERWRITE |
+    .powersafe_overwrite()
     // when data is appended to a file, the data is appended first then the size of the file is
     // extended, never the other way around
-    vars::SQLITE_IOCAP_SAFE_APPEND |
+    .safe_append()
     // information is written to disk in the same order as calls to xWrite()
-    vars::SQLITE_IOCAP_SEQUENTIAL;
+    .sequential()
+    .bits();
 
 /// A `SQLite3` extended error code
 pub type SqliteErr = i32;
 
-pub type VfsResult<T> = Result<T, SqliteErr>;
+/// The result type returned by `Vfs` methods. The error type defaults to
+/// [`SqliteErr`]; implementations may use [`crate::error::VfsError`] in their
+/// own helpers and convert with `?`.
+pub type VfsResult<T, E = SqliteErr> = Result<T, E>;
 
 // FileWrapper needs to be repr(C) and have sqlite3_file as it's first member
 // because it's a "subclass" of sqlite3_file
@@ -46,6 +73,8 @@ struct FileWrapper<Handle> {
     file: ffi::sqlite3_file,
     vfs: *mut ffi::sqlite3_vfs,
     handle: Handle,
+    /// The last timeout passed to `SQLITE_FCNTL_LOCK_TIMEOUT`
+    lock_timeout: c_int,
 }
 
 struct AppData<Vfs> {
@@ -53,6 +82,103 @@ struct AppData<Vfs> {
     vfs: Vfs,
     io_methods: ffi::sqlite3_io_methods,
     sqlite_api: SqliteApi,
+    /// The name this vfs was registered under
+    name: String,
+    time_source: Option<fn() -> i64>,
+    /// See `RegisterOpts::wal_only`
+    wal_only: bool,
+    /// The result of `Vfs::capabilities`, queried when registering
+    capabilities: VfsCapabilities,
+    /// The number of files currently open via this vfs
+    open_files: AtomicUsize,
+    /// See `RegisterOpts::cancellation`
+    cancellation: Option<CancellationToken>,
+}
+
+impl<Vfs> AppData<Vfs> {
+    /// Fails with `err` if I/O has been cancelled.
+    fn check_cancelled(&self, err: SqliteErr) -> VfsResult<()> {
+        match &self.cancellation {
+            Some(token) => token.check(err),
+            None => Ok(()),
+        }
+    }
+}
+
+/// A connection's busy handler, passed to [`Vfs::set_busy_handler`].
+#[derive(Debug, Clone, Copy)]
+pub struct BusyHandler {
+    callback: unsafe extern "C" fn(*mut c_void) -> c_int,
+    arg: *mut c_void,
+}
+
+// Safety: the handler is only invoked from vfs methods called by the
+// connection, which holds its mutex for the duration of the call.
+unsafe impl Send for BusyHandler {}
+
+impl BusyHandler {
+    /// Invokes the busy handler, which may sleep. Returns true if the caller
+    /// should try again, or false if it should give up and return
+    /// `SQLITE_BUSY`.
+    ///
+    /// # Safety
+    /// The handler must only be invoked from a vfs method called by `SQLite`
+    /// on the file it was registered with, such as `Vfs::lock`, while that
+    /// file is open. It must not be invoked from another thread or after the
+    /// method returns, and must not be invoked on the behalf of a different
+    /// connection.
+    pub unsafe fn invoke(&self) -> bool {
+        unsafe { (self.callback)(self.arg) != 0 }
+    }
+}
+
+/// A flag which the host application sets to abort the I/O of a vfs, for
+/// example when shutting down or when a remote store stops responding.
+///
+/// Pass a clone in [`RegisterOpts::cancellation`], and another to the vfs
+/// when constructing it. Once cancelled, reads, writes, and syncs fail
+/// straight away with `SQLITE_IOERR_READ`, `SQLITE_IOERR_WRITE`, and
+/// `SQLITE_IOERR_FSYNC`, without calling the vfs. Cancellation is
+/// cooperative: calls already in progress only return early if the vfs
+/// checks [`CancellationToken::is_cancelled`] while it waits. Reads and
+/// writes which return after the token was cancelled fail as well, whatever
+/// the vfs returned, so a vfs may simply stop waiting and return.
+///
+/// `SQLite` can't roll back a transaction while I/O fails, so a cancelled
+/// write may leave a hot journal behind, which is rolled back by the next
+/// connection once the token has been [`reset`](CancellationToken::reset).
+#[derive(Debug, Clone, Default)]
+pub struct CancellationToken {
+    cancelled: Arc<AtomicBool>,
+}
+
+impl CancellationToken {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Cancels all I/O of vfs's holding a clone of this token.
+    pub fn cancel(&self) {
+        self.cancelled.store(true, Ordering::Release);
+    }
+
+    /// Allows I/O again after the token was cancelled.
+    pub fn reset(&self) {
+        self.cancelled.store(false, Ordering::Release);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.cancelled.load(Ordering::Acquire)
+    }
+
+    /// Returns `Err(err)` if the token has been cancelled.
+    pub fn check(&self, err: SqliteErr) -> VfsResult<()> {
+        if self.is_cancelled() {
+            Err(err)
+        } else {
+            Ok(())
+        }
+    }
 }
 
 #[derive(Debug)]
@@ -67,6 +193,26 @@ pub enum PragmaErr {
     Fail(SqliteErr, Option<String>),
 }
 
+impl Pragma<'_> {
+    /// Parses the pragma's argument, failing with an error message if the
+    /// argument is missing or invalid.
+    ///
+    /// ```
+    /// use sqlite_plugin::vfs::Pragma;
+    /// let pragma = Pragma { name: "cache_pages", arg: Some("64") };
+    /// assert_eq!(pragma.arg_as::<u32>().ok(), Some(64));
+    /// ```
+    pub fn arg_as<T: FromStr>(&self) -> Result<T, PragmaErr> {
+        let arg = self.arg.ok_or_else(|| PragmaErr::required_arg(self))?;
+        arg.parse().map_err(|_| {
+            PragmaErr::Fail(
+                SQLITE_ERROR,
+                Some(format!("invalid argument for pragma {}: {arg}", self.name)),
+            )
+        })
+    }
+}
+
 impl PragmaErr {
     pub fn required_arg(p: &Pragma<'_>) -> Self {
         PragmaErr::Fail(
@@ -79,10 +225,83 @@ impl PragmaErr {
     }
 }
 
+/// The result of a pragma handled by `Vfs::pragma`, which `SQLite` returns to
+/// the caller as a single text value.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct PragmaResult(Option<String>);
+
+impl PragmaResult {
+    /// The pragma returns no rows.
+    pub fn none() -> Self {
+        Self(None)
+    }
+
+    pub fn text(text: impl Into<String>) -> Self {
+        Self(Some(text.into()))
+    }
+
+    pub fn int(value: i64) -> Self {
+        Self(Some(value.to_string()))
+    }
+
+    pub fn into_text(self) -> Option<String> {
+        self.0
+    }
+}
+
+impl From<Option<String>> for PragmaResult {
+    fn from(text: Option<String>) -> Self {
+        Self(text)
+    }
+}
+
+impl From<String> for PragmaResult {
+    fn from(text: String) -> Self {
+        Self::text(text)
+    }
+}
+
+impl From<i64> for PragmaResult {
+    fn from(value: i64) -> Self {
+        Self::int(value)
+    }
+}
+
 fn fallible(mut cb: impl FnMut() -> Result<i32, SqliteErr>) -> i32 {
     cb().unwrap_or_else(|err| err)
 }
 
+/// Enters a trace level span with the given fields when the `tracing` feature
+/// is enabled, returning a [`TraceSpan`].
+macro_rules! trace_span {
+    ($name:literal, $($fields:tt)*) => {{
+        #[cfg(feature = "tracing")]
+        let span = TraceSpan(
+            tracing::trace_span!($name, $($fields)*, rc = tracing::field::Empty).entered(),
+        );
+        #[cfg(not(feature = "tracing"))]
+        let span = TraceSpan;
+        span
+    }};
+}
+
+/// A span entered by `trace_span!`, which does nothing unless the `tracing`
+/// feature is enabled.
+#[cfg(feature = "tracing")]
+struct TraceSpan(tracing::span::EnteredSpan);
+#[cfg(not(feature = "tracing"))]
+struct TraceSpan;
+
+impl TraceSpan {
+    /// Records the return code of the trampoline and exits the span.
+    #[inline(always)]
+    fn exit(self, rc: c_int) -> c_int {
+        #[cfg(feature = "tracing")]
+        self.0.record("rc", rc);
+        rc
+    }
+}
+
 unsafe fn lossy_cstr<'a>(p: *const c_char) -> VfsResult<Cow<'a, str>> {
     unsafe {
         p.as_ref()
@@ -91,6 +310,12 @@ unsafe fn lossy_cstr<'a>(p: *const c_char) -> VfsResult<Cow<'a, str>> {
     }
 }
 
+/// # Safety
+/// Every byte of `data` must be initialized.
+unsafe fn assume_init_mut(data: &mut [MaybeUninit<u8>]) -> &mut [u8] {
+    unsafe { &mut *(core::ptr::from_mut(data) as *mut [u8]) }
+}
+
 macro_rules! unwrap_appdata {
     ($p_vfs:expr, $t_vfs:ty) => {
         unsafe {
@@ -136,53 +361,334 @@ macro_rules! unwrap_file {
 pub trait VfsHandle: Send {
     fn readonly(&self) -> bool;
     fn in_memory(&self) -> bool;
+
+    /// Returns the kind of file this handle was opened as, from
+    /// [`OpenOpts::kind`]. Lets code working with handles, such as layers,
+    /// treat for example WAL writes differently from main database writes.
+    ///
+    /// Defaults to [`OpenKind::Unknown`], so implementations should store the
+    /// kind when opening the file and return it here.
+    fn open_kind(&self) -> OpenKind {
+        OpenKind::Unknown
+    }
 }
 
 #[allow(unused_variables)]
 pub trait Vfs: Send + Sync {
     type Handle: VfsHandle;
 
+    /// Called once the vfs has been registered, with the name it was
+    /// registered under. The name lives as long as the registration, so
+    /// implementations registered under several names can tell them apart.
+    ///
+    /// `db` is the `sqlite3*` database handle passed to the extension entry
+    /// point when registered by `register_dynamic_with_db`, and null
+    /// otherwise. Useful for registering functions or auto-extensions
+    /// alongside the vfs.
+    fn on_register(&self, name: &str, db: *mut c_void) {}
+
+    /// Returns the optional features this vfs implements. Called once when
+    /// registering, to decide which `sqlite3_io_methods` to provide, so it
+    /// must not depend on later state.
+    ///
+    /// The default implementation declares no optional features, in which
+    /// case the shared memory and memory-mapped I/O methods are never called
+    /// and WAL mode is only available with `locking_mode=EXCLUSIVE`.
+    fn capabilities(&self) -> VfsCapabilities {
+        VfsCapabilities::new()
+    }
+
+    /// Called by [`unregister`] once the vfs has been removed from `SQLite`
+    /// and no files are open, just before its memory is reclaimed. Use this
+    /// to flush caches or stop background work.
+    ///
+    /// Returning an error (such as `SQLITE_BUSY`) re-registers the vfs and
+    /// aborts the unregistration, passing the error on to the caller.
+    fn shutdown(&self) -> VfsResult<()> {
+        Ok(())
+    }
+
     /// construct a canonical version of the given path
+    ///
+    /// Return `Ok(path)` to keep the input, or a newly computed `Cow::Owned`
+    /// path, for example to prepend a working directory to a relative path.
+    /// Owned paths aren't tied to the input's lifetime.
+    ///
+    /// The returned path must be shorter than `RegisterOpts::max_path_len`
+    /// bytes. Longer paths fail with `SQLITE_CANTOPEN_FULLPATH` rather than
+    /// being truncated.
     fn canonical_path<'a>(&self, path: Cow<'a, str>) -> VfsResult<Cow<'a, str>> {
         Ok(path)
     }
 
+    /// Returns true if the paths `a` and `b` refer to the same file.
+    ///
+    /// The default implementation compares the paths returned by
+    /// [`Vfs::canonical_path`]. Implementations with some notion of file
+    /// identity, such as inodes, should override this so that for example a
+    /// symlink to a journal isn't treated as a distinct file.
+    fn same_file(&self, a: &str, b: &str) -> VfsResult<bool> {
+        Ok(self.canonical_path(Cow::Borrowed(a))? == self.canonical_path(Cow::Borrowed(b))?)
+    }
+
     // file system operations
+
+    /// Opens the file at `path`.
+    ///
+    /// `path` is `None` when `SQLite` opens an anonymous temporary file, see
+    /// `OpenOpts::is_temp_file`. Each such open must return a new, distinct
+    /// file which is deleted when the handle is closed.
+    ///
+    /// Whether the file may be created depends on `opts.mode()`:
+    ///
+    /// | mode                               | file exists       | file is missing   |
+    /// |------------------------------------|-------------------|-------------------|
+    /// | `ReadOnly`                         | open              | `SQLITE_CANTOPEN` |
+    /// | `ReadWrite { create: None }`       | open              | `SQLITE_CANTOPEN` |
+    /// | `ReadWrite { create: Create }`     | open              | create            |
+    /// | `ReadWrite { create: MustCreate }` | `SQLITE_CANTOPEN` | create            |
+    ///
+    /// `SQLite` only asks for `MustCreate` when opening temporary files, and
+    /// masks it out of the flags passed to `sqlite3_open_v2`.
+    ///
+    /// Return a `SQLITE_CANTOPEN` extended code to tell `SQLite` why the file
+    /// couldn't be opened, either directly from [`vars`] or via
+    /// [`crate::error::VfsError`]:
+    ///
+    /// ```
+    /// use sqlite_plugin::{error::VfsError, flags::OpenOpts, vars, vfs::VfsResult};
+    ///
+    /// fn check_path(path: &str, opts: &OpenOpts) -> VfsResult<()> {
+    ///     if path.ends_with('/') {
+    ///         return Err(vars::SQLITE_CANTOPEN_ISDIR);
+    ///     }
+    ///     if opts.no_follow() && path.starts_with("link:") {
+    ///         return Err(VfsError::CantOpenSymlink.into());
+    ///     }
+    ///     Ok(())
+    /// }
+    /// # let opts = OpenOpts::new(vars::SQLITE_OPEN_NOFOLLOW);
+    /// # assert_eq!(check_path("dir/", &opts), Err(vars::SQLITE_CANTOPEN_ISDIR));
+    /// # assert_eq!(check_path("link:db", &opts), Err(vars::SQLITE_CANTOPEN_SYMLINK));
+    /// ```
     fn open(&self, path: Option<&str>, opts: OpenOpts) -> VfsResult<Self::Handle>;
-    fn delete(&self, path: &str) -> VfsResult<()>;
+
+    /// Called after a successful `open` with the flags which will be reported
+    /// back to `SQLite` via xOpen's `pOutFlags`, allowing the implementation to
+    /// adjust them. For example, to report `SQLITE_OPEN_MEMORY` conditionally.
+    ///
+    /// Not called if `SQLite` didn't ask for the output flags.
+    fn post_open(&self, handle: &mut Self::Handle, flags: &mut i32) {}
+
+    /// Delete the file at `path`. If `sync_dir` is true, the deletion must be
+    /// durable before returning, which for filesystem-backed vfs
+    /// implementations usually means syncing the containing directory.
+    fn delete(&self, path: &str, sync_dir: bool) -> VfsResult<()>;
     fn access(&self, path: &str, flags: AccessFlags) -> VfsResult<bool>;
 
     // file operations
     fn file_size(&self, handle: &mut Self::Handle) -> VfsResult<usize>;
     fn truncate(&self, handle: &mut Self::Handle, size: usize) -> VfsResult<()>;
+    /// The smallest size `SQLite` may truncate the file to. Truncating below
+    /// it fails with `SQLITE_IOERR_TRUNCATE` without calling `truncate`,
+    /// which protects implementations that keep metadata in the first bytes
+    /// of a file. Defaults to 0.
+    fn min_file_size(&self, handle: &mut Self::Handle) -> usize {
+        0
+    }
+
+    /// Writes `data` at `offset`, returning the number of bytes written.
+    ///
+    /// `offset + data.len()` never overflows a `usize`, but may still be far
+    /// larger than the file, so use checked arithmetic when resizing buffers
+    /// and return `SQLITE_IOERR_WRITE` or `SQLITE_FULL` for offsets which
+    /// can't be stored.
+    ///
+    /// Short writes are retried with the remaining bytes, so implementations
+    /// may write less than `data.len()`. Returning `Ok(0)` fails the write
+    /// with `SQLITE_FULL`.
     fn write(&self, handle: &mut Self::Handle, offset: usize, data: &[u8]) -> VfsResult<usize>;
+    /// Reads into `data` from `offset`, returning the number of bytes read.
+    /// As with `write`, `offset + data.len()` never overflows a `usize`.
+    ///
+    /// `SQLite`'s reads go through `read_exact`, which retries short reads
+    /// with the remaining bytes, so implementations may read less than
+    /// `data.len()`. Return `Ok(0)` at the end of the file.
     fn read(&self, handle: &mut Self::Handle, offset: usize, data: &mut [u8]) -> VfsResult<usize>;
 
+    /// Like `read`, but reads into a buffer which may be uninitialized,
+    /// returning the number of bytes read. The first `n` bytes of `data` must
+    /// be initialized when returning `Ok(n)`.
+    ///
+    /// The buffer passed to xRead isn't initialized, so the default
+    /// implementation zero-fills `data` before calling `read`. Implementations
+    /// which already hold the file's contents in memory can override this to
+    /// copy straight into `data` instead.
+    fn read_into_uninit(
+        &self,
+        handle: &mut Self::Handle,
+        offset: usize,
+        data: &mut [MaybeUninit<u8>],
+    ) -> VfsResult<usize> {
+        data.fill(MaybeUninit::new(0));
+        self.read(handle, offset, unsafe { assume_init_mut(data) })
+    }
+
+    /// Fills `data` from `offset` by calling `read_into_uninit` until the
+    /// buffer is full or it returns `Ok(0)`. If the end of the file is reached
+    /// first, the rest of `data` is zero-filled and `SQLITE_IOERR_SHORT_READ`
+    /// is returned, as `SQLite` requires. Override this to handle reads as a
+    /// whole rather than through `read`.
+    ///
+    /// Every byte of `data` must be initialized when this returns, whether or
+    /// not it succeeds.
+    fn read_exact(
+        &self,
+        handle: &mut Self::Handle,
+        offset: usize,
+        data: &mut [MaybeUninit<u8>],
+    ) -> VfsResult<()> {
+        let mut filled = 0;
+        let result = loop {
+            if filled == data.len() {
+                break Ok(());
+            }
+            match self.read_into_uninit(handle, offset + filled, &mut data[filled..]) {
+                Ok(0) => break Err(vars::SQLITE_IOERR_SHORT_READ),
+                Ok(n) if n > data.len() - filled => break Err(vars::SQLITE_IOERR_READ),
+                Ok(n) => filled += n,
+                Err(err) => break Err(err),
+            }
+        };
+        // From https://sqlite.org/c3ref/io_methods.html:
+        // "If xRead() returns SQLITE_IOERR_SHORT_READ it must also fill in the unread portions
+        // of the buffer with zeros."
+        data[filled..].fill(MaybeUninit::new(0));
+        result
+    }
+
+    /// Upgrades the lock held on `handle` to `level`.
+    ///
+    /// If the lock is held elsewhere, return `Err(SQLITE_BUSY)` rather than
+    /// blocking. `SQLite` will invoke the connection's busy handler, such as
+    /// the one installed by `PRAGMA busy_timeout`, and retry the lock until the
+    /// handler gives up, at which point the statement fails with
+    /// `SQLITE_BUSY`. Implementations therefore don't need their own retry
+    /// loop to respect the busy timeout.
     fn lock(&self, handle: &mut Self::Handle, level: LockLevel) -> VfsResult<()>;
 
     fn unlock(&self, handle: &mut Self::Handle, level: LockLevel) -> VfsResult<()>;
 
     fn check_reserved_lock(&self, handle: &mut Self::Handle) -> VfsResult<bool>;
 
-    fn sync(&self, handle: &mut Self::Handle) -> VfsResult<()> {
+    /// Makes all previous writes to the file durable. `SQLite` calls this
+    /// (xSync) when committing a transaction and during checkpoints, unless
+    /// `PRAGMA synchronous=OFF`.
+    fn sync(&self, handle: &mut Self::Handle, flags: SyncFlags) -> VfsResult<()> {
+        Ok(())
+    }
+
+    /// Ensures that all previous writes to the file are ordered before any
+    /// subsequent writes, without necessarily making them durable.
+    ///
+    /// `SQLite` has no file-level barrier and never calls this method, it only
+    /// calls `sync`. It exists so layered vfs's can request a cheaper ordering
+    /// guarantee from the vfs they wrap, for example from their own `sync`
+    /// when durability is provided elsewhere. The default implementation
+    /// falls back to a normal `sync`, which is always a valid barrier.
+    fn barrier(&self, handle: &mut Self::Handle) -> VfsResult<()> {
+        self.sync(handle, SyncFlags::new(vars::SQLITE_SYNC_NORMAL))
+    }
+
+    /// Called (`SQLITE_FCNTL_SYNC`) immediately before `SQLite` syncs the
+    /// database file, or in place of `sync` when `PRAGMA synchronous=OFF`.
+    /// `super_journal` is the super-journal's name when the sync is part of
+    /// a multi-database commit. Returning an error aborts the commit before
+    /// `sync` is called.
+    ///
+    /// Together with `commit_phase_two`, this lets replicated vfs's flush
+    /// batched writes exactly at commit. The default implementation does
+    /// nothing.
+    fn sync_fcntl(&self, handle: &mut Self::Handle, super_journal: Option<&str>) -> VfsResult<()> {
+        Ok(())
+    }
+
+    /// Called (`SQLITE_FCNTL_COMMIT_PHASETWO`) once a transaction has been
+    /// committed to the database file, after the final `sync` and before the
+    /// write lock is released with `unlock`. An error is reported to the
+    /// committing statement, but the transaction remains committed. The
+    /// default implementation does nothing.
+    fn commit_phase_two(&self, handle: &mut Self::Handle) -> VfsResult<()> {
+        Ok(())
+    }
+
+    /// Called (`SQLITE_FCNTL_OVERWRITE`) when the entire database file is
+    /// about to be rewritten, such as by `VACUUM`, before any of the writes.
+    /// Vfs's where reads are expensive can use this to drop cached pages and
+    /// skip reading back data that is about to be overwritten. Returning an
+    /// error aborts the rewrite. The default implementation does nothing.
+    fn overwrite_hint(&self, handle: &mut Self::Handle) -> VfsResult<()> {
         Ok(())
     }
 
+    /// Closes the file, consuming the handle.
+    ///
+    /// Implementations which buffer writes must persist them here, unless
+    /// the file was opened with `delete_on_close` or has been deleted, in
+    /// which case buffered writes may be discarded. `SQLite` doesn't always
+    /// call `sync` before closing a file it has written to, for example with
+    /// `PRAGMA synchronous=OFF`, or for temporary files, which are never
+    /// synced.
+    ///
+    /// `SQLite` treats the file as closed even if this returns an error and
+    /// never retries the close, so the handle is dropped either way. Errors
+    /// which could be recovered from by retrying, such as a failed flush to
+    /// remote storage, should be handled before returning.
     fn close(&self, handle: Self::Handle) -> VfsResult<()>;
 
     fn pragma(
         &self,
         handle: &mut Self::Handle,
         pragma: Pragma<'_>,
-    ) -> Result<Option<String>, PragmaErr> {
+    ) -> Result<PragmaResult, PragmaErr> {
         Err(PragmaErr::NotFound)
     }
 
+    /// Sets a runtime tunable, such as a retry count, in response to a pragma
+    /// named after the vfs: `PRAGMA "myvfs.retry_count" = 5` calls this with
+    /// the key `retry_count` and the value `5`, when the vfs is registered as
+    /// `myvfs`. The vfs name is matched case-insensitively. `SQLite` treats a
+    /// dotted pragma name as a schema name unless it is quoted.
+    ///
+    /// These pragmas are never passed to `pragma`. Unknown keys should return
+    /// `SQLITE_NOTFOUND`, and invalid values any other error, both of which
+    /// fail the pragma with a message naming the key. The default
+    /// implementation returns `SQLITE_NOTFOUND`.
+    fn set_config(&self, handle: &mut Self::Handle, key: &str, value: &str) -> VfsResult<()> {
+        Err(vars::SQLITE_NOTFOUND)
+    }
+
     // system queries
+
+    /// Returns the sector size of the device backing `handle`, which must be a
+    /// power of two between [`MIN_SECTOR_SIZE`] and [`MAX_SECTOR_SIZE`].
+    /// `SQLite` can't handle errors here, so errors and invalid sizes are
+    /// logged and replaced with [`DEFAULT_SECTOR_SIZE`].
     fn sector_size(&self, handle: &mut Self::Handle) -> VfsResult<i32> {
         Ok(DEFAULT_SECTOR_SIZE)
     }
 
+    /// Returns the `SQLITE_IOCAP_*` flags describing the guarantees of the
+    /// storage backing `handle`, see [`DeviceCharacteristics`]. The default
+    /// implementation returns [`DEFAULT_DEVICE_CHARACTERISTICS`].
+    ///
+    /// Vfs implementations serving files which can't change, such as a
+    /// snapshot in an object store, can include
+    /// [`DeviceCharacteristics::immutable`]. `SQLite` then treats the database
+    /// as read-only, failing writes with `SQLITE_READONLY`, and never calls
+    /// `lock`, `unlock`, or `check_reserved_lock`, or looks for a hot journal.
+    /// Together with `readonly` handles, which open the file as read-only
+    /// too, reads need no locking at all.
     fn device_characteristics(&self, handle: &mut Self::Handle) -> VfsResult<i32> {
         Ok(DEFAULT_DEVICE_CHARACTERISTICS)
     }
@@ -207,6 +713,9 @@ pub trait Vfs: Send + Sync {
         Err(vars::SQLITE_IOERR)
     }
 
+    /// A memory barrier for the shared memory region (xShmBarrier). `SQLite`
+    /// calls this in WAL mode to order accesses to the wal-index, it doesn't
+    /// order writes to the file itself, see `barrier`.
     fn shm_barrier(&self, handle: &mut Self::Handle) {}
 
     fn shm_unmap(&self, handle: &mut Self::Handle, delete: bool) -> VfsResult<()> {
@@ -242,15 +751,181 @@ pub trait Vfs: Send + Sync {
     fn unfetch(&self, handle: &mut Self::Handle, offset: i64, ptr: *mut u8) -> VfsResult<()> {
         Ok(())
     }
+
+    /// Queries (`requested` is `None`) or sets the maximum number of bytes of
+    /// the file to memory-map (`SQLITE_FCNTL_MMAP_SIZE`), returning the
+    /// previous limit. `SQLite` sets the limit whenever `PRAGMA mmap_size`
+    /// changes, and a limit of 0 means the file shouldn't be mapped at all.
+    ///
+    /// `SQLite` only uses the result to report the limit from `PRAGMA
+    /// mmap_size`. It keeps calling `fetch` as long as its own limit is
+    /// positive, so `fetch` should decline pages beyond the vfs's limit. The
+    /// default implementation returns `SQLITE_NOTFOUND`.
+    fn mmap_size(&self, handle: &mut Self::Handle, requested: Option<i64>) -> VfsResult<i64> {
+        Err(vars::SQLITE_NOTFOUND)
+    }
+
+    /// Called when the main database file is opened with the busy handler of
+    /// the connection which opened it (`SQLITE_FCNTL_BUSYHANDLER`).
+    ///
+    /// Implementations whose `lock` waits for contended locks, rather than
+    /// returning `SQLITE_BUSY` straight away, can store the handler in the
+    /// handle and invoke it while waiting, as the unix vfs does. See
+    /// [`BusyHandler::invoke`] for when it may be called.
+    fn set_busy_handler(&self, handle: &mut Self::Handle, handler: BusyHandler) {}
+
+    /// Returns true if the file has been renamed, deleted, or replaced since
+    /// it was opened (`SQLITE_FCNTL_HAS_MOVED`). For example, an object store
+    /// vfs might compare the object's current etag against the one it read.
+    fn has_moved(&self, handle: &mut Self::Handle) -> VfsResult<bool> {
+        Ok(false)
+    }
+
+    /// Returns the lock currently held on the file, which `SQLite` reports
+    /// for debugging via `SQLITE_FCNTL_LOCKSTATE`. Implementations which track
+    /// their lock level in the handle should return it here. The default
+    /// implementation returns `Unlocked`.
+    fn lock_state(&self, handle: &mut Self::Handle) -> VfsResult<LockLevel> {
+        Ok(LockLevel::Unlocked)
+    }
+
+    /// Sets how long, in milliseconds, `lock` may block waiting for a lock
+    /// before returning `SQLITE_BUSY` (`SQLITE_FCNTL_LOCK_TIMEOUT`). A
+    /// timeout of 0 means `lock` shouldn't block. For example, a network vfs
+    /// might use this to bound how long it waits to acquire a remote lease.
+    ///
+    /// `SQLite` only sends this when built with `SQLITE_ENABLE_SETLK_TIMEOUT`,
+    /// but applications may send it directly with `sqlite3_file_control`. The
+    /// previous timeout is reported back to the caller.
+    fn lock_timeout(&self, handle: &mut Self::Handle, millis: i32) -> VfsResult<()> {
+        Ok(())
+    }
+
+    /// Queries (`value` is `None`) or sets the persistent WAL flag of a file
+    /// (`SQLITE_FCNTL_PERSIST_WAL`), returning the current value. When set, the
+    /// WAL and shared memory files are left in place when the last connection
+    /// closes.
+    ///
+    /// The default implementation reports the flag as unset and doesn't
+    /// support setting it.
+    fn persist_wal(&self, handle: &mut Self::Handle, value: Option<bool>) -> VfsResult<bool> {
+        match value {
+            None => Ok(false),
+            Some(_) => Err(vars::SQLITE_NOTFOUND),
+        }
+    }
+
+    /// Queries (`value` is `None`) or sets the powersafe overwrite flag of a
+    /// file (`SQLITE_FCNTL_POWERSAFE_OVERWRITE`), returning the current value.
+    ///
+    /// The default implementation reports whether `device_characteristics`
+    /// includes `SQLITE_IOCAP_POWERSAFE_OVERWRITE` and doesn't support
+    /// setting it.
+    fn powersafe_overwrite(
+        &self,
+        handle: &mut Self::Handle,
+        value: Option<bool>,
+    ) -> VfsResult<bool> {
+        match value {
+            None => Ok(
+                DeviceCharacteristics::from(self.device_characteristics(handle)?)
+                    .contains(DeviceCharacteristics::new().powersafe_overwrite()),
+            ),
+            Some(_) => Err(vars::SQLITE_NOTFOUND),
+        }
+    }
+
+    /// Called when `SQLite` sets the chunk size of a file (`SQLITE_FCNTL_CHUNK_SIZE`).
+    ///
+    /// Implementations may use this to round file growth up to a multiple of
+    /// `size` bytes, which can reduce fragmentation on block storage. A size
+    /// of zero disables chunking. The default implementation ignores the hint.
+    fn chunk_size(&self, handle: &mut Self::Handle, size: usize) -> VfsResult<()> {
+        Ok(())
+    }
+
+    /// Begin a batch of writes which must be applied atomically
+    /// (`SQLITE_FCNTL_BEGIN_ATOMIC_WRITE`). All writes until the next call to
+    /// `commit_atomic_write` or `rollback_atomic_write` belong to the batch.
+    ///
+    /// `SQLite` only uses batch atomic writes when `device_characteristics`
+    /// includes `SQLITE_IOCAP_BATCH_ATOMIC`, in which case all three atomic
+    /// write methods must be implemented. The default implementations return
+    /// `SQLITE_NOTFOUND`.
+    fn begin_atomic_write(&self, handle: &mut Self::Handle) -> VfsResult<()> {
+        Err(vars::SQLITE_NOTFOUND)
+    }
+
+    /// Atomically apply all writes in the current batch (`SQLITE_FCNTL_COMMIT_ATOMIC_WRITE`).
+    fn commit_atomic_write(&self, handle: &mut Self::Handle) -> VfsResult<()> {
+        Err(vars::SQLITE_NOTFOUND)
+    }
+
+    /// Discard all writes in the current batch (`SQLITE_FCNTL_ROLLBACK_ATOMIC_WRITE`).
+    fn rollback_atomic_write(&self, handle: &mut Self::Handle) -> VfsResult<()> {
+        Err(vars::SQLITE_NOTFOUND)
+    }
+
+    /// Returns a name for a new temporary file, in response to an application
+    /// invoking `SQLITE_FCNTL_TEMPFILENAME`. `SQLite` itself never asks for a
+    /// temporary filename, it opens temporary files by calling `open` without
+    /// a path, see `OpenOpts::is_temp_file`.
+    ///
+    /// When this returns `SQLITE_NOTFOUND`, which is what the default
+    /// implementation does, a random name is generated using `randomness`.
+    fn temp_filename(&self) -> VfsResult<String> {
+        Err(vars::SQLITE_NOTFOUND)
+    }
+
+    /// Fill `buf` with random bytes, returning the number of bytes written.
+    ///
+    /// This method, along with `sleep`, `current_time`, and
+    /// `current_time_int64`, is delegated to the base vfs when it returns
+    /// `SQLITE_NOTFOUND`, which is what the default implementations do.
+    /// Override them to run `SQLite` against injected time or randomness.
+    ///
+    /// Note that `SQLite` seeds its own PRNG, used for example by
+    /// `randomblob()`, once per process from the default vfs. Register the vfs
+    /// as the default before `SQLite` first needs randomness to seed it too.
+    fn randomness(&self, buf: &mut [u8]) -> VfsResult<usize> {
+        Err(vars::SQLITE_NOTFOUND)
+    }
+
+    /// Sleep for at least `micros` microseconds, returning the number of
+    /// microseconds actually slept.
+    ///
+    /// `SQLite` sleeps between retries of a busy lock when the connection has
+    /// a busy timeout, backing off from 1ms up to 100ms. The timeout counts
+    /// the requested sleeps rather than wall clock time, so a test vfs can
+    /// record the sleeps and return straight away to exercise lock contention
+    /// without slowing down.
+    fn sleep(&self, micros: i32) -> VfsResult<i32> {
+        Err(vars::SQLITE_NOTFOUND)
+    }
+
+    /// Returns the current time as a Julian Day Number.
+    /// If only one of `current_time` and `current_time_int64` is
+    /// implemented, the other is derived from it.
+    fn current_time(&self) -> VfsResult<f64> {
+        Err(vars::SQLITE_NOTFOUND)
+    }
+
+    /// Returns the current time as milliseconds since the Julian epoch,
+    /// i.e. the Julian Day Number multiplied by 86400000.
+    fn current_time_int64(&self) -> VfsResult<i64> {
+        Err(vars::SQLITE_NOTFOUND)
+    }
 }
 
 #[derive(Clone)]
 pub struct SqliteApi {
     register: unsafe extern "C" fn(arg1: *mut ffi::sqlite3_vfs, arg2: c_int) -> c_int,
+    unregister: unsafe extern "C" fn(arg1: *mut ffi::sqlite3_vfs) -> c_int,
     find: unsafe extern "C" fn(arg1: *const c_char) -> *mut ffi::sqlite3_vfs,
     mprintf: unsafe extern "C" fn(arg1: *const c_char, ...) -> *mut c_char,
     log: unsafe extern "C" fn(arg1: c_int, arg2: *const c_char, ...),
     libversion_number: unsafe extern "C" fn() -> c_int,
+    uri: UriRoutines,
 }
 
 impl SqliteApi {
@@ -258,35 +933,82 @@ impl SqliteApi {
     pub fn new_static() -> Self {
         Self {
             register: ffi::sqlite3_vfs_register,
+            unregister: ffi::sqlite3_vfs_unregister,
             find: ffi::sqlite3_vfs_find,
             mprintf: ffi::sqlite3_mprintf,
             log: ffi::sqlite3_log,
             libversion_number: ffi::sqlite3_libversion_number,
+            uri: UriRoutines {
+                parameter: ffi::sqlite3_uri_parameter,
+                boolean: ffi::sqlite3_uri_boolean,
+                int64: ffi::sqlite3_uri_int64,
+            },
         }
     }
 
-    /// Initializes SqliteApi from a filled `sqlite3_api_routines` object.
+    /// Initializes `SqliteApi` from a filled `sqlite3_api_routines` object.
+    /// Fails with `SQLITE_INTERNAL` if a required routine is missing, use
+    /// [`SqliteApi::try_new_dynamic`] to find out which.
     /// # Safety
     /// `api` must be a valid, aligned pointer to a `sqlite3_api_routines` struct
     #[cfg(feature = "dynamic")]
     pub unsafe fn new_dynamic(api: &ffi::sqlite3_api_routines) -> VfsResult<Self> {
+        Ok(unsafe { Self::try_new_dynamic(api) }?)
+    }
+
+    /// Like [`SqliteApi::new_dynamic`], but reports which routine is missing.
+    /// # Safety
+    /// `api` must be a valid, aligned pointer to a `sqlite3_api_routines` struct
+    #[cfg(feature = "dynamic")]
+    pub unsafe fn try_new_dynamic(
+        api: &ffi::sqlite3_api_routines,
+    ) -> Result<Self, crate::error::MissingApiRoutine> {
+        use crate::error::MissingApiRoutine;
+        macro_rules! routine {
+            ($name:ident) => {
+                api.$name
+                    .ok_or(MissingApiRoutine { name: stringify!($name) })?
+            };
+        }
         Ok(Self {
-            register: api.vfs_register.ok_or(vars::SQLITE_INTERNAL)?,
-            find: api.vfs_find.ok_or(vars::SQLITE_INTERNAL)?,
-            mprintf: api.mprintf.ok_or(vars::SQLITE_INTERNAL)?,
-            log: api.log.ok_or(vars::SQLITE_INTERNAL)?,
-            libversion_number: api.libversion_number.ok_or(vars::SQLITE_INTERNAL)?,
+            register: routine!(vfs_register),
+            unregister: routine!(vfs_unregister),
+            find: routine!(vfs_find),
+            mprintf: routine!(mprintf),
+            log: routine!(log),
+            libversion_number: routine!(libversion_number),
+            uri: UriRoutines {
+                parameter: routine!(uri_parameter),
+                boolean: routine!(uri_boolean),
+                int64: routine!(uri_int64),
+            },
         })
     }
 
-    /// Copies the provided string into a memory buffer allocated by `sqlite3_mprintf`.
-    /// Writes the pointer to the memory buffer to `out` if `out` is not null.
+    /// Returns the version number of the `SQLite` library providing these
+    /// routines, such as 3037002 for version 3.37.2.
+    pub fn version_number(&self) -> i32 {
+        unsafe { (self.libversion_number)() }
+    }
+
+    /// Copies the provided string into a memory buffer allocated by `sqlite3_mprintf`,
+    /// and writes the pointer to the memory buffer to `out`. This is how file
+    /// controls such as `SQLITE_FCNTL_VFSNAME` return strings to `SQLite`.
+    ///
+    /// The string is copied as is, `%` is not treated as a format specifier.
+    /// Fails with `SQLITE_INTERNAL` if `out` is null or the string contains
+    /// a NUL byte, and with `SQLITE_NOMEM` if the allocation fails, leaving
+    /// `out` unchanged.
+    ///
     /// # Safety
-    /// 1. the out pointer must not be null
+    /// 1. `out` must be null or valid for writes
     /// 2. it is the callers responsibility to eventually free the allocated buffer
     pub unsafe fn mprintf(&self, s: &str, out: *mut *const c_char) -> VfsResult<()> {
+        if out.is_null() {
+            return Err(vars::SQLITE_INTERNAL);
+        }
         let s = CString::new(s).map_err(|_| vars::SQLITE_INTERNAL)?;
-        let p = unsafe { (self.mprintf)(s.as_ptr()) };
+        let p = unsafe { (self.mprintf)(c"%s".as_ptr(), s.as_ptr()) };
         if p.is_null() {
             Err(vars::SQLITE_NOMEM)
         } else {
@@ -298,22 +1020,86 @@ impl SqliteApi {
     }
 }
 
-pub struct RegisterOpts {
+#[derive(Debug, Default, Clone)]
+pub struct RegisterOpts<'a> {
     /// If true, make this vfs the default vfs for `SQLite`.
     pub make_default: bool,
+
+    /// The maximum length of a pathname returned by `Vfs::canonical_path`,
+    /// used as `sqlite3_vfs.mxPathname`. Defaults to 512 when `None`.
+    /// Must be positive.
+    pub max_path_len: Option<i32>,
+
+    /// The name of the vfs to use as the base vfs, which provides
+    /// randomness, sleep, the current time, and dynamic library loading.
+    /// Defaults to `SQLite`'s default vfs when `None`.
+    pub base_vfs: Option<&'a str>,
+
+    /// The `sqlite3_io_methods.iVersion` to advertise, between 1 and 3.
+    /// Version 1 omits the shared memory methods, which disables WAL mode
+    /// unless `locking_mode=exclusive`, and version 2 omits `xFetch` and
+    /// `xUnfetch`. Defaults to the lowest version providing the features
+    /// declared by `Vfs::capabilities` when `None`.
+    pub io_methods_version: Option<i32>,
+
+    /// A clock returning the current time in milliseconds since the Unix
+    /// epoch, used when the `Vfs` doesn't implement `current_time` or
+    /// `current_time_int64`. Useful on targets where the base vfs has no
+    /// clock, such as WASM or bare-metal. Defaults to the base vfs's clock
+    /// when `None`.
+    pub time_source: Option<fn() -> i64>,
+
+    /// If true, opening a rollback journal fails with `SQLITE_CANTOPEN` and
+    /// logs an error, for vfs implementations which only support WAL mode.
+    ///
+    /// Writes fail until the database is switched to WAL. Switching writes
+    /// the database header, which itself needs a journal, so first switch to
+    /// a journal mode which doesn't open a journal file:
+    /// `PRAGMA journal_mode=MEMORY; PRAGMA journal_mode=WAL`. Databases stay
+    /// in WAL mode once switched, and switching back to a rollback journal
+    /// mode such as `DELETE` fails with `SQLITE_CANTOPEN`.
+    pub wal_only: bool,
+
+    /// A token which aborts the vfs's reads, writes, and syncs once
+    /// cancelled, see [`CancellationToken`].
+    pub cancellation: Option<CancellationToken>,
 }
 
+/// Register a vfs with `SQLite` using the statically linked API.
+///
+/// Returns a [`VfsRegistration`] holding the registered `sqlite3_vfs`.
+/// The registration takes ownership of `vfs`, which is dropped by
+/// [`unregister`]. To register the same vfs under several names, for example
+/// a read-only variant, register a clone under each name and keep any shared
+/// state behind an `Arc`. Each registration is independent and can be
+/// unregistered separately, the shared state is dropped along with the last
+/// clone.
+///
+/// Fails with `SQLITE_MISUSE` if `name` is empty or longer than
+/// [`MAX_VFS_NAME_LEN`] bytes.
 #[cfg(feature = "static")]
 pub fn register_static<T: Vfs>(
     name: CString,
     vfs: T,
-    opts: RegisterOpts,
-) -> VfsResult<SqliteLogger> {
-    register_inner(SqliteApi::new_static(), name, vfs, opts)
+    opts: RegisterOpts<'_>,
+) -> VfsResult<VfsRegistration> {
+    register_inner(SqliteApi::new_static(), name, vfs, opts, null_mut())
+}
+
+/// Like [`register_static`], but discards the returned [`VfsRegistration`].
+/// The vfs can still be unregistered by name with [`unregister`].
+#[cfg(feature = "static")]
+pub fn register_static_simple<T: Vfs>(
+    name: CString,
+    vfs: T,
+    opts: RegisterOpts<'_>,
+) -> VfsResult<()> {
+    register_static(name, vfs, opts).map(drop)
 }
 
 /// Register a vfs with `SQLite` using the dynamic API. This API is available when
 /// `SQLite` is initializing extensions.
+/// See [`register_static`] for registering a vfs under several names.
 /// # Safety
 /// `p_api` must be a valid, aligned pointer to a `sqlite3_api_routines` struct
 #[cfg(feature = "dynamic")]
@@ -321,29 +1107,82 @@ pub unsafe fn register_dynamic<T: Vfs>(
     p_api: *mut ffi::sqlite3_api_routines,
     name: CString,
     vfs: T,
-    opts: RegisterOpts,
-) -> VfsResult<SqliteLogger> {
+    opts: RegisterOpts<'_>,
+) -> VfsResult<VfsRegistration> {
+    let api = unsafe { p_api.as_ref() }.ok_or(vars::SQLITE_INTERNAL)?;
+    let sqlite_api = unsafe { SqliteApi::new_dynamic(api)? };
+    register_inner(sqlite_api, name, vfs, opts, null_mut())
+}
+
+/// Like [`register_dynamic`], but also passes the database handle given to the
+/// extension entry point to [`Vfs::on_register`].
+/// # Safety
+/// `p_api` must be a valid, aligned pointer to a `sqlite3_api_routines` struct.
+/// `db` must be the `sqlite3*` passed to the extension entry point.
+#[cfg(feature = "dynamic")]
+pub unsafe fn register_dynamic_with_db<T: Vfs>(
+    db: *mut c_void,
+    p_api: *mut ffi::sqlite3_api_routines,
+    name: CString,
+    vfs: T,
+    opts: RegisterOpts<'_>,
+) -> VfsResult<VfsRegistration> {
     let api = unsafe { p_api.as_ref() }.ok_or(vars::SQLITE_INTERNAL)?;
     let sqlite_api = unsafe { SqliteApi::new_dynamic(api)? };
-    register_inner(sqlite_api, name, vfs, opts)
+    register_inner(sqlite_api, name, vfs, opts, db)
 }
 
 fn register_inner<T: Vfs>(
     sqlite_api: SqliteApi,
     name: CString,
     vfs: T,
-    opts: RegisterOpts,
-) -> VfsResult<SqliteLogger> {
-    let version = unsafe { (sqlite_api.libversion_number)() };
+    opts: RegisterOpts<'_>,
+    db: *mut c_void,
+) -> VfsResult<VfsRegistration> {
+    let logger = SqliteLogger::new(sqlite_api.log);
+
+    // panicking here would abort the host process when loaded as an extension
+    let version = sqlite_api.version_number();
     if version < MIN_SQLITE_VERSION_NUMBER {
-        panic!(
-            "sqlite3 must be at least version {}, found version {}",
-            MIN_SQLITE_VERSION_NUMBER, version
+        logger.log(
+            SqliteLogLevel::Error,
+            &format!(
+                "sqlite3 must be at least version {MIN_SQLITE_VERSION_NUMBER}, found version {version}"
+            ),
+        );
+        return Err(vars::SQLITE_ERROR);
+    }
+
+    let name_len = name.as_bytes().len();
+    if name_len == 0 || name_len > MAX_VFS_NAME_LEN {
+        logger.log(
+            SqliteLogLevel::Error,
+            &format!("vfs name must be between 1 and {MAX_VFS_NAME_LEN} bytes, got {name_len}"),
         );
+        return Err(vars::SQLITE_MISUSE);
+    }
+
+    let max_path_len = opts.max_path_len.unwrap_or(DEFAULT_MAX_PATH_LEN);
+    if max_path_len <= 0 {
+        return Err(vars::SQLITE_MISUSE);
+    }
+
+    let capabilities = vfs.capabilities();
+    let io_methods_version = opts.io_methods_version.unwrap_or(
+        if capabilities.contains(VfsCapabilities::new().fetch()) {
+            3
+        } else if capabilities.contains(VfsCapabilities::new().shm()) {
+            2
+        } else {
+            1
+        },
+    );
+    if !(1..=3).contains(&io_methods_version) {
+        return Err(vars::SQLITE_MISUSE);
     }
 
     let io_methods = ffi::sqlite3_io_methods {
-        iVersion: 3,
+        iVersion: io_methods_version,
         xClose: Some(x_close::<T>),
         xRead: Some(x_read::<T>),
         xWrite: Some(x_write::<T>),
@@ -356,29 +1195,54 @@ fn register_inner<T: Vfs>(
         xFileControl: Some(x_file_control::<T>),
         xSectorSize: Some(x_sector_size::<T>),
         xDeviceCharacteristics: Some(x_device_characteristics::<T>),
-        xShmMap: Some(x_shm_map::<T>),
-        xShmLock: Some(x_shm_lock::<T>),
-        xShmBarrier: Some(x_shm_barrier::<T>),
-        xShmUnmap: Some(x_shm_unmap::<T>),
-        xFetch: Some(x_fetch::<T>),
-        xUnfetch: Some(x_unfetch::<T>),
+        xShmMap: (io_methods_version >= 2).then_some(x_shm_map::<T>),
+        xShmLock: (io_methods_version >= 2).then_some(x_shm_lock::<T>),
+        xShmBarrier: (io_methods_version >= 2).then_some(x_shm_barrier::<T>),
+        xShmUnmap: (io_methods_version >= 2).then_some(x_shm_unmap::<T>),
+        xFetch: (io_methods_version >= 3).then_some(x_fetch::<T>),
+        xUnfetch: (io_methods_version >= 3).then_some(x_unfetch::<T>),
     };
 
-    let logger = SqliteLogger::new(sqlite_api.log);
+    let base_vfs = match opts.base_vfs {
+        Some(base_name) => {
+            let base_name = CString::new(base_name).map_err(|_| {
+                logger.log(SqliteLogLevel::Error, "base vfs name contains a NUL byte");
+                vars::SQLITE_MISUSE
+            })?;
+            let base_vfs = unsafe { (sqlite_api.find)(base_name.as_ptr()) };
+            if base_vfs.is_null() {
+                return Err(vars::SQLITE_NOTFOUND);
+            }
+            base_vfs
+        }
+        None => unsafe { (sqlite_api.find)(null_mut()) },
+    };
 
+    let name_str = name.to_string_lossy().into_owned();
     let p_name = ManuallyDrop::new(name).as_ptr();
-    let base_vfs = unsafe { (sqlite_api.find)(null_mut()) };
     let vfs_register = sqlite_api.register;
-    let p_appdata = Box::into_raw(Box::new(AppData { base_vfs, vfs, io_methods, sqlite_api }));
+    let p_appdata = Box::into_raw(Box::new(AppData {
+        base_vfs,
+        vfs,
+        io_methods,
+        sqlite_api,
+        name: name_str,
+        time_source: opts.time_source,
+        wal_only: opts.wal_only,
+        capabilities,
+        open_files: AtomicUsize::new(0),
+        cancellation: opts.cancellation,
+    }));
 
     let filewrapper_size: c_int = size_of::<FileWrapper<T::Handle>>()
         .try_into()
         .map_err(|_| vars::SQLITE_INTERNAL)?;
 
     let p_vfs = Box::into_raw(Box::new(ffi::sqlite3_vfs {
-        iVersion: 3,
+        // version 3 only adds the system call methods, which we don't support
+        iVersion: 2,
         szOsFile: filewrapper_size,
-        mxPathname: DEFAULT_MAX_PATH_LEN,
+        mxPathname: max_path_len,
         pNext: null_mut(),
         zName: p_name,
         pAppData: p_appdata.cast(),
@@ -410,40 +1274,238 @@ fn register_inner<T: Vfs>(
         };
         Err(result)
     } else {
-        Ok(logger)
+        REGISTRY.lock().push(Registration {
+            vfs: p_vfs,
+            unregister: unregister_inner::<T>,
+        });
+        let appdata = unsafe { &*p_appdata };
+        appdata.vfs.on_register(&appdata.name, db);
+        let name = unsafe { CStr::from_ptr((*p_vfs).zName) }.into();
+        Ok(VfsRegistration { vfs: p_vfs, name, logger })
     }
 }
 
-unsafe extern "C" fn x_open<T: Vfs>(
-    p_vfs: *mut ffi::sqlite3_vfs,
-    z_name: ffi::sqlite3_filename,
-    p_file: *mut ffi::sqlite3_file,
-    flags: c_int,
-    p_out_flags: *mut c_int,
-) -> c_int {
-    // From https://sqlite.org/c3ref/vfs.html:
-    // "Note that the xOpen method must set the sqlite3_file.pMethods to either a valid
-    // sqlite3_io_methods object or to NULL. xOpen must do this even if the open fails."
-    if let Some(f) = unsafe { p_file.as_mut() } {
-        f.pMethods = core::ptr::null();
+/// A vfs registered by [`register_static`] or [`register_dynamic`].
+///
+/// Dropping the registration leaves the vfs registered, call
+/// [`VfsRegistration::unregister`] or [`unregister`] to remove it.
+pub struct VfsRegistration {
+    vfs: *mut ffi::sqlite3_vfs,
+    /// A copy of the name, since the vfs may be unregistered by name while
+    /// the registration still exists
+    name: CString,
+    logger: SqliteLogger,
+}
+
+// SAFETY: the pointer is only used to identify the registration and is never
+// dereferenced by it
+unsafe impl Send for VfsRegistration {}
+unsafe impl Sync for VfsRegistration {}
+
+impl core::fmt::Debug for VfsRegistration {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        f.debug_struct("VfsRegistration")
+            .field("vfs", &self.vfs)
+            .field("name", &self.name)
+            .finish()
     }
+}
 
-    fallible(|| {
-        let opts = flags.into();
-        let name = unsafe { lossy_cstr(z_name) }.ok();
-        let vfs = unwrap_vfs!(p_vfs, T)?;
-        let handle = vfs.open(name.as_ref().map(|s| s.as_ref()), opts)?;
+impl VfsRegistration {
+    /// Returns the registered `sqlite3_vfs`, which remains valid until the
+    /// vfs is unregistered. For example, it can be passed to
+    /// `sqlite3_vfs_register` to make the vfs the default after the fact.
+    pub fn as_ptr(&self) -> *mut ffi::sqlite3_vfs {
+        self.vfs
+    }
 
-        let appdata = unwrap_appdata!(p_vfs, T)?;
+    /// Returns the name the vfs was registered under.
+    pub fn name(&self) -> &CStr {
+        &self.name
+    }
 
-        if let Some(p_out_flags) = unsafe { p_out_flags.as_mut() } {
-            let mut out_flags = flags;
-            if handle.readonly() {
-                out_flags |= vars::SQLITE_OPEN_READONLY;
-            }
-            if handle.in_memory() {
-                out_flags |= vars::SQLITE_OPEN_MEMORY;
+    /// Returns a logger which writes to the `SQLite` log.
+    pub fn logger(&self) -> SqliteLogger {
+        self.logger
+    }
+
+    /// Unregisters this vfs, see [`unregister`].
+    ///
+    /// # Safety
+    /// See [`unregister`].
+    pub unsafe fn unregister(self) -> VfsResult<()> {
+        unsafe { unregister_where(|vfs| vfs == self.vfs) }
+    }
+}
+
+/// Unregister a vfs previously registered by this crate, and free all of the
+/// memory allocated for it during registration, including the `Vfs` itself.
+///
+/// Returns `SQLITE_NOTFOUND` if no vfs with this name was registered by this
+/// crate, and `SQLITE_BUSY` if any files are still open via the vfs. Otherwise
+/// [`Vfs::shutdown`] is called, and any error it returns leaves the vfs
+/// registered.
+///
+/// # Safety
+/// `SQLite` does not track which connections use a vfs. The caller must
+/// ensure that every connection opened with this vfs has been closed,
+/// including in-memory connections which may not have any open files.
+pub unsafe fn unregister(name: &str) -> VfsResult<()> {
+    unsafe { unregister_where(|vfs| CStr::from_ptr((*vfs).zName).to_bytes() == name.as_bytes()) }
+}
+
+/// Unregisters the most recent registration matching `pred`.
+/// # Safety
+/// See [`unregister`].
+unsafe fn unregister_where(pred: impl Fn(*mut ffi::sqlite3_vfs) -> bool) -> VfsResult<()> {
+    let mut registry = REGISTRY.lock();
+    let idx = registry
+        .iter()
+        .rposition(|r| pred(r.vfs))
+        .ok_or(vars::SQLITE_NOTFOUND)?;
+    let registration = &registry[idx];
+    unsafe { (registration.unregister)(registration.vfs)? };
+    registry.swap_remove(idx);
+    Ok(())
+}
+
+/// Unregisters `p_vfs` from `SQLite` and frees it.
+/// # Safety
+/// `p_vfs` must have been allocated by `register_inner::<T>`
+unsafe fn unregister_inner<T: Vfs>(p_vfs: *mut ffi::sqlite3_vfs) -> VfsResult<()> {
+    let appdata = unwrap_appdata!(p_vfs, T)?;
+    if appdata.open_files.load(Ordering::Acquire) > 0 {
+        return Err(vars::SQLITE_BUSY);
+    }
+    // if unregistering fails the vfs is put back, restoring it as the
+    // default if it was one
+    let was_default = unsafe { (appdata.sqlite_api.find)(core::ptr::null()) } == p_vfs;
+    let reregister = || unsafe { (appdata.sqlite_api.register)(p_vfs, was_default.into()) };
+    let result = unsafe { (appdata.sqlite_api.unregister)(p_vfs) };
+    if result != vars::SQLITE_OK {
+        return Err(result);
+    }
+    if appdata.open_files.load(Ordering::Acquire) > 0 {
+        // a file was opened while we were unregistering
+        reregister();
+        return Err(vars::SQLITE_BUSY);
+    }
+    if let Err(err) = appdata.vfs.shutdown() {
+        reregister();
+        return Err(err);
+    }
+    unsafe {
+        let p_appdata = (*p_vfs).pAppData.cast::<AppData<T>>();
+        let p_name = (*p_vfs).zName;
+        drop(Box::from_raw(p_vfs));
+        drop(Box::from_raw(p_appdata));
+        drop(CString::from_raw(p_name as *mut c_char));
+    }
+    Ok(())
+}
+
+/// A vfs registered by this crate, which can be passed to [`unregister`].
+struct Registration {
+    vfs: *mut ffi::sqlite3_vfs,
+    unregister: unsafe fn(*mut ffi::sqlite3_vfs) -> VfsResult<()>,
+}
+
+static REGISTRY: Registry = Registry {
+    locked: AtomicBool::new(false),
+    registrations: UnsafeCell::new(Vec::new()),
+};
+
+/// A minimal spin lock protecting the list of registered vfs's. We can't
+/// depend on std's `Mutex`, and the lock is only held briefly during
+/// registration and unregistration.
+struct Registry {
+    locked: AtomicBool,
+    registrations: UnsafeCell<Vec<Registration>>,
+}
+
+// Safety: access to registrations is synchronized by the locked flag, and the
+// raw pointers in Registration are only dereferenced while holding the lock.
+unsafe impl Sync for Registry {}
+
+impl Registry {
+    fn lock(&self) -> RegistryGuard<'_> {
+        while self
+            .locked
+            .compare_exchange_weak(false, true, Ordering::Acquire, Ordering::Relaxed)
+            .is_err()
+        {
+            core::hint::spin_loop();
+        }
+        RegistryGuard { registry: self }
+    }
+}
+
+struct RegistryGuard<'a> {
+    registry: &'a Registry,
+}
+
+impl core::ops::Deref for RegistryGuard<'_> {
+    type Target = Vec<Registration>;
+    fn deref(&self) -> &Self::Target {
+        unsafe { &*self.registry.registrations.get() }
+    }
+}
+
+impl core::ops::DerefMut for RegistryGuard<'_> {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        unsafe { &mut *self.registry.registrations.get() }
+    }
+}
+
+impl Drop for RegistryGuard<'_> {
+    fn drop(&mut self) {
+        self.registry.locked.store(false, Ordering::Release);
+    }
+}
+
+unsafe extern "C" fn x_open<T: Vfs>(
+    p_vfs: *mut ffi::sqlite3_vfs,
+    z_name: ffi::sqlite3_filename,
+    p_file: *mut ffi::sqlite3_file,
+    flags: c_int,
+    p_out_flags: *mut c_int,
+) -> c_int {
+    // From https://sqlite.org/c3ref/vfs.html:
+    // "Note that the xOpen method must set the sqlite3_file.pMethods to either a valid
+    // sqlite3_io_methods object or to NULL. xOpen must do this even if the open fails."
+    if let Some(f) = unsafe { p_file.as_mut() } {
+        f.pMethods = core::ptr::null();
+    }
+
+    fallible(|| {
+        let appdata = unwrap_appdata!(p_vfs, T)?;
+        let opts = OpenOpts::new(flags).with_uri(z_name, appdata.sqlite_api.uri);
+        let name = unsafe { lossy_cstr(z_name) }.ok();
+        if appdata.wal_only && opts.kind() == OpenKind::MainJournal {
+            SqliteLogger::new(appdata.sqlite_api.log).log(
+                SqliteLogLevel::Error,
+                &format!(
+                    "vfs {} only supports WAL mode, use PRAGMA journal_mode=WAL",
+                    appdata.name
+                ),
+            );
+            return Err(vars::SQLITE_CANTOPEN);
+        }
+        let vfs = unwrap_vfs!(p_vfs, T)?;
+        // count the file as open before calling into the vfs so that a
+        // concurrent unregister can't free the vfs out from under us
+        appdata.open_files.fetch_add(1, Ordering::AcqRel);
+        let mut handle = match vfs.open(name.as_ref().map(|s| s.as_ref()), opts) {
+            Ok(handle) => handle,
+            Err(err) => {
+                appdata.open_files.fetch_sub(1, Ordering::AcqRel);
+                return Err(err);
             }
+        };
+
+        if let Some(p_out_flags) = unsafe { p_out_flags.as_mut() } {
+            let mut out_flags = open_out_flags(flags, &handle);
+            vfs.post_open(&mut handle, &mut out_flags);
             *p_out_flags = out_flags;
         }
 
@@ -457,6 +1519,7 @@ unsafe extern "C" fn x_open<T: Vfs>(
                     file: ffi::sqlite3_file { pMethods: &appdata.io_methods },
                     vfs: p_vfs,
                     handle,
+                    lock_timeout: 0,
                 },
             );
         }
@@ -465,15 +1528,35 @@ unsafe extern "C" fn x_open<T: Vfs>(
     })
 }
 
+/// Computes the flags reported back to `SQLite` by xOpen. The access mode
+/// reflects the opened handle rather than the request, so a handle opened
+/// read-only reports only `SQLITE_OPEN_READONLY`. `SQLITE_OPEN_CREATE` only
+/// applies to the request and is never reported.
+fn open_out_flags(flags: c_int, handle: &impl VfsHandle) -> c_int {
+    let mut out_flags = flags
+        & !(vars::SQLITE_OPEN_READONLY | vars::SQLITE_OPEN_READWRITE | vars::SQLITE_OPEN_CREATE);
+    out_flags |= if handle.readonly() {
+        vars::SQLITE_OPEN_READONLY
+    } else {
+        vars::SQLITE_OPEN_READWRITE
+    };
+    if handle.in_memory() {
+        out_flags |= vars::SQLITE_OPEN_MEMORY;
+    } else {
+        out_flags &= !vars::SQLITE_OPEN_MEMORY;
+    }
+    out_flags
+}
+
 unsafe extern "C" fn x_delete<T: Vfs>(
     p_vfs: *mut ffi::sqlite3_vfs,
     z_name: ffi::sqlite3_filename,
-    _sync_dir: c_int,
+    sync_dir: c_int,
 ) -> c_int {
     fallible(|| {
         let name = unsafe { lossy_cstr(z_name)? };
         let vfs = unwrap_vfs!(p_vfs, T)?;
-        vfs.delete(&name)?;
+        vfs.delete(&name, sync_dir != 0)?;
         Ok(vars::SQLITE_OK)
     })
 }
@@ -504,9 +1587,16 @@ unsafe extern "C" fn x_full_pathname<T: Vfs>(
         let name = unsafe { lossy_cstr(z_name)? };
         let vfs = unwrap_vfs!(p_vfs, T)?;
         let full_name = vfs.canonical_path(name)?;
-        let n_out = n_out.try_into().map_err(|_| vars::SQLITE_INTERNAL)?;
+        let n_out: usize = n_out.try_into().map_err(|_| vars::SQLITE_INTERNAL)?;
+        if z_out.is_null() {
+            return Err(vars::SQLITE_INTERNAL);
+        }
+        // the name plus its trailing null byte must fit in the output buffer
+        let from = full_name.as_bytes();
+        if from.len() >= n_out {
+            return Err(vars::SQLITE_CANTOPEN_FULLPATH);
+        }
         let out = unsafe { slice::from_raw_parts_mut(z_out as *mut u8, n_out) };
-        let from = &full_name.as_bytes()[..full_name.len().min(n_out - 1)];
         // copy the name into the output buffer
         out[..from.len()].copy_from_slice(from);
         // add the trailing null byte
@@ -535,8 +1625,10 @@ unsafe extern "C" fn x_close<T: Vfs>(p_file: *mut ffi::sqlite3_file) -> c_int {
             (file.vfs, file.handle)
         };
 
-        let vfs = unwrap_vfs!(vfs, T)?;
-        vfs.close(handle)?;
+        let appdata = unwrap_appdata!(vfs, T)?;
+        let result = appdata.vfs.close(handle);
+        appdata.open_files.fetch_sub(1, Ordering::AcqRel);
+        result?;
         Ok(vars::SQLITE_OK)
     })
 }
@@ -547,22 +1639,22 @@ unsafe extern "C" fn x_read<T: Vfs>(
     i_amt: c_int,
     i_ofst: ffi::sqlite_int64,
 ) -> c_int {
-    fallible(|| {
+    let span = trace_span!("x_read", offset = i_ofst, len = i_amt);
+    span.exit(fallible(|| {
         let file = unwrap_file!(p_file, T)?;
-        let vfs = unwrap_vfs!(file.vfs, T)?;
+        let appdata = unwrap_appdata!(file.vfs, T)?;
+        appdata.check_cancelled(vars::SQLITE_IOERR_READ)?;
+        let vfs = &appdata.vfs;
         let buf_len: usize = i_amt.try_into().map_err(|_| vars::SQLITE_IOERR_READ)?;
         let offset: usize = i_ofst.try_into().map_err(|_| vars::SQLITE_IOERR_READ)?;
-        let buf = unsafe { slice::from_raw_parts_mut(buf.cast::<u8>(), buf_len) };
-        let bytes_read = vfs.read(&mut file.handle, offset, buf)?;
-        if bytes_read < buf_len {
-            // From https://sqlite.org/c3ref/io_methods.html:
-            // "If xRead() returns SQLITE_IOERR_SHORT_READ it must also fill in the unread portions
-            // of the buffer with zeros."
-            buf[bytes_read..].fill(0);
-            return Err(vars::SQLITE_IOERR_SHORT_READ);
-        }
+        offset.checked_add(buf_len).ok_or(vars::SQLITE_IOERR_READ)?;
+        // the buffer SQLite reads into may not be initialized
+        let buf = unsafe { slice::from_raw_parts_mut(buf.cast::<MaybeUninit<u8>>(), buf_len) };
+        vfs.read_exact(&mut file.handle, offset, buf)?;
+        // the read may have been cut short by the cancellation
+        appdata.check_cancelled(vars::SQLITE_IOERR_READ)?;
         Ok(vars::SQLITE_OK)
-    })
+    }))
 }
 
 unsafe extern "C" fn x_write<T: Vfs>(
@@ -571,40 +1663,62 @@ unsafe extern "C" fn x_write<T: Vfs>(
     i_amt: c_int,
     i_ofst: ffi::sqlite_int64,
 ) -> c_int {
-    fallible(|| {
+    let span = trace_span!("x_write", offset = i_ofst, len = i_amt);
+    span.exit(fallible(|| {
         let file = unwrap_file!(p_file, T)?;
-        let vfs = unwrap_vfs!(file.vfs, T)?;
+        let appdata = unwrap_appdata!(file.vfs, T)?;
+        let vfs = &appdata.vfs;
         let buf_len: usize = i_amt.try_into().map_err(|_| vars::SQLITE_IOERR_WRITE)?;
         let offset: usize = i_ofst.try_into().map_err(|_| vars::SQLITE_IOERR_WRITE)?;
-        let buf = unsafe { slice::from_raw_parts(buf.cast::<u8>(), buf_len) };
-        let n = vfs.write(&mut file.handle, offset, buf)?;
-        if n != buf_len {
-            return Err(vars::SQLITE_IOERR_WRITE);
+        offset
+            .checked_add(buf_len)
+            .ok_or(vars::SQLITE_IOERR_WRITE)?;
+        let mut buf = unsafe { slice::from_raw_parts(buf.cast::<u8>(), buf_len) };
+        let mut offset = offset;
+        // retry short writes until the entire buffer has been written. like
+        // the unix vfs, a write which makes no progress means the disk is full
+        while !buf.is_empty() {
+            appdata.check_cancelled(vars::SQLITE_IOERR_WRITE)?;
+            match vfs.write(&mut file.handle, offset, buf)? {
+                0 => return Err(vars::SQLITE_FULL),
+                n if n > buf.len() => return Err(vars::SQLITE_IOERR_WRITE),
+                n => {
+                    buf = &buf[n..];
+                    offset += n;
+                }
+            }
         }
+        appdata.check_cancelled(vars::SQLITE_IOERR_WRITE)?;
         Ok(vars::SQLITE_OK)
-    })
+    }))
 }
 
 unsafe extern "C" fn x_truncate<T: Vfs>(
     p_file: *mut ffi::sqlite3_file,
     size: ffi::sqlite_int64,
 ) -> c_int {
-    fallible(|| {
+    let span = trace_span!("x_truncate", size);
+    span.exit(fallible(|| {
         let file = unwrap_file!(p_file, T)?;
         let vfs = unwrap_vfs!(file.vfs, T)?;
         let size: usize = size.try_into().map_err(|_| vars::SQLITE_IOERR_TRUNCATE)?;
+        if size < vfs.min_file_size(&mut file.handle) {
+            return Err(vars::SQLITE_IOERR_TRUNCATE);
+        }
         vfs.truncate(&mut file.handle, size)?;
         Ok(vars::SQLITE_OK)
-    })
+    }))
 }
 
-unsafe extern "C" fn x_sync<T: Vfs>(p_file: *mut ffi::sqlite3_file, _flags: c_int) -> c_int {
-    fallible(|| {
+unsafe extern "C" fn x_sync<T: Vfs>(p_file: *mut ffi::sqlite3_file, flags: c_int) -> c_int {
+    let span = trace_span!("x_sync", flags);
+    span.exit(fallible(|| {
         let file = unwrap_file!(p_file, T)?;
-        let vfs = unwrap_vfs!(file.vfs, T)?;
-        vfs.sync(&mut file.handle)?;
+        let appdata = unwrap_appdata!(file.vfs, T)?;
+        appdata.check_cancelled(vars::SQLITE_IOERR_FSYNC)?;
+        appdata.vfs.sync(&mut file.handle, flags.into())?;
         Ok(vars::SQLITE_OK)
-    })
+    }))
 }
 
 unsafe extern "C" fn x_file_size<T: Vfs>(
@@ -622,23 +1736,25 @@ unsafe extern "C" fn x_file_size<T: Vfs>(
 }
 
 unsafe extern "C" fn x_lock<T: Vfs>(p_file: *mut ffi::sqlite3_file, raw_lock: c_int) -> c_int {
-    fallible(|| {
+    let span = trace_span!("x_lock", level = raw_lock);
+    span.exit(fallible(|| {
         let level: LockLevel = raw_lock.into();
         let file = unwrap_file!(p_file, T)?;
         let vfs = unwrap_vfs!(file.vfs, T)?;
         vfs.lock(&mut file.handle, level)?;
         Ok(vars::SQLITE_OK)
-    })
+    }))
 }
 
 unsafe extern "C" fn x_unlock<T: Vfs>(p_file: *mut ffi::sqlite3_file, raw_lock: c_int) -> c_int {
-    fallible(|| {
+    let span = trace_span!("x_unlock", level = raw_lock);
+    span.exit(fallible(|| {
         let level: LockLevel = raw_lock.into();
         let file = unwrap_file!(p_file, T)?;
         let vfs = unwrap_vfs!(file.vfs, T)?;
         vfs.unlock(&mut file.handle, level)?;
         Ok(vars::SQLITE_OK)
-    })
+    }))
 }
 
 unsafe extern "C" fn x_check_reserved_lock<T: Vfs>(
@@ -655,6 +1771,32 @@ unsafe extern "C" fn x_check_reserved_lock<T: Vfs>(
     })
 }
 
+/// Returns the key of a pragma named `<vfs name>.<key>`, see `Vfs::set_config`.
+fn config_key<'a>(vfs_name: &str, pragma_name: &'a str) -> Option<&'a str> {
+    let (prefix, key) = pragma_name.split_once('.')?;
+    (prefix.eq_ignore_ascii_case(vfs_name) && !key.is_empty()).then_some(key)
+}
+
+fn set_config<T: Vfs>(
+    vfs: &T,
+    handle: &mut T::Handle,
+    key: &str,
+    pragma: Pragma<'_>,
+) -> Result<PragmaResult, PragmaErr> {
+    let value = pragma.arg.ok_or_else(|| PragmaErr::required_arg(&pragma))?;
+    match vfs.set_config(handle, key, value) {
+        Ok(()) => Ok(PragmaResult::none()),
+        Err(vars::SQLITE_NOTFOUND) => Err(PragmaErr::Fail(
+            SQLITE_ERROR,
+            Some(format!("unknown vfs config: {key}")),
+        )),
+        Err(err) => Err(PragmaErr::Fail(
+            err,
+            Some(format!("invalid value for vfs config {key}: {value}")),
+        )),
+    }
+}
+
 unsafe extern "C" fn x_file_control<T: Vfs>(
     p_file: *mut ffi::sqlite3_file,
     op: c_int,
@@ -663,18 +1805,10 @@ unsafe extern "C" fn x_file_control<T: Vfs>(
     /*
     Other interesting ops:
     SIZE_HINT: hint of how large the database will grow during the current transaction
-    COMMIT_PHASETWO: after transaction commits before file unlocks (only used in WAL mode)
-    VFS_NAME: should return this vfs's name + / + base vfs's name
-
-    Atomic write support: (requires SQLITE_IOCAP_BATCH_ATOMIC device characteristic)
-    Docs: https://www3.sqlite.org/cgi/src/technote/714f6cbbf78c8a1351cbd48af2b438f7f824b336
-    BEGIN_ATOMIC_WRITE: start an atomic write operation
-    COMMIT_ATOMIC_WRITE: commit an atomic write operation
-    ROLLBACK_ATOMIC_WRITE: rollback an atomic write operation
     */
 
-    if op == vars::SQLITE_FCNTL_PRAGMA {
-        return fallible(|| {
+    match op {
+        vars::SQLITE_FCNTL_PRAGMA => fallible(|| {
             let file = unwrap_file!(p_file, T)?;
             let vfs = unwrap_vfs!(file.vfs, T)?;
 
@@ -689,9 +1823,14 @@ unsafe extern "C" fn x_file_control<T: Vfs>(
                     .map(|p| CStr::from_ptr(p).to_string_lossy())
             };
             let pragma = Pragma { name: &name, arg: arg.as_deref() };
+            let appdata = unwrap_appdata!(file.vfs, T)?;
 
-            let (result, msg) = match vfs.pragma(&mut file.handle, pragma) {
-                Ok(msg) => (Ok(vars::SQLITE_OK), msg),
+            let result = match config_key(&appdata.name, &name) {
+                Some(key) => set_config(vfs, &mut file.handle, key, pragma),
+                None => vfs.pragma(&mut file.handle, pragma),
+            };
+            let (result, msg) = match result {
+                Ok(msg) => (Ok(vars::SQLITE_OK), msg.into_text()),
                 Err(PragmaErr::NotFound) => (Err(vars::SQLITE_NOTFOUND), None),
                 Err(PragmaErr::Fail(err, msg)) => (Err(err), msg),
             };
@@ -699,31 +1838,248 @@ unsafe extern "C" fn x_file_control<T: Vfs>(
             if let Some(msg) = msg {
                 // write the msg back to the first element of the args array.
                 // SQLite is responsible for eventually freeing the result
-                let appdata = unwrap_appdata!(file.vfs, T)?;
                 unsafe { appdata.sqlite_api.mprintf(&msg, args)? };
             }
 
             result
-        });
+        }),
+
+        vars::SQLITE_FCNTL_VFSNAME => fallible(|| {
+            let file = unwrap_file!(p_file, T)?;
+            let appdata = unwrap_appdata!(file.vfs, T)?;
+
+            // the result is this vfs's name followed by the base vfs's name,
+            // separated by a slash
+            let name = match unsafe { appdata.base_vfs.as_ref() } {
+                Some(base) => format!("{}/{}", appdata.name, unsafe { lossy_cstr(base.zName) }?),
+                None => appdata.name.clone(),
+            };
+
+            // p_arg is a pointer to a string which SQLite is responsible for freeing
+            unsafe { appdata.sqlite_api.mprintf(&name, p_arg.cast())? };
+            Ok(vars::SQLITE_OK)
+        }),
+
+        vars::SQLITE_FCNTL_CHUNK_SIZE => fallible(|| {
+            let file = unwrap_file!(p_file, T)?;
+            let vfs = unwrap_vfs!(file.vfs, T)?;
+
+            // p_arg is a pointer to an integer containing the new chunk size,
+            // where a size <= 0 disables chunking
+            let size = unsafe { p_arg.cast::<c_int>().as_ref() }.ok_or(vars::SQLITE_INTERNAL)?;
+            let size = (*size).try_into().unwrap_or(0);
+            vfs.chunk_size(&mut file.handle, size)?;
+            Ok(vars::SQLITE_OK)
+        }),
+
+        vars::SQLITE_FCNTL_MMAP_SIZE => fallible(|| {
+            let file = unwrap_file!(p_file, T)?;
+            let vfs = unwrap_vfs!(file.vfs, T)?;
+
+            // p_arg is a pointer to the requested limit, a negative limit is a
+            // query. it is overwritten with the previous limit
+            let arg = unsafe { p_arg.cast::<i64>().as_mut() }.ok_or(vars::SQLITE_INTERNAL)?;
+            let requested = (*arg >= 0).then_some(*arg);
+            *arg = vfs.mmap_size(&mut file.handle, requested)?;
+            Ok(vars::SQLITE_OK)
+        }),
+
+        vars::SQLITE_FCNTL_LOCK_TIMEOUT => fallible(|| {
+            let file = unwrap_file!(p_file, T)?;
+            let vfs = unwrap_vfs!(file.vfs, T)?;
+
+            // p_arg is a pointer to the new timeout, which is overwritten with
+            // the previous timeout
+            let arg = unsafe { p_arg.cast::<c_int>().as_mut() }.ok_or(vars::SQLITE_INTERNAL)?;
+            vfs.lock_timeout(&mut file.handle, *arg)?;
+            *arg = core::mem::replace(&mut file.lock_timeout, *arg);
+            Ok(vars::SQLITE_OK)
+        }),
+
+        vars::SQLITE_FCNTL_TEMPFILENAME => fallible(|| {
+            let file = unwrap_file!(p_file, T)?;
+            let appdata = unwrap_appdata!(file.vfs, T)?;
+            let name = match appdata.vfs.temp_filename() {
+                Err(vars::SQLITE_NOTFOUND) => {
+                    // use the same prefix as SQLite's builtin vfs's
+                    let mut random = [0_u8; 8];
+                    let n = unsafe { x_randomness::<T>(file.vfs, 8, random.as_mut_ptr().cast()) };
+                    if n != 8 {
+                        // refuse to hand out a predictable name
+                        return Err(vars::SQLITE_IOERR);
+                    }
+                    format!("etilqs_{:016x}", u64::from_le_bytes(random))
+                }
+                name => name?,
+            };
+
+            // p_arg is a pointer to a string which the caller is responsible for freeing
+            unsafe { appdata.sqlite_api.mprintf(&name, p_arg.cast())? };
+            Ok(vars::SQLITE_OK)
+        }),
+
+        vars::SQLITE_FCNTL_SYNC => fallible(|| {
+            let file = unwrap_file!(p_file, T)?;
+            let vfs = unwrap_vfs!(file.vfs, T)?;
+
+            // p_arg is null or the super-journal's name during a multi-database commit
+            let super_journal = unsafe { p_arg.cast::<c_char>().as_ref() }
+                .map(|p| unsafe { CStr::from_ptr(p) }.to_string_lossy());
+            vfs.sync_fcntl(&mut file.handle, super_journal.as_deref())?;
+            Ok(vars::SQLITE_OK)
+        }),
+
+        vars::SQLITE_FCNTL_COMMIT_PHASETWO => fallible(|| {
+            let file = unwrap_file!(p_file, T)?;
+            let vfs = unwrap_vfs!(file.vfs, T)?;
+            vfs.commit_phase_two(&mut file.handle)?;
+            Ok(vars::SQLITE_OK)
+        }),
+
+        vars::SQLITE_FCNTL_OVERWRITE => fallible(|| {
+            let file = unwrap_file!(p_file, T)?;
+            let vfs = unwrap_vfs!(file.vfs, T)?;
+            vfs.overwrite_hint(&mut file.handle)?;
+            Ok(vars::SQLITE_OK)
+        }),
+
+        vars::SQLITE_FCNTL_BUSYHANDLER => fallible(|| {
+            let file = unwrap_file!(p_file, T)?;
+            let vfs = unwrap_vfs!(file.vfs, T)?;
+
+            // p_arg points to the busy handler callback followed by its argument
+            let args = p_arg.cast::<*mut c_void>();
+            if args.is_null() {
+                return Err(vars::SQLITE_INTERNAL);
+            }
+            let (callback, arg) = unsafe { (*args, *args.add(1)) };
+            let callback = NonNull::new(callback).ok_or(vars::SQLITE_INTERNAL)?;
+            let callback = unsafe {
+                core::mem::transmute::<*mut c_void, unsafe extern "C" fn(*mut c_void) -> c_int>(
+                    callback.as_ptr(),
+                )
+            };
+            vfs.set_busy_handler(&mut file.handle, BusyHandler { callback, arg });
+            Ok(vars::SQLITE_OK)
+        }),
+
+        vars::SQLITE_FCNTL_HAS_MOVED => fallible(|| {
+            let file = unwrap_file!(p_file, T)?;
+            let vfs = unwrap_vfs!(file.vfs, T)?;
+
+            // p_arg is a pointer to an integer which receives the result
+            let arg = unsafe { p_arg.cast::<c_int>().as_mut() }.ok_or(vars::SQLITE_INTERNAL)?;
+            *arg = vfs.has_moved(&mut file.handle)?.into();
+            Ok(vars::SQLITE_OK)
+        }),
+
+        vars::SQLITE_FCNTL_LOCKSTATE => fallible(|| {
+            let file = unwrap_file!(p_file, T)?;
+            let vfs = unwrap_vfs!(file.vfs, T)?;
+
+            // p_arg is a pointer to an integer which receives the lock level
+            let arg = unsafe { p_arg.cast::<c_int>().as_mut() }.ok_or(vars::SQLITE_INTERNAL)?;
+            *arg = vfs.lock_state(&mut file.handle)?.into();
+            Ok(vars::SQLITE_OK)
+        }),
+
+        vars::SQLITE_FCNTL_PERSIST_WAL | vars::SQLITE_FCNTL_POWERSAFE_OVERWRITE => fallible(|| {
+            let file = unwrap_file!(p_file, T)?;
+            let vfs = unwrap_vfs!(file.vfs, T)?;
+
+            // p_arg is a pointer to an integer which is negative to query the
+            // flag, in which case the current value is written back to it,
+            // and otherwise zero or one to clear or set it
+            let arg = unsafe { p_arg.cast::<c_int>().as_mut() }.ok_or(vars::SQLITE_INTERNAL)?;
+            let value = (*arg >= 0).then_some(*arg != 0);
+            let current = if op == vars::SQLITE_FCNTL_PERSIST_WAL {
+                vfs.persist_wal(&mut file.handle, value)?
+            } else {
+                vfs.powersafe_overwrite(&mut file.handle, value)?
+            };
+            if value.is_none() {
+                *arg = current.into();
+            }
+            Ok(vars::SQLITE_OK)
+        }),
+
+        // Atomic write support: (requires SQLITE_IOCAP_BATCH_ATOMIC device characteristic)
+        // Docs: https://www3.sqlite.org/cgi/src/technote/714f6cbbf78c8a1351cbd48af2b438f7f824b336
+        vars::SQLITE_FCNTL_BEGIN_ATOMIC_WRITE => fallible(|| {
+            let file = unwrap_file!(p_file, T)?;
+            let vfs = unwrap_vfs!(file.vfs, T)?;
+            let result = vfs.begin_atomic_write(&mut file.handle);
+            if cfg!(debug_assertions) && result == Err(vars::SQLITE_NOTFOUND) {
+                let characteristics = vfs.device_characteristics(&mut file.handle)?;
+                debug_assert!(
+                    characteristics & vars::SQLITE_IOCAP_BATCH_ATOMIC == 0,
+                    "vfs advertises SQLITE_IOCAP_BATCH_ATOMIC but does not implement atomic writes"
+                );
+            }
+            result?;
+            Ok(vars::SQLITE_OK)
+        }),
+
+        vars::SQLITE_FCNTL_COMMIT_ATOMIC_WRITE => fallible(|| {
+            let file = unwrap_file!(p_file, T)?;
+            let vfs = unwrap_vfs!(file.vfs, T)?;
+            vfs.commit_atomic_write(&mut file.handle)?;
+            Ok(vars::SQLITE_OK)
+        }),
+
+        vars::SQLITE_FCNTL_ROLLBACK_ATOMIC_WRITE => fallible(|| {
+            let file = unwrap_file!(p_file, T)?;
+            let vfs = unwrap_vfs!(file.vfs, T)?;
+            vfs.rollback_atomic_write(&mut file.handle)?;
+            Ok(vars::SQLITE_OK)
+        }),
+
+        // SQLite answers some ops itself without calling into the vfs, such
+        // as SQLITE_FCNTL_FILE_POINTER, SQLITE_FCNTL_DATA_VERSION, and
+        // SQLITE_FCNTL_RESERVE_BYTES. The data version comes from the pager,
+        // which bumps it whenever it notices another connection changed the
+        // database. Reserved bytes are recorded in the database header.
+        _ => vars::SQLITE_NOTFOUND,
     }
-    vars::SQLITE_NOTFOUND
 }
 
 // system queries
 
+fn is_valid_sector_size(size: i32) -> bool {
+    (MIN_SECTOR_SIZE..=MAX_SECTOR_SIZE).contains(&size) && size.count_ones() == 1
+}
+
 unsafe extern "C" fn x_sector_size<T: Vfs>(p_file: *mut ffi::sqlite3_file) -> c_int {
     fallible(|| {
         let file = unwrap_file!(p_file, T)?;
-        let vfs = unwrap_vfs!(file.vfs, T)?;
-        vfs.sector_size(&mut file.handle)
+        let appdata = unwrap_appdata!(file.vfs, T)?;
+        match appdata.vfs.sector_size(&mut file.handle) {
+            Ok(size) if is_valid_sector_size(size) => Ok(size),
+            size => {
+                SqliteLogger::new(appdata.sqlite_api.log).log(
+                    SqliteLogLevel::Warn,
+                    &format!("invalid sector size {size:?}, using {DEFAULT_SECTOR_SIZE}"),
+                );
+                Ok(DEFAULT_SECTOR_SIZE)
+            }
+        }
     })
 }
 
 unsafe extern "C" fn x_device_characteristics<T: Vfs>(p_file: *mut ffi::sqlite3_file) -> c_int {
     fallible(|| {
         let file = unwrap_file!(p_file, T)?;
-        let vfs = unwrap_vfs!(file.vfs, T)?;
-        vfs.device_characteristics(&mut file.handle)
+        let appdata = unwrap_appdata!(file.vfs, T)?;
+        let characteristics = appdata.vfs.device_characteristics(&mut file.handle)?;
+        // SQLite would otherwise start batches the vfs can't commit
+        if appdata
+            .capabilities
+            .contains(VfsCapabilities::new().batch_atomic())
+        {
+            Ok(characteristics)
+        } else {
+            Ok(characteristics & !vars::SQLITE_IOCAP_BATCH_ATOMIC)
+        }
     })
 }
 
@@ -822,7 +2178,8 @@ unsafe extern "C" fn x_unfetch<T: Vfs>(
     })
 }
 
-// the following functions are wrappers around the base vfs functions
+// the following functions are wrappers around the base vfs functions, some of
+// which may be overridden by the Vfs implementation
 
 unsafe extern "C" fn x_dlopen<T: Vfs>(
     p_vfs: *mut ffi::sqlite3_vfs,
@@ -870,11 +2227,34 @@ unsafe extern "C" fn x_dlclose<T: Vfs>(p_vfs: *mut ffi::sqlite3_vfs, p_handle: *
     }
 }
 
+const MILLIS_PER_DAY: f64 = 86_400_000.0;
+
+/// The Unix epoch as milliseconds since the Julian epoch.
+const UNIX_EPOCH_JULIAN_MILLIS: i64 = 210_866_760_000_000;
+
+/// Returns the current time from `RegisterOpts::time_source` as milliseconds
+/// since the Julian epoch, or `SQLITE_NOTFOUND` if there isn't one.
+fn time_source_millis<T: Vfs>(p_vfs: *mut ffi::sqlite3_vfs) -> VfsResult<i64> {
+    let appdata = unwrap_appdata!(p_vfs, T)?;
+    let time_source = appdata.time_source.ok_or(vars::SQLITE_NOTFOUND)?;
+    Ok(time_source() + UNIX_EPOCH_JULIAN_MILLIS)
+}
+
 unsafe extern "C" fn x_randomness<T: Vfs>(
     p_vfs: *mut ffi::sqlite3_vfs,
     n_byte: c_int,
     z_out: *mut c_char,
 ) -> c_int {
+    if let Ok(vfs) = unwrap_vfs!(p_vfs, T) {
+        if let (Ok(len), false) = (usize::try_from(n_byte), z_out.is_null()) {
+            let buf = unsafe { slice::from_raw_parts_mut(z_out.cast::<u8>(), len) };
+            match vfs.randomness(buf) {
+                Ok(n) => return n.min(len) as c_int,
+                Err(vars::SQLITE_NOTFOUND) => {}
+                Err(_) => return 0,
+            }
+        }
+    }
     if let Ok(vfs) = unwrap_base_vfs!(p_vfs, T) {
         if let Some(x_randomness) = vfs.xRandomness {
             return unsafe { x_randomness(vfs, n_byte, z_out) };
@@ -884,6 +2264,13 @@ unsafe extern "C" fn x_randomness<T: Vfs>(
 }
 
 unsafe extern "C" fn x_sleep<T: Vfs>(p_vfs: *mut ffi::sqlite3_vfs, microseconds: c_int) -> c_int {
+    if let Ok(vfs) = unwrap_vfs!(p_vfs, T) {
+        match vfs.sleep(microseconds) {
+            Ok(slept) => return slept,
+            Err(vars::SQLITE_NOTFOUND) => {}
+            Err(_) => return 0,
+        }
+    }
     if let Ok(vfs) = unwrap_base_vfs!(p_vfs, T) {
         if let Some(x_sleep) = vfs.xSleep {
             return unsafe { x_sleep(vfs, microseconds) };
@@ -896,6 +2283,29 @@ unsafe extern "C" fn x_current_time<T: Vfs>(
     p_vfs: *mut ffi::sqlite3_vfs,
     p_time: *mut f64,
 ) -> c_int {
+    if let Ok(vfs) = unwrap_vfs!(p_vfs, T) {
+        let time = match vfs.current_time() {
+            Err(vars::SQLITE_NOTFOUND) => match vfs.current_time_int64() {
+                Err(vars::SQLITE_NOTFOUND) => time_source_millis::<T>(p_vfs),
+                millis => millis,
+            }
+            .map(|millis| millis as f64 / MILLIS_PER_DAY),
+            time => time,
+        };
+        match time {
+            Ok(time) => {
+                return match unsafe { p_time.as_mut() } {
+                    Some(out) => {
+                        *out = time;
+                        vars::SQLITE_OK
+                    }
+                    None => vars::SQLITE_INTERNAL,
+                };
+            }
+            Err(vars::SQLITE_NOTFOUND) => {}
+            Err(err) => return err,
+        }
+    }
     if let Ok(vfs) = unwrap_base_vfs!(p_vfs, T) {
         if let Some(x_current_time) = vfs.xCurrentTime {
             return unsafe { x_current_time(vfs, p_time) };
@@ -908,9 +2318,49 @@ unsafe extern "C" fn x_current_time_int64<T: Vfs>(
     p_vfs: *mut ffi::sqlite3_vfs,
     p_time: *mut i64,
 ) -> c_int {
+    if let Ok(vfs) = unwrap_vfs!(p_vfs, T) {
+        let time = match vfs.current_time_int64() {
+            Err(vars::SQLITE_NOTFOUND) => match vfs.current_time() {
+                Err(vars::SQLITE_NOTFOUND) => time_source_millis::<T>(p_vfs),
+                days => days.map(|days| (days * MILLIS_PER_DAY) as i64),
+            },
+            time => time,
+        };
+        match time {
+            Ok(time) => {
+                return match unsafe { p_time.as_mut() } {
+                    Some(out) => {
+                        *out = time;
+                        vars::SQLITE_OK
+                    }
+                    None => vars::SQLITE_INTERNAL,
+                };
+            }
+            Err(vars::SQLITE_NOTFOUND) => {}
+            Err(err) => return err,
+        }
+    }
     if let Ok(vfs) = unwrap_base_vfs!(p_vfs, T) {
-        if let Some(x_current_time_int64) = vfs.xCurrentTimeInt64 {
-            return unsafe { x_current_time_int64(vfs, p_time) };
+        // xCurrentTimeInt64 was added in version 2 of sqlite3_vfs
+        if vfs.iVersion >= 2 {
+            if let Some(x_current_time_int64) = vfs.xCurrentTimeInt64 {
+                return unsafe { x_current_time_int64(vfs, p_time) };
+            }
+        }
+        if let Some(x_current_time) = vfs.xCurrentTime {
+            // fall back to converting the base vfs's julian day number
+            let mut days = 0.0;
+            let rc = unsafe { x_current_time(vfs, &mut days) };
+            if rc != vars::SQLITE_OK {
+                return rc;
+            }
+            return match unsafe { p_time.as_mut() } {
+                Some(out) => {
+                    *out = (days * MILLIS_PER_DAY) as i64;
+                    vars::SQLITE_OK
+                }
+                None => vars::SQLITE_INTERNAL,
+            };
         }
     }
     vars::SQLITE_INTERNAL
@@ -923,10 +2373,11 @@ mod tests {
 
     use super::*;
     use crate::{
-        flags::{CreateMode, OpenKind, OpenMode},
+        flags::{CreateMode, OpenMode, SyncMode},
         mock::*,
     };
     use alloc::{sync::Arc, vec::Vec};
+    use core::ptr::null;
     use parking_lot::Mutex;
     use rusqlite::{Connection, OpenFlags};
     use std::{boxed::Box, io::Write, println};
@@ -935,6 +2386,75 @@ mod tests {
         println!("{arg2}");
     }
 
+    /// registers a new mock vfs under the given name, returning its shared state
+    fn register_mock(name: &str, hooks: impl Hooks + Send + 'static) -> Arc<Mutex<MockState>> {
+        let shared = Arc::new(Mutex::new(MockState::new(Box::new(hooks))));
+        let vfs = MockVfs::new(shared.clone());
+        let registration =
+            register_static(CString::new(name).unwrap(), vfs, RegisterOpts::default())
+                .expect("failed to register vfs");
+        shared.lock().setup_logger(registration.logger());
+        shared
+    }
+
+    fn open_mock(path: &str, vfs: &str) -> rusqlite::Result<Connection> {
+        Connection::open_with_flags_and_vfs(
+            path,
+            OpenFlags::SQLITE_OPEN_READ_WRITE | OpenFlags::SQLITE_OPEN_CREATE,
+            vfs,
+        )
+    }
+
+    /// opens a file by calling the registered vfs's xOpen directly, returning
+    /// the result code, the reported output flags, and the storage backing
+    /// the `sqlite3_file`
+    fn raw_open(vfs: &str, name: Option<&CStr>, flags: c_int) -> (c_int, c_int, Vec<u64>) {
+        let vfs = CString::new(vfs).unwrap();
+        let p_vfs = unsafe { rusqlite::ffi::sqlite3_vfs_find(vfs.as_ptr()) };
+        let vfs = unsafe { p_vfs.cast::<ffi::sqlite3_vfs>().as_ref() }.expect("vfs not found");
+        let mut file = alloc::vec![0_u64; (vfs.szOsFile as usize).div_ceil(8)];
+        let mut out_flags = 0;
+        let rc = unsafe {
+            vfs.xOpen.unwrap()(
+                p_vfs.cast(),
+                name.map_or(null(), CStr::as_ptr),
+                file.as_mut_ptr().cast(),
+                flags,
+                &mut out_flags,
+            )
+        };
+        (rc, out_flags, file)
+    }
+
+    /// closes a file opened with `raw_open`
+    fn raw_close(file: &mut [u64]) -> c_int {
+        let p_file = file.as_mut_ptr().cast::<ffi::sqlite3_file>();
+        unsafe { (*(*p_file).pMethods).xClose.unwrap()(p_file) }
+    }
+
+    /// returns the `sqlite3_file` backing the main database of the connection
+    fn main_db_file(conn: &Connection) -> *mut ffi::sqlite3_file {
+        let mut p_file: *mut ffi::sqlite3_file = null_mut();
+        let rc = unsafe {
+            rusqlite::ffi::sqlite3_file_control(
+                conn.handle(),
+                c"main".as_ptr(),
+                vars::SQLITE_FCNTL_FILE_POINTER,
+                (&raw mut p_file).cast(),
+            )
+        };
+        assert_eq!(rc, vars::SQLITE_OK);
+        assert!(!p_file.is_null(), "main db file is not open");
+        p_file
+    }
+
+    fn file_control(file: *mut ffi::sqlite3_file, op: c_int, arg: *mut c_void) -> c_int {
+        unsafe {
+            let x_file_control = (*(*file).pMethods).xFileControl.unwrap();
+            x_file_control(file, op, arg)
+        }
+    }
+
     #[test]
     fn sanity() -> Result<(), Box<dyn std::error::Error>> {
         unsafe {
@@ -967,15 +2487,15 @@ mod tests {
 
         let shared = Arc::new(Mutex::new(MockState::new(Box::new(H {}))));
         let vfs = MockVfs::new(shared.clone());
-        let logger = register_static(
+        let registration = register_static(
             CString::new("mock").unwrap(),
             vfs,
-            RegisterOpts { make_default: true },
+            RegisterOpts { make_default: true, ..Default::default() },
         )
         .map_err(|_| "failed to register vfs")?;
 
         // setup the logger
-        shared.lock().setup_logger(logger);
+        shared.lock().setup_logger(registration.logger());
 
         // create a sqlite connection using the mock vfs
         let conn = Connection::open_with_flags_and_vfs(
@@ -1021,4 +2541,2671 @@ mod tests {
 
         Ok(())
     }
+
+    #[test]
+    fn file_control_vfsname() -> Result<(), Box<dyn std::error::Error>> {
+        register_mock("mock_vfsname", NoopHooks);
+        let conn = open_mock("vfsname.db", "mock_vfsname")?;
+
+        let mut p: *mut c_char = null_mut();
+        let rc = unsafe {
+            rusqlite::ffi::sqlite3_file_control(
+                conn.handle(),
+                c"main".as_ptr(),
+                vars::SQLITE_FCNTL_VFSNAME,
+                (&raw mut p).cast(),
+            )
+        };
+        assert_eq!(rc, vars::SQLITE_OK);
+        assert!(!p.is_null());
+        let name = String::from(unsafe { CStr::from_ptr(p) }.to_str()?);
+        unsafe { rusqlite::ffi::sqlite3_free(p.cast()) };
+
+        // the base vfs is whatever the default vfs was at registration time
+        let (ours, base) = name.split_once('/').expect("missing base vfs name");
+        assert_eq!(ours, "mock_vfsname");
+        assert!(!base.is_empty());
+
+        Ok(())
+    }
+
+    #[test]
+    fn file_control_chunk_size() -> Result<(), Box<dyn std::error::Error>> {
+        struct H {
+            chunk_size: Arc<Mutex<Option<usize>>>,
+        }
+        impl Hooks for H {
+            fn chunk_size(&mut self, _: MockHandle, size: usize) {
+                *self.chunk_size.lock() = Some(size);
+            }
+        }
+        let chunk_size = Arc::new(Mutex::new(None));
+        register_mock("mock_chunk_size", H { chunk_size: chunk_size.clone() });
+        let conn = open_mock("chunk_size.db", "mock_chunk_size")?;
+
+        let mut size: c_int = 65536;
+        let rc = unsafe {
+            rusqlite::ffi::sqlite3_file_control(
+                conn.handle(),
+                c"main".as_ptr(),
+                vars::SQLITE_FCNTL_CHUNK_SIZE,
+                (&raw mut size).cast(),
+            )
+        };
+        assert_eq!(rc, vars::SQLITE_OK);
+        assert_eq!(*chunk_size.lock(), Some(65536));
+
+        // a negative chunk size disables chunking
+        let mut size: c_int = -1;
+        let rc = unsafe {
+            rusqlite::ffi::sqlite3_file_control(
+                conn.handle(),
+                c"main".as_ptr(),
+                vars::SQLITE_FCNTL_CHUNK_SIZE,
+                (&raw mut size).cast(),
+            )
+        };
+        assert_eq!(rc, vars::SQLITE_OK);
+        assert_eq!(*chunk_size.lock(), Some(0));
+
+        // growing the database and its journal still works after setting a chunk size
+        conn.execute_batch("pragma main.journal_size_limit = 16384")?;
+        conn.execute("create table t (data blob)", [])?;
+        for _ in 0..16 {
+            conn.execute("insert into t values (zeroblob(4096))", [])?;
+        }
+        let n: i64 = conn.query_row("select count(*) from t", [], |row| row.get(0))?;
+        assert_eq!(n, 16);
+
+        Ok(())
+    }
+
+    #[test]
+    fn sync_flags() -> Result<(), Box<dyn std::error::Error>> {
+        struct H {
+            modes: Arc<Mutex<Vec<SyncMode>>>,
+        }
+        impl Hooks for H {
+            fn sync(&mut self, _: MockHandle, flags: SyncFlags) {
+                self.modes.lock().push(flags.mode());
+            }
+        }
+        let modes = Arc::new(Mutex::new(Vec::new()));
+        register_mock("mock_sync_flags", H { modes: modes.clone() });
+        let conn = open_mock("sync_flags.db", "mock_sync_flags")?;
+
+        conn.execute_batch("pragma synchronous = full")?;
+        conn.execute("create table t (val int)", [])?;
+        let synced = core::mem::take(&mut *modes.lock());
+        assert!(!synced.is_empty());
+        assert!(synced.iter().all(|m| *m == SyncMode::Normal));
+
+        // fullfsync causes SQLite to request a full sync
+        conn.execute_batch("pragma fullfsync = on")?;
+        conn.execute("insert into t (val) values (1)", [])?;
+        let synced = core::mem::take(&mut *modes.lock());
+        assert!(!synced.is_empty());
+        assert!(synced.iter().all(|m| *m == SyncMode::Full));
+
+        Ok(())
+    }
+
+    #[test]
+    fn handles_report_open_kind() -> rusqlite::Result<()> {
+        struct H {
+            kinds: Arc<Mutex<Vec<OpenKind>>>,
+        }
+        impl Hooks for H {
+            fn write(&mut self, handle: MockHandle, _: usize, _: &[u8]) {
+                let mut kinds = self.kinds.lock();
+                if !kinds.contains(&handle.open_kind()) {
+                    kinds.push(handle.open_kind());
+                }
+            }
+        }
+        let kinds = Arc::new(Mutex::new(Vec::new()));
+        register_mock("mock_open_kind", H { kinds: kinds.clone() });
+        let conn = open_mock("open_kind.db", "mock_open_kind")?;
+        conn.execute("create table t (val int)", [])?;
+
+        // the journal is written before the changes reach the main database
+        assert_eq!(*kinds.lock(), [OpenKind::MainJournal, OpenKind::MainDb]);
+        Ok(())
+    }
+
+    #[test]
+    fn delete_sync_dir() -> Result<(), Box<dyn std::error::Error>> {
+        struct H {
+            deletes: Arc<Mutex<Vec<(String, bool)>>>,
+        }
+        impl Hooks for H {
+            fn delete(&mut self, path: &str, sync_dir: bool) {
+                self.deletes.lock().push((String::from(path), sync_dir));
+            }
+        }
+        let deletes = Arc::new(Mutex::new(Vec::new()));
+        register_mock("mock_delete_sync_dir", H { deletes: deletes.clone() });
+        let conn = open_mock("delete_sync_dir.db", "mock_delete_sync_dir")?;
+        let journal = String::from("delete_sync_dir.db-journal");
+
+        conn.execute_batch("pragma journal_mode = delete; pragma synchronous = full")?;
+        conn.execute("create table t (val int)", [])?;
+        let deleted = core::mem::take(&mut *deletes.lock());
+        assert_eq!(deleted, [(journal.clone(), false)]);
+
+        // synchronous=extra asks for the journal deletion to be durable
+        conn.execute_batch("pragma synchronous = extra")?;
+        conn.execute("insert into t (val) values (1)", [])?;
+        let deleted = core::mem::take(&mut *deletes.lock());
+        assert_eq!(deleted, [(journal, true)]);
+
+        Ok(())
+    }
+
+    #[test]
+    fn sync_fcntl_and_commit_phase_two() -> rusqlite::Result<()> {
+        struct H {
+            events: Arc<Mutex<Vec<&'static str>>>,
+        }
+        impl Hooks for H {
+            fn sync(&mut self, _: MockHandle, _: SyncFlags) {
+                self.events.lock().push("sync");
+            }
+            fn sync_fcntl(&mut self, _: MockHandle, super_journal: Option<&str>) {
+                assert_eq!(super_journal, None);
+                self.events.lock().push("sync_fcntl");
+            }
+            fn commit_phase_two(&mut self, _: MockHandle) {
+                self.events.lock().push("commit_phase_two");
+            }
+        }
+        let events = Arc::new(Mutex::new(Vec::new()));
+        register_mock("mock_commit_hooks", H { events: events.clone() });
+        let conn = open_mock("commit_hooks.db", "mock_commit_hooks")?;
+
+        // the journal is synced first, then the database file right before the commit completes
+        conn.execute("create table t (val int)", [])?;
+        let seen = core::mem::take(&mut *events.lock());
+        assert!(
+            seen.ends_with(&["sync_fcntl", "sync", "commit_phase_two"]),
+            "{seen:?}"
+        );
+
+        // without syncs, SQLITE_FCNTL_SYNC is still sent in place of xSync
+        conn.execute_batch("pragma synchronous = off")?;
+        conn.execute("insert into t (val) values (1)", [])?;
+        let seen = core::mem::take(&mut *events.lock());
+        assert_eq!(seen, ["sync_fcntl", "commit_phase_two"]);
+
+        Ok(())
+    }
+
+    #[test]
+    fn vacuum_sends_overwrite_hint() -> rusqlite::Result<()> {
+        struct H {
+            events: Arc<Mutex<Vec<(MockHandle, &'static str)>>>,
+        }
+        impl Hooks for H {
+            fn write(&mut self, handle: MockHandle, _: usize, _: &[u8]) {
+                self.events.lock().push((handle, "write"));
+            }
+            fn overwrite_hint(&mut self, handle: MockHandle) {
+                self.events.lock().push((handle, "overwrite_hint"));
+            }
+        }
+        let events = Arc::new(Mutex::new(Vec::new()));
+        register_mock("mock_overwrite", H { events: events.clone() });
+        let conn = open_mock("overwrite.db", "mock_overwrite")?;
+        conn.execute("create table t (val blob)", [])?;
+        for _ in 0..20 {
+            conn.execute("insert into t values (zeroblob(4096))", [])?;
+        }
+        conn.execute("delete from t where rowid % 2 = 0", [])?;
+
+        events.lock().clear();
+        conn.execute_batch("vacuum")?;
+        let seen = core::mem::take(&mut *events.lock());
+        let hints: Vec<usize> = seen
+            .iter()
+            .enumerate()
+            .filter(|(_, (_, event))| *event == "overwrite_hint")
+            .map(|(i, _)| i)
+            .collect();
+        assert_eq!(hints.len(), 1, "{seen:?}");
+
+        // the database file is only written once it has been hinted
+        let (db, _) = seen[hints[0]];
+        let writes: Vec<usize> = seen
+            .iter()
+            .enumerate()
+            .filter(|(_, event)| **event == (db, "write"))
+            .map(|(i, _)| i)
+            .collect();
+        assert!(!writes.is_empty(), "{seen:?}");
+        assert!(writes.iter().all(|&i| i > hints[0]), "{seen:?}");
+
+        // nothing is hinted for regular writes
+        conn.execute("insert into t values (1)", [])?;
+        assert!(
+            events
+                .lock()
+                .iter()
+                .all(|(_, event)| *event != "overwrite_hint")
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn atomic_write() -> Result<(), Box<dyn std::error::Error>> {
+        register_mock("mock_atomic_write", NoopHooks);
+        let conn = open_mock("atomic_write.db", "mock_atomic_write")?;
+        conn.execute("create table t (val int)", [])?;
+
+        // the bundled SQLite is not compiled with SQLITE_ENABLE_BATCH_ATOMIC_WRITE
+        // so we drive the atomic write path directly
+        let file = main_db_file(&conn);
+        let methods = unsafe { &*(*file).pMethods };
+        let write = |file: *mut ffi::sqlite3_file, data: &[u8]| unsafe {
+            let rc = methods.xWrite.unwrap()(file, data.as_ptr().cast(), data.len() as c_int, 0);
+            assert_eq!(rc, vars::SQLITE_OK);
+        };
+        let read = |file: *mut ffi::sqlite3_file| unsafe {
+            let mut buf = [0u8; 5];
+            let rc = methods.xRead.unwrap()(file, buf.as_mut_ptr().cast(), 5, 0);
+            assert_eq!(rc, vars::SQLITE_OK);
+            buf
+        };
+
+        let original = read(file);
+
+        // rolled back writes are discarded
+        let rc = file_control(file, vars::SQLITE_FCNTL_BEGIN_ATOMIC_WRITE, null_mut());
+        assert_eq!(rc, vars::SQLITE_OK);
+        write(file, b"hello");
+        assert_eq!(&read(file), b"hello");
+        let rc = file_control(file, vars::SQLITE_FCNTL_ROLLBACK_ATOMIC_WRITE, null_mut());
+        assert_eq!(rc, vars::SQLITE_OK);
+        assert_eq!(read(file), original);
+
+        // committed writes are kept
+        let rc = file_control(file, vars::SQLITE_FCNTL_BEGIN_ATOMIC_WRITE, null_mut());
+        assert_eq!(rc, vars::SQLITE_OK);
+        write(file, b"world");
+        let rc = file_control(file, vars::SQLITE_FCNTL_COMMIT_ATOMIC_WRITE, null_mut());
+        assert_eq!(rc, vars::SQLITE_OK);
+        assert_eq!(&read(file), b"world");
+
+        // restore the header so the connection closes cleanly
+        write(file, &original);
+
+        Ok(())
+    }
+
+    #[test]
+    fn short_read_past_eof() -> Result<(), Box<dyn std::error::Error>> {
+        register_mock("mock_short_read", NoopHooks);
+        let conn = open_mock("short_read.db", "mock_short_read")?;
+        conn.execute("create table t (val int)", [])?;
+
+        let file = main_db_file(&conn);
+        let methods = unsafe { &*(*file).pMethods };
+        let mut size: ffi::sqlite3_int64 = 0;
+        let rc = unsafe { methods.xFileSize.unwrap()(file, &mut size) };
+        assert_eq!(rc, vars::SQLITE_OK);
+        assert!(size > 0);
+
+        let read = |offset: ffi::sqlite3_int64| unsafe {
+            let mut buf = [0xAA_u8; 100];
+            let rc = methods.xRead.unwrap()(file, buf.as_mut_ptr().cast(), 100, offset);
+            (rc, buf)
+        };
+
+        // a read straddling EOF returns the available bytes followed by zeros
+        let (rc, buf) = read(size - 50);
+        assert_eq!(rc, vars::SQLITE_IOERR_SHORT_READ);
+        let (rc, expected) = read(size - 100);
+        assert_eq!(rc, vars::SQLITE_OK);
+        assert_eq!(buf[..50], expected[50..]);
+        assert!(buf[50..].iter().all(|&b| b == 0));
+
+        // a read entirely past EOF is zero-filled
+        let (rc, buf) = read(size + 4096);
+        assert_eq!(rc, vars::SQLITE_IOERR_SHORT_READ);
+        assert!(buf.iter().all(|&b| b == 0));
+
+        Ok(())
+    }
+
+    #[test]
+    fn uri_typed_parameters() -> Result<(), Box<dyn std::error::Error>> {
+        /// the boolean and integer parameters of each main database
+        type Params = ([bool; 3], [i64; 3]);
+        struct H {
+            params: Arc<Mutex<Vec<Params>>>,
+        }
+        impl Hooks for H {
+            fn open(&mut self, _: &Option<&str>, opts: &OpenOpts) {
+                if opts.kind() == OpenKind::MainDb {
+                    // Safety: the file is being opened
+                    self.params.lock().push(unsafe {
+                        (
+                            [
+                                opts.uri_boolean("vfs_cache", false),
+                                opts.uri_boolean("prefetch", true),
+                                opts.uri_boolean("missing", true),
+                            ],
+                            [
+                                opts.uri_int64("max_bytes", 0),
+                                opts.uri_int64("mask", 0),
+                                opts.uri_int64("bad", -1),
+                            ],
+                        )
+                    });
+                }
+            }
+        }
+        let params = Arc::new(Mutex::new(Vec::new()));
+        register_mock("mock_uri_typed", H { params: params.clone() });
+
+        let flags = OpenFlags::SQLITE_OPEN_READ_WRITE | OpenFlags::SQLITE_OPEN_CREATE;
+        let _conn = Connection::open_with_flags_and_vfs(
+            "file:typed.db?vfs_cache=YES&prefetch=off&max_bytes=1048576&mask=0xff&bad=12abc",
+            flags | OpenFlags::SQLITE_OPEN_URI,
+            "mock_uri_typed",
+        )?;
+        // without a URI every parameter falls back to its default
+        let _plain = Connection::open_with_flags_and_vfs(
+            "typed.db?vfs_cache=yes&max_bytes=1",
+            flags,
+            "mock_uri_typed",
+        )?;
+
+        let params = params.lock();
+        assert_eq!(params[0], ([true, false, true], [1048576, 255, -1]));
+        assert_eq!(params[1], ([false, true, true], [0, 0, -1]));
+        Ok(())
+    }
+
+    #[test]
+    fn uri_parameters() -> Result<(), Box<dyn std::error::Error>> {
+        struct Params {
+            bucket: Option<String>,
+            flag: Option<String>,
+            missing: Option<String>,
+        }
+        struct H {
+            params: Arc<Mutex<Vec<(String, Params)>>>,
+        }
+        impl Hooks for H {
+            fn open(&mut self, path: &Option<&str>, opts: &OpenOpts) {
+                // Safety: the file is being opened
+                let params = unsafe {
+                    Params {
+                        bucket: opts.uri_parameter("bucket").map(String::from),
+                        flag: opts.uri_parameter("flag").map(String::from),
+                        missing: opts.uri_parameter("missing").map(String::from),
+                    }
+                };
+                self.params
+                    .lock()
+                    .push((String::from(path.unwrap()), params));
+            }
+        }
+        let params = Arc::new(Mutex::new(Vec::new()));
+        register_mock("mock_uri", H { params: params.clone() });
+
+        let _conn = Connection::open_with_flags_and_vfs(
+            "file:uri.db?bucket=mybucket&flag",
+            OpenFlags::SQLITE_OPEN_READ_WRITE
+                | OpenFlags::SQLITE_OPEN_CREATE
+                | OpenFlags::SQLITE_OPEN_URI,
+            "mock_uri",
+        )?;
+        let _plain = open_mock("plain.db?bucket=mybucket", "mock_uri")?;
+
+        let params = params.lock();
+        assert_eq!(params.len(), 2);
+
+        let (path, uri) = &params[0];
+        assert_eq!(path, "uri.db");
+        assert_eq!(uri.bucket.as_deref(), Some("mybucket"));
+        assert_eq!(uri.flag.as_deref(), Some(""));
+        assert_eq!(uri.missing, None);
+
+        // without SQLITE_OPEN_URI the query string is part of the filename
+        let (path, plain) = &params[1];
+        assert_eq!(path, "plain.db?bucket=mybucket");
+        assert_eq!(plain.bucket, None);
+
+        Ok(())
+    }
+
+    #[test]
+    fn unregister_frees_vfs() -> rusqlite::Result<()> {
+        let shared = register_mock("mock_unregister", NoopHooks);
+        let name = CString::new("mock_unregister").unwrap();
+        assert!(!unsafe { ffi::sqlite3_vfs_find(name.as_ptr()) }.is_null());
+
+        // the vfs can't be unregistered while it has open files
+        let conn = open_mock("unregister.db", "mock_unregister")?;
+        conn.execute("create table t (val int)", [])?;
+        assert_eq!(
+            unsafe { unregister("mock_unregister") },
+            Err(vars::SQLITE_BUSY)
+        );
+        assert!(!unsafe { ffi::sqlite3_vfs_find(name.as_ptr()) }.is_null());
+
+        drop(conn);
+        assert_eq!(unsafe { unregister("mock_unregister") }, Ok(()));
+        assert!(unsafe { ffi::sqlite3_vfs_find(name.as_ptr()) }.is_null());
+        // the MockVfs has been dropped, releasing its reference to the state
+        assert_eq!(Arc::strong_count(&shared), 1);
+
+        assert_eq!(
+            unsafe { unregister("mock_unregister") },
+            Err(vars::SQLITE_NOTFOUND)
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn shutdown_can_refuse_unregister() {
+        struct H(Arc<AtomicBool>);
+        impl Hooks for H {
+            fn shutdown(&mut self) -> VfsResult<()> {
+                if self.0.load(Ordering::Acquire) {
+                    Ok(())
+                } else {
+                    Err(vars::SQLITE_BUSY)
+                }
+            }
+        }
+
+        let ready = Arc::new(AtomicBool::new(false));
+        let shared = register_mock("mock_shutdown", H(ready.clone()));
+        let name = CString::new("mock_shutdown").unwrap();
+
+        assert_eq!(
+            unsafe { unregister("mock_shutdown") },
+            Err(vars::SQLITE_BUSY)
+        );
+        // the vfs is still registered and usable
+        assert!(!unsafe { ffi::sqlite3_vfs_find(name.as_ptr()) }.is_null());
+        let conn = open_mock("shutdown.db", "mock_shutdown").unwrap();
+        drop(conn);
+
+        ready.store(true, Ordering::Release);
+        assert_eq!(unsafe { unregister("mock_shutdown") }, Ok(()));
+        assert!(unsafe { ffi::sqlite3_vfs_find(name.as_ptr()) }.is_null());
+        assert_eq!(Arc::strong_count(&shared), 1);
+    }
+
+    #[test]
+    fn mock_handles_share_files() {
+        let shared = register_mock("mock_shared_files", NoopHooks);
+        let vfs = MockVfs::new(shared.clone());
+        let opts = || {
+            OpenOpts::new(
+                vars::SQLITE_OPEN_MAIN_DB | vars::SQLITE_OPEN_READWRITE | vars::SQLITE_OPEN_CREATE,
+            )
+        };
+        let mut a = vfs.open(Some("shared.db"), opts()).unwrap();
+        let mut b = vfs.open(Some("shared.db"), opts()).unwrap();
+        assert_ne!(a, b);
+        assert_eq!(shared.lock().file(&a).unwrap().open_handles, 2);
+
+        assert_eq!(vfs.write(&mut a, 0, b"hello"), Ok(5));
+        let mut buf = [0; 5];
+        assert_eq!(vfs.read(&mut b, 0, &mut buf), Ok(5));
+        assert_eq!(&buf, b"hello");
+
+        // deleting an open file removes its name straight away, while open
+        // handles keep the data until they're closed
+        assert_eq!(vfs.delete("shared.db", false), Ok(()));
+        assert!(shared.lock().file_names().is_empty());
+        assert_eq!(vfs.access("shared.db", AccessFlags::Exists), Ok(false));
+        assert_eq!(vfs.close(a), Ok(()));
+        assert_eq!(vfs.file_size(&mut b), Ok(5));
+
+        // reopening the name creates a new, empty file
+        let mut c = vfs.open(Some("shared.db"), opts()).unwrap();
+        assert_eq!(vfs.file_size(&mut c), Ok(0));
+        assert_eq!(vfs.read(&mut b, 0, &mut buf), Ok(5));
+        assert_eq!(vfs.close(b), Ok(()));
+        assert_eq!(shared.lock().file_names(), ["shared.db"]);
+        assert_eq!(vfs.close(c), Ok(()));
+        assert_eq!(shared.lock().file_names(), ["shared.db"]);
+    }
+
+    #[test]
+    fn mock_open_files_and_contents() -> rusqlite::Result<()> {
+        let vfs = MockVfs::new(register_mock("mock_open_files", NoopHooks));
+        let conn = open_mock("open_files.db", "mock_open_files")?;
+        conn.execute("create table t (val int)", [])?;
+        conn.execute("insert into t values (1), (2), (3)", [])?;
+        let page_size: u32 = conn.query_row("pragma page_size", [], |r| r.get(0))?;
+        let page_count: u32 = conn.query_row("pragma page_count", [], |r| r.get(0))?;
+
+        // the journal is deleted once each transaction commits
+        assert_eq!(vfs.open_files(), ["open_files.db"]);
+        assert_eq!(vfs.file_contents("open_files.db-journal"), None);
+
+        drop(conn);
+        assert!(vfs.open_files().is_empty());
+
+        // the serialized database remains readable after the connection closes
+        let data = vfs.file_contents("open_files.db").unwrap();
+        assert_eq!(data.len(), (page_size * page_count) as usize);
+        assert!(data.starts_with(b"SQLite format 3\0"));
+        Ok(())
+    }
+
+    #[test]
+    fn mock_wal_lifecycle() -> rusqlite::Result<()> {
+        struct H {
+            shm_maps: Arc<Mutex<usize>>,
+        }
+        impl Hooks for H {
+            fn shm_map(&mut self, _: MockHandle, _: usize, _: bool) {
+                *self.shm_maps.lock() += 1;
+            }
+        }
+        let shm_maps = Arc::new(Mutex::new(0));
+        let vfs = MockVfs::new(register_mock("mock_wal", H { shm_maps: shm_maps.clone() }));
+        let count = |conn: &Connection| {
+            conn.query_row("select count(*) from t", [], |r| r.get::<_, i64>(0))
+        };
+
+        let conn = open_mock("wal.db", "mock_wal")?;
+        let mode: String = conn.query_row("pragma journal_mode = wal", [], |r| r.get(0))?;
+        assert_eq!(mode, "wal");
+        conn.execute("create table t (val int)", [])?;
+        for i in 0..100 {
+            conn.execute("insert into t values (?)", [i])?;
+        }
+        assert!(*shm_maps.lock() > 0);
+
+        // writes go to the wal until it is checkpointed
+        assert_eq!(vfs.open_files(), ["wal.db", "wal.db-wal"]);
+        assert!(!vfs.file_contents("wal.db-wal").unwrap().is_empty());
+        let (busy, wal_frames, checkpointed): (i64, i64, i64) =
+            conn.query_row("pragma wal_checkpoint(truncate)", [], |r| {
+                Ok((r.get(0)?, r.get(1)?, r.get(2)?))
+            })?;
+        assert_eq!((busy, wal_frames, checkpointed), (0, 0, 0));
+        assert_eq!(vfs.file_contents("wal.db-wal"), Some(Vec::new()));
+        conn.execute("insert into t values (100)", [])?;
+
+        // the last connection to close checkpoints and deletes the wal
+        drop(conn);
+        assert!(vfs.open_files().is_empty());
+        assert_eq!(vfs.file_contents("wal.db-wal"), None);
+
+        let conn = open_mock("wal.db", "mock_wal")?;
+        let mode: String = conn.query_row("pragma journal_mode", [], |r| r.get(0))?;
+        assert_eq!(mode, "wal");
+        assert_eq!(count(&conn)?, 101);
+        let sum: i64 = conn.query_row("select sum(val) from t", [], |r| r.get(0))?;
+        assert_eq!(sum, (0..=100).sum());
+        Ok(())
+    }
+
+    #[test]
+    fn mock_shm_locks_conflict() {
+        use ShmLockMode::*;
+        let vfs = MockVfs::new(register_mock("mock_shm_locks", NoopHooks));
+        let opts = OpenOpts::new(
+            vars::SQLITE_OPEN_MAIN_DB | vars::SQLITE_OPEN_READWRITE | vars::SQLITE_OPEN_CREATE,
+        );
+        let mut a = vfs.open(Some("shm_locks.db"), opts).unwrap();
+        let mut b = vfs.open(Some("shm_locks.db"), opts).unwrap();
+
+        // shared locks are compatible with each other, but not exclusive ones
+        assert_eq!(vfs.shm_lock(&mut a, 3, 2, LockShared), Ok(()));
+        assert_eq!(vfs.shm_lock(&mut b, 4, 1, LockShared), Ok(()));
+        assert_eq!(
+            vfs.shm_lock(&mut b, 3, 1, LockExclusive),
+            Err(vars::SQLITE_BUSY)
+        );
+        assert_eq!(vfs.shm_lock(&mut b, 5, 3, LockExclusive), Ok(()));
+        assert_eq!(
+            vfs.shm_lock(&mut a, 7, 1, LockShared),
+            Err(vars::SQLITE_BUSY)
+        );
+        assert_eq!(
+            vfs.shm_lock(&mut a, 8, 1, LockShared),
+            Err(vars::SQLITE_IOERR_SHMLOCK)
+        );
+
+        // once released the lock can be taken
+        assert_eq!(vfs.shm_lock(&mut a, 3, 2, UnlockShared), Ok(()));
+        assert_eq!(vfs.shm_lock(&mut b, 3, 1, LockExclusive), Ok(()));
+
+        // unmapping releases every lock held by the handle
+        assert_eq!(vfs.shm_unmap(&mut b, false), Ok(()));
+        assert_eq!(vfs.shm_lock(&mut a, 0, 8, LockExclusive), Ok(()));
+        assert_eq!(vfs.close(a), Ok(()));
+        assert_eq!(vfs.close(b), Ok(()));
+    }
+
+    #[test]
+    fn mock_delete_on_close() {
+        let shared = register_mock("mock_delete_on_close", NoopHooks);
+        let vfs = MockVfs::new(shared.clone());
+        let opts =
+            |flags| OpenOpts::new(vars::SQLITE_OPEN_READWRITE | vars::SQLITE_OPEN_CREATE | flags);
+        let temp = opts(vars::SQLITE_OPEN_TEMP_JOURNAL | vars::SQLITE_OPEN_DELETEONCLOSE);
+
+        // a named file is unlinked when the handle asking for it is closed
+        let other = vfs.open(Some("temp.db"), opts(0)).unwrap();
+        let file = vfs.open(Some("temp.db"), temp).unwrap();
+        assert_eq!(vfs.close(other), Ok(()));
+        assert_eq!(shared.lock().file_names(), ["temp.db"]);
+        assert_eq!(vfs.close(file), Ok(()));
+        assert!(shared.lock().file_names().is_empty());
+
+        // but only once, leaving a new file created under the same name alone
+        let file = vfs.open(Some("temp.db"), temp).unwrap();
+        assert_eq!(vfs.delete("temp.db", false), Ok(()));
+        let other = vfs.open(Some("temp.db"), opts(0)).unwrap();
+        assert_eq!(vfs.close(file), Ok(()));
+        assert_eq!(shared.lock().file_names(), ["temp.db"]);
+        assert_eq!(vfs.close(other), Ok(()));
+
+        // an anonymous file is reclaimed along with its handle
+        let file = vfs
+            .open(None, opts(vars::SQLITE_OPEN_TEMP_JOURNAL))
+            .unwrap();
+        assert_eq!(shared.lock().file_names().len(), 2);
+        assert_eq!(vfs.close(file), Ok(()));
+        assert_eq!(shared.lock().file_names(), ["temp.db"]);
+        assert_eq!(vfs.open_files(), Vec::<String>::new());
+    }
+
+    #[test]
+    fn close_persists_buffered_writes() -> rusqlite::Result<()> {
+        struct H;
+        impl Hooks for H {
+            fn buffer_writes(&mut self, _: MockHandle) -> bool {
+                true
+            }
+            fn sync(&mut self, _: MockHandle, _: SyncFlags) {
+                panic!("synchronous=off never syncs");
+            }
+        }
+        let shared = register_mock("mock_buffered", H);
+        let vfs = MockVfs::new(shared);
+        let contains = |needle: &[u8]| {
+            let data = vfs.file_contents("buffered.db").unwrap_or_default();
+            data.windows(needle.len()).any(|w| w == needle)
+        };
+
+        let conn = open_mock("buffered.db", "mock_buffered")?;
+        conn.execute_batch(
+            "pragma synchronous = off;
+            create table t (val text);
+            insert into t values ('buffered');",
+        )?;
+        // the insert was committed without a sync, so is still buffered
+        assert!(!contains(b"buffered"));
+        drop(conn);
+        assert!(contains(b"buffered"));
+
+        let conn = open_mock("buffered.db", "mock_buffered")?;
+        let val: String = conn.query_row("select val from t", [], |r| r.get(0))?;
+        assert_eq!(val, "buffered");
+        Ok(())
+    }
+
+    #[test]
+    fn mock_exclusive_create() {
+        register_mock("mock_exclusive_create", NoopHooks);
+        let name = c"exclusive.db";
+        let create =
+            vars::SQLITE_OPEN_MAIN_JOURNAL | vars::SQLITE_OPEN_READWRITE | vars::SQLITE_OPEN_CREATE;
+
+        // creating a file which doesn't exist succeeds either way
+        let (rc, _, mut first) = raw_open(
+            "mock_exclusive_create",
+            Some(name),
+            create | vars::SQLITE_OPEN_EXCLUSIVE,
+        );
+        assert_eq!(rc, vars::SQLITE_OK);
+
+        // once it exists only a non-exclusive create opens it
+        let (rc, _, _) = raw_open(
+            "mock_exclusive_create",
+            Some(name),
+            create | vars::SQLITE_OPEN_EXCLUSIVE,
+        );
+        assert_eq!(rc, vars::SQLITE_CANTOPEN);
+        let (rc, _, mut second) = raw_open("mock_exclusive_create", Some(name), create);
+        assert_eq!(rc, vars::SQLITE_OK);
+
+        assert_eq!(raw_close(&mut first), vars::SQLITE_OK);
+        assert_eq!(raw_close(&mut second), vars::SQLITE_OK);
+    }
+
+    #[test]
+    fn open_follows_create_mode() -> rusqlite::Result<()> {
+        let vfs = MockVfs::new(register_mock("mock_create_mode", NoopHooks));
+        let conn = open_mock("x.db", "mock_create_mode")?;
+        conn.execute("create table t (val int)", [])?;
+
+        let main_db = vars::SQLITE_OPEN_MAIN_DB;
+        let readonly = main_db | vars::SQLITE_OPEN_READONLY;
+        let readwrite = main_db | vars::SQLITE_OPEN_READWRITE;
+        let create = readwrite | vars::SQLITE_OPEN_CREATE;
+        let must_create = create | vars::SQLITE_OPEN_EXCLUSIVE;
+        for (flags, exists, missing) in [
+            (readonly, vars::SQLITE_OK, vars::SQLITE_CANTOPEN),
+            (readwrite, vars::SQLITE_OK, vars::SQLITE_CANTOPEN),
+            (create, vars::SQLITE_OK, vars::SQLITE_OK),
+            (must_create, vars::SQLITE_CANTOPEN, vars::SQLITE_OK),
+        ] {
+            let (rc, _, mut file) = raw_open("mock_create_mode", Some(c"x.db"), flags);
+            assert_eq!(rc, exists, "{flags:#x}");
+            if rc == vars::SQLITE_OK {
+                assert_eq!(raw_close(&mut file), vars::SQLITE_OK);
+            }
+            let (rc, _, mut file) = raw_open("mock_create_mode", Some(c"missing.db"), flags);
+            assert_eq!(rc, missing, "{flags:#x}");
+            if rc == vars::SQLITE_OK {
+                assert_eq!(raw_close(&mut file), vars::SQLITE_OK);
+                vfs.delete("missing.db", false).unwrap();
+            }
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn mock_concurrent_connections() -> rusqlite::Result<()> {
+        let shared = register_mock("mock_concurrent", NoopHooks);
+        let conn = open_mock("concurrent.db", "mock_concurrent")?;
+        conn.execute("create table t (val int)", [])?;
+        conn.execute("insert into t values (1), (2), (3)", [])?;
+
+        let readers: Vec<_> = (0..4)
+            .map(|_| {
+                std::thread::spawn(|| {
+                    let conn = open_mock("concurrent.db", "mock_concurrent")?;
+                    conn.query_row("select sum(val) from t", [], |r| r.get::<_, i64>(0))
+                })
+            })
+            .collect();
+        for reader in readers {
+            assert_eq!(reader.join().unwrap()?, 6);
+        }
+
+        // writes from one connection are visible to the others
+        let other = open_mock("concurrent.db", "mock_concurrent")?;
+        other.execute("insert into t values (4)", [])?;
+        let sum: i64 = conn.query_row("select sum(val) from t", [], |r| r.get(0))?;
+        assert_eq!(sum, 10);
+        drop((conn, other));
+        assert_eq!(shared.lock().file_names(), ["concurrent.db"]);
+        Ok(())
+    }
+
+    #[test]
+    fn register_max_path_len() {
+        let register = |name: &str, max_path_len| {
+            let shared = Arc::new(Mutex::new(MockState::new(Box::new(NoopHooks))));
+            let opts = RegisterOpts { max_path_len, ..Default::default() };
+            register_static(CString::new(name).unwrap(), MockVfs::new(shared), opts)
+        };
+        assert_eq!(
+            register("mock_path_zero", Some(0)).err(),
+            Some(vars::SQLITE_MISUSE)
+        );
+        assert_eq!(
+            register("mock_path_neg", Some(-1)).err(),
+            Some(vars::SQLITE_MISUSE)
+        );
+
+        let mx_pathname = |name: &str| {
+            let name = CString::new(name).unwrap();
+            unsafe { (*ffi::sqlite3_vfs_find(name.as_ptr())).mxPathname }
+        };
+        register("mock_path_default", None).unwrap();
+        assert_eq!(mx_pathname("mock_path_default"), DEFAULT_MAX_PATH_LEN);
+        register("mock_path_long", Some(4096)).unwrap();
+        assert_eq!(mx_pathname("mock_path_long"), 4096);
+    }
+
+    #[test]
+    fn register_base_vfs() {
+        let register = |name: &str, base_vfs| {
+            let shared = Arc::new(Mutex::new(MockState::new(Box::new(NoopHooks))));
+            let opts = RegisterOpts { base_vfs, ..Default::default() };
+            register_static(CString::new(name).unwrap(), MockVfs::new(shared), opts)
+        };
+        let base_vfs_name = |name: &str| {
+            let name = CString::new(name).unwrap();
+            let p_vfs = unsafe { ffi::sqlite3_vfs_find(name.as_ptr()) };
+            let base_vfs = unwrap_base_vfs!(p_vfs, MockVfs).unwrap();
+            String::from(unsafe { CStr::from_ptr(base_vfs.zName) }.to_str().unwrap())
+        };
+
+        register("mock_base_excl", Some("unix-excl")).unwrap();
+        assert_eq!(base_vfs_name("mock_base_excl"), "unix-excl");
+
+        assert_eq!(
+            register("mock_base_missing", Some("missing")).err(),
+            Some(vars::SQLITE_NOTFOUND)
+        );
+        assert_eq!(
+            register("mock_base_nul", Some("unix\0")).err(),
+            Some(vars::SQLITE_MISUSE)
+        );
+    }
+
+    #[test]
+    fn injected_time_and_randomness() -> rusqlite::Result<()> {
+        // 2000-01-01 00:00:00 UTC as milliseconds since the Julian epoch
+        const Y2K: i64 = 211_813_444_800_000;
+        struct H;
+        impl Hooks for H {
+            fn randomness(&mut self, buf: &mut [u8]) -> VfsResult<usize> {
+                buf.fill(7);
+                Ok(buf.len())
+            }
+            fn sleep(&mut self, micros: i32) -> VfsResult<i32> {
+                Ok(micros)
+            }
+            fn current_time_int64(&mut self) -> VfsResult<i64> {
+                Ok(Y2K)
+            }
+        }
+        register_mock("mock_time", H);
+        let conn = open_mock("time.db", "mock_time")?;
+        let now: String = conn.query_row("select datetime('now')", [], |r| r.get(0))?;
+        assert_eq!(now, "2000-01-01 00:00:00");
+
+        let name = CString::new("mock_time").unwrap();
+        unsafe {
+            let p_vfs = ffi::sqlite3_vfs_find(name.as_ptr());
+            let vfs = &*p_vfs;
+
+            // current_time is derived from current_time_int64
+            let mut days = 0.0;
+            assert_eq!(vfs.xCurrentTime.unwrap()(p_vfs, &mut days), vars::SQLITE_OK);
+            assert_eq!(days, 2_451_544.5);
+
+            let mut buf = [0u8; 16];
+            let n = vfs.xRandomness.unwrap()(p_vfs, buf.len() as c_int, buf.as_mut_ptr().cast());
+            assert_eq!(n, 16);
+            assert_eq!(buf, [7; 16]);
+
+            // sleeping for a day returns immediately
+            assert_eq!(vfs.xSleep.unwrap()(p_vfs, 86_400_000), 86_400_000);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn time_delegates_to_base_vfs() -> rusqlite::Result<()> {
+        register_mock("mock_time_base", NoopHooks);
+        let conn = open_mock("time_base.db", "mock_time_base")?;
+        let year: i64 = conn.query_row("select cast(strftime('%Y', 'now') as int)", [], |r| {
+            r.get(0)
+        })?;
+        assert!(year >= 2024);
+        Ok(())
+    }
+
+    #[test]
+    fn current_time_int64_falls_back_to_base_current_time() {
+        unsafe extern "C" fn j2000(_: *mut ffi::sqlite3_vfs, p_time: *mut f64) -> c_int {
+            unsafe { *p_time = 2451545.25 };
+            vars::SQLITE_OK
+        }
+        unsafe extern "C" fn bogus(_: *mut ffi::sqlite3_vfs, _: *mut i64) -> c_int {
+            vars::SQLITE_ERROR
+        }
+        // register copies of the default vfs which can only report the time
+        // as a julian day number
+        let register_base = |name: &'static CStr, version, x_current_time_int64| {
+            let mut base = unsafe { *ffi::sqlite3_vfs_find(null()) };
+            base.zName = name.as_ptr();
+            base.iVersion = version;
+            base.xCurrentTime = Some(j2000);
+            base.xCurrentTimeInt64 = x_current_time_int64;
+            let rc = unsafe { ffi::sqlite3_vfs_register(Box::leak(Box::new(base)), 0) };
+            assert_eq!(rc, vars::SQLITE_OK);
+        };
+        register_base(c"base_time_v2", 2, None);
+        // version 1 vfs's don't have xCurrentTimeInt64, so it's ignored
+        register_base(c"base_time_v1", 1, Some(bogus));
+
+        for (name, base_vfs) in [
+            (c"mock_time_v2", "base_time_v2"),
+            (c"mock_time_v1", "base_time_v1"),
+        ] {
+            let shared = Arc::new(Mutex::new(MockState::new(Box::new(NoopHooks))));
+            let opts = RegisterOpts {
+                base_vfs: Some(base_vfs),
+                ..Default::default()
+            };
+            let registration =
+                register_static(name.into(), MockVfs::new(shared.clone()), opts).unwrap();
+            shared.lock().setup_logger(registration.logger());
+            let p_vfs = registration.as_ptr();
+            let mut millis = 0;
+            let rc = unsafe { (*p_vfs).xCurrentTimeInt64.unwrap()(p_vfs, &mut millis) };
+            assert_eq!(rc, vars::SQLITE_OK);
+            assert_eq!(millis, 2451545 * 86_400_000 + 21_600_000);
+        }
+    }
+
+    #[test]
+    fn full_pathname_buffer_size() {
+        register_mock("mock_full_pathname", NoopHooks);
+        let name = CString::new("mock_full_pathname").unwrap();
+        let p_vfs = unsafe { ffi::sqlite3_vfs_find(name.as_ptr()) };
+        let x_full_pathname = unsafe { (*p_vfs).xFullPathname.unwrap() };
+        let full_pathname = |n_out: usize| {
+            let mut out = std::vec![0xAAu8; n_out];
+            let rc = unsafe {
+                x_full_pathname(
+                    p_vfs,
+                    c"abcd".as_ptr(),
+                    n_out as c_int,
+                    out.as_mut_ptr().cast(),
+                )
+            };
+            (rc, out)
+        };
+
+        let (rc, _) = full_pathname(0);
+        assert_eq!(rc, vars::SQLITE_CANTOPEN_FULLPATH);
+
+        // no room for the trailing null byte
+        let (rc, out) = full_pathname(4);
+        assert_eq!(rc, vars::SQLITE_CANTOPEN_FULLPATH);
+        assert_eq!(out, [0xAA; 4], "output must not be modified on failure");
+
+        let (rc, out) = full_pathname(5);
+        assert_eq!(rc, vars::SQLITE_OK);
+        assert_eq!(out, b"abcd\0");
+
+        let (rc, out) = full_pathname(8);
+        assert_eq!(rc, vars::SQLITE_OK);
+        assert_eq!(&out[..5], b"abcd\0");
+    }
+
+    #[test]
+    fn full_pathname_too_long() {
+        let shared = Arc::new(Mutex::new(MockState::new(Box::new(NoopHooks))));
+        let opts = RegisterOpts {
+            max_path_len: Some(16),
+            ..Default::default()
+        };
+        let registration = register_static(
+            CString::new("mock_long_path").unwrap(),
+            MockVfs::new(shared.clone()),
+            opts,
+        )
+        .unwrap();
+        shared.lock().setup_logger(registration.logger());
+
+        let err = open_mock("a_name_longer_than_sixteen.db", "mock_long_path").unwrap_err();
+        assert_eq!(
+            err.sqlite_error_code(),
+            Some(rusqlite::ErrorCode::CannotOpen)
+        );
+        // the truncated name must never be opened
+        assert!(shared.lock().file_names().is_empty());
+
+        open_mock("short.db", "mock_long_path").unwrap();
+        assert_eq!(shared.lock().file_names(), ["short.db"]);
+    }
+
+    #[test]
+    fn short_writes_are_retried() -> Result<(), Box<dyn std::error::Error>> {
+        struct H {
+            max_write: Arc<Mutex<Option<usize>>>,
+        }
+        impl Hooks for H {
+            fn max_write(&mut self, _: MockHandle) -> Option<usize> {
+                *self.max_write.lock()
+            }
+        }
+        let max_write = Arc::new(Mutex::new(Some(7)));
+        register_mock("mock_short_write", H { max_write: max_write.clone() });
+        let conn = open_mock("short_write.db", "mock_short_write")?;
+
+        conn.execute("create table t (val text)", [])?;
+        conn.execute("insert into t values (?)", ["x".repeat(10_000)])?;
+        let len: i64 = conn.query_row("select length(val) from t", [], |r| r.get(0))?;
+        assert_eq!(len, 10_000);
+        let integrity: String = conn.query_row("pragma integrity_check", [], |r| r.get(0))?;
+        assert_eq!(integrity, "ok");
+
+        // a write which makes no progress fails rather than looping forever
+        *max_write.lock() = Some(0);
+        let file = main_db_file(&conn);
+        let methods = unsafe { &*(*file).pMethods };
+        let buf = [0_u8; 16];
+        let rc = unsafe { methods.xWrite.unwrap()(file, buf.as_ptr().cast(), 16, 0) };
+        assert_eq!(rc, vars::SQLITE_FULL);
+
+        Ok(())
+    }
+
+    #[test]
+    fn short_reads_are_retried() -> Result<(), Box<dyn std::error::Error>> {
+        struct H {
+            max_read: Arc<Mutex<Option<usize>>>,
+        }
+        impl Hooks for H {
+            fn max_read(&mut self, _: MockHandle) -> Option<usize> {
+                *self.max_read.lock()
+            }
+        }
+        let max_read = Arc::new(Mutex::new(Some(3)));
+        register_mock("mock_short_read", H { max_read: max_read.clone() });
+        let conn = open_mock("short_read.db", "mock_short_read")?;
+        conn.execute("create table t (val text)", [])?;
+        conn.execute("insert into t values (?)", ["x".repeat(10_000)])?;
+        drop(conn);
+
+        let conn = open_mock("short_read.db", "mock_short_read")?;
+        let integrity: String = conn.query_row("pragma integrity_check", [], |r| r.get(0))?;
+        assert_eq!(integrity, "ok");
+
+        let file = main_db_file(&conn);
+        let methods = unsafe { &*(*file).pMethods };
+        let mut buf = [0xAA_u8; 16];
+        let rc = unsafe { methods.xRead.unwrap()(file, buf.as_mut_ptr().cast(), 16, 0) };
+        assert_eq!(rc, vars::SQLITE_OK);
+        assert_eq!(&buf, b"SQLite format 3\0");
+
+        // reads which run past the end of the file are still zero-filled
+        let size: i64 = conn.query_row("pragma page_count", [], |r| r.get(0))?;
+        let offset = size * 4096 - 4;
+        let rc = unsafe { methods.xRead.unwrap()(file, buf.as_mut_ptr().cast(), 16, offset) };
+        assert_eq!(rc, vars::SQLITE_IOERR_SHORT_READ);
+        assert_eq!(buf[4..], [0; 12]);
+
+        Ok(())
+    }
+
+    #[test]
+    fn immutable_files_skip_locking() -> rusqlite::Result<()> {
+        #[derive(Default)]
+        struct Calls {
+            immutable: bool,
+            locks: usize,
+            accesses: Vec<String>,
+        }
+        struct H(Arc<Mutex<Calls>>);
+        impl Hooks for H {
+            fn device_characteristics(&mut self, _: MockHandle) -> VfsResult<i32> {
+                let characteristics = DeviceCharacteristics::from(DEFAULT_DEVICE_CHARACTERISTICS);
+                if self.0.lock().immutable {
+                    return Ok(characteristics.immutable().bits());
+                }
+                Ok(characteristics.bits())
+            }
+            fn lock(&mut self, _: MockHandle, _: LockLevel) -> VfsResult<()> {
+                self.0.lock().locks += 1;
+                Ok(())
+            }
+            fn unlock(&mut self, _: MockHandle, _: LockLevel) {
+                self.0.lock().locks += 1;
+            }
+            fn access(&mut self, path: &str, _: AccessFlags) {
+                self.0.lock().accesses.push(String::from(path));
+            }
+        }
+        let calls = Arc::new(Mutex::new(Calls::default()));
+        register_mock("mock_immutable", H(calls.clone()));
+        let conn = open_mock("immutable.db", "mock_immutable")?;
+        conn.execute_batch("create table t (val int); insert into t values (42);")?;
+        drop(conn);
+        assert!(calls.lock().locks > 0);
+
+        *calls.lock() = Calls { immutable: true, ..Calls::default() };
+        let conn = open_mock("immutable.db", "mock_immutable")?;
+        let val: i64 = conn.query_row("select val from t", [], |r| r.get(0))?;
+        assert_eq!(val, 42);
+        let err = conn.execute("insert into t values (1)", []).unwrap_err();
+        assert_eq!(err.sqlite_error_code(), Some(rusqlite::ErrorCode::ReadOnly));
+        drop(conn);
+
+        // no locks were taken, and no hot journal was looked for
+        let calls = calls.lock();
+        assert_eq!(calls.locks, 0);
+        assert_eq!(calls.accesses, Vec::<String>::new());
+        Ok(())
+    }
+
+    #[test]
+    fn sector_size_is_validated() -> rusqlite::Result<()> {
+        struct H {
+            size: Arc<Mutex<VfsResult<i32>>>,
+        }
+        impl Hooks for H {
+            fn sector_size(&mut self, _: MockHandle) -> VfsResult<i32> {
+                *self.size.lock()
+            }
+        }
+        let size = Arc::new(Mutex::new(Ok(DEFAULT_SECTOR_SIZE)));
+        register_mock("mock_sector_size", H { size: size.clone() });
+        let conn = open_mock("sector_size.db", "mock_sector_size")?;
+        conn.execute("create table t (val int)", [])?;
+        let file = main_db_file(&conn);
+        let x_sector_size = unsafe { (*(*file).pMethods).xSectorSize.unwrap() };
+
+        for valid in [MIN_SECTOR_SIZE, 1024, 4096, MAX_SECTOR_SIZE] {
+            *size.lock() = Ok(valid);
+            assert_eq!(unsafe { x_sector_size(file) }, valid);
+        }
+        let invalid = [
+            Ok(0),
+            Ok(-4096),
+            Ok(256),
+            Ok(1000),
+            Ok(MAX_SECTOR_SIZE * 2),
+            Err(vars::SQLITE_IOERR),
+        ];
+        for invalid in invalid {
+            *size.lock() = invalid;
+            assert_eq!(unsafe { x_sector_size(file) }, DEFAULT_SECTOR_SIZE);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn read_exact_initializes_buffer() {
+        struct H;
+        impl Hooks for H {
+            fn max_read(&mut self, _: MockHandle) -> Option<usize> {
+                Some(3)
+            }
+        }
+        // registering sets up the state's logger
+        let vfs = MockVfs::new(register_mock("mock_read_uninit", H));
+        let opts = OpenOpts::new(
+            vars::SQLITE_OPEN_MAIN_DB | vars::SQLITE_OPEN_READWRITE | vars::SQLITE_OPEN_CREATE,
+        );
+        let mut handle = vfs.open(Some("uninit.db"), opts).unwrap();
+        assert_eq!(vfs.write(&mut handle, 0, b"hello world"), Ok(11));
+
+        let mut buf = [MaybeUninit::uninit(); 8];
+        assert_eq!(vfs.read_exact(&mut handle, 0, &mut buf), Ok(()));
+        assert_eq!(unsafe { assume_init_mut(&mut buf) }, b"hello wo");
+
+        let mut buf = [MaybeUninit::uninit(); 8];
+        assert_eq!(
+            vfs.read_exact(&mut handle, 8, &mut buf),
+            Err(vars::SQLITE_IOERR_SHORT_READ)
+        );
+        assert_eq!(unsafe { assume_init_mut(&mut buf) }, b"rld\0\0\0\0\0");
+    }
+
+    #[test]
+    fn reads_across_eof() {
+        struct H {
+            max_read: Arc<Mutex<Option<usize>>>,
+        }
+        impl Hooks for H {
+            fn max_read(&mut self, _: MockHandle) -> Option<usize> {
+                *self.max_read.lock()
+            }
+        }
+        let max_read = Arc::new(Mutex::new(None));
+        register_mock("mock_read_eof", H { max_read: max_read.clone() });
+        let flags =
+            vars::SQLITE_OPEN_MAIN_DB | vars::SQLITE_OPEN_READWRITE | vars::SQLITE_OPEN_CREATE;
+        let (rc, _, mut file) = raw_open("mock_read_eof", Some(c"read_eof.db"), flags);
+        assert_eq!(rc, vars::SQLITE_OK);
+        let p_file = file.as_mut_ptr().cast::<ffi::sqlite3_file>();
+        let methods = unsafe { &*(*p_file).pMethods };
+        let data = b"0123456789";
+        let rc = unsafe { methods.xWrite.unwrap()(p_file, data.as_ptr().cast(), 10, 0) };
+        assert_eq!(rc, vars::SQLITE_OK);
+
+        // every combination of offset and length around the end of the file,
+        // with the vfs reading as much as it can or a few bytes at a time
+        for max in [None, Some(1), Some(3)] {
+            *max_read.lock() = max;
+            for offset in 0..14 {
+                for len in 0..14 {
+                    let mut buf = [0xaa_u8; 14];
+                    let rc = unsafe {
+                        methods.xRead.unwrap()(p_file, buf.as_mut_ptr().cast(), len, offset)
+                    };
+                    let (offset, len) = (offset as usize, len as usize);
+                    let end = (offset + len).min(data.len());
+                    let read = data.get(offset..end).unwrap_or_default();
+                    let expected_rc = if read.len() == len {
+                        vars::SQLITE_OK
+                    } else {
+                        vars::SQLITE_IOERR_SHORT_READ
+                    };
+                    assert_eq!(rc, expected_rc, "max={max:?} offset={offset} len={len}");
+                    // the unread part of the buffer is zero-filled, and
+                    // nothing past the requested length is touched
+                    assert_eq!(&buf[..read.len()], read);
+                    assert!(buf[read.len()..len].iter().all(|&b| b == 0));
+                    assert!(buf[len..].iter().all(|&b| b == 0xaa));
+                }
+            }
+        }
+        assert_eq!(raw_close(&mut file), vars::SQLITE_OK);
+    }
+
+    #[test]
+    fn lock_busy_invokes_busy_handler() -> Result<(), Box<dyn std::error::Error>> {
+        struct H {
+            busy: Arc<Mutex<usize>>,
+        }
+        impl Hooks for H {
+            fn lock(&mut self, _: MockHandle, level: LockLevel) -> VfsResult<()> {
+                let mut busy = self.busy.lock();
+                if level == LockLevel::Exclusive && *busy > 0 {
+                    *busy -= 1;
+                    return Err(vars::SQLITE_BUSY);
+                }
+                Ok(())
+            }
+        }
+        let busy = Arc::new(Mutex::new(0));
+        register_mock("mock_lock_busy", H { busy: busy.clone() });
+        let conn = open_mock("lock_busy.db", "mock_lock_busy")?;
+        conn.execute("create table t (val int)", [])?;
+
+        // without a busy handler the statement fails immediately. rusqlite
+        // installs a busy timeout by default, so clear it first
+        conn.busy_timeout(core::time::Duration::ZERO)?;
+        *busy.lock() = 1;
+        let err = conn.execute("insert into t values (1)", []).unwrap_err();
+        assert_eq!(
+            err.sqlite_error_code(),
+            Some(rusqlite::ErrorCode::DatabaseBusy)
+        );
+        assert_eq!(*busy.lock(), 0);
+
+        // with a busy timeout SQLite retries the lock until it succeeds
+        conn.busy_timeout(core::time::Duration::from_secs(5))?;
+        *busy.lock() = 3;
+        conn.execute("insert into t values (2)", [])?;
+        assert_eq!(*busy.lock(), 0);
+        let vals: i64 = conn.query_row("select sum(val) from t", [], |r| r.get(0))?;
+        assert_eq!(vals, 2);
+
+        Ok(())
+    }
+
+    #[test]
+    fn busy_timeout_sleeps_through_vfs() -> Result<(), Box<dyn std::error::Error>> {
+        struct H {
+            busy: Arc<Mutex<usize>>,
+            sleeps: Arc<Mutex<Vec<i32>>>,
+        }
+        impl Hooks for H {
+            fn lock(&mut self, _: MockHandle, level: LockLevel) -> VfsResult<()> {
+                let mut busy = self.busy.lock();
+                if level == LockLevel::Exclusive && *busy > 0 {
+                    *busy -= 1;
+                    return Err(vars::SQLITE_BUSY);
+                }
+                Ok(())
+            }
+            fn sleep(&mut self, micros: i32) -> VfsResult<i32> {
+                self.sleeps.lock().push(micros);
+                Ok(micros)
+            }
+        }
+        let busy = Arc::new(Mutex::new(0));
+        let sleeps = Arc::new(Mutex::new(Vec::new()));
+        register_mock(
+            "mock_busy_sleep",
+            H {
+                busy: busy.clone(),
+                sleeps: sleeps.clone(),
+            },
+        );
+        let conn = open_mock("busy_sleep.db", "mock_busy_sleep")?;
+        conn.execute("create table t (val int)", [])?;
+
+        // SQLite backs off between retries until the lock is acquired
+        conn.busy_timeout(core::time::Duration::from_secs(5))?;
+        *busy.lock() = 4;
+        conn.execute("insert into t values (1)", [])?;
+        assert_eq!(*sleeps.lock(), [1000, 2000, 5000, 10_000]);
+
+        // and gives up once the sleeps add up to the timeout, without any
+        // time actually passing
+        sleeps.lock().clear();
+        conn.busy_timeout(core::time::Duration::from_millis(20))?;
+        *busy.lock() = usize::MAX;
+        let err = conn.execute("insert into t values (2)", []).unwrap_err();
+        assert_eq!(
+            err.sqlite_error_code(),
+            Some(rusqlite::ErrorCode::DatabaseBusy)
+        );
+        assert_eq!(*sleeps.lock(), [1000, 2000, 5000, 10_000, 2000]);
+        Ok(())
+    }
+
+    #[test]
+    fn cancellation_aborts_io() -> Result<(), Box<dyn std::error::Error>> {
+        /// a read which blocks until the token is cancelled, like a request
+        /// to a remote store which stopped responding
+        struct H {
+            token: CancellationToken,
+            block: Arc<AtomicBool>,
+            reads: Arc<AtomicUsize>,
+        }
+        impl Hooks for H {
+            fn read(&mut self, _: MockHandle, _: usize, _: &[u8]) {
+                self.reads.fetch_add(1, Ordering::SeqCst);
+                while self.block.load(Ordering::SeqCst) && !self.token.is_cancelled() {
+                    std::thread::sleep(core::time::Duration::from_millis(1));
+                }
+            }
+        }
+        let token = CancellationToken::new();
+        let block = Arc::new(AtomicBool::new(false));
+        let reads = Arc::new(AtomicUsize::new(0));
+        let hooks = H {
+            token: token.clone(),
+            block: block.clone(),
+            reads: reads.clone(),
+        };
+        let shared = Arc::new(Mutex::new(MockState::new(Box::new(hooks))));
+        let opts = RegisterOpts {
+            cancellation: Some(token.clone()),
+            ..Default::default()
+        };
+        let registration = register_static(
+            CString::new("mock_cancel").unwrap(),
+            MockVfs::new(shared.clone()),
+            opts,
+        )
+        .unwrap();
+        shared.lock().setup_logger(registration.logger());
+        let conn = open_mock("cancel.db", "mock_cancel")?;
+        conn.execute("create table t (val int)", [])?;
+        conn.execute("insert into t values (1)", [])?;
+
+        // the blocked read returns once the token is cancelled, and fails
+        block.store(true, Ordering::SeqCst);
+        let canceller = std::thread::spawn({
+            let token = token.clone();
+            move || {
+                std::thread::sleep(core::time::Duration::from_millis(20));
+                token.cancel();
+            }
+        });
+        let err = conn
+            .query_row("select val from t", [], |r| r.get::<_, i64>(0))
+            .unwrap_err();
+        assert_eq!(
+            err.sqlite_error_code(),
+            Some(rusqlite::ErrorCode::SystemIoFailure)
+        );
+        canceller.join().unwrap();
+
+        // further reads and writes fail without reaching the vfs
+        block.store(false, Ordering::SeqCst);
+        let before = reads.load(Ordering::SeqCst);
+        let err = conn
+            .query_row("select val from t", [], |r| r.get::<_, i64>(0))
+            .unwrap_err();
+        assert_eq!(
+            err.sqlite_error_code(),
+            Some(rusqlite::ErrorCode::SystemIoFailure)
+        );
+        assert!(conn.execute("insert into t values (2)", []).is_err());
+        assert_eq!(reads.load(Ordering::SeqCst), before);
+
+        // and work again once the token is reset
+        token.reset();
+        let vals: Vec<i64> = conn
+            .prepare("select val from t")?
+            .query_map([], |r| r.get(0))?
+            .collect::<Result<_, _>>()?;
+        assert_eq!(vals, [1]);
+        Ok(())
+    }
+
+    #[test]
+    fn lock_waits_with_busy_handler() -> Result<(), Box<dyn std::error::Error>> {
+        /// a lock which waits for contention to clear, invoking the busy
+        /// handler each time it finds the lock held
+        struct H {
+            handler: Option<BusyHandler>,
+            busy: Arc<Mutex<usize>>,
+            invoked: Arc<Mutex<usize>>,
+        }
+        impl Hooks for H {
+            fn set_busy_handler(&mut self, _: MockHandle, handler: BusyHandler) {
+                self.handler = Some(handler);
+            }
+            fn lock(&mut self, _: MockHandle, level: LockLevel) -> VfsResult<()> {
+                if level != LockLevel::Exclusive {
+                    return Ok(());
+                }
+                while *self.busy.lock() > 0 {
+                    let handler = self.handler.ok_or(vars::SQLITE_BUSY)?;
+                    *self.invoked.lock() += 1;
+                    if !unsafe { handler.invoke() } {
+                        return Err(vars::SQLITE_BUSY);
+                    }
+                    *self.busy.lock() -= 1;
+                }
+                Ok(())
+            }
+        }
+        let busy = Arc::new(Mutex::new(0));
+        let invoked = Arc::new(Mutex::new(0));
+        let hooks = H {
+            handler: None,
+            busy: busy.clone(),
+            invoked: invoked.clone(),
+        };
+        register_mock("mock_busy_handler", hooks);
+        let conn = open_mock("busy_handler.db", "mock_busy_handler")?;
+        conn.execute("create table t (val int)", [])?;
+
+        // a handler which always retries keeps the lock waiting. rusqlite's
+        // busy_timeout handler can't be used here since it calls xSleep, which
+        // would reenter the mock while its state is locked.
+        conn.busy_handler(Some(|_| true))?;
+        *busy.lock() = 3;
+        conn.execute("insert into t values (1)", [])?;
+        assert_eq!((*busy.lock(), *invoked.lock()), (0, 3));
+
+        // without one the handler gives up straight away
+        conn.busy_handler(None)?;
+        *busy.lock() = 1;
+        *invoked.lock() = 0;
+        let err = conn.execute("insert into t values (2)", []).unwrap_err();
+        assert_eq!(
+            err.sqlite_error_code(),
+            Some(rusqlite::ErrorCode::DatabaseBusy)
+        );
+        assert_eq!(*invoked.lock(), 1);
+        Ok(())
+    }
+
+    #[test]
+    fn post_open_adjusts_out_flags() {
+        struct H;
+        impl Hooks for H {
+            fn post_open(&mut self, _: MockHandle, flags: &mut i32) {
+                *flags |= vars::SQLITE_OPEN_MEMORY;
+            }
+        }
+        register_mock("mock_post_open", H);
+
+        let flags = vars::SQLITE_OPEN_MAIN_DB | vars::SQLITE_OPEN_READWRITE;
+        let (rc, out_flags, mut file) = raw_open(
+            "mock_post_open",
+            Some(c"post_open.db"),
+            flags | vars::SQLITE_OPEN_CREATE,
+        );
+        assert_eq!(rc, vars::SQLITE_OK);
+        assert_eq!(out_flags, flags | vars::SQLITE_OPEN_MEMORY);
+        assert_eq!(raw_close(&mut file), vars::SQLITE_OK);
+    }
+
+    #[test]
+    fn mock_in_memory_out_flags() {
+        register_mock("mock_in_memory", NoopHooks);
+
+        let flags = vars::SQLITE_OPEN_MAIN_DB | vars::SQLITE_OPEN_READWRITE;
+        for flags in [flags, flags | vars::SQLITE_OPEN_MEMORY] {
+            let (rc, out_flags, mut file) = raw_open(
+                "mock_in_memory",
+                Some(c"in_memory.db"),
+                flags | vars::SQLITE_OPEN_CREATE,
+            );
+            assert_eq!(rc, vars::SQLITE_OK);
+            assert_eq!(out_flags, flags);
+            assert_eq!(raw_close(&mut file), vars::SQLITE_OK);
+        }
+    }
+
+    #[test]
+    fn out_flags_reflect_handle() {
+        struct Handle {
+            readonly: bool,
+            in_memory: bool,
+        }
+        impl VfsHandle for Handle {
+            fn readonly(&self) -> bool {
+                self.readonly
+            }
+            fn in_memory(&self) -> bool {
+                self.in_memory
+            }
+        }
+        let out_flags =
+            |flags, readonly, in_memory| open_out_flags(flags, &Handle { readonly, in_memory });
+        let main_db = vars::SQLITE_OPEN_MAIN_DB;
+        let rwc = vars::SQLITE_OPEN_READWRITE | vars::SQLITE_OPEN_CREATE;
+
+        // readonly memory
+        assert_eq!(
+            out_flags(main_db | rwc, true, true),
+            main_db | vars::SQLITE_OPEN_READONLY | vars::SQLITE_OPEN_MEMORY
+        );
+        // readwrite disk
+        assert_eq!(
+            out_flags(main_db | rwc, false, false),
+            main_db | vars::SQLITE_OPEN_READWRITE
+        );
+        // readonly disk, whether or not readonly was requested
+        for flags in [vars::SQLITE_OPEN_READONLY, rwc] {
+            assert_eq!(
+                out_flags(main_db | flags, true, false),
+                main_db | vars::SQLITE_OPEN_READONLY
+            );
+        }
+    }
+
+    #[test]
+    fn anonymous_temp_file() {
+        struct H {
+            temp_files: Arc<Mutex<Vec<bool>>>,
+        }
+        impl Hooks for H {
+            fn open(&mut self, _: &Option<&str>, opts: &OpenOpts) {
+                self.temp_files.lock().push(opts.is_temp_file());
+            }
+        }
+        let temp_files = Arc::new(Mutex::new(Vec::new()));
+        let shared = register_mock("mock_temp_file", H { temp_files: temp_files.clone() });
+
+        let flags = vars::SQLITE_OPEN_TEMP_DB
+            | vars::SQLITE_OPEN_READWRITE
+            | vars::SQLITE_OPEN_CREATE
+            | vars::SQLITE_OPEN_DELETEONCLOSE;
+        let (rc, _, mut file) = raw_open("mock_temp_file", None, flags);
+        assert_eq!(rc, vars::SQLITE_OK);
+        assert_eq!(shared.lock().file_names().len(), 1);
+
+        let p_file = file.as_mut_ptr().cast::<ffi::sqlite3_file>();
+        let data = [1_u8; 8];
+        let rc =
+            unsafe { (*(*p_file).pMethods).xWrite.unwrap()(p_file, data.as_ptr().cast(), 8, 0) };
+        assert_eq!(rc, vars::SQLITE_OK);
+        assert_eq!(raw_close(&mut file), vars::SQLITE_OK);
+
+        // a named main db is not a temp file
+        let flags =
+            vars::SQLITE_OPEN_MAIN_DB | vars::SQLITE_OPEN_READWRITE | vars::SQLITE_OPEN_CREATE;
+        let (rc, _, mut file) = raw_open("mock_temp_file", Some(c"temp_file.db"), flags);
+        assert_eq!(rc, vars::SQLITE_OK);
+        assert_eq!(raw_close(&mut file), vars::SQLITE_OK);
+
+        assert_eq!(*temp_files.lock(), [true, false]);
+        assert_eq!(shared.lock().file_names(), ["temp_file.db"]);
+        // every temp file has been closed, so the vfs can be unregistered
+        let rc = unsafe { unregister("mock_temp_file") };
+        assert_eq!(rc, Ok(()));
+    }
+
+    #[test]
+    fn on_register_receives_name_and_db() {
+        struct H {
+            registered: Arc<Mutex<Vec<(String, usize)>>>,
+        }
+        impl Hooks for H {
+            fn on_register(&mut self, name: &str, db: *mut c_void) {
+                self.registered.lock().push((name.into(), db as usize));
+            }
+        }
+        let registered = Arc::new(Mutex::new(Vec::new()));
+        let hooks = H { registered: registered.clone() };
+        let shared = Arc::new(Mutex::new(MockState::new(Box::new(hooks))));
+        let mut fake_db = 0_u8;
+        let fake_db: *mut c_void = (&raw mut fake_db).cast();
+        register_inner(
+            SqliteApi::new_static(),
+            CString::new("mock_on_register").unwrap(),
+            MockVfs::new(shared.clone()),
+            RegisterOpts::default(),
+            fake_db,
+        )
+        .unwrap();
+        register_static(
+            CString::new("mock_on_register_static").unwrap(),
+            MockVfs::new(shared.clone()),
+            RegisterOpts::default(),
+        )
+        .unwrap();
+        assert_eq!(
+            *registered.lock(),
+            [
+                ("mock_on_register".into(), fake_db as usize),
+                ("mock_on_register_static".into(), 0)
+            ]
+        );
+    }
+
+    #[cfg(feature = "dynamic")]
+    #[test]
+    fn missing_api_routine() {
+        // Safety: every field of sqlite3_api_routines is an Option
+        let mut api: ffi::sqlite3_api_routines = unsafe { core::mem::zeroed() };
+        let missing = |api: &ffi::sqlite3_api_routines| {
+            unsafe { SqliteApi::try_new_dynamic(api) }
+                .err()
+                .map(|err| err.name)
+        };
+        assert_eq!(missing(&api), Some("vfs_register"));
+        api.vfs_register = Some(ffi::sqlite3_vfs_register);
+        api.vfs_unregister = Some(ffi::sqlite3_vfs_unregister);
+        api.vfs_find = Some(ffi::sqlite3_vfs_find);
+        assert_eq!(missing(&api), Some("mprintf"));
+        assert_eq!(
+            unsafe { SqliteApi::new_dynamic(&api) }.err(),
+            Some(vars::SQLITE_INTERNAL)
+        );
+        api.mprintf = Some(ffi::sqlite3_mprintf);
+        api.log = Some(ffi::sqlite3_log);
+        api.libversion_number = Some(ffi::sqlite3_libversion_number);
+        api.uri_parameter = Some(ffi::sqlite3_uri_parameter);
+        assert_eq!(missing(&api), Some("uri_boolean"));
+        api.uri_boolean = Some(ffi::sqlite3_uri_boolean);
+        api.uri_int64 = Some(ffi::sqlite3_uri_int64);
+        assert_eq!(missing(&api), None);
+    }
+
+    #[test]
+    fn register_rejects_old_sqlite() {
+        unsafe extern "C" fn old_version() -> c_int {
+            3036000
+        }
+        let mut api = SqliteApi::new_static();
+        assert_eq!(api.version_number(), sqlite_version_number());
+        assert_eq!(sqlite_version_number(), rusqlite::version_number());
+
+        api.libversion_number = old_version;
+        assert_eq!(api.version_number(), 3036000);
+        let vfs = MockVfs::new(Arc::new(Mutex::new(MockState::new(Box::new(NoopHooks)))));
+        let result = register_inner(
+            api,
+            CString::new("mock_old_sqlite").unwrap(),
+            vfs,
+            RegisterOpts::default(),
+            null_mut(),
+        );
+        assert_eq!(result.err(), Some(vars::SQLITE_ERROR));
+        assert!(unsafe { ffi::sqlite3_vfs_find(c"mock_old_sqlite".as_ptr()) }.is_null());
+    }
+
+    #[cfg(feature = "dynamic")]
+    #[test]
+    fn register_dynamic_rejects_old_sqlite() {
+        unsafe extern "C" fn old_version() -> c_int {
+            3036000
+        }
+        // Safety: every field of sqlite3_api_routines is an Option
+        let mut api: ffi::sqlite3_api_routines = unsafe { core::mem::zeroed() };
+        api.vfs_register = Some(ffi::sqlite3_vfs_register);
+        api.vfs_unregister = Some(ffi::sqlite3_vfs_unregister);
+        api.vfs_find = Some(ffi::sqlite3_vfs_find);
+        api.mprintf = Some(ffi::sqlite3_mprintf);
+        api.log = Some(ffi::sqlite3_log);
+        api.libversion_number = Some(old_version);
+        api.uri_parameter = Some(ffi::sqlite3_uri_parameter);
+        api.uri_boolean = Some(ffi::sqlite3_uri_boolean);
+        api.uri_int64 = Some(ffi::sqlite3_uri_int64);
+
+        let vfs = MockVfs::new(Arc::new(Mutex::new(MockState::new(Box::new(NoopHooks)))));
+        let result = unsafe {
+            register_dynamic(
+                &raw mut api,
+                CString::new("mock_old_sqlite_dynamic").unwrap(),
+                vfs,
+                RegisterOpts::default(),
+            )
+        };
+        assert_eq!(result.err(), Some(vars::SQLITE_ERROR));
+        let name = c"mock_old_sqlite_dynamic";
+        assert!(unsafe { ffi::sqlite3_vfs_find(name.as_ptr()) }.is_null());
+    }
+
+    #[test]
+    fn register_rejects_invalid_names() {
+        let register = |name: &str, base_vfs| {
+            let shared = Arc::new(Mutex::new(MockState::new(Box::new(NoopHooks))));
+            let opts = RegisterOpts { base_vfs, ..Default::default() };
+            let vfs = MockVfs::new(shared.clone());
+            let registration = register_static(CString::new(name).unwrap(), vfs, opts)?;
+            shared.lock().setup_logger(registration.logger());
+            Ok::<_, SqliteErr>(())
+        };
+        assert_eq!(register("", None), Err(vars::SQLITE_MISUSE));
+        let long_name = "v".repeat(MAX_VFS_NAME_LEN + 1);
+        assert_eq!(register(&long_name, None), Err(vars::SQLITE_MISUSE));
+        assert_eq!(
+            register("mock_nul_base", Some("unix\0dotfile")),
+            Err(vars::SQLITE_MISUSE)
+        );
+
+        // nothing was registered
+        let long_name = CString::new(long_name).unwrap();
+        assert!(unsafe { ffi::sqlite3_vfs_find(long_name.as_ptr()) }.is_null());
+        assert!(unsafe { ffi::sqlite3_vfs_find(c"mock_nul_base".as_ptr()) }.is_null());
+
+        let max_name = "v".repeat(MAX_VFS_NAME_LEN);
+        register(&max_name, None).unwrap();
+        assert_eq!(unsafe { unregister(&max_name) }, Ok(()));
+    }
+
+    #[test]
+    fn registration_handle() {
+        let shared = Arc::new(Mutex::new(MockState::new(Box::new(NoopHooks))));
+        let registration = register_static(
+            CString::new("mock_registration").unwrap(),
+            MockVfs::new(shared.clone()),
+            RegisterOpts::default(),
+        )
+        .unwrap();
+        shared.lock().setup_logger(registration.logger());
+
+        assert_eq!(registration.name(), c"mock_registration");
+        let found = unsafe { ffi::sqlite3_vfs_find(c"mock_registration".as_ptr()) };
+        assert_eq!(registration.as_ptr(), found);
+
+        assert_eq!(unsafe { registration.unregister() }, Ok(()));
+        assert!(unsafe { ffi::sqlite3_vfs_find(c"mock_registration".as_ptr()) }.is_null());
+        assert_eq!(
+            unsafe { unregister("mock_registration") },
+            Err(vars::SQLITE_NOTFOUND)
+        );
+
+        // the registration outlives unregistering the vfs by name
+        let registration = register_static(
+            CString::new("mock_registration_by_name").unwrap(),
+            MockVfs::new(shared.clone()),
+            RegisterOpts::default(),
+        )
+        .unwrap();
+        assert_eq!(unsafe { unregister("mock_registration_by_name") }, Ok(()));
+        assert_eq!(registration.name(), c"mock_registration_by_name");
+        assert!(format!("{registration:?}").contains("mock_registration_by_name"));
+    }
+
+    #[test]
+    fn register_clones_under_multiple_names() -> rusqlite::Result<()> {
+        let shared = Arc::new(Mutex::new(MockState::new(Box::new(NoopHooks))));
+        let vfs = MockVfs::new(shared.clone());
+        for name in ["mock_shared_a", "mock_shared_b"] {
+            let name = CString::new(name).unwrap();
+            let registration = register_static(name, vfs.clone(), RegisterOpts::default()).unwrap();
+            shared.lock().setup_logger(registration.logger());
+        }
+        drop(vfs);
+
+        let conn = open_mock("shared.db", "mock_shared_a")?;
+        conn.execute("create table t (val int)", [])?;
+        conn.execute("insert into t values (42)", [])?;
+        drop(conn);
+
+        // the second registration sees the state written through the first
+        unsafe { unregister("mock_shared_a") }.unwrap();
+        let conn = open_mock("shared.db", "mock_shared_b")?;
+        let val: i64 = conn.query_row("select val from t", [], |r| r.get(0))?;
+        assert_eq!(val, 42);
+        drop(conn);
+
+        unsafe { unregister("mock_shared_b") }.unwrap();
+        assert_eq!(Arc::strong_count(&shared), 1);
+        Ok(())
+    }
+
+    #[test]
+    fn register_io_methods_version() -> rusqlite::Result<()> {
+        let register = |name: &str, io_methods_version| {
+            let shared = Arc::new(Mutex::new(MockState::new(Box::new(NoopHooks))));
+            let opts = RegisterOpts { io_methods_version, ..Default::default() };
+            let registration = register_static(
+                CString::new(name).unwrap(),
+                MockVfs::new(shared.clone()),
+                opts,
+            )?;
+            shared.lock().setup_logger(registration.logger());
+            Ok::<_, SqliteErr>(())
+        };
+        assert_eq!(register("mock_io_v0", Some(0)), Err(vars::SQLITE_MISUSE));
+        assert_eq!(register("mock_io_v4", Some(4)), Err(vars::SQLITE_MISUSE));
+
+        register("mock_io_v1", Some(1)).unwrap();
+        let conn = open_mock("io_v1.db", "mock_io_v1")?;
+        conn.execute("create table t (val int)", [])?;
+        let methods = unsafe { &*(*main_db_file(&conn)).pMethods };
+        assert_eq!(methods.iVersion, 1);
+        assert!(methods.xShmMap.is_none() && methods.xFetch.is_none());
+        // without shared memory SQLite refuses to switch to WAL
+        let mode: String = conn.query_row("pragma journal_mode = wal", [], |r| r.get(0))?;
+        assert_eq!(mode, "delete");
+
+        register("mock_io_v2", Some(2)).unwrap();
+        let conn = open_mock("io_v2.db", "mock_io_v2")?;
+        conn.execute("create table t (val int)", [])?;
+        let methods = unsafe { &*(*main_db_file(&conn)).pMethods };
+        assert_eq!(methods.iVersion, 2);
+        assert!(methods.xShmMap.is_some() && methods.xFetch.is_none());
+        Ok(())
+    }
+
+    #[test]
+    fn capabilities_decide_io_methods_version() -> rusqlite::Result<()> {
+        struct H(VfsCapabilities);
+        impl Hooks for H {
+            fn capabilities(&mut self) -> VfsCapabilities {
+                self.0
+            }
+        }
+        let caps = VfsCapabilities::new;
+        for (name, capabilities, version) in [
+            ("mock_caps_none", caps(), 1),
+            ("mock_caps_batch", caps().batch_atomic(), 1),
+            ("mock_caps_shm", caps().shm(), 2),
+            ("mock_caps_fetch", caps().fetch(), 3),
+            ("mock_caps_all", caps().shm().fetch(), 3),
+        ] {
+            register_mock(name, H(capabilities));
+            let conn = open_mock(&format!("{name}.db"), name)?;
+            conn.execute("create table t (val int)", [])?;
+            let methods = unsafe { &*(*main_db_file(&conn)).pMethods };
+            assert_eq!(methods.iVersion, version, "{capabilities:?}");
+            assert_eq!(methods.xShmMap.is_some(), version >= 2);
+            assert_eq!(methods.xFetch.is_some(), version >= 3);
+        }
+
+        // an explicit version overrides the capabilities
+        let shared = Arc::new(Mutex::new(MockState::new(Box::new(H(caps())))));
+        let opts = RegisterOpts {
+            io_methods_version: Some(2),
+            ..Default::default()
+        };
+        let registration = register_static(
+            CString::new("mock_caps_v2").unwrap(),
+            MockVfs::new(shared.clone()),
+            opts,
+        )
+        .unwrap();
+        shared.lock().setup_logger(registration.logger());
+        let conn = open_mock("caps_v2.db", "mock_caps_v2")?;
+        conn.execute("create table t (val int)", [])?;
+        let methods = unsafe { &*(*main_db_file(&conn)).pMethods };
+        assert_eq!(methods.iVersion, 2);
+        Ok(())
+    }
+
+    #[test]
+    fn canonical_path_prepends_prefix() -> rusqlite::Result<()> {
+        struct H;
+        impl Hooks for H {
+            fn canonical_path(&mut self, path: &str) -> Option<String> {
+                (!path.starts_with('/')).then(|| format!("/prefix/{path}"))
+            }
+        }
+        let shared = register_mock("mock_prefix", H);
+        let conn = open_mock("relative.db", "mock_prefix")?;
+        conn.execute("create table t (val int)", [])?;
+        let path: String =
+            conn.query_row("select file from pragma_database_list", [], |r| r.get(0))?;
+        assert_eq!(path, "/prefix/relative.db");
+        assert!(shared.lock().file_names().contains(&"/prefix/relative.db"));
+        Ok(())
+    }
+
+    #[test]
+    fn same_file_compares_canonical_paths() {
+        struct H;
+        impl Hooks for H {
+            fn canonical_path(&mut self, path: &str) -> Option<String> {
+                path.strip_prefix("./").map(String::from)
+            }
+        }
+        let vfs = MockVfs::new(register_mock("mock_same_file", H));
+        assert_eq!(vfs.same_file("same.db", "./same.db"), Ok(true));
+        assert_eq!(vfs.same_file("same.db", "same.db"), Ok(true));
+        assert_eq!(vfs.same_file("same.db", "./other.db"), Ok(false));
+    }
+
+    #[test]
+    fn truncate_respects_min_file_size() -> Result<(), Box<dyn std::error::Error>> {
+        struct H;
+        impl Hooks for H {
+            fn min_file_size(&mut self, _: MockHandle) -> usize {
+                100
+            }
+        }
+        register_mock("mock_min_file_size", H);
+        let conn = open_mock("min_file_size.db", "mock_min_file_size")?;
+        conn.execute("create table t (val int)", [])?;
+
+        let file = main_db_file(&conn);
+        let x_truncate = unsafe { (*(*file).pMethods).xTruncate.unwrap() };
+        assert_eq!(unsafe { x_truncate(file, 99) }, vars::SQLITE_IOERR_TRUNCATE);
+        assert_eq!(unsafe { x_truncate(file, 100) }, vars::SQLITE_OK);
+        Ok(())
+    }
+
+    #[test]
+    fn sparse_regions_read_as_zeros() -> Result<(), Box<dyn std::error::Error>> {
+        register_mock("mock_sparse", NoopHooks);
+        let conn = open_mock("sparse.db", "mock_sparse")?;
+        conn.execute("create table t (val int)", [])?;
+
+        let file = main_db_file(&conn);
+        let methods = unsafe { &*(*file).pMethods };
+        let file_size = || unsafe {
+            let mut size: ffi::sqlite3_int64 = 0;
+            assert_eq!(methods.xFileSize.unwrap()(file, &mut size), vars::SQLITE_OK);
+            size
+        };
+        let read = |offset: ffi::sqlite3_int64| unsafe {
+            let mut buf = [0xAA_u8; 100];
+            let rc = methods.xRead.unwrap()(file, buf.as_mut_ptr().cast(), 100, offset);
+            (rc, buf)
+        };
+        let size = file_size();
+
+        // growing the file with truncate exposes a zero-filled region
+        let rc = unsafe { methods.xTruncate.unwrap()(file, size + 4096) };
+        assert_eq!(rc, vars::SQLITE_OK);
+        assert_eq!(file_size(), size + 4096);
+        let (rc, buf) = read(size + 1000);
+        assert_eq!(rc, vars::SQLITE_OK);
+        assert!(buf.iter().all(|&b| b == 0));
+
+        // a write past EOF leaves a zero-filled gap
+        let data = [1_u8; 10];
+        let offset = size + 8192;
+        let rc = unsafe { methods.xWrite.unwrap()(file, data.as_ptr().cast(), 10, offset) };
+        assert_eq!(rc, vars::SQLITE_OK);
+        let (rc, buf) = read(offset - 90);
+        assert_eq!(rc, vars::SQLITE_OK);
+        assert!(buf[..90].iter().all(|&b| b == 0));
+        assert_eq!(buf[90..], data);
+
+        // reads straddling the logical EOF are short and zero-filled
+        let (rc, buf) = read(offset + 5);
+        assert_eq!(rc, vars::SQLITE_IOERR_SHORT_READ);
+        assert_eq!(buf[..5], data[..5]);
+        assert!(buf[5..].iter().all(|&b| b == 0));
+
+        Ok(())
+    }
+
+    #[test]
+    fn close_error_releases_file() {
+        struct H;
+        impl Hooks for H {
+            fn close(&mut self, _: MockHandle) -> VfsResult<()> {
+                Err(vars::SQLITE_IOERR_CLOSE)
+            }
+        }
+        let shared = register_mock("mock_close_error", H);
+        let flags = vars::SQLITE_OPEN_MAIN_DB
+            | vars::SQLITE_OPEN_READWRITE
+            | vars::SQLITE_OPEN_CREATE
+            | vars::SQLITE_OPEN_DELETEONCLOSE;
+        let (rc, _, mut file) = raw_open("mock_close_error", Some(c"close_error.db"), flags);
+        assert_eq!(rc, vars::SQLITE_OK);
+
+        // the error is reported, but the file is closed and cleaned up anyway
+        assert_eq!(raw_close(&mut file), vars::SQLITE_IOERR_CLOSE);
+        let p_file = file.as_mut_ptr().cast::<ffi::sqlite3_file>();
+        assert!(unsafe { (*p_file).pMethods }.is_null());
+        assert!(shared.lock().file_names().is_empty());
+        assert_eq!(unsafe { unregister("mock_close_error") }, Ok(()));
+    }
+
+    #[test]
+    fn file_control_persist_wal_and_powersafe_overwrite() -> rusqlite::Result<()> {
+        register_mock("mock_fcntl_flags", NoopHooks);
+        let conn = open_mock("fcntl_flags.db", "mock_fcntl_flags")?;
+        conn.execute("create table t (val int)", [])?;
+        let file_control = |op, mut arg: c_int| {
+            let rc = unsafe {
+                rusqlite::ffi::sqlite3_file_control(
+                    conn.handle(),
+                    c"main".as_ptr(),
+                    op,
+                    (&raw mut arg).cast(),
+                )
+            };
+            (rc, arg)
+        };
+
+        // MockVfs advertises SQLITE_IOCAP_POWERSAFE_OVERWRITE
+        assert_eq!(
+            file_control(vars::SQLITE_FCNTL_POWERSAFE_OVERWRITE, -1),
+            (vars::SQLITE_OK, 1)
+        );
+        assert_eq!(
+            file_control(vars::SQLITE_FCNTL_PERSIST_WAL, -1),
+            (vars::SQLITE_OK, 0)
+        );
+        // setting either flag is unsupported by default
+        assert_eq!(
+            file_control(vars::SQLITE_FCNTL_PERSIST_WAL, 1).0,
+            vars::SQLITE_NOTFOUND
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn file_control_temp_filename() -> rusqlite::Result<()> {
+        struct H {
+            temp_files: Arc<Mutex<usize>>,
+        }
+        impl Hooks for H {
+            fn open(&mut self, _: &Option<&str>, opts: &OpenOpts) {
+                if opts.is_temp_file() {
+                    *self.temp_files.lock() += 1;
+                }
+            }
+            fn temp_filename(&mut self) -> VfsResult<String> {
+                Ok("custom-temp".into())
+            }
+        }
+        let temp_files = Arc::new(Mutex::new(0));
+        register_mock("mock_temp_filename", H { temp_files: temp_files.clone() });
+        register_mock("mock_temp_filename_default", NoopHooks);
+
+        let temp_filename = |conn: &Connection| {
+            let mut p: *mut c_char = null_mut();
+            let rc = unsafe {
+                rusqlite::ffi::sqlite3_file_control(
+                    conn.handle(),
+                    c"main".as_ptr(),
+                    vars::SQLITE_FCNTL_TEMPFILENAME,
+                    (&raw mut p).cast(),
+                )
+            };
+            assert_eq!(rc, vars::SQLITE_OK);
+            let name = String::from(unsafe { CStr::from_ptr(p) }.to_str().unwrap());
+            unsafe { rusqlite::ffi::sqlite3_free(p.cast()) };
+            name
+        };
+
+        let conn = open_mock("temp_filename.db", "mock_temp_filename")?;
+        conn.execute("create table t (val int)", [])?;
+        assert_eq!(temp_filename(&conn), "custom-temp");
+
+        // SQLite opens temporary files without a name rather than asking for one
+        conn.execute_batch(
+            "pragma temp_store = file;
+            create temp table tt (val text);
+            pragma temp.cache_size = 2;
+            with recursive n(i) as (select 1 union all select i + 1 from n where i < 1000)
+            insert into tt select printf('%0100d', i) from n;",
+        )?;
+        assert!(*temp_files.lock() > 0);
+
+        let conn = open_mock("temp_filename_default.db", "mock_temp_filename_default")?;
+        conn.execute("create table t (val int)", [])?;
+        let (a, b) = (temp_filename(&conn), temp_filename(&conn));
+        assert!(a.starts_with("etilqs_"), "{a}");
+        assert_ne!(a, b);
+
+        // without randomness the default name can't be generated
+        struct NoRandomness;
+        impl Hooks for NoRandomness {
+            fn randomness(&mut self, _: &mut [u8]) -> VfsResult<usize> {
+                Err(vars::SQLITE_IOERR)
+            }
+        }
+        register_mock("mock_temp_filename_no_randomness", NoRandomness);
+        let conn = open_mock(
+            "temp_filename_no_randomness.db",
+            "mock_temp_filename_no_randomness",
+        )?;
+        conn.execute("create table t (val int)", [])?;
+        let mut p: *mut c_char = null_mut();
+        let rc = unsafe {
+            rusqlite::ffi::sqlite3_file_control(
+                conn.handle(),
+                c"main".as_ptr(),
+                vars::SQLITE_FCNTL_TEMPFILENAME,
+                (&raw mut p).cast(),
+            )
+        };
+        assert_eq!(rc, vars::SQLITE_IOERR);
+        assert!(p.is_null());
+        Ok(())
+    }
+
+    #[test]
+    fn data_version_tracks_other_connections() -> rusqlite::Result<()> {
+        register_mock("mock_data_version", NoopHooks);
+        let a = open_mock("data_version.db", "mock_data_version")?;
+        let b = open_mock("data_version.db", "mock_data_version")?;
+        a.execute("create table t (val int)", [])?;
+        let data_version = |conn: &Connection| {
+            let mut version: u32 = 0;
+            let rc = unsafe {
+                rusqlite::ffi::sqlite3_file_control(
+                    conn.handle(),
+                    c"main".as_ptr(),
+                    vars::SQLITE_FCNTL_DATA_VERSION,
+                    (&raw mut version).cast(),
+                )
+            };
+            assert_eq!(rc, vars::SQLITE_OK);
+            version
+        };
+
+        let count = |conn: &Connection| {
+            conn.query_row("select count(*) from t", [], |r| r.get::<_, i64>(0))
+        };
+
+        // the version changes with the connection's own writes
+        let before = data_version(&a);
+        a.execute("insert into t values (1)", [])?;
+        let version = data_version(&a);
+        assert_ne!(version, before);
+        count(&a)?;
+        assert_eq!(data_version(&a), version);
+
+        // and with other connections' writes, once the connection notices them
+        b.execute("insert into t values (2)", [])?;
+        assert_eq!(count(&a)?, 2);
+        assert_ne!(data_version(&a), version);
+        Ok(())
+    }
+
+    #[test]
+    fn seeded_randomness_is_deterministic() -> rusqlite::Result<()> {
+        /// A xorshift PRNG with a fixed seed
+        struct Seeded(u64);
+        impl Hooks for Seeded {
+            fn randomness(&mut self, buf: &mut [u8]) -> VfsResult<usize> {
+                for b in buf.iter_mut() {
+                    self.0 ^= self.0 << 13;
+                    self.0 ^= self.0 >> 7;
+                    self.0 ^= self.0 << 17;
+                    *b = self.0 as u8;
+                }
+                Ok(buf.len())
+            }
+        }
+        let temp_filenames = |vfs: &str| -> rusqlite::Result<Vec<String>> {
+            register_mock(vfs, Seeded(42));
+            let conn = open_mock(&format!("{vfs}.db"), vfs)?;
+            conn.execute("create table t (val int)", [])?;
+            let names = (0..3).map(|_| {
+                let mut p: *mut c_char = null_mut();
+                let rc = unsafe {
+                    rusqlite::ffi::sqlite3_file_control(
+                        conn.handle(),
+                        c"main".as_ptr(),
+                        vars::SQLITE_FCNTL_TEMPFILENAME,
+                        (&raw mut p).cast(),
+                    )
+                };
+                assert_eq!(rc, vars::SQLITE_OK);
+                let name = String::from(unsafe { CStr::from_ptr(p) }.to_str().unwrap());
+                unsafe { rusqlite::ffi::sqlite3_free(p.cast()) };
+                name
+            });
+            Ok(names.collect())
+        };
+
+        // vfs instances with the same seed generate the same names
+        let a = temp_filenames("mock_seeded_a")?;
+        let b = temp_filenames("mock_seeded_b")?;
+        assert_eq!(a, b);
+        assert!(a[0] != a[1] && a[1] != a[2], "{a:?}");
+        Ok(())
+    }
+
+    #[test]
+    fn file_control_has_moved() -> rusqlite::Result<()> {
+        struct H {
+            moved: Arc<Mutex<bool>>,
+        }
+        impl Hooks for H {
+            fn has_moved(&mut self, _: MockHandle) -> VfsResult<bool> {
+                Ok(*self.moved.lock())
+            }
+        }
+        let moved = Arc::new(Mutex::new(false));
+        register_mock("mock_has_moved", H { moved: moved.clone() });
+        let conn = open_mock("has_moved.db", "mock_has_moved")?;
+        conn.execute("create table t (val int)", [])?;
+        let has_moved = || {
+            let mut arg: c_int = -1;
+            let rc = unsafe {
+                rusqlite::ffi::sqlite3_file_control(
+                    conn.handle(),
+                    c"main".as_ptr(),
+                    vars::SQLITE_FCNTL_HAS_MOVED,
+                    (&raw mut arg).cast(),
+                )
+            };
+            assert_eq!(rc, vars::SQLITE_OK);
+            arg
+        };
+
+        assert_eq!(has_moved(), 0);
+        *moved.lock() = true;
+        assert_eq!(has_moved(), 1);
+        Ok(())
+    }
+
+    #[test]
+    fn file_control_lock_state() -> rusqlite::Result<()> {
+        struct H(LockLevel);
+        impl Hooks for H {
+            fn lock(&mut self, _: MockHandle, level: LockLevel) -> VfsResult<()> {
+                self.0 = level;
+                Ok(())
+            }
+            fn lock_state(&mut self, _: MockHandle) -> VfsResult<LockLevel> {
+                Ok(self.0)
+            }
+        }
+        register_mock("mock_lock_state", H(LockLevel::Unlocked));
+        let conn = open_mock("lock_state.db", "mock_lock_state")?;
+        conn.execute("create table t (val int)", [])?;
+        let lock_state = || {
+            let mut arg: c_int = -1;
+            let rc = unsafe {
+                rusqlite::ffi::sqlite3_file_control(
+                    conn.handle(),
+                    c"main".as_ptr(),
+                    vars::SQLITE_FCNTL_LOCKSTATE,
+                    (&raw mut arg).cast(),
+                )
+            };
+            assert_eq!(rc, vars::SQLITE_OK);
+            arg
+        };
+
+        conn.execute_batch("begin immediate")?;
+        assert_eq!(lock_state(), vars::SQLITE_LOCK_RESERVED);
+        conn.execute_batch("commit")?;
+        Ok(())
+    }
+
+    #[test]
+    fn file_control_mmap_size() -> rusqlite::Result<()> {
+        struct H {
+            limit: i64,
+            requests: Arc<Mutex<Vec<Option<i64>>>>,
+        }
+        impl Hooks for H {
+            // SQLite only sends the limit to vfs's providing memory-mapped I/O
+            fn capabilities(&mut self) -> VfsCapabilities {
+                VfsCapabilities::new().fetch()
+            }
+            fn mmap_size(&mut self, _: MockHandle, requested: Option<i64>) -> VfsResult<i64> {
+                self.requests.lock().push(requested);
+                let previous = self.limit;
+                if let Some(limit) = requested {
+                    // this vfs maps at most 32KiB
+                    self.limit = limit.min(32768);
+                }
+                Ok(previous)
+            }
+        }
+        let requests = Arc::new(Mutex::new(Vec::new()));
+        register_mock("mock_mmap_size", H { limit: 0, requests: requests.clone() });
+        let conn = open_mock("mmap_size.db", "mock_mmap_size")?;
+        conn.execute("create table t (val int)", [])?;
+
+        // the pragma sets the limit, then reports the vfs's limit by querying
+        // it with a negative limit
+        let limit: i64 = conn.query_row("pragma mmap_size = 65536", [], |r| r.get(0))?;
+        assert_eq!(limit, 32768);
+        assert!(requests.lock().ends_with(&[Some(65536), None]));
+
+        let limit: i64 = conn.query_row("pragma mmap_size = 0", [], |r| r.get(0))?;
+        assert_eq!(limit, 0);
+        assert!(requests.lock().ends_with(&[Some(0), None]));
+        Ok(())
+    }
+
+    #[test]
+    fn file_control_lock_timeout() -> rusqlite::Result<()> {
+        struct H {
+            timeouts: Arc<Mutex<Vec<i32>>>,
+        }
+        impl Hooks for H {
+            fn lock_timeout(&mut self, _: MockHandle, millis: i32) -> VfsResult<()> {
+                if millis < 0 {
+                    return Err(vars::SQLITE_MISUSE);
+                }
+                self.timeouts.lock().push(millis);
+                Ok(())
+            }
+        }
+        let timeouts = Arc::new(Mutex::new(Vec::new()));
+        register_mock("mock_lock_timeout", H { timeouts: timeouts.clone() });
+        let conn = open_mock("lock_timeout.db", "mock_lock_timeout")?;
+        conn.execute("create table t (val int)", [])?;
+        let set_timeout = |millis: c_int| {
+            let mut arg = millis;
+            let rc = unsafe {
+                rusqlite::ffi::sqlite3_file_control(
+                    conn.handle(),
+                    c"main".as_ptr(),
+                    vars::SQLITE_FCNTL_LOCK_TIMEOUT,
+                    (&raw mut arg).cast(),
+                )
+            };
+            (rc, arg)
+        };
+
+        assert_eq!(set_timeout(500), (vars::SQLITE_OK, 0));
+        assert_eq!(set_timeout(1000), (vars::SQLITE_OK, 500));
+        // a rejected timeout leaves the previous one in place
+        assert_eq!(set_timeout(-1), (vars::SQLITE_MISUSE, -1));
+        assert_eq!(set_timeout(0), (vars::SQLITE_OK, 1000));
+        assert_eq!(*timeouts.lock(), [500, 1000, 0]);
+        Ok(())
+    }
+
+    #[test]
+    fn huge_write_offset() {
+        // on 32-bit targets SQLite's 64-bit offsets can approach usize::MAX,
+        // simulate that by calling the vfs directly
+        let shared = register_mock("mock_huge_offset", NoopHooks);
+        let vfs = MockVfs::new(shared);
+        let opts = OpenOpts::new(
+            vars::SQLITE_OPEN_MAIN_DB | vars::SQLITE_OPEN_READWRITE | vars::SQLITE_OPEN_CREATE,
+        );
+        let mut handle = vfs.open(Some("huge_offset.db"), opts).unwrap();
+        assert_eq!(
+            vfs.write(&mut handle, usize::MAX - 4, &[0; 10]),
+            Err(vars::SQLITE_IOERR_WRITE)
+        );
+        let mut buf = [1; 10];
+        assert_eq!(vfs.read(&mut handle, usize::MAX - 4, &mut buf), Ok(0));
+        assert_eq!(buf, [0; 10]);
+    }
+
+    #[cfg(feature = "tracing")]
+    #[test]
+    fn trampolines_are_traced() -> rusqlite::Result<()> {
+        use tracing::field::{Field, Visit};
+        use tracing::span::{Attributes, Id, Record};
+        use tracing::{Event, Metadata, Subscriber};
+
+        /// records the name and return code of every span
+        type Span = (&'static str, Option<i64>);
+        #[derive(Clone, Default)]
+        struct Spans(Arc<Mutex<Vec<Span>>>);
+        struct Rc<'a>(&'a mut Option<i64>);
+        impl Visit for Rc<'_> {
+            fn record_i64(&mut self, field: &Field, value: i64) {
+                if field.name() == "rc" {
+                    *self.0 = Some(value);
+                }
+            }
+            fn record_debug(&mut self, _: &Field, _: &dyn core::fmt::Debug) {}
+        }
+        impl Subscriber for Spans {
+            fn enabled(&self, _: &Metadata<'_>) -> bool {
+                true
+            }
+            fn new_span(&self, span: &Attributes<'_>) -> Id {
+                let mut spans = self.0.lock();
+                spans.push((span.metadata().name(), None));
+                Id::from_u64(spans.len() as u64)
+            }
+            fn record(&self, span: &Id, values: &Record<'_>) {
+                let mut spans = self.0.lock();
+                let idx = span.into_u64() as usize - 1;
+                values.record(&mut Rc(&mut spans[idx].1));
+            }
+            fn record_follows_from(&self, _: &Id, _: &Id) {}
+            fn event(&self, _: &Event<'_>) {}
+            fn enter(&self, _: &Id) {}
+            fn exit(&self, _: &Id) {}
+        }
+
+        register_mock("mock_traced", NoopHooks);
+        let spans = Spans::default();
+        tracing::subscriber::with_default(spans.clone(), || {
+            let conn = open_mock("traced.db", "mock_traced")?;
+            conn.execute("create table t (val int)", [])?;
+            conn.execute("insert into t values (1)", [])
+        })?;
+
+        let spans = spans.0.lock();
+        for name in ["x_read", "x_write", "x_sync", "x_lock", "x_unlock"] {
+            assert!(
+                spans.contains(&(name, Some(0))),
+                "missing {name} in {spans:?}"
+            );
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn wal_only_rejects_rollback_journals() -> rusqlite::Result<()> {
+        let shared = Arc::new(Mutex::new(MockState::new(Box::new(NoopHooks))));
+        let opts = RegisterOpts { wal_only: true, ..Default::default() };
+        let registration = register_static(
+            CString::new("mock_wal_only").unwrap(),
+            MockVfs::new(shared.clone()),
+            opts,
+        )
+        .unwrap();
+        shared.lock().setup_logger(registration.logger());
+
+        // writing with a rollback journal fails
+        let conn = open_mock("wal_only.db", "mock_wal_only")?;
+        let err = conn.execute("create table t (val int)", []).unwrap_err();
+        assert_eq!(
+            err.sqlite_error_code(),
+            Some(rusqlite::ErrorCode::CannotOpen)
+        );
+
+        // switching to WAL writes the database header, which needs a journal
+        // that doesn't live in a file
+        conn.execute_batch("pragma journal_mode = memory")?;
+        let mode: String = conn.query_row("pragma journal_mode = wal", [], |r| r.get(0))?;
+        assert_eq!(mode, "wal");
+        conn.execute("create table t (val int)", [])?;
+        conn.execute("insert into t values (1)", [])?;
+
+        // switching back to a rollback journal fails
+        let err = conn
+            .query_row("pragma journal_mode = delete", [], |r| {
+                r.get::<_, String>(0)
+            })
+            .unwrap_err();
+        assert_eq!(
+            err.sqlite_error_code(),
+            Some(rusqlite::ErrorCode::CannotOpen)
+        );
+        conn.execute("insert into t values (2)", [])?;
+        Ok(())
+    }
+
+    #[test]
+    fn injected_time_source() -> rusqlite::Result<()> {
+        let shared = Arc::new(Mutex::new(MockState::new(Box::new(NoopHooks))));
+        let opts = RegisterOpts {
+            // 2000-01-01 00:00:00 UTC
+            time_source: Some(|| 946_684_800_000),
+            ..Default::default()
+        };
+        let registration = register_static(
+            CString::new("mock_time_source").unwrap(),
+            MockVfs::new(shared.clone()),
+            opts,
+        )
+        .unwrap();
+        shared.lock().setup_logger(registration.logger());
+
+        let conn = open_mock("time_source.db", "mock_time_source")?;
+        let now: String = conn.query_row("select datetime('now')", [], |r| r.get(0))?;
+        assert_eq!(now, "2000-01-01 00:00:00");
+        Ok(())
+    }
+
+    #[test]
+    fn pragma_results() -> rusqlite::Result<()> {
+        struct H;
+        impl Hooks for H {
+            fn pragma(
+                &mut self,
+                _: MockHandle,
+                pragma: Pragma<'_>,
+            ) -> Result<PragmaResult, PragmaErr> {
+                match pragma.name {
+                    "double" => Ok(PragmaResult::int(pragma.arg_as::<i64>()? * 2)),
+                    "greet" => Ok(PragmaResult::text("hello")),
+                    "percent" => Ok(PragmaResult::text("100% %s %n")),
+                    "noop" => Ok(PragmaResult::none()),
+                    _ => Err(PragmaErr::NotFound),
+                }
+            }
+        }
+        register_mock("mock_pragma_results", H);
+        let conn = open_mock("pragma_results.db", "mock_pragma_results")?;
+
+        // pragma results are always text
+        let double: String = conn.query_row("pragma double = 21", [], |r| r.get(0))?;
+        assert_eq!(double, "42");
+        let greet: String = conn.query_row("pragma greet", [], |r| r.get(0))?;
+        assert_eq!(greet, "hello");
+        // results are copied rather than used as a format string
+        let percent: String = conn.query_row("pragma percent", [], |r| r.get(0))?;
+        assert_eq!(percent, "100% %s %n");
+        conn.execute_batch("pragma noop")?;
+
+        let err = conn.execute_batch("pragma double = abc").unwrap_err();
+        assert!(
+            err.to_string()
+                .contains("invalid argument for pragma double: abc"),
+            "{err}"
+        );
+        let err = conn.execute_batch("pragma double").unwrap_err();
+        assert!(err.to_string().contains("argument required"), "{err}");
+        Ok(())
+    }
+
+    #[test]
+    fn set_config_pragmas() -> rusqlite::Result<()> {
+        struct H {
+            retry_count: Arc<Mutex<u32>>,
+            pragmas: Arc<Mutex<Vec<String>>>,
+        }
+        impl Hooks for H {
+            fn set_config(&mut self, _: MockHandle, key: &str, value: &str) -> VfsResult<()> {
+                match key {
+                    "retry_count" => {
+                        *self.retry_count.lock() =
+                            value.parse().map_err(|_| vars::SQLITE_MISUSE)?;
+                        Ok(())
+                    }
+                    _ => Err(vars::SQLITE_NOTFOUND),
+                }
+            }
+            fn pragma(
+                &mut self,
+                _: MockHandle,
+                pragma: Pragma<'_>,
+            ) -> Result<PragmaResult, PragmaErr> {
+                self.pragmas.lock().push(String::from(pragma.name));
+                Err(PragmaErr::NotFound)
+            }
+        }
+        let retry_count = Arc::new(Mutex::new(0));
+        let pragmas = Arc::new(Mutex::new(Vec::new()));
+        register_mock(
+            "mock_config",
+            H {
+                retry_count: retry_count.clone(),
+                pragmas: pragmas.clone(),
+            },
+        );
+        let conn = open_mock("config.db", "mock_config")?;
+
+        conn.execute_batch(r#"pragma "mock_config.retry_count" = 5"#)?;
+        assert_eq!(*retry_count.lock(), 5);
+        conn.execute_batch(r#"pragma "MOCK_CONFIG.retry_count" = '7'"#)?;
+        assert_eq!(*retry_count.lock(), 7);
+
+        let err = conn
+            .execute_batch(r#"pragma "mock_config.retry_count" = many"#)
+            .unwrap_err();
+        assert!(
+            err.to_string()
+                .contains("invalid value for vfs config retry_count: many"),
+            "{err}"
+        );
+        let err = conn
+            .execute_batch(r#"pragma "mock_config.timeout" = 1"#)
+            .unwrap_err();
+        assert!(
+            err.to_string().contains("unknown vfs config: timeout"),
+            "{err}"
+        );
+        let err = conn
+            .execute_batch(r#"pragma "mock_config.retry_count""#)
+            .unwrap_err();
+        assert!(err.to_string().contains("argument required"), "{err}");
+        assert_eq!(*retry_count.lock(), 7);
+
+        // other pragmas are passed to Vfs::pragma as usual
+        conn.execute_batch(r#"pragma "other_vfs.retry_count" = 1; pragma "mock_config." = 1"#)?;
+        assert_eq!(*pragmas.lock(), ["other_vfs.retry_count", "mock_config."]);
+        Ok(())
+    }
+
+    #[test]
+    fn reserve_bytes_are_set_on_the_connection() -> Result<(), Box<dyn std::error::Error>> {
+        let shared = register_mock("mock_reserve", NoopHooks);
+        let conn = open_mock("reserve.db", "mock_reserve")?;
+        let reserve_bytes = |n: c_int| {
+            let mut n = n;
+            let rc = unsafe {
+                rusqlite::ffi::sqlite3_file_control(
+                    conn.handle(),
+                    c"main".as_ptr(),
+                    vars::SQLITE_FCNTL_RESERVE_BYTES,
+                    (&raw mut n).cast(),
+                )
+            };
+            assert_eq!(rc, vars::SQLITE_OK);
+            n
+        };
+
+        // -1 queries the reserve, anything else sets it before the database
+        // is created, returning the previous value
+        assert_eq!(reserve_bytes(-1), 0);
+        assert_eq!(reserve_bytes(32), 0);
+        conn.execute("create table t (val int)", [])?;
+        assert_eq!(reserve_bytes(-1), 32);
+
+        // the reserve is stored in the database header, next to the page size
+        let page_size: i64 = conn.query_row("pragma page_size", [], |r| r.get(0))?;
+        let vfs = MockVfs::new(shared);
+        let mut handle = vfs
+            .open(
+                Some("reserve.db"),
+                OpenOpts::new(vars::SQLITE_OPEN_MAIN_DB | vars::SQLITE_OPEN_READWRITE),
+            )
+            .unwrap();
+        let mut header = [0; 100];
+        assert_eq!(vfs.read(&mut handle, 0, &mut header), Ok(100));
+        assert_eq!(header[20], 32);
+        assert_eq!(
+            i64::from(u16::from_be_bytes([header[16], header[17]])),
+            page_size
+        );
+        vfs.close(handle).unwrap();
+        Ok(())
+    }
 }
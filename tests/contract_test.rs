@@ -41,7 +41,7 @@ impl Vfs for AlwaysFailOpenVfs {
     fn open(&self, _: Option<&str>, _: OpenOpts) -> VfsResult<Self::Handle> {
         Err(vars::SQLITE_CANTOPEN)
     }
-    fn delete(&self, _: &str) -> VfsResult<()> {
+    fn delete(&self, _: &str, _: bool) -> VfsResult<()> {
         Ok(())
     }
     fn access(&self, _: &str, _: AccessFlags) -> VfsResult<bool> {
@@ -76,12 +76,8 @@ impl Vfs for AlwaysFailOpenVfs {
 #[test]
 fn xopen_failure_sets_pmethods_null() {
     let name = unique_name("failopen");
-    sqlite_plugin::vfs::register_static(
-        name.clone(),
-        AlwaysFailOpenVfs,
-        RegisterOpts { make_default: false },
-    )
-    .expect("register");
+    sqlite_plugin::vfs::register_static(name.clone(), AlwaysFailOpenVfs, RegisterOpts::default())
+        .expect("register");
 
     unsafe {
         let vfs = ffi::sqlite3_vfs_find(name.as_ptr());
@@ -123,7 +119,7 @@ impl Vfs for ShortReadVfs {
     fn open(&self, _: Option<&str>, _: OpenOpts) -> VfsResult<Self::Handle> {
         Ok(ZeroHandle)
     }
-    fn delete(&self, _: &str) -> VfsResult<()> {
+    fn delete(&self, _: &str, _: bool) -> VfsResult<()> {
         Ok(())
     }
     fn access(&self, _: &str, _: AccessFlags) -> VfsResult<bool> {
@@ -138,10 +134,10 @@ impl Vfs for ShortReadVfs {
     fn write(&self, _: &mut Self::Handle, _: usize, d: &[u8]) -> VfsResult<usize> {
         Ok(d.len())
     }
-    fn read(&self, _: &mut Self::Handle, _: usize, buf: &mut [u8]) -> VfsResult<usize> {
+    fn read(&self, _: &mut Self::Handle, offset: usize, buf: &mut [u8]) -> VfsResult<usize> {
         // Fill only the "read" prefix with a sentinel; leave the tail untouched
         // so we can verify the wrapper (not us) is the one zero-filling.
-        let n = self.bytes.min(buf.len());
+        let n = self.bytes.saturating_sub(offset).min(buf.len());
         buf[..n].fill(0xCC);
         Ok(n)
     }
@@ -165,7 +161,7 @@ fn xread_short_read_zero_fills_and_reports_status() {
     sqlite_plugin::vfs::register_static(
         name.clone(),
         ShortReadVfs { bytes: 4 },
-        RegisterOpts { make_default: false },
+        RegisterOpts::default(),
     )
     .expect("register");
 
@@ -12,7 +12,7 @@ use std::ptr::NonNull;
 use std::sync::Arc;
 use std::sync::atomic::{AtomicU64, Ordering};
 
-use sqlite_plugin::flags::{AccessFlags, LockLevel, OpenOpts};
+use sqlite_plugin::flags::{AccessFlags, LockLevel, OpenOpts, SyncFlags, VfsCapabilities};
 use sqlite_plugin::vars;
 use sqlite_plugin::vfs::{RegisterOpts, Vfs, VfsHandle, VfsResult};
 
@@ -62,6 +62,10 @@ struct FetchVfs {
 impl Vfs for FetchVfs {
     type Handle = Handle;
 
+    fn capabilities(&self) -> VfsCapabilities {
+        VfsCapabilities::new().fetch()
+    }
+
     fn open(&self, path: Option<&str>, _: OpenOpts) -> VfsResult<Self::Handle> {
         let p = self.dir.join(path.unwrap_or("temp.db"));
         if let Some(d) = p.parent() {
@@ -81,7 +85,7 @@ impl Vfs for FetchVfs {
         })
     }
 
-    fn delete(&self, path: &str) -> VfsResult<()> {
+    fn delete(&self, path: &str, _: bool) -> VfsResult<()> {
         let _ = fs::remove_file(self.dir.join(path));
         Ok(())
     }
@@ -132,7 +136,7 @@ impl Vfs for FetchVfs {
     fn check_reserved_lock(&self, _: &mut Self::Handle) -> VfsResult<bool> {
         Ok(false)
     }
-    fn sync(&self, h: &mut Self::Handle) -> VfsResult<()> {
+    fn sync(&self, h: &mut Self::Handle, _: SyncFlags) -> VfsResult<()> {
         h.file.sync_all().map_err(|_| vars::SQLITE_IOERR_FSYNC)
     }
     fn close(&self, _: Self::Handle) -> VfsResult<()> {
@@ -202,7 +206,7 @@ fn setup(prefix: &str) -> (tempfile::TempDir, String, Arc<FetchCounters>) {
     sqlite_plugin::vfs::register_static(
         std::ffi::CString::new(name.as_str()).expect("name"),
         vfs,
-        RegisterOpts { make_default: false },
+        RegisterOpts::default(),
     )
     .expect("register");
     (dir, name, counters)
@@ -0,0 +1,170 @@
+//! Tests for `SqliteLogger`, using a process-wide `SQLite` log callback to
+//! capture every message passed to `sqlite3_log`. `SQLite` invokes the log
+//! callback on the calling thread, so messages are captured per thread to
+//! keep tests independent.
+
+use std::cell::RefCell;
+use std::ffi::CString;
+use std::os::raw::c_int;
+use std::sync::Once;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use sqlite_plugin::flags::{AccessFlags, LockLevel, OpenOpts};
+use sqlite_plugin::logger::{DEFAULT_MAX_LOG_LEN, SqliteLogLevel, SqliteLogger};
+use sqlite_plugin::vars;
+use sqlite_plugin::vfs::{RegisterOpts, Vfs, VfsHandle, VfsResult};
+
+static VFS_COUNTER: AtomicU64 = AtomicU64::new(1);
+
+thread_local! {
+    static CAPTURED: RefCell<Vec<(c_int, String)>> = const { RefCell::new(Vec::new()) };
+}
+
+fn capture(code: c_int, msg: &str) {
+    CAPTURED.with_borrow_mut(|c| c.push((code, msg.to_owned())));
+}
+
+/// Drains all messages logged so far by the current thread.
+fn captured() -> Vec<(c_int, String)> {
+    CAPTURED.take()
+}
+
+fn messages() -> Vec<String> {
+    captured().into_iter().map(|(_, msg)| msg).collect()
+}
+
+struct NoopHandle;
+impl VfsHandle for NoopHandle {
+    fn readonly(&self) -> bool {
+        false
+    }
+    fn in_memory(&self) -> bool {
+        false
+    }
+}
+
+struct NoopVfs;
+impl Vfs for NoopVfs {
+    type Handle = NoopHandle;
+    fn open(&self, _: Option<&str>, _: OpenOpts) -> VfsResult<Self::Handle> {
+        Err(vars::SQLITE_CANTOPEN)
+    }
+    fn delete(&self, _: &str, _: bool) -> VfsResult<()> {
+        Ok(())
+    }
+    fn access(&self, _: &str, _: AccessFlags) -> VfsResult<bool> {
+        Ok(false)
+    }
+    fn file_size(&self, _: &mut Self::Handle) -> VfsResult<usize> {
+        Ok(0)
+    }
+    fn truncate(&self, _: &mut Self::Handle, _: usize) -> VfsResult<()> {
+        Ok(())
+    }
+    fn write(&self, _: &mut Self::Handle, _: usize, d: &[u8]) -> VfsResult<usize> {
+        Ok(d.len())
+    }
+    fn read(&self, _: &mut Self::Handle, _: usize, _: &mut [u8]) -> VfsResult<usize> {
+        Ok(0)
+    }
+    fn lock(&self, _: &mut Self::Handle, _: LockLevel) -> VfsResult<()> {
+        Ok(())
+    }
+    fn unlock(&self, _: &mut Self::Handle, _: LockLevel) -> VfsResult<()> {
+        Ok(())
+    }
+    fn check_reserved_lock(&self, _: &mut Self::Handle) -> VfsResult<bool> {
+        Ok(false)
+    }
+    fn close(&self, _: Self::Handle) -> VfsResult<()> {
+        Ok(())
+    }
+}
+
+fn setup() -> SqliteLogger {
+    static INIT: Once = Once::new();
+    INIT.call_once(|| {
+        // SQLITE_CONFIG_LOG must be set before SQLite is initialized, which
+        // happens implicitly the first time we register a vfs below.
+        unsafe { rusqlite::trace::config_log(Some(capture)) }.expect("config_log");
+    });
+    let n = VFS_COUNTER.fetch_add(1, Ordering::Relaxed);
+    let name = CString::new(format!("logger_{n}")).expect("vfs name");
+    let logger = sqlite_plugin::vfs::register_static(name, NoopVfs, RegisterOpts::default())
+        .expect("register")
+        .logger();
+    captured();
+    logger
+}
+
+#[test]
+fn splits_lines() {
+    let logger = setup();
+    logger.log(SqliteLogLevel::Warn, "one\ntwo\n\nthree\n");
+    assert_eq!(
+        captured(),
+        vec![
+            (vars::SQLITE_WARNING, "one".to_owned()),
+            (vars::SQLITE_WARNING, "two".to_owned()),
+            (vars::SQLITE_WARNING, "three".to_owned()),
+        ]
+    );
+}
+
+#[test]
+fn info_and_debug_are_prefixed_notices() {
+    let logger = setup().with_max_len(10);
+    logger.log(SqliteLogLevel::Info, "started");
+    logger.log(SqliteLogLevel::Debug, "0123456789");
+    assert_eq!(
+        captured(),
+        vec![
+            (vars::SQLITE_NOTICE, "info: star".to_owned()),
+            (vars::SQLITE_NOTICE, "info: ted".to_owned()),
+            (vars::SQLITE_NOTICE, "debug: 012".to_owned()),
+            (vars::SQLITE_NOTICE, "debug: 345".to_owned()),
+            (vars::SQLITE_NOTICE, "debug: 678".to_owned()),
+            (vars::SQLITE_NOTICE, "debug: 9".to_owned()),
+        ]
+    );
+}
+
+#[test]
+fn chunks_long_lines() {
+    let logger = setup().with_max_len(4);
+    logger.log(SqliteLogLevel::Notice, "0123456789\nabc");
+    assert_eq!(messages(), vec!["0123", "4567", "89", "abc"]);
+}
+
+#[test]
+fn chunks_respect_utf8() {
+    // each 'é' is two bytes, so a chunk of 5 bytes must back off to 4
+    let logger = setup().with_max_len(5);
+    logger.log(SqliteLogLevel::Notice, "ééééé");
+    assert_eq!(messages(), vec!["éé", "éé", "é"]);
+}
+
+#[test]
+fn default_max_len() {
+    let logger = setup();
+    logger.log(
+        SqliteLogLevel::Notice,
+        &"x".repeat(DEFAULT_MAX_LOG_LEN * 2 + 1),
+    );
+    let lens: Vec<usize> = messages().iter().map(String::len).collect();
+    assert_eq!(lens, vec![DEFAULT_MAX_LOG_LEN, DEFAULT_MAX_LOG_LEN, 1]);
+}
+
+#[test]
+fn percent_is_not_a_format_specifier() {
+    let logger = setup();
+    logger.log(SqliteLogLevel::Notice, "%s %d %n");
+    assert_eq!(messages(), vec!["%s %d %n"]);
+}
+
+#[test]
+fn interior_nul_is_dropped() {
+    let logger = setup();
+    logger.log_bytes(SqliteLogLevel::Notice, b"before\0after");
+    assert!(captured().is_empty());
+}